@@ -0,0 +1,182 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The Fixed ACPI Description Table (FADT).
+//!
+//! The FADT describes the fixed power management hardware: the PM timer,
+//! the PM1 event and control blocks, the reset register, and so on. Its
+//! signature is `FACP`, for historical reasons.
+//!
+//! The FADT has grown with every revision of the specification, so every
+//! field past the ACPI 1.0 ones is optional, and reads as `None` if the
+//! table is too short to contain it.
+//!
+//! Note that actually powering the system off requires the `\_S5` sleep
+//! type values from the DSDT, which is AML bytecode; that's out of scope
+//! for now.
+//!
+//! Refer to section 5.2.9, "Fixed ACPI Description Table (FADT)" of the
+//! ACPI specification.
+use memory::PAddr;
+use super::{Bytes, GenericAddress, Sdt};
+
+/// The FADT's signature.
+pub const SIGNATURE: [u8; 4] = *b"FACP";
+
+/// `IAPC_BOOT_ARCH`: the system has legacy devices on the LPC or ISA bus.
+pub const LEGACY_DEVICES: u16 = 1 << 0;
+/// `IAPC_BOOT_ARCH`: the system has an 8042 keyboard controller.
+pub const HAS_8042: u16 = 1 << 1;
+
+/// Fixed feature flag: the reset register is supported.
+pub const RESET_REG_SUP: u32 = 1 << 10;
+
+/// The Fixed ACPI Description Table.
+#[derive(Clone, Debug)]
+pub struct Fadt<'a> { table: Sdt<'a> }
+
+impl<'a> Fadt<'a> {
+    /// Wraps a table as a FADT.
+    ///
+    /// # Returns
+    /// + `Err` if the table has the wrong signature.
+    pub fn new(table: Sdt<'a>) -> Result<Self, &'static str> {
+        if table.signature() != SIGNATURE {
+            Err("Table is not a FADT!")
+        } else {
+            Ok(Fadt { table: table })
+        }
+    }
+
+    /// Returns the underlying table.
+    #[inline] pub fn table(&self) -> &Sdt<'a> { &self.table }
+
+    #[inline]
+    fn bytes(&self) -> &'a [u8] { self.table.bytes() }
+
+    /// Returns the 32-bit physical address of the FACS.
+    #[inline]
+    pub fn firmware_ctrl(&self) -> Option<PAddr> {
+        self.bytes().u32_at(36).map(|addr| PAddr::from(addr as u64))
+    }
+
+    /// Returns the physical address of the DSDT.
+    ///
+    /// This is the 64-bit `X_DSDT` field, if present and nonzero, or the
+    /// 32-bit `DSDT` field otherwise.
+    pub fn dsdt(&self) -> Option<PAddr> {
+        self.bytes().u64_at(140)
+            .and_then(|addr| if addr == 0 { None } else { Some(addr) })
+            .or_else(|| self.bytes().u32_at(40).map(|addr| addr as u64))
+            .map(PAddr::from)
+    }
+
+    /// Returns the interrupt the SCI is wired to (in 8259 mode).
+    #[inline]
+    pub fn sci_interrupt(&self) -> Option<u16> { self.bytes().u16_at(46) }
+
+    /// Returns the I/O port of the SMI command port.
+    ///
+    /// This is zero if the system doesn't support System Management mode.
+    #[inline]
+    pub fn smi_command(&self) -> Option<u32> { self.bytes().u32_at(48) }
+
+    /// Returns the value to write to the SMI command port to enable ACPI.
+    #[inline]
+    pub fn acpi_enable(&self) -> Option<u8> { self.bytes().u8_at(52) }
+
+    /// Returns the value to write to the SMI command port to disable ACPI.
+    #[inline]
+    pub fn acpi_disable(&self) -> Option<u8> { self.bytes().u8_at(53) }
+
+    /// Returns the I/O port of the PM1a event register block.
+    #[inline]
+    pub fn pm1a_event_block(&self) -> Option<u32> { self.bytes().u32_at(56) }
+
+    /// Returns the I/O port of the PM1b event register block, if any.
+    #[inline]
+    pub fn pm1b_event_block(&self) -> Option<u32> {
+        self.bytes().u32_at(60).and_then(nonzero)
+    }
+
+    /// Returns the I/O port of the PM1a control register block.
+    #[inline]
+    pub fn pm1a_control_block(&self) -> Option<u32> {
+        self.bytes().u32_at(64)
+    }
+
+    /// Returns the I/O port of the PM1b control register block, if any.
+    #[inline]
+    pub fn pm1b_control_block(&self) -> Option<u32> {
+        self.bytes().u32_at(68).and_then(nonzero)
+    }
+
+    /// Returns the I/O port of the PM timer, if there is one.
+    #[inline]
+    pub fn pm_timer_block(&self) -> Option<u32> {
+        self.bytes().u32_at(76).and_then(nonzero)
+    }
+
+    /// Returns the length of the PM1 control register block, in bytes.
+    #[inline]
+    pub fn pm1_control_length(&self) -> Option<u8> { self.bytes().u8_at(89) }
+
+    /// Returns the index of the century in the RTC's CMOS RAM, if any.
+    #[inline]
+    pub fn century(&self) -> Option<u8> {
+        self.bytes().u8_at(108).and_then(|c| if c == 0 { None }
+                                             else { Some(c) })
+    }
+
+    /// Returns the IA-PC boot architecture flags (`IAPC_BOOT_ARCH`).
+    ///
+    /// This field was added in ACPI 2.0.
+    #[inline]
+    pub fn boot_arch_flags(&self) -> Option<u16> {
+        if self.table.revision() < 2 { None }
+        else { self.bytes().u16_at(109) }
+    }
+
+    /// Returns true if the system has an 8042 keyboard controller.
+    ///
+    /// Systems with ACPI 1.0 FADTs are assumed to have one.
+    #[inline]
+    pub fn has_8042(&self) -> bool {
+        self.boot_arch_flags().map(|flags| flags & HAS_8042 != 0)
+            .unwrap_or(true)
+    }
+
+    /// Returns the fixed feature flags.
+    #[inline]
+    pub fn flags(&self) -> Option<u32> { self.bytes().u32_at(112) }
+
+    /// Returns the location of the reset register, if it's supported.
+    ///
+    /// This field was added in ACPI 2.0.
+    pub fn reset_register(&self) -> Option<GenericAddress> {
+        self.flags()
+            .and_then(|flags| if flags & RESET_REG_SUP == 0 { None }
+                              else { GenericAddress::parse(self.bytes(), 116) })
+    }
+
+    /// Returns the value to write to the reset register to reset the system.
+    #[inline]
+    pub fn reset_value(&self) -> Option<u8> { self.bytes().u8_at(128) }
+
+    /// Returns the location of the PM1a control register block.
+    ///
+    /// This is the 64-bit `X_PM1a_CNT_BLK` field, which was added in ACPI
+    /// 2.0.
+    #[inline]
+    pub fn x_pm1a_control_block(&self) -> Option<GenericAddress> {
+        GenericAddress::parse(self.bytes(), 172)
+    }
+}
+
+#[inline]
+fn nonzero(n: u32) -> Option<u32> { if n == 0 { None } else { Some(n) } }
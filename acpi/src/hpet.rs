@@ -0,0 +1,94 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The High Precision Event Timer description table.
+//!
+//! Refer to section 3.2.4, "The ACPI 2.0 HPET Description Table (HPET)" of
+//! the _IA-PC HPET (High Precision Event Timers) Specification_.
+use super::{Bytes, GenericAddress, Sdt};
+
+/// The HPET table's signature.
+pub const SIGNATURE: [u8; 4] = *b"HPET";
+
+/// Length of the HPET table, in bytes.
+const LEN: usize = 56;
+
+/// The HPET description table.
+#[derive(Clone, Debug)]
+pub struct Hpet<'a> { table: Sdt<'a> }
+
+impl<'a> Hpet<'a> {
+    /// Wraps a table as an HPET table.
+    ///
+    /// # Returns
+    /// + `Err` if the table has the wrong signature or is too short.
+    pub fn new(table: Sdt<'a>) -> Result<Self, &'static str> {
+        if table.signature() != SIGNATURE {
+            Err("Table is not an HPET table!")
+        } else if table.length() < LEN {
+            Err("HPET table is truncated!")
+        } else {
+            Ok(Hpet { table: table })
+        }
+    }
+
+    /// Returns the underlying table.
+    #[inline] pub fn table(&self) -> &Sdt<'a> { &self.table }
+
+    /// Returns the event timer block ID.
+    ///
+    /// This is a copy of the low 32 bits of the HPET's capabilities
+    /// register.
+    #[inline]
+    pub fn event_timer_block_id(&self) -> u32 {
+        self.table.bytes().u32_at(36).unwrap()
+    }
+
+    /// Returns the location of the HPET's registers.
+    #[inline]
+    pub fn base_address(&self) -> GenericAddress {
+        GenericAddress::parse(self.table.bytes(), 40).unwrap()
+    }
+
+    /// Returns the HPET's sequence number.
+    #[inline] pub fn number(&self) -> u8 { self.table.bytes()[52] }
+
+    /// Returns the minimum clock tick in periodic mode.
+    #[inline]
+    pub fn minimum_tick(&self) -> u16 {
+        self.table.bytes().u16_at(53).unwrap()
+    }
+
+    /// Returns the page protection and OEM attributes.
+    #[inline]
+    pub fn page_protection(&self) -> u8 { self.table.bytes()[55] }
+
+    /// Returns the number of comparators (timers) in the first timer block.
+    #[inline]
+    pub fn comparators(&self) -> u8 {
+        ((self.event_timer_block_id() >> 8) & 0x1f) as u8 + 1
+    }
+
+    /// Returns true if the main counter is 64 bits wide.
+    #[inline]
+    pub fn is_64bit(&self) -> bool {
+        self.event_timer_block_id() & (1 << 13) != 0
+    }
+
+    /// Returns true if the HPET can replace the PIT and RTC interrupts.
+    #[inline]
+    pub fn legacy_replacement(&self) -> bool {
+        self.event_timer_block_id() & (1 << 15) != 0
+    }
+
+    /// Returns the PCI vendor ID of the HPET.
+    #[inline]
+    pub fn vendor_id(&self) -> u16 {
+        (self.event_timer_block_id() >> 16) as u16
+    }
+}
@@ -0,0 +1,215 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Parsing the Advanced Configuration and Power Interface (ACPI) tables.
+//!
+//! The firmware describes the hardware topology in a tree of tables: the
+//! Root System Description Pointer (RSDP) points to a root table (the RSDT,
+//! or the XSDT on ACPI 2.0 and later), which points to every other table.
+//! We're interested in:
+//!
+//!  + the MADT, which lists the CPUs' local APICs and the I/O APICs
+//!  + the FADT, which describes the power management hardware
+//!  + the HPET table, which describes the High Precision Event Timer
+//!
+//! Tables are parsed from byte slices, which are obtained through the
+//! [`PhysicalMemory`] trait, so this crate doesn't need to know anything
+//! about paging (and can be tested on the host).
+//!
+//! For more information, refer to the
+//! [ACPI Specification](http://www.uefi.org/specifications), chapter 5,
+//! "ACPI Software Programming Model".
+//!
+//! [`PhysicalMemory`]: trait.PhysicalMemory.html
+#![no_std]
+
+extern crate memory;
+#[cfg(test)] #[macro_use] extern crate std;
+
+use memory::PAddr;
+
+pub mod rsdp;
+pub mod sdt;
+pub mod madt;
+pub mod fadt;
+pub mod hpet;
+
+#[cfg(test)] mod test;
+
+pub use self::rsdp::Rsdp;
+pub use self::sdt::Sdt;
+pub use self::madt::Madt;
+pub use self::fadt::Fadt;
+pub use self::hpet::Hpet;
+
+/// Access to the physical memory that ACPI tables live in.
+pub trait PhysicalMemory<'a> {
+    /// Returns the `len` bytes of physical memory starting at `addr`.
+    fn read(&mut self, addr: PAddr, len: usize)
+           -> Result<&'a [u8], &'static str>;
+}
+
+/// Returns true if the bytes in `bytes` sum to zero.
+///
+/// Every ACPI table is checksummed this way.
+#[inline]
+pub fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Reads little-endian integers out of a table.
+///
+/// Reads past the end of the table return `None`, as later revisions of a
+/// table often append fields.
+trait Bytes {
+    fn u8_at(&self, offset: usize) -> Option<u8>;
+    fn u16_at(&self, offset: usize) -> Option<u16>;
+    fn u32_at(&self, offset: usize) -> Option<u32>;
+    fn u64_at(&self, offset: usize) -> Option<u64>;
+}
+
+impl Bytes for [u8] {
+    #[inline]
+    fn u8_at(&self, offset: usize) -> Option<u8> {
+        self.get(offset).cloned()
+    }
+
+    #[inline]
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        self.get(offset .. offset + 2)
+            .map(|b| b[0] as u16 | (b[1] as u16) << 8)
+    }
+
+    #[inline]
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        self.u16_at(offset).and_then(|low|
+            self.u16_at(offset + 2).map(|high| low as u32 | (high as u32) << 16))
+    }
+
+    #[inline]
+    fn u64_at(&self, offset: usize) -> Option<u64> {
+        self.u32_at(offset).and_then(|low|
+            self.u32_at(offset + 4).map(|high| low as u64 | (high as u64) << 32))
+    }
+}
+
+/// The address spaces a `GenericAddress` may refer to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AddressSpace { /// Physical memory
+                        SystemMemory
+                      , /// I/O ports
+                        SystemIo
+                      , /// PCI configuration space
+                        PciConfig
+                      , /// Any other address space
+                        Other(u8)
+                      }
+
+/// A Generic Address Structure, describing the location of a register.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct GenericAddress { /// The address space the register is in
+                            pub space: AddressSpace
+                          , /// The size of the register, in bits
+                            pub bit_width: u8
+                          , /// The offset of the register, in bits
+                            pub bit_offset: u8
+                          , /// The access size
+                            pub access_size: u8
+                          , /// The address of the register
+                            pub address: u64
+                          }
+
+impl GenericAddress {
+    /// Parses the Generic Address Structure at `offset` in `bytes`.
+    fn parse(bytes: &[u8], offset: usize) -> Option<Self> {
+        bytes.u64_at(offset + 4).map(|address|
+            GenericAddress {
+                space: match bytes[offset] { 0 => AddressSpace::SystemMemory
+                                           , 1 => AddressSpace::SystemIo
+                                           , 2 => AddressSpace::PciConfig
+                                           , n => AddressSpace::Other(n)
+                                           }
+              , bit_width: bytes[offset + 1]
+              , bit_offset: bytes[offset + 2]
+              , access_size: bytes[offset + 3]
+              , address: address
+              })
+    }
+}
+
+/// The ACPI tables that SOS knows how to use.
+#[derive(Clone, Debug)]
+pub struct Tables<'a> { /// The RSDP
+                        pub rsdp: Rsdp<'a>
+                      , /// The MADT, if present
+                        pub madt: Option<Madt<'a>>
+                      , /// The FADT, if present
+                        pub fadt: Option<Fadt<'a>>
+                      , /// The HPET table, if present
+                        pub hpet: Option<Hpet<'a>>
+                      }
+
+impl<'a> Tables<'a> {
+    /// Parses the tables pointed to by the RSDP at `rsdp`.
+    ///
+    /// The root table and every table it points to are read from `memory`,
+    /// and their checksums are validated. Tables with invalid checksums or
+    /// unknown signatures are skipped.
+    ///
+    /// # Returns
+    /// + `Ok(Tables)` if the RSDP and root table are valid
+    /// + `Err` if they are not, or if they couldn't be read.
+    pub fn parse<M>(rsdp_addr: PAddr, memory: &mut M)
+                   -> Result<Self, &'static str>
+    where M: PhysicalMemory<'a> {
+        let mut rsdp = Rsdp::parse(memory.read(rsdp_addr, rsdp::V1_LEN)?)?;
+        if rsdp.revision() >= 2 {
+            // re-read the RSDP, including the extended fields
+            rsdp = Rsdp::parse(memory.read(rsdp_addr, rsdp::V2_LEN)?)?;
+        }
+
+        let (root_addr, entry_size) = match rsdp.xsdt_address() {
+            Some(xsdt) => (xsdt, 8)
+          , None => (rsdp.rsdt_address(), 4)
+        };
+        let root = read_table(root_addr, memory)?;
+        match (root.signature(), entry_size) {
+            (sdt::XSDT, 8) | (sdt::RSDT, 4) => {}
+          , _ => return Err("Root table has the wrong signature!")
+        }
+
+        let mut tables = Tables { rsdp: rsdp
+                                , madt: None
+                                , fadt: None
+                                , hpet: None
+                                };
+        for addr in root.entries(entry_size) {
+            // skip tables we can't read or that fail the checksum.
+            let table = match read_table(addr, memory) {
+                Ok(table) => table
+              , Err(_) => continue
+            };
+            match table.signature() {
+                madt::SIGNATURE => tables.madt = Madt::new(table).ok()
+              , fadt::SIGNATURE => tables.fadt = Fadt::new(table).ok()
+              , hpet::SIGNATURE => tables.hpet = Hpet::new(table).ok()
+              , _ => {}
+            }
+        }
+        Ok(tables)
+    }
+}
+
+/// Reads and validates the table at `addr`.
+fn read_table<'a, M>(addr: PAddr, memory: &mut M)
+                    -> Result<Sdt<'a>, &'static str>
+where M: PhysicalMemory<'a> {
+    let header = memory.read(addr, sdt::HEADER_LEN)?;
+    let len = header.u32_at(4).ok_or("ACPI table header is truncated!")?;
+    Sdt::parse(addr, memory.read(addr, len as usize)?)
+}
@@ -0,0 +1,325 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The Multiple APIC Description Table (MADT).
+//!
+//! The MADT lists every processor's local APIC, every I/O APIC, and how the
+//! legacy ISA IRQs are routed to global system interrupts (GSIs).
+//!
+//! Refer to section 5.2.12, "Multiple APIC Description Table (MADT)" of
+//! the ACPI specification.
+use memory::PAddr;
+use super::{Bytes, Sdt};
+
+/// The MADT's signature.
+pub const SIGNATURE: [u8; 4] = *b"APIC";
+
+/// Offset of the first interrupt controller structure in the MADT.
+const ENTRIES_OFFSET: usize = 44;
+
+/// The processor is enabled.
+pub const PROCESSOR_ENABLED: u32 = 1 << 0;
+/// The processor is disabled, but may be brought online.
+pub const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// The system also has dual 8259 PICs.
+pub const PCAT_COMPAT: u32 = 1 << 0;
+
+/// The Multiple APIC Description Table.
+#[derive(Clone, Debug)]
+pub struct Madt<'a> { table: Sdt<'a> }
+
+impl<'a> Madt<'a> {
+    /// Wraps a table as a MADT.
+    ///
+    /// # Returns
+    /// + `Err` if the table has the wrong signature or is too short.
+    pub fn new(table: Sdt<'a>) -> Result<Self, &'static str> {
+        if table.signature() != SIGNATURE {
+            Err("Table is not a MADT!")
+        } else if table.length() < ENTRIES_OFFSET {
+            Err("MADT is truncated!")
+        } else {
+            Ok(Madt { table: table })
+        }
+    }
+
+    /// Returns the underlying table.
+    #[inline] pub fn table(&self) -> &Sdt<'a> { &self.table }
+
+    /// Returns the physical address of the local APIC.
+    ///
+    /// This is the address in the MADT header, unless a local APIC address
+    /// override entry is present.
+    pub fn local_apic_address(&self) -> PAddr {
+        self.entries()
+            .filter_map(|entry| match entry {
+                Entry::LocalApicAddressOverride { address } => Some(address)
+              , _ => None
+            })
+            .next()
+            .unwrap_or_else(|| {
+                PAddr::from(self.table.bytes().u32_at(36).unwrap() as u64)
+            })
+    }
+
+    /// Returns true if the system also has dual 8259 PICs.
+    #[inline]
+    pub fn has_legacy_pics(&self) -> bool {
+        self.table.bytes().u32_at(40).unwrap() & PCAT_COMPAT != 0
+    }
+
+    /// Returns an iterator over the MADT's interrupt controller structures.
+    #[inline]
+    pub fn entries(&self) -> Entries<'a> {
+        Entries { bytes: &self.table.bytes()[ENTRIES_OFFSET..] }
+    }
+
+    /// Returns an iterator over the processors that are enabled or may be
+    /// brought online.
+    pub fn processors(&self) -> Processors<'a> {
+        Processors { entries: self.entries() }
+    }
+
+    /// Returns an iterator over the I/O APICs.
+    pub fn io_apics(&self) -> IoApics<'a> {
+        IoApics { entries: self.entries() }
+    }
+
+    /// Returns an iterator over the interrupt source overrides.
+    pub fn interrupt_overrides(&self) -> InterruptOverrides<'a> {
+        InterruptOverrides { entries: self.entries() }
+    }
+}
+
+/// An interrupt controller structure in the MADT.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Entry {
+    /// A processor's local APIC.
+    ProcessorLocalApic { /// The ACPI processor UID
+                         processor_id: u8
+                       , /// The processor's local APIC ID
+                         apic_id: u8
+                       , /// Flags (`PROCESSOR_ENABLED`, etc)
+                         flags: u32
+                       }
+  , /// An I/O APIC.
+    IoApic { /// The I/O APIC's ID
+             id: u8
+           , /// The physical address of the I/O APIC's registers
+             address: PAddr
+           , /// The first GSI handled by this I/O APIC
+             gsi_base: u32
+           }
+  , /// An ISA IRQ that's not identity-mapped to a GSI.
+    InterruptSourceOverride { /// The bus (always 0, for ISA)
+                              bus: u8
+                            , /// The ISA IRQ
+                              source: u8
+                            , /// The GSI the IRQ is routed to
+                              gsi: u32
+                            , /// MPS INTI flags (polarity and trigger mode)
+                              flags: u16
+                            }
+  , /// A local APIC's NMI pin.
+    LocalApicNmi { /// The ACPI processor UID, or `0xff` for all processors
+                   processor_id: u8
+                 , /// MPS INTI flags (polarity and trigger mode)
+                   flags: u16
+                 , /// The local APIC LINT pin the NMI is connected to
+                   lint: u8
+                 }
+  , /// A 64-bit physical address for the local APIC.
+    LocalApicAddressOverride { /// The physical address of the local APIC
+                               address: PAddr
+                             }
+  , /// A processor's local x2APIC.
+    ProcessorLocalX2Apic { /// The processor's x2APIC ID
+                           x2apic_id: u32
+                         , /// Flags (`PROCESSOR_ENABLED`, etc)
+                           flags: u32
+                         , /// The ACPI processor UID
+                           processor_uid: u32
+                         }
+  , /// An entry type we don't know how to parse.
+    Unknown { /// The entry's type
+              kind: u8
+            , /// The entry's length
+              length: u8
+            }
+}
+
+impl Entry {
+    /// Parses an entry from `bytes`, which are exactly one entry long.
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let kind = bytes[0];
+        match kind {
+            0 => bytes.u32_at(4).map(|flags|
+                    Entry::ProcessorLocalApic { processor_id: bytes[2]
+                                              , apic_id: bytes[3]
+                                              , flags: flags
+                                              })
+          , 1 => bytes.u32_at(4).and_then(|address|
+                    bytes.u32_at(8).map(|gsi_base|
+                        Entry::IoApic { id: bytes[2]
+                                      , address: PAddr::from(address as u64)
+                                      , gsi_base: gsi_base
+                                      }))
+          , 2 => bytes.u32_at(4).and_then(|gsi|
+                    bytes.u16_at(8).map(|flags|
+                        Entry::InterruptSourceOverride { bus: bytes[2]
+                                                       , source: bytes[3]
+                                                       , gsi: gsi
+                                                       , flags: flags
+                                                       }))
+          , 4 => bytes.u16_at(3).and_then(|flags|
+                    bytes.u8_at(5).map(|lint|
+                        Entry::LocalApicNmi { processor_id: bytes[2]
+                                            , flags: flags
+                                            , lint: lint
+                                            }))
+          , 5 => bytes.u64_at(4).map(|address|
+                    Entry::LocalApicAddressOverride {
+                        address: PAddr::from(address)
+                    })
+          , 9 => bytes.u32_at(4).and_then(|x2apic_id|
+                    bytes.u32_at(8).and_then(|flags|
+                        bytes.u32_at(12).map(|uid|
+                            Entry::ProcessorLocalX2Apic {
+                                x2apic_id: x2apic_id
+                              , flags: flags
+                              , processor_uid: uid
+                            })))
+          , _ => Some(Entry::Unknown { kind: kind, length: bytes[1] })
+        }
+    }
+}
+
+/// An iterator over the interrupt controller structures in a MADT.
+///
+/// Iteration stops at the first malformed entry.
+#[derive(Clone, Debug)]
+pub struct Entries<'a> { bytes: &'a [u8] }
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        if self.bytes.len() < 2 {
+            return None
+        }
+        let len = self.bytes[1] as usize;
+        if len < 2 || len > self.bytes.len() {
+            self.bytes = &[];
+            return None
+        }
+        let (entry, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Entry::parse(entry).or_else(|| { self.bytes = &[]; None })
+    }
+}
+
+/// A processor described by the MADT.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Processor { /// The processor's (x2)APIC ID
+                       pub apic_id: u32
+                     , /// The ACPI processor UID
+                       pub processor_uid: u32
+                     , /// Whether the processor is already enabled
+                       pub is_enabled: bool
+                     }
+
+/// An iterator over the usable processors in a MADT.
+#[derive(Clone, Debug)]
+pub struct Processors<'a> { entries: Entries<'a> }
+
+impl<'a> Iterator for Processors<'a> {
+    type Item = Processor;
+
+    fn next(&mut self) -> Option<Processor> {
+        for entry in &mut self.entries {
+            let (apic_id, uid, flags) = match entry {
+                Entry::ProcessorLocalApic { processor_id, apic_id, flags } =>
+                    (apic_id as u32, processor_id as u32, flags)
+              , Entry::ProcessorLocalX2Apic { x2apic_id, flags
+                                            , processor_uid } =>
+                    (x2apic_id, processor_uid, flags)
+              , _ => continue
+            };
+            if flags & (PROCESSOR_ENABLED | PROCESSOR_ONLINE_CAPABLE) != 0 {
+                return Some(Processor { apic_id: apic_id
+                                      , processor_uid: uid
+                                      , is_enabled: flags & PROCESSOR_ENABLED
+                                                    != 0
+                                      })
+            }
+        }
+        None
+    }
+}
+
+/// An I/O APIC described by the MADT.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct IoApic { /// The I/O APIC's ID
+                    pub id: u8
+                  , /// The physical address of the I/O APIC's registers
+                    pub address: PAddr
+                  , /// The first GSI handled by this I/O APIC
+                    pub gsi_base: u32
+                  }
+
+/// An iterator over the I/O APICs in a MADT.
+#[derive(Clone, Debug)]
+pub struct IoApics<'a> { entries: Entries<'a> }
+
+impl<'a> Iterator for IoApics<'a> {
+    type Item = IoApic;
+
+    fn next(&mut self) -> Option<IoApic> {
+        for entry in &mut self.entries {
+            if let Entry::IoApic { id, address, gsi_base } = entry {
+                return Some(IoApic { id: id
+                                   , address: address
+                                   , gsi_base: gsi_base
+                                   })
+            }
+        }
+        None
+    }
+}
+
+/// A legacy ISA IRQ that's routed to a different GSI.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct InterruptOverride { /// The ISA IRQ
+                               pub source: u8
+                             , /// The GSI the IRQ is routed to
+                               pub gsi: u32
+                             , /// MPS INTI flags (polarity and trigger mode)
+                               pub flags: u16
+                             }
+
+/// An iterator over the interrupt source overrides in a MADT.
+#[derive(Clone, Debug)]
+pub struct InterruptOverrides<'a> { entries: Entries<'a> }
+
+impl<'a> Iterator for InterruptOverrides<'a> {
+    type Item = InterruptOverride;
+
+    fn next(&mut self) -> Option<InterruptOverride> {
+        for entry in &mut self.entries {
+            if let Entry::InterruptSourceOverride { source, gsi, flags, .. }
+                = entry {
+                return Some(InterruptOverride { source: source
+                                              , gsi: gsi
+                                              , flags: flags
+                                              })
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,124 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The Root System Description Pointer (RSDP).
+//!
+//! On BIOS systems, the RSDP is found by scanning the first KiB of the
+//! Extended BIOS Data Area and the BIOS ROM area between `0xe0000` and
+//! `0xfffff` for its signature, on a 16-byte boundary. Multiboot 2
+//! bootloaders also pass a copy of it in a boot information tag.
+use memory::PAddr;
+use super::{Bytes, checksum};
+
+use core::{fmt, str};
+
+/// The RSDP signature.
+pub const SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+
+/// Length of an ACPI 1.0 RSDP, in bytes.
+pub const V1_LEN: usize = 20;
+
+/// Length of an ACPI 2.0 RSDP, in bytes.
+pub const V2_LEN: usize = 36;
+
+/// Physical address of the BIOS ROM area that may contain the RSDP.
+pub const BIOS_AREA_START: u64 = 0xe_0000;
+
+/// Physical address of the end of the BIOS ROM area.
+pub const BIOS_AREA_END: u64 = 0x10_0000;
+
+/// Number of bytes of the Extended BIOS Data Area that may contain the RSDP.
+pub const EBDA_SEARCH_LEN: usize = 1024;
+
+/// A Root System Description Pointer.
+#[derive(Clone)]
+pub struct Rsdp<'a> { bytes: &'a [u8] }
+
+impl<'a> Rsdp<'a> {
+    /// Parses and validates the RSDP at the start of `bytes`.
+    ///
+    /// If the RSDP is an ACPI 2.0 RSDP, but `bytes` isn't long enough to
+    /// hold the extended fields, only the ACPI 1.0 fields are used.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, &'static str> {
+        if bytes.len() < V1_LEN {
+            return Err("RSDP is truncated!")
+        }
+        if &bytes[..8] != SIGNATURE {
+            return Err("RSDP has the wrong signature!")
+        }
+        if !checksum(&bytes[..V1_LEN]) {
+            return Err("RSDP checksum is invalid!")
+        }
+        if bytes[15] < 2 || bytes.len() < V2_LEN {
+            return Ok(Rsdp { bytes: &bytes[..V1_LEN] })
+        }
+        let len = bytes.u32_at(20).unwrap() as usize;
+        if len < V2_LEN || len > bytes.len() {
+            return Err("RSDP has an invalid length!")
+        }
+        if !checksum(&bytes[..len]) {
+            return Err("RSDP extended checksum is invalid!")
+        }
+        Ok(Rsdp { bytes: &bytes[..len] })
+    }
+
+    /// Returns the ACPI revision of the RSDP.
+    ///
+    /// This is 0 for ACPI 1.0, and 2 for ACPI 2.0 and later.
+    #[inline] pub fn revision(&self) -> u8 { self.bytes[15] }
+
+    /// Returns the OEM ID of the RSDP.
+    #[inline]
+    pub fn oem_id(&self) -> &'a str {
+        str::from_utf8(&self.bytes[9..15]).unwrap_or("?")
+    }
+
+    /// Returns the physical address of the RSDT.
+    #[inline]
+    pub fn rsdt_address(&self) -> PAddr {
+        PAddr::from(self.bytes.u32_at(16).unwrap() as u64)
+    }
+
+    /// Returns the physical address of the XSDT, if this is an ACPI 2.0
+    /// RSDP with an XSDT.
+    #[inline]
+    pub fn xsdt_address(&self) -> Option<PAddr> {
+        self.bytes.u64_at(24)
+            .and_then(|addr| if addr == 0 { None }
+                             else { Some(PAddr::from(addr)) })
+    }
+}
+
+impl<'a> fmt::Debug for Rsdp<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Rsdp")
+         .field("revision", &self.revision())
+         .field("oem_id", &self.oem_id())
+         .field("rsdt_address", &self.rsdt_address())
+         .field("xsdt_address", &self.xsdt_address())
+         .finish()
+    }
+}
+
+/// Scans `area` for a valid RSDP on a 16-byte boundary.
+///
+/// `area` should start on a 16-byte boundary in physical memory.
+///
+/// # Returns
+/// + `Some(offset)` with the offset of the RSDP in `area`, if one was found
+/// + `None` if `area` doesn't contain an RSDP.
+pub fn scan(area: &[u8]) -> Option<usize> {
+    let mut offset = 0;
+    while offset + V1_LEN <= area.len() {
+        if Rsdp::parse(&area[offset..]).is_ok() {
+            return Some(offset)
+        }
+        offset += 16;
+    }
+    None
+}
@@ -0,0 +1,119 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! System Description Tables.
+//!
+//! Every ACPI table other than the RSDP begins with the same 36-byte header,
+//! containing the table's signature, length, and checksum.
+use memory::PAddr;
+use super::{Bytes, checksum};
+
+use core::{fmt, str};
+
+/// Length of the common table header, in bytes.
+pub const HEADER_LEN: usize = 36;
+
+/// Signature of the Root System Description Table.
+pub const RSDT: [u8; 4] = *b"RSDT";
+
+/// Signature of the Extended System Description Table.
+pub const XSDT: [u8; 4] = *b"XSDT";
+
+/// A System Description Table whose checksum has been validated.
+#[derive(Clone)]
+pub struct Sdt<'a> { addr: PAddr
+                   , bytes: &'a [u8]
+                   }
+
+impl<'a> Sdt<'a> {
+    /// Parses and validates the table at the start of `bytes`.
+    ///
+    /// `addr` is the physical address the table was read from.
+    pub fn parse(addr: PAddr, bytes: &'a [u8]) -> Result<Self, &'static str> {
+        let len = bytes.u32_at(4).ok_or("ACPI table header is truncated!")?
+                as usize;
+        if len < HEADER_LEN {
+            return Err("ACPI table is shorter than its header!")
+        }
+        if len > bytes.len() {
+            return Err("ACPI table is truncated!")
+        }
+        if !checksum(&bytes[..len]) {
+            return Err("ACPI table checksum is invalid!")
+        }
+        Ok(Sdt { addr: addr, bytes: &bytes[..len] })
+    }
+
+    /// Returns the physical address of the table.
+    #[inline] pub fn address(&self) -> PAddr { self.addr }
+
+    /// Returns the table's signature.
+    #[inline]
+    pub fn signature(&self) -> [u8; 4] {
+        [self.bytes[0], self.bytes[1], self.bytes[2], self.bytes[3]]
+    }
+
+    /// Returns the length of the table (including the header), in bytes.
+    #[inline] pub fn length(&self) -> usize { self.bytes.len() }
+
+    /// Returns the table's revision.
+    #[inline] pub fn revision(&self) -> u8 { self.bytes[8] }
+
+    /// Returns the OEM ID of the table.
+    #[inline]
+    pub fn oem_id(&self) -> &'a str {
+        str::from_utf8(&self.bytes[10..16]).unwrap_or("?")
+    }
+
+    /// Returns the whole table, including the header.
+    #[inline] pub fn bytes(&self) -> &'a [u8] { self.bytes }
+
+    /// Returns the table's contents following the header.
+    #[inline] pub fn data(&self) -> &'a [u8] { &self.bytes[HEADER_LEN..] }
+
+    /// Returns an iterator over the physical addresses in a root table.
+    ///
+    /// `entry_size` is 4 for the RSDT, and 8 for the XSDT.
+    #[inline]
+    pub fn entries(&self, entry_size: usize) -> Entries<'a> {
+        Entries { data: self.data(), entry_size: entry_size }
+    }
+}
+
+impl<'a> fmt::Debug for Sdt<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sdt")
+         .field("signature", &str::from_utf8(&self.bytes[..4]).unwrap_or("?"))
+         .field("address", &self.addr)
+         .field("length", &self.length())
+         .field("revision", &self.revision())
+         .field("oem_id", &self.oem_id())
+         .finish()
+    }
+}
+
+/// An iterator over the table addresses in an RSDT or XSDT.
+#[derive(Clone, Debug)]
+pub struct Entries<'a> { data: &'a [u8]
+                       , entry_size: usize
+                       }
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = PAddr;
+
+    fn next(&mut self) -> Option<PAddr> {
+        let addr = match self.entry_size {
+            4 => self.data.u32_at(0).map(|addr| addr as u64)
+          , _ => self.data.u64_at(0)
+        };
+        if addr.is_some() {
+            self.data = &self.data[self.entry_size..];
+        }
+        addr.map(PAddr::from)
+    }
+}
@@ -0,0 +1,445 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Tests for parsing ACPI tables from byte slices.
+use super::*;
+use super::madt::{Entry, IoApic, InterruptOverride, Processor};
+use memory::PAddr;
+use std::vec::Vec;
+
+/// Fake physical memory, backed by a byte slice starting at `base`.
+struct FakeMemory<'a> { base: u64
+                      , bytes: &'a [u8]
+                      }
+
+impl<'a> PhysicalMemory<'a> for FakeMemory<'a> {
+    fn read(&mut self, addr: PAddr, len: usize)
+           -> Result<&'a [u8], &'static str> {
+        let start = (*addr).checked_sub(self.base)
+                           .ok_or("address below fake memory!")? as usize;
+        self.bytes.get(start .. start + len).ok_or("address past fake memory!")
+    }
+}
+
+/// Sets the checksum byte at `offset` so that `bytes` sums to zero.
+fn fix_checksum(bytes: &mut [u8], offset: usize) {
+    bytes[offset] = 0;
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    bytes[offset] = 0u8.wrapping_sub(sum);
+}
+
+fn push_u16(bytes: &mut Vec<u8>, n: u16) {
+    bytes.extend_from_slice(&[n as u8, (n >> 8) as u8]);
+}
+
+fn push_u32(bytes: &mut Vec<u8>, n: u32) {
+    push_u16(bytes, n as u16);
+    push_u16(bytes, (n >> 16) as u16);
+}
+
+fn push_u64(bytes: &mut Vec<u8>, n: u64) {
+    push_u32(bytes, n as u32);
+    push_u32(bytes, (n >> 32) as u32);
+}
+
+fn rsdp_v1(rsdt: u32) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(rsdp::SIGNATURE);
+    bytes.push(0);
+    bytes.extend_from_slice(b"SOSOEM");
+    bytes.push(0);
+    push_u32(&mut bytes, rsdt);
+    fix_checksum(&mut bytes, 8);
+    bytes
+}
+
+fn rsdp_v2(rsdt: u32, xsdt: u64) -> Vec<u8> {
+    let mut bytes = rsdp_v1(rsdt);
+    bytes[15] = 2;
+    fix_checksum(&mut bytes, 8);
+    push_u32(&mut bytes, rsdp::V2_LEN as u32);
+    push_u64(&mut bytes, xsdt);
+    bytes.extend_from_slice(&[0, 0, 0, 0]);
+    fix_checksum(&mut bytes, 32);
+    bytes
+}
+
+/// Builds a table with the given signature, revision, and contents.
+fn table(signature: &[u8; 4], revision: u8, data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(signature);
+    push_u32(&mut bytes, (sdt::HEADER_LEN + data.len()) as u32);
+    bytes.push(revision);
+    bytes.push(0);
+    bytes.extend_from_slice(b"SOSOEM");
+    bytes.extend_from_slice(b"SOSTABLE");
+    push_u32(&mut bytes, 1);
+    push_u32(&mut bytes, 0);
+    push_u32(&mut bytes, 0);
+    bytes.extend_from_slice(data);
+    fix_checksum(&mut bytes, 9);
+    bytes
+}
+
+fn madt_data() -> Vec<u8> {
+    let mut data = Vec::new();
+    push_u32(&mut data, 0xfee0_0000);
+    push_u32(&mut data, madt::PCAT_COMPAT);
+    // processor 0: enabled
+    data.extend_from_slice(&[0, 8, 0, 0]);
+    push_u32(&mut data, madt::PROCESSOR_ENABLED);
+    // processor 1: disabled, and can't be brought online
+    data.extend_from_slice(&[0, 8, 1, 1]);
+    push_u32(&mut data, 0);
+    // processor 2: online capable
+    data.extend_from_slice(&[0, 8, 2, 2]);
+    push_u32(&mut data, madt::PROCESSOR_ONLINE_CAPABLE);
+    // I/O APIC 4
+    data.extend_from_slice(&[1, 12, 4, 0]);
+    push_u32(&mut data, 0xfec0_0000);
+    push_u32(&mut data, 0);
+    // IRQ 0 -> GSI 2
+    data.extend_from_slice(&[2, 10, 0, 0]);
+    push_u32(&mut data, 2);
+    push_u16(&mut data, 0);
+    // NMI on LINT1 for all processors
+    data.extend_from_slice(&[4, 6, 0xff, 0, 0, 1]);
+    // an entry type we don't know about
+    data.extend_from_slice(&[0x7f, 4, 0, 0]);
+    // x2APIC processor
+    data.extend_from_slice(&[9, 16, 0, 0]);
+    push_u32(&mut data, 0x100);
+    push_u32(&mut data, madt::PROCESSOR_ENABLED);
+    push_u32(&mut data, 3);
+    data
+}
+
+fn fadt_data(len: usize) -> Vec<u8> {
+    let mut data = vec![0; len - sdt::HEADER_LEN];
+    {
+        let mut set = |offset: usize, bytes: &[u8]| {
+            let offset = offset - sdt::HEADER_LEN;
+            if offset + bytes.len() <= data.len() {
+                data[offset .. offset + bytes.len()].copy_from_slice(bytes);
+            }
+        };
+        set(40, &[0x00, 0x10, 0x00, 0x00]);     // DSDT
+        set(46, &[9, 0]);                       // SCI_INT
+        set(56, &[0x00, 0x06, 0, 0]);           // PM1a_EVT_BLK
+        set(64, &[0x04, 0x06, 0, 0]);           // PM1a_CNT_BLK
+        set(76, &[0x08, 0x06, 0, 0]);           // PM_TMR_BLK
+        set(89, &[2]);                          // PM1_CNT_LEN
+        set(108, &[0x32]);                      // CENTURY
+        set(109, &[fadt::HAS_8042 as u8, 0]);  // IAPC_BOOT_ARCH
+        set(112, &[0, 0x04, 0, 0]);             // flags: RESET_REG_SUP
+        set(116, &[1, 8, 0, 1, 0xf9, 0x0c, 0, 0, 0, 0, 0, 0]); // RESET_REG
+        set(128, &[0x06]);                      // RESET_VALUE
+    }
+    data
+}
+
+fn hpet_data() -> Vec<u8> {
+    let mut data = Vec::new();
+    // vendor 0x8086, legacy replacement capable, 64-bit, 3 comparators
+    push_u32(&mut data, 0x8086_a201);
+    data.extend_from_slice(&[0, 64, 0, 0]);
+    push_u64(&mut data, 0xfed0_0000);
+    data.push(0);
+    push_u16(&mut data, 0x80);
+    data.push(0);
+    data
+}
+
+#[test]
+fn checksum_valid_and_invalid() {
+    assert!(checksum(&[]));
+    assert!(checksum(&[0x01, 0xff]));
+    assert!(!checksum(&[0x01, 0xfe]));
+}
+
+#[test]
+fn rsdp_v1_parses() {
+    let bytes = rsdp_v1(0x1234_5678);
+    let rsdp = Rsdp::parse(&bytes).unwrap();
+    assert_eq!(rsdp.revision(), 0);
+    assert_eq!(rsdp.oem_id(), "SOSOEM");
+    assert_eq!(rsdp.rsdt_address(), PAddr::from(0x1234_5678));
+    assert_eq!(rsdp.xsdt_address(), None);
+}
+
+#[test]
+fn rsdp_v2_parses() {
+    let bytes = rsdp_v2(0x1000, 0x2_0000_0000);
+    let rsdp = Rsdp::parse(&bytes).unwrap();
+    assert_eq!(rsdp.revision(), 2);
+    assert_eq!(rsdp.xsdt_address(), Some(PAddr::from(0x2_0000_0000)));
+    // only the ACPI 1.0 fields are available from the first 20 bytes
+    let rsdp = Rsdp::parse(&bytes[..rsdp::V1_LEN]).unwrap();
+    assert_eq!(rsdp.xsdt_address(), None);
+}
+
+#[test]
+fn rsdp_bad_checksum() {
+    let mut bytes = rsdp_v1(0x1000);
+    bytes[16] ^= 1;
+    assert!(Rsdp::parse(&bytes).is_err());
+
+    let mut bytes = rsdp_v2(0x1000, 0x2000);
+    bytes[24] ^= 1;
+    assert!(Rsdp::parse(&bytes).is_err());
+    // the ACPI 1.0 checksum doesn't cover the XSDT address
+    assert!(Rsdp::parse(&bytes[..rsdp::V1_LEN]).is_ok());
+}
+
+#[test]
+fn rsdp_bad_signature() {
+    let mut bytes = rsdp_v1(0x1000);
+    bytes[0] = b'X';
+    fix_checksum(&mut bytes, 8);
+    assert!(Rsdp::parse(&bytes).is_err());
+    assert!(Rsdp::parse(&bytes[..10]).is_err());
+}
+
+#[test]
+fn rsdp_scan() {
+    let mut area = vec![0u8; 256];
+    // a signature with a bad checksum, and one that isn't 16-byte aligned
+    let mut bad = rsdp_v1(0x1000);
+    bad[8] ^= 0xff;
+    area[32 .. 32 + rsdp::V1_LEN].copy_from_slice(&bad);
+    let good = rsdp_v1(0x1000);
+    area[72 .. 72 + rsdp::V1_LEN].copy_from_slice(&good);
+    assert_eq!(rsdp::scan(&area), None);
+
+    area[160 .. 160 + rsdp::V1_LEN].copy_from_slice(&good);
+    assert_eq!(rsdp::scan(&area), Some(160));
+}
+
+#[test]
+fn sdt_parses() {
+    let bytes = table(b"TEST", 3, &[1, 2, 3, 4]);
+    let sdt = Sdt::parse(PAddr::from(0x1000), &bytes).unwrap();
+    assert_eq!(sdt.signature(), *b"TEST");
+    assert_eq!(sdt.length(), sdt::HEADER_LEN + 4);
+    assert_eq!(sdt.revision(), 3);
+    assert_eq!(sdt.oem_id(), "SOSOEM");
+    assert_eq!(sdt.data(), &[1, 2, 3, 4]);
+    assert_eq!(sdt.address(), PAddr::from(0x1000));
+}
+
+#[test]
+fn sdt_rejects_bad_tables() {
+    let mut bytes = table(b"TEST", 1, &[1, 2, 3, 4]);
+    assert!(Sdt::parse(PAddr::from(0), &bytes[..sdt::HEADER_LEN]).is_err());
+    bytes[sdt::HEADER_LEN] = 0xff;
+    assert!(Sdt::parse(PAddr::from(0), &bytes).is_err());
+}
+
+#[test]
+fn root_table_entries() {
+    let mut data = Vec::new();
+    push_u32(&mut data, 0x1000);
+    push_u32(&mut data, 0x2000);
+    let rsdt = table(&sdt::RSDT, 1, &data);
+    let rsdt = Sdt::parse(PAddr::from(0), &rsdt).unwrap();
+    assert_eq!( rsdt.entries(4).collect::<Vec<_>>()
+              , vec![PAddr::from(0x1000), PAddr::from(0x2000)]);
+
+    let mut data = Vec::new();
+    push_u64(&mut data, 0x1_0000_1000);
+    let xsdt = table(&sdt::XSDT, 1, &data);
+    let xsdt = Sdt::parse(PAddr::from(0), &xsdt).unwrap();
+    assert_eq!( xsdt.entries(8).collect::<Vec<_>>()
+              , vec![PAddr::from(0x1_0000_1000)]);
+}
+
+#[test]
+fn madt_entries() {
+    let bytes = table(&madt::SIGNATURE, 4, &madt_data());
+    let madt = Madt::new(Sdt::parse(PAddr::from(0), &bytes).unwrap())
+        .unwrap();
+    assert_eq!(madt.local_apic_address(), PAddr::from(0xfee0_0000));
+    assert!(madt.has_legacy_pics());
+    assert_eq!(madt.entries().count(), 8);
+    assert_eq!( madt.entries().nth(5)
+              , Some(Entry::LocalApicNmi { processor_id: 0xff
+                                         , flags: 0
+                                         , lint: 1 }));
+    assert_eq!( madt.entries().nth(6)
+              , Some(Entry::Unknown { kind: 0x7f, length: 4 }));
+    assert_eq!( madt.processors().collect::<Vec<_>>()
+              , vec![ Processor { apic_id: 0, processor_uid: 0
+                                , is_enabled: true }
+                    , Processor { apic_id: 2, processor_uid: 2
+                                , is_enabled: false }
+                    , Processor { apic_id: 0x100, processor_uid: 3
+                                , is_enabled: true }
+                    ]);
+    assert_eq!( madt.io_apics().collect::<Vec<_>>()
+              , vec![IoApic { id: 4
+                            , address: PAddr::from(0xfec0_0000)
+                            , gsi_base: 0 }]);
+    assert_eq!( madt.interrupt_overrides().collect::<Vec<_>>()
+              , vec![InterruptOverride { source: 0, gsi: 2, flags: 0 }]);
+}
+
+#[test]
+fn madt_local_apic_override() {
+    let mut data = madt_data();
+    data.extend_from_slice(&[5, 12, 0, 0]);
+    push_u64(&mut data, 0x1_fee0_0000);
+    let bytes = table(&madt::SIGNATURE, 4, &data);
+    let madt = Madt::new(Sdt::parse(PAddr::from(0), &bytes).unwrap())
+        .unwrap();
+    assert_eq!(madt.local_apic_address(), PAddr::from(0x1_fee0_0000));
+}
+
+#[test]
+fn madt_stops_at_malformed_entry() {
+    let mut data = madt_data();
+    // an entry whose length runs past the end of the table
+    data.extend_from_slice(&[0, 32, 0, 0]);
+    let bytes = table(&madt::SIGNATURE, 4, &data);
+    let madt = Madt::new(Sdt::parse(PAddr::from(0), &bytes).unwrap())
+        .unwrap();
+    assert_eq!(madt.entries().count(), 8);
+}
+
+#[test]
+fn wrong_signature_is_rejected() {
+    let bytes = table(b"TEST", 1, &hpet_data());
+    let sdt = Sdt::parse(PAddr::from(0), &bytes).unwrap();
+    assert!(Madt::new(sdt.clone()).is_err());
+    assert!(Fadt::new(sdt.clone()).is_err());
+    assert!(Hpet::new(sdt).is_err());
+}
+
+#[test]
+fn fadt_fields() {
+    let bytes = table(&fadt::SIGNATURE, 4, &fadt_data(244));
+    let fadt = Fadt::new(Sdt::parse(PAddr::from(0), &bytes).unwrap())
+        .unwrap();
+    assert_eq!(fadt.dsdt(), Some(PAddr::from(0x1000)));
+    assert_eq!(fadt.sci_interrupt(), Some(9));
+    assert_eq!(fadt.pm1a_event_block(), Some(0x600));
+    assert_eq!(fadt.pm1b_event_block(), None);
+    assert_eq!(fadt.pm1a_control_block(), Some(0x604));
+    assert_eq!(fadt.pm_timer_block(), Some(0x608));
+    assert_eq!(fadt.pm1_control_length(), Some(2));
+    assert_eq!(fadt.century(), Some(0x32));
+    assert!(fadt.has_8042());
+    assert_eq!( fadt.reset_register()
+              , Some(GenericAddress { space: AddressSpace::SystemIo
+                                    , bit_width: 8
+                                    , bit_offset: 0
+                                    , access_size: 1
+                                    , address: 0xcf9 }));
+    assert_eq!(fadt.reset_value(), Some(6));
+}
+
+#[test]
+fn fadt_v1_fields() {
+    // an ACPI 1.0 FADT is only 116 bytes long
+    let bytes = table(&fadt::SIGNATURE, 1, &fadt_data(116));
+    let fadt = Fadt::new(Sdt::parse(PAddr::from(0), &bytes).unwrap())
+        .unwrap();
+    assert_eq!(fadt.dsdt(), Some(PAddr::from(0x1000)));
+    assert_eq!(fadt.pm_timer_block(), Some(0x608));
+    assert_eq!(fadt.boot_arch_flags(), None);
+    assert!(fadt.has_8042());
+    assert_eq!(fadt.reset_register(), None);
+    assert_eq!(fadt.reset_value(), None);
+    assert_eq!(fadt.x_pm1a_control_block(), None);
+}
+
+#[test]
+fn hpet_fields() {
+    let bytes = table(&hpet::SIGNATURE, 1, &hpet_data());
+    let hpet = Hpet::new(Sdt::parse(PAddr::from(0), &bytes).unwrap())
+        .unwrap();
+    assert_eq!(hpet.comparators(), 3);
+    assert!(hpet.is_64bit());
+    assert!(hpet.legacy_replacement());
+    assert_eq!(hpet.vendor_id(), 0x8086);
+    assert_eq!(hpet.base_address().space, AddressSpace::SystemMemory);
+    assert_eq!(hpet.base_address().address, 0xfed0_0000);
+    assert_eq!(hpet.number(), 0);
+    assert_eq!(hpet.minimum_tick(), 0x80);
+}
+
+/// Lays out an RSDP and tables 0x100 bytes apart in fake memory.
+fn memory_with(rsdp: Vec<u8>, tables: &[Vec<u8>]) -> Vec<u8> {
+    let mut memory = vec![0; 0x100 * (tables.len() + 1)];
+    memory[..rsdp.len()].copy_from_slice(&rsdp);
+    for (i, table) in tables.iter().enumerate() {
+        let start = 0x100 * (i + 1);
+        memory[start .. start + table.len()].copy_from_slice(table);
+    }
+    memory
+}
+
+#[test]
+fn tables_from_rsdt() {
+    let mut data = Vec::new();
+    for addr in &[0x1200, 0x1300, 0x1400, 0x1500] {
+        push_u32(&mut data, *addr);
+    }
+    let mut bad_madt = table(&madt::SIGNATURE, 4, &madt_data());
+    bad_madt[sdt::HEADER_LEN] ^= 1;
+    let memory = memory_with( rsdp_v1(0x1100)
+                            , &[ table(&sdt::RSDT, 1, &data)
+                               , table(&fadt::SIGNATURE, 4, &fadt_data(244))
+                               , table(&hpet::SIGNATURE, 1, &hpet_data())
+                               , table(b"SSDT", 1, &[])
+                               , bad_madt
+                               ]);
+    let tables = Tables::parse( PAddr::from(0x1000)
+                              , &mut FakeMemory { base: 0x1000
+                                                , bytes: &memory })
+        .unwrap();
+    assert_eq!(tables.rsdp.revision(), 0);
+    assert!(tables.fadt.is_some());
+    assert!(tables.hpet.is_some());
+    // the MADT has a bad checksum, so it's skipped
+    assert!(tables.madt.is_none());
+}
+
+#[test]
+fn tables_from_xsdt() {
+    let mut data = Vec::new();
+    push_u64(&mut data, 0x1300);
+    let memory = memory_with( rsdp_v2(0xdead, 0x1200)
+                            , &[ table(&sdt::RSDT, 1, &[])
+                               , table(&sdt::XSDT, 1, &data)
+                               , table(&madt::SIGNATURE, 4, &madt_data())
+                               ]);
+    let tables = Tables::parse( PAddr::from(0x1000)
+                              , &mut FakeMemory { base: 0x1000
+                                                , bytes: &memory })
+        .unwrap();
+    assert_eq!(tables.rsdp.revision(), 2);
+    assert_eq!(tables.madt.unwrap().io_apics().count(), 1);
+    assert!(tables.fadt.is_none());
+    assert!(tables.hpet.is_none());
+}
+
+#[test]
+fn tables_bad_root() {
+    // the RSDP points at a table that isn't an RSDT
+    let memory = memory_with( rsdp_v1(0x1100)
+                            , &[table(&sdt::XSDT, 1, &[])]);
+    assert!(Tables::parse( PAddr::from(0x1000)
+                         , &mut FakeMemory { base: 0x1000
+                                           , bytes: &memory })
+        .is_err());
+    // there's no RSDP at all
+    assert!(Tables::parse( PAddr::from(0x1100)
+                         , &mut FakeMemory { base: 0x1000
+                                           , bytes: &memory })
+        .is_err());
+}
@@ -1,5 +1,6 @@
 //! This module integrates the buddy heap allocator into the Rust runtime.
 use spin::Mutex;
+use cpu::interrupts;
 use core::ptr;
 
 use ::{Allocator, Layout};
@@ -34,10 +35,22 @@ pub unsafe fn init_heap(start_addr: *mut u8, heap_size: usize ) {
     assert_has_not_been_called!("the kernel heap may not be initialized \
                                  more than once!");
     trace!(target: "alloc", "init_heap() was called.");
-    *(ALLOC.lock())
-        = Some(Heap::new( start_addr
-                                      , &mut KERNEL_FREE_LISTS
-                                      , heap_size));
+    with_heap(|heap| {
+        *heap = Some(Heap::new( start_addr
+                              , &mut KERNEL_FREE_LISTS
+                              , heap_size))
+    });
+}
+
+/// Run `f` with the kernel heap locked.
+///
+/// Interrupts are disabled while the lock is held, so that a thread holding
+/// it can't be preempted, leaving whatever runs next on this CPU (another
+/// thread, or an interrupt handler) to spin on it forever.
+#[inline]
+fn with_heap<F, R>(f: F) -> R
+where F: FnOnce(&mut Option<Heap<'static>>) -> R {
+    interrupts::without_interrupts(|| f(&mut *ALLOC.lock()))
 }
 
 // -- integrate the heap allocator into the Rust runtime ------------------
@@ -45,31 +58,31 @@ pub unsafe fn init_heap(start_addr: *mut u8, heap_size: usize ) {
 #[no_mangle]
 pub extern "C" fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
     trace!("__rust_allocate() was called.");
-    unsafe {
-        ALLOC.lock().as_mut()
-             .expect("Cannot allocate memory, no system allocator exists!")
-             .alloc(Layout::from_size_align(size, align))
-             .map(|blck| {
-                 // TODO: can we use `inspect()` here instead?
-                 //       - eliza, 1/23/2017
-                 trace!( target: "alloc"
-                       , "__rust_allocate: allocated {:?}", blck);
-                 blck })
-            // TODO: how to handle various error conditions here in
-            //       ways the stdlib expects?
-            //          - eliza, 02/02/2017
-             .unwrap()
-    }
+    with_heap(|heap| unsafe {
+        heap.as_mut()
+            .expect("Cannot allocate memory, no system allocator exists!")
+            .alloc(Layout::from_size_align(size, align))
+            .map(|blck| {
+                // TODO: can we use `inspect()` here instead?
+                //       - eliza, 1/23/2017
+                trace!( target: "alloc"
+                      , "__rust_allocate: allocated {:?}", blck);
+                blck })
+           // TODO: how to handle various error conditions here in
+           //       ways the stdlib expects?
+           //          - eliza, 02/02/2017
+            .unwrap()
+    })
 }
 #[allow(missing_docs)]
 #[no_mangle]
 pub extern "C" fn __rust_deallocate( ptr: *mut u8, old_size: usize
                                    , align: usize ) {
-    unsafe {
-        ALLOC.lock().as_mut()
-             .expect("Cannot deallocate memory, no system allocator exists!")
-             .dealloc(ptr, Layout::from_size_align(old_size, align))
-    }
+    with_heap(|heap| unsafe {
+        heap.as_mut()
+            .expect("Cannot deallocate memory, no system allocator exists!")
+            .dealloc(ptr, Layout::from_size_align(old_size, align))
+    })
 }
 
 #[allow(missing_docs)]
@@ -77,17 +90,17 @@ pub extern "C" fn __rust_deallocate( ptr: *mut u8, old_size: usize
 pub extern "C" fn __rust_reallocate( ptr: *mut u8, old_size: usize
                                    , size: usize, align: usize )
                                    -> *mut u8 {
-    unsafe {
-        ALLOC.lock().as_mut()
-             .expect("Cannot reallocate memory, no system allocator exists!")
-             .realloc( ptr
-                     , Layout::from_size_align(old_size, align)
-                     , Layout::from_size_align(size, align))
-             // TODO: how to handle various error conditions here in
-             //       ways the stdlib expects?
-             //          - eliza, 02/02/2017
-             .unwrap()
-     }
+    with_heap(|heap| unsafe {
+        heap.as_mut()
+            .expect("Cannot reallocate memory, no system allocator exists!")
+            .realloc( ptr
+                    , Layout::from_size_align(old_size, align)
+                    , Layout::from_size_align(size, align))
+            // TODO: how to handle various error conditions here in
+            //       ways the stdlib expects?
+            //          - eliza, 02/02/2017
+            .unwrap()
+    })
 }
 
 /// This is currently unsupported, so we just silently ignore it
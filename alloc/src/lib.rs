@@ -75,6 +75,9 @@ extern crate spin;
 #[cfg(feature = "buddy_as_system")]
 #[macro_use] extern crate once;
 
+#[cfg(feature = "buddy_as_system")]
+extern crate cpu;
+
 #[macro_use] extern crate log;
 
 extern crate params;
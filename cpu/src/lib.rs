@@ -10,6 +10,7 @@
 #![crate_type = "lib"]
 #![feature(const_fn)]
 #![feature(asm)]
+#![feature(global_asm)]
 #![feature(naked_functions)]
 #![feature(slice_patterns)]
 #![feature(linkage)]
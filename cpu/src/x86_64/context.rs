@@ -11,10 +11,11 @@
 //! This is inteded to be general-purpose and composable, so that the same
 //! code can be reused for interrupts and for multithreading.
 
-use core::mem;
-use core::fmt;
+use core::{fmt, mem, ptr};
 use super::flags::{Flags as RFlags};
+use super::fpu::FpuState;
 use super::segment;
+use super::PrivilegeLevel;
 
 /// Registers pushed to the stack when handling an interrupt or context switch.
 #[repr(C, packed)]
@@ -130,6 +131,15 @@ pub struct InterruptFrame {
   , __pad_4: u16
 }
 
+impl InterruptFrame {
+    /// Returns true if the interrupt occurred while running in user mode.
+    #[inline]
+    pub fn is_from_user(&self) -> bool {
+        let cs = self.cs;
+        cs.get_rpl() == PrivilegeLevel::UserMode
+    }
+}
+
 #[cfg(test)]
 mod test {
     #[test]
@@ -139,6 +149,75 @@ mod test {
 
         assert_eq!(size_of::<InterruptFrame>(), 32);
     }
+
+    #[test]
+    fn test_is_from_user() {
+        use super::{InterruptFrame, RFlags};
+        use ::segment::Selector;
+        use core::ptr;
+
+        let frame = |cs| InterruptFrame { rip: ptr::null()
+                                        , cs: Selector::from_raw(cs)
+                                        , __pad_1: 0, __pad_2: 0
+                                        , rflags: RFlags::empty()
+                                        , rsp: ptr::null()
+                                        , ss: Selector::from_raw(0)
+                                        , __pad_3: 0, __pad_4: 0
+                                        };
+        assert!(!frame(0x08).is_from_user());
+        assert!(frame(0x1b).is_from_user());
+    }
+
+    extern "C" fn never_run() -> ! { loop { } }
+
+    #[test]
+    fn test_new_context_stack_layout() {
+        use super::{Context, INITIAL_RFLAGS};
+        use ::fpu::FpuState;
+
+        let mut stack = [0xffff_ffff_u64; 32];
+        // a top that isn't even word-aligned.
+        let top = unsafe { (stack.as_mut_ptr() as *mut u8).offset(250) };
+        let context = unsafe { Context::new(top, never_run, FpuState::none()) };
+        let rsp = context.rsp as *const u64;
+        unsafe {
+            assert_eq!(*rsp, INITIAL_RFLAGS);
+            for i in 1..7 {
+                assert_eq!(*rsp.offset(i), 0);
+            }
+            assert_eq!(*rsp.offset(7), never_run as usize as u64);
+            assert_eq!(*rsp.offset(8), 0);
+        }
+        // after `ret`, `rsp` is aligned as it is after a `call`.
+        assert_eq!((context.rsp as usize + 8 * 8) % 16, 8);
+        assert!(context.rsp as usize + 9 * 8 <= top as usize);
+    }
+
+    static mut MAIN: super::Context = super::Context::empty();
+    static mut THREAD: super::Context = super::Context::empty();
+    static mut THREAD_RAN: bool = false;
+
+    extern "C" fn switch_back() -> ! {
+        unsafe {
+            THREAD_RAN = true;
+            super::switch_to(&mut THREAD, &MAIN);
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn test_switch_to_and_back() {
+        use super::{Context, switch_to};
+        use ::fpu::FpuState;
+
+        let mut stack = [0u64; 512];
+        unsafe {
+            let top = stack.as_mut_ptr().offset(512) as *mut u8;
+            THREAD = Context::new(top, switch_back, FpuState::none());
+            switch_to(&mut MAIN, &THREAD);
+            assert!(THREAD_RAN);
+        }
+    }
 }
 
 impl fmt::Debug for InterruptFrame {
@@ -161,24 +240,105 @@ impl fmt::Debug for InterruptFrame {
 }
 
 /// Thread execution context
-#[repr(C, packed)]
+///
+/// Only the stack pointer is kept here: [`switch_to`] pushes the rest of the
+/// callee-saved registers to the thread's stack before saving it, and pops
+/// them off the next thread's stack after loading its `rsp`.
+///
+/// [`switch_to`]: fn.switch_to.html
+#[repr(C)]
 pub struct Context { /// Value of the stack pointer (`rsp`) register
                      pub rsp: *mut u8
-                   , /// Value of the caller-saved registers
-                     pub registers: Registers
-                   , /// Value of the instruction pointer (`rip`) register
-                     pub rip: *mut u8
-                 //, pub stack: [u8] // TODO: should be box
+                   , /// Where the FPU, SSE, and AVX state is saved
+                     pub fpu: FpuState
                    }
 
+/// Number of words that `switch_to` pushes to a thread's stack, not
+/// counting the return address: `rbp`, `rbx`, `r12` to `r15`, and `rflags`.
+const SAVED_WORDS: usize = 7;
+
+/// Value of `rflags` that new threads start with: interrupts disabled, and
+/// only the reserved bit 1 set.
+const INITIAL_RFLAGS: u64 = 0x2;
+
 impl Context {
-    pub fn empty() -> Self {
-        unsafe {
-            Context { rsp: mem::transmute(0u64)
-                    , registers: Registers::empty()
-                    , rip: mem::transmute(0u64)
-                  //, stack: [0u8; 8]
-                    }
+    pub const fn empty() -> Self {
+        Context { rsp: ptr::null_mut()
+                , fpu: FpuState::none()
+                }
+    }
+
+    /// Returns a new `Context` that starts executing `entry` on the stack
+    /// ending at `stack_top`, the first time it is switched to.
+    ///
+    /// `entry` starts with interrupts disabled, and with `rbp` zeroed, so
+    /// that backtraces stop there. It must never return.
+    ///
+    /// # Safety
+    /// + `stack_top` must be the (exclusive) end of a mapped, writable stack
+    ///   at least a page long, which nothing else uses.
+    pub unsafe fn new( stack_top: *mut u8, entry: extern "C" fn() -> !
+                     , fpu: FpuState)
+                     -> Self {
+        // `entry` is entered by `ret`, so it must see the stack as though it
+        // were called: 16-byte aligned before the return address was pushed.
+        let top = (stack_top as usize & !0xf) as *mut u64;
+        // a null return address for `entry`, which it never uses.
+        *top.offset(-1) = 0;
+        *top.offset(-2) = entry as usize as u64;
+        let rsp = top.offset(-2 - SAVED_WORDS as isize);
+        for i in 1..SAVED_WORDS {
+            *rsp.offset(i as isize) = 0;
         }
+        *rsp = INITIAL_RFLAGS;
+        Context { rsp: rsp as *mut u8, fpu: fpu }
     }
 }
+
+// `cpu_context_switch(old: *mut Context, new: *const Context)`
+//
+// The registers are pushed in the opposite order to the one `Context::new`
+// lays them out in; `rsp` is the first field of `Context`.
+global_asm!("
+    .text
+    .global cpu_context_switch
+cpu_context_switch:
+    push %rbp
+    push %rbx
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    pushfq
+    mov %rsp, (%rdi)
+    mov (%rsi), %rsp
+    popfq
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbx
+    pop %rbp
+    ret
+");
+
+extern "C" {
+    fn cpu_context_switch(old: *mut Context, new: *const Context);
+}
+
+/// Save the running thread's callee-saved registers to `old`, and resume the
+/// thread that was saved to `new`.
+///
+/// This returns when something switches back to `old`. The caller-saved
+/// registers are saved by the compiler around the call, as for any other
+/// function, and the FPU state is not switched here.
+///
+/// # Safety
+/// + `new` must have been made by `Context::new`, or saved by `switch_to`,
+///   and its stack must still be mapped.
+/// + This should be called with interrupts disabled, so that nothing runs
+///   with `old` half-saved.
+#[inline]
+pub unsafe fn switch_to(old: &mut Context, new: &Context) {
+    cpu_context_switch(old, new)
+}
@@ -0,0 +1,360 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The x87 FPU, SSE, and AVX register state.
+//!
+//! The kernel itself is compiled without floating-point or SIMD
+//! instructions, so this state only belongs to the code it runs. Each task
+//! that uses it needs an [`FpuState`] to save it in when it is switched out.
+//!
+//! If the CPU supports it, the state is saved with `xsave`, which also
+//! saves the AVX registers, and the size of the save area comes from
+//! `cpuid`. Otherwise, `fxsave` is used, and the area is 512 bytes.
+//!
+//! [`FpuState`]: struct.FpuState.html
+#![warn(missing_docs)]
+use ::control_regs::{cr0, cr4};
+use ::cpuid::{self, cpu_features};
+
+use core::{fmt, ptr};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT,
+                        ATOMIC_USIZE_INIT};
+
+/// Save areas must be aligned to this many bytes.
+pub const STATE_ALIGN: usize = 64;
+
+/// Size of the `fxsave` area (and the legacy region of the `xsave` area).
+pub const LEGACY_AREA_SIZE: usize = 512;
+
+/// Size of the `xsave` header, which follows the legacy region.
+const XSAVE_HEADER_SIZE: usize = 64;
+
+/// The x87 FPU control word after `fninit`.
+pub const FCW_DEFAULT: u16 = 0x037f;
+
+/// `MXCSR` after reset: every exception masked, round to nearest.
+pub const MXCSR_DEFAULT: u32 = 0x1f80;
+
+/// Offset of `MXCSR` in the legacy region.
+const MXCSR_OFFSET: usize = 24;
+
+bitflags! {
+    /// State components that `xsave` manages, as set in `XCR0`.
+    pub flags XFeatures: u64 {
+        /// x87 FPU state
+        const X87 = 1 << 0
+      , /// SSE state (`%xmm0`-`%xmm15` and `MXCSR`)
+        const SSE = 1 << 1
+      , /// AVX state (the upper halves of `%ymm0`-`%ymm15`)
+        const AVX = 1 << 2
+    }
+}
+
+bitflags! {
+    /// Contents of the SSE control and status register, `MXCSR`.
+    pub flags Mxcsr: u32 {
+        /// Invalid operation flag
+        const INVALID = 1 << 0
+      , /// Denormal flag
+        const DENORMAL = 1 << 1
+      , /// Divide-by-zero flag
+        const DIVIDE_BY_ZERO = 1 << 2
+      , /// Overflow flag
+        const OVERFLOW = 1 << 3
+      , /// Underflow flag
+        const UNDERFLOW = 1 << 4
+      , /// Precision (inexact result) flag
+        const PRECISION = 1 << 5
+      , /// Denormals are zeros
+        const DAZ = 1 << 6
+      , /// Invalid operation mask
+        const INVALID_MASK = 1 << 7
+      , /// Denormal mask
+        const DENORMAL_MASK = 1 << 8
+      , /// Divide-by-zero mask
+        const DIVIDE_BY_ZERO_MASK = 1 << 9
+      , /// Overflow mask
+        const OVERFLOW_MASK = 1 << 10
+      , /// Underflow mask
+        const UNDERFLOW_MASK = 1 << 11
+      , /// Precision mask
+        const PRECISION_MASK = 1 << 12
+      , /// Rounding control, low bit
+        const ROUNDING_LOW = 1 << 13
+      , /// Rounding control, high bit
+        const ROUNDING_HIGH = 1 << 14
+      , /// Flush to zero
+        const FLUSH_TO_ZERO = 1 << 15
+      , /// Every exception flag
+        const EXCEPTIONS = INVALID.bits | DENORMAL.bits | DIVIDE_BY_ZERO.bits
+                         | OVERFLOW.bits | UNDERFLOW.bits | PRECISION.bits
+    }
+}
+
+/// The names of the `MXCSR` exceptions, in bit order.
+const EXCEPTION_NAMES: [&'static str; 6] = [ "invalid operation"
+                                           , "denormal operand"
+                                           , "divide by zero"
+                                           , "overflow"
+                                           , "underflow"
+                                           , "precision"
+                                           ];
+
+impl Mxcsr {
+    /// Returns the exceptions that have been raised.
+    #[inline]
+    pub fn raised(&self) -> Mxcsr { *self & EXCEPTIONS }
+
+    /// Returns the exceptions that have been raised, and aren't masked.
+    ///
+    /// These are the ones that caused a SIMD floating-point exception.
+    #[inline]
+    pub fn unmasked(&self) -> Mxcsr {
+        // each exception's mask bit is 7 bits above its flag.
+        Mxcsr::from_bits_truncate(self.bits & !(self.bits >> 7)) & EXCEPTIONS
+    }
+}
+
+impl fmt::Display for Mxcsr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MXCSR {:#06x}", self.bits)?;
+        let unmasked = self.unmasked();
+        let mut names = EXCEPTION_NAMES.iter().enumerate()
+            .filter(|&(bit, _)| unmasked.bits & (1 << bit) != 0)
+            .map(|(_, name)| name);
+        if let Some(name) = names.next() {
+            write!(f, " ({}", name)?;
+            for name in names { write!(f, ", {}", name)?; }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+/// True if `xsave` is used to save the state.
+static USE_XSAVE: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The size of a save area, in bytes.
+static STATE_SIZE: AtomicUsize = AtomicUsize::new(LEGACY_AREA_SIZE);
+
+/// The state components enabled in `XCR0`, if `xsave` is used.
+static ENABLED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Read an extended control register.
+#[inline]
+pub unsafe fn xgetbv(xcr: u32) -> u64 {
+    let (high, low): (u32, u32);
+    asm!( "xgetbv"
+        : "={eax}"(low), "={edx}"(high)
+        : "{ecx}"(xcr)
+        :: "volatile");
+    (high as u64) << 32 | low as u64
+}
+
+/// Write an extended control register.
+#[inline]
+pub unsafe fn xsetbv(xcr: u32, value: u64) {
+    asm!( "xsetbv"
+        :: "{ecx}"(xcr), "{eax}"(value as u32), "{edx}"((value >> 32) as u32)
+        :: "volatile");
+}
+
+/// Clear the task switched flag in `%cr0`, so the FPU can be used.
+#[inline]
+pub unsafe fn clts() {
+    asm!("clts" :::: "volatile");
+}
+
+/// Returns the current value of `MXCSR`.
+pub unsafe fn mxcsr() -> Mxcsr {
+    let mut value: u32 = 0;
+    asm!("stmxcsr $0" : "=*m"(&mut value) ::: "volatile");
+    Mxcsr::from_bits_truncate(value)
+}
+
+/// Returns true if the state is saved with `xsave`.
+#[inline]
+pub fn uses_xsave() -> bool { USE_XSAVE.load(Ordering::Relaxed) }
+
+/// Returns the size of a save area, in bytes.
+///
+/// This is only correct once `initialize()` has been called.
+#[inline]
+pub fn state_size() -> usize { STATE_SIZE.load(Ordering::Relaxed) }
+
+/// Returns the state components that are saved and restored.
+#[inline]
+pub fn enabled() -> XFeatures {
+    XFeatures::from_bits_truncate(ENABLED.load(Ordering::Relaxed) as u64)
+}
+
+/// Enable the FPU, SSE, and (if it's supported) AVX on this CPU.
+///
+/// This sets `OSFXSR` and `OSXMMEXCPT` (and `OSXSAVE`, if `xsave` is
+/// supported) in `%cr4`, enables every supported state component in `XCR0`,
+/// and resets the FPU.
+///
+/// # Returns
+/// + `Ok` with the state components that are enabled
+/// + `Err` if the CPU doesn't support SSE2.
+///
+/// # Safety
+/// + This must be called on every CPU, before anything uses the FPU.
+pub unsafe fn initialize() -> Result<XFeatures, &'static str> {
+    let features = cpu_features();
+    if !features.sse || !features.sse2 {
+        return Err("CPU does not support SSE2!")
+    }
+
+    // use the FPU's native error reporting, and make `wait` honour `TS`.
+    let mut flags = cr0::read();
+    flags.remove(cr0::EM);
+    flags.insert(cr0::MP | cr0::NE);
+    cr0::write(flags);
+
+    let mut flags = cr4::read();
+    flags.insert(cr4::OSFXSR | cr4::OSXMMEXCPT);
+    if features.xsave { flags.insert(cr4::OSXSAVE) }
+    cr4::write(flags);
+
+    let enabled = if features.xsave {
+        let state = cpuid::cpuid(0xd, 0);
+        let supported = XFeatures::from_bits_truncate(
+            (state.edx as u64) << 32 | state.eax as u64);
+        let enabled = if features.avx { supported & (X87 | SSE | AVX) }
+                      else { supported & (X87 | SSE) };
+        xsetbv(0, enabled.bits());
+        // leaf 0xd's `%ebx` is the size needed for what's enabled in XCR0.
+        STATE_SIZE.store( cpuid::cpuid(0xd, 0).ebx as usize
+                        , Ordering::Relaxed);
+        enabled
+    } else {
+        STATE_SIZE.store(LEGACY_AREA_SIZE, Ordering::Relaxed);
+        X87 | SSE
+    };
+    USE_XSAVE.store(features.xsave, Ordering::Relaxed);
+    ENABLED.store(enabled.bits() as usize, Ordering::Relaxed);
+
+    clts();
+    asm!("fninit" :::: "volatile");
+    let mxcsr = MXCSR_DEFAULT;
+    asm!("ldmxcsr $0" :: "*m"(&mxcsr) :: "volatile");
+    Ok(enabled)
+}
+
+/// A save area for the FPU, SSE, and AVX state of a task.
+///
+/// This only points at the area, which belongs to someone else, so it can be
+/// copied freely. An empty `FpuState` points at nothing, and belongs to a
+/// task that doesn't use the FPU.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FpuState { area: *mut u8 }
+
+impl FpuState {
+    /// Returns an empty `FpuState`.
+    #[inline]
+    pub const fn none() -> Self { FpuState { area: ptr::null_mut() } }
+
+    /// Returns an `FpuState` that saves the state in `area`, and reset the
+    /// state in it.
+    ///
+    /// # Safety
+    /// + `area` must be valid for `state_size()` bytes, aligned to
+    ///   `STATE_ALIGN`, and live as long as the `FpuState` is used.
+    pub unsafe fn new(area: *mut u8) -> Self {
+        assert!( area as usize % STATE_ALIGN == 0
+               , "FPU save area must be aligned to {} bytes!", STATE_ALIGN);
+        let state = FpuState { area: area };
+        state.reset();
+        state
+    }
+
+    /// Returns true if this is an empty `FpuState`.
+    #[inline]
+    pub fn is_none(&self) -> bool { self.area.is_null() }
+
+    /// Returns a pointer to the save area.
+    #[inline]
+    pub fn as_ptr(&self) -> *mut u8 { self.area }
+
+    /// Reset the state in the save area to the state after `fninit`.
+    ///
+    /// # Safety
+    /// + The same as `new`.
+    pub unsafe fn reset(&self) {
+        let len = if uses_xsave() { LEGACY_AREA_SIZE + XSAVE_HEADER_SIZE }
+                  else { LEGACY_AREA_SIZE };
+        // with an empty `xsave` header, `xrstor` puts every component in
+        // its initial state, except `MXCSR`.
+        ptr::write_bytes(self.area, 0, len);
+        ptr::write(self.area as *mut u16, FCW_DEFAULT);
+        ptr::write( self.area.offset(MXCSR_OFFSET as isize) as *mut u32
+                  , MXCSR_DEFAULT);
+    }
+
+    /// Save the current CPU's state in the save area.
+    ///
+    /// # Safety
+    /// + `TS` must be clear in `%cr0`, or this will fault.
+    /// + This must not be empty.
+    pub unsafe fn save(&self) {
+        if uses_xsave() {
+            let mask = enabled().bits();
+            asm!( "xsave64 ($0)"
+                :: "r"(self.area), "{eax}"(mask as u32)
+                 , "{edx}"((mask >> 32) as u32)
+                : "memory"
+                : "volatile");
+        } else {
+            asm!("fxsave64 ($0)" :: "r"(self.area) : "memory" : "volatile");
+        }
+    }
+
+    /// Load the state in the save area into the current CPU.
+    ///
+    /// # Safety
+    /// + The same as `save`.
+    pub unsafe fn restore(&self) {
+        if uses_xsave() {
+            let mask = enabled().bits();
+            asm!( "xrstor64 ($0)"
+                :: "r"(self.area), "{eax}"(mask as u32)
+                 , "{edx}"((mask >> 32) as u32)
+                : "memory"
+                : "volatile");
+        } else {
+            asm!("fxrstor64 ($0)" :: "r"(self.area) : "memory" : "volatile");
+        }
+    }
+}
+
+// the area is only accessed by the CPU the task is running on.
+unsafe impl Send for FpuState {}
+unsafe impl Sync for FpuState {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_mxcsr_masks_everything() {
+        let mxcsr = Mxcsr::from_bits_truncate(MXCSR_DEFAULT | 0b11_1111);
+        assert_eq!(mxcsr.raised(), EXCEPTIONS);
+        assert!(mxcsr.unmasked().is_empty());
+    }
+
+    #[test]
+    fn test_unmasked_exceptions() {
+        // divide by zero and precision raised; only divide by zero unmasked.
+        let mxcsr = Mxcsr::from_bits_truncate(
+            (MXCSR_DEFAULT & !DIVIDE_BY_ZERO_MASK.bits)
+            | DIVIDE_BY_ZERO.bits | PRECISION.bits);
+        assert_eq!(mxcsr.raised(), DIVIDE_BY_ZERO | PRECISION);
+        assert_eq!(mxcsr.unmasked(), DIVIDE_BY_ZERO);
+    }
+}
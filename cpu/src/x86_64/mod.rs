@@ -15,6 +15,7 @@
 #[path = "../x86_all/mod.rs"] mod cpu_all;
 
 pub mod context;
+pub mod fpu;
 pub mod task;
 pub mod msr;
 
@@ -7,10 +7,41 @@
 //  directory of this repository for more information.
 //
 //! Code for interacting with the Model-Specific Registers (MSRs).
+use ::segment;
 
 /// Extended Feature Enable Register (EFER) on IA-32
 pub const IA32_EFER: u32 = 0xc0000080;
 
+/// Set in `IA32_EFER` to enable the `syscall` and `sysret` instructions.
+pub const EFER_SCE: u64 = 1 << 0;
+
+/// Segment selectors loaded by `syscall` and `sysret`
+pub const IA32_STAR: u32 = 0xc0000081;
+
+/// Entry point of `syscall` in 64-bit mode
+pub const IA32_LSTAR: u32 = 0xc0000082;
+
+/// `%rflags` bits cleared by `syscall` (also called `SFMASK`)
+pub const IA32_FMASK: u32 = 0xc0000084;
+
+/// Local APIC base address and mode
+pub const IA32_APIC_BASE: u32 = 0x1b;
+
+/// Page Attribute Table (PAT)
+pub const IA32_PAT: u32 = 0x277;
+
+/// TSC value at which the local APIC timer fires, in TSC-deadline mode
+pub const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+/// Base address of the `%gs` segment
+pub const IA32_GS_BASE: u32 = 0xc0000101;
+
+/// Base address of the `%gs` segment that `swapgs` swaps in
+///
+/// While the kernel is running, this holds the user `%gs` base, and
+/// `IA32_GS_BASE` holds the kernel's.
+pub const IA32_KERNEL_GS_BASE: u32 = 0xc0000102;
+
 /// Write `value` to the specified `msr`
 ///
 /// # Arguments
@@ -37,6 +68,15 @@ pub unsafe fn read(msr: u32) -> u64 {
     ((high as u64) << 32) | (low as u64)
 }
 
+/// Exchange the `%gs` base with the contents of `IA32_KERNEL_GS_BASE`.
+///
+/// # Safety
+/// + Every `swapgs` on the way into the kernel must be matched by one on
+///   the way out, or the kernel will end up running with the user `%gs`.
+#[inline]
+pub unsafe fn swapgs() {
+    asm!("swapgs" :::: "volatile");
+}
 
 /// Enable the NXE (No Execute) in the IA-32 EFER register.
 ///
@@ -46,3 +86,47 @@ pub unsafe fn enable_nxe() {
     let efer = read(IA32_EFER) | nxe_bit;
     write(IA32_EFER, efer);
 }
+
+/// Returns the value of `IA32_STAR` for the kernel's GDT.
+///
+/// `syscall` loads the kernel `%cs` from bits 47:32, and `%ss` from the
+/// entry after it. `sysretq` loads the user `%ss` from the entry after the
+/// one in bits 63:48, and the user `%cs` from the entry after that, so they
+/// point at the kernel data segment, with RPL 3.
+#[inline]
+fn star() -> u64 {
+    let sysret_base = segment::USER_DS.bits() - 8;
+    (sysret_base as u64) << 48 | (segment::KERNEL_CS.bits() as u64) << 32
+}
+
+/// Enable `syscall`, with `entry` as its entry point.
+///
+/// # Arguments
+/// + `entry`: the address `syscall` jumps to
+/// + `rflags_mask`: the `%rflags` bits that `syscall` clears
+///
+/// # Safety
+/// + `entry` must be able to handle a `syscall`: it is entered in kernel
+///   mode, but with the user stack and `%gs` base.
+pub unsafe fn enable_syscall(entry: u64, rflags_mask: u64) {
+    write(IA32_STAR, star());
+    write(IA32_LSTAR, entry);
+    write(IA32_FMASK, rflags_mask);
+    write(IA32_EFER, read(IA32_EFER) | EFER_SCE);
+}
+
+#[cfg(test)]
+mod tests {
+    use ::segment::{KERNEL_CS, KERNEL_DS, USER_CS, USER_DS};
+
+    #[test]
+    fn test_star_selectors() {
+        let star = super::star();
+        let syscall_base = (star >> 32) as u16;
+        let sysret_base = (star >> 48) as u16;
+        assert_eq!(syscall_base, KERNEL_CS.bits());
+        assert_eq!(syscall_base + 8, KERNEL_DS.bits());
+        assert_eq!(sysret_base + 8, USER_DS.bits());
+        assert_eq!(sysret_base + 16, USER_CS.bits());
+    }
+}
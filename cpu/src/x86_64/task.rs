@@ -10,12 +10,90 @@
 use ::segment;
 use memory::VAddr;
 
+use core::mem::size_of;
+
 /// A 64-bit Task State Descriptor
-#[repr(packed)]
-pub struct StateDescriptor { pub upper: segment::Descriptor
-                           , pub lower: u64
+///
+/// In long mode, system segment descriptors are 16 bytes long: the lower 8
+/// bytes are laid out like a normal segment descriptor, and the upper 8
+/// bytes hold the high 32 bits of the base address.
+#[repr(C, packed)]
+pub struct StateDescriptor { /// The low 32 bits of the base, the limit and flags
+                             pub lower: segment::Descriptor
+                           , /// The high 32 bits of the base address
+                             pub upper: u64
                            }
 
+impl StateDescriptor {
+    /// Returns a new null `StateDescriptor`.
+    pub const fn null() -> Self {
+        StateDescriptor { lower: segment::Descriptor::null(), upper: 0 }
+    }
+
+    /// Returns a new available TSS descriptor for `tss`.
+    pub fn new(tss: &'static StateSegment) -> Self {
+        use segment::{Flags, SysType, PRESENT};
+        let base = tss as *const _ as u64;
+        let limit = size_of::<StateSegment>() as u32 - 1;
+        let mut lower = segment::Descriptor::new(base as u32, limit);
+        lower.flags = lower.flags | PRESENT
+                    | Flags::from_bits_truncate(SysType::TssAvailable as u16);
+        StateDescriptor { lower: lower, upper: base >> 32 }
+    }
+}
+
+/// The kernel's Task State Segment.
+///
+/// In long mode, the TSS is no longer used for task switching, but it holds
+/// the stack pointers that the CPU switches to on a privilege level change
+/// or when an interrupt uses the interrupt stack table.
+pub static mut TSS: StateSegment = StateSegment::new();
+
+/// Size of each interrupt stack, in bytes.
+pub const IST_STACK_SIZE: usize = 4096 * 4;
+
+/// IST index of the stack used by the double fault handler.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+/// IST index of the stack used by the non-maskable interrupt handler.
+pub const NMI_IST: u8 = 2;
+/// IST index of the stack used by the machine check handler.
+pub const MACHINE_CHECK_IST: u8 = 3;
+
+/// Number of IST stacks that each CPU needs.
+pub const N_IST_STACKS: usize = 3;
+
+/// Stacks for the interrupt stack table.
+///
+/// These live in the kernel's `.bss`, so that they are usable before the
+/// heap or the kernel page tables are set up. Note that they have no guard
+/// pages. They are only used by the bootstrap processor.
+static mut IST_STACKS: [[u64; IST_STACK_SIZE / 8]; N_IST_STACKS]
+    = [[0; IST_STACK_SIZE / 8]; N_IST_STACKS];
+
+/// Point the `TSS`'s interrupt stack table at the IST stacks.
+///
+/// # Safety
+/// + This must not be called while any of the IST stacks are in use.
+pub unsafe fn init_interrupt_stacks() {
+    for (i, stack) in IST_STACKS.iter().enumerate() {
+        // stacks grow down, so the IST entry is the end of the stack.
+        let top = VAddr::from(stack.as_ptr() as usize + IST_STACK_SIZE);
+        TSS.set_ist(i as u8 + 1, top);
+        trace!("IST[{}] = {:#x}", i + 1, top);
+    }
+}
+
+/// Load `selector` into the task register with the `ltr` instruction.
+///
+/// # Safety
+/// + `selector` must select a valid, available TSS descriptor in the GDT.
+pub unsafe fn load_tr(selector: segment::Selector) {
+    asm!(  "ltr $0"
+        :: "r"(selector.bits())
+        :  "memory"
+        :  "intel", "volatile");
+}
+
 
 /// A 64-bit Task State Segment
 #[repr(C, packed)]
@@ -25,7 +103,7 @@ pub struct StateSegment {
   , /// 64-bit values of the stack pointers (`%rsp`) for privilege rings 0-2
     //  TODO: should this be an array or just three u64s?
     pub rsp: [VAddr; 3]
-  , _reserved_2: u32
+  , _reserved_2: u64
   , /// 64-bit values of the interrupt stack table registers
     pub ist: [VAddr; 7]
   , _reserved_3: u64
@@ -44,7 +122,37 @@ impl StateSegment {
                      , ist: [ VAddr::new(0); 7 ]
                      , _reserved_3: 0
                      , _reserved_4: 0
-                     , iomap_base_offset: 0
+                       // no I/O permission bitmap
+                     , iomap_base_offset: 104
                      }
     }
+
+    /// Sets interrupt stack table entry `index` to `stack_top`.
+    ///
+    /// IST indices start at 1, as an index of 0 in an IDT gate means that
+    /// the IST isn't used.
+    ///
+    /// # Panics
+    /// + If `index` is not between 1 and 7.
+    pub fn set_ist(&mut self, index: u8, stack_top: VAddr) {
+        assert!( index >= 1 && index <= 7
+               , "IST index must be between 1 and 7!");
+        self.ist[index as usize - 1] = stack_top;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::mem::size_of;
+    use super::{StateDescriptor, StateSegment};
+
+    #[test]
+    fn test_state_segment_correct_size() {
+        assert_eq!(size_of::<StateSegment>(), 104);
+    }
+
+    #[test]
+    fn test_state_descriptor_correct_size() {
+        assert_eq!(size_of::<StateDescriptor>(), 16);
+    }
 }
@@ -71,6 +71,11 @@ cpu_flag! {
         protect.",
     WP, is_write_protected, enable_write_protect
 }
+cpu_flag! {
+    doc="If set, the next FPU, SSE, or AVX instruction raises a Device Not \
+        Available exception.",
+    TS, is_task_switched, set_task_switched
+}
 
 ///// Set the write protect bit in `%cr0`.
 //pub fn enable_write_protect() {
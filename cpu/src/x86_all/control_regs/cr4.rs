@@ -82,6 +82,11 @@ bitflags! {
         ///
         /// If set, enables unmasked SSE exceptions.
         const OSXMMEXCPT = 1 << 10
+      , /// User-Mode Instruction Prevention
+        ///
+        /// If set, the `SGDT`, `SIDT`, `SLDT`, `SMSW` and `STR` instructions
+        /// cannot be executed outside of Ring 0.
+        const UMIP = 1 << 11
       , /// Virtual Machine Extensions Enable
         const VMXE = 1 << 13
       , /// Safer Mode Extensions Enable
@@ -100,7 +105,7 @@ bitflags! {
         const SMEP = 1 << 20
       , /// Supervisor Mode Access Protection Enable
         ///
-        /// If set, access of data in a higher ring generates a faul
+        /// If set, access of data in a higher ring generates a fault
         const SMAP = 1 << 21
       , /// Protection Key Enable
         const PKE = 1 << 22
@@ -117,3 +122,21 @@ cpu_flag! {
     doc="If disabled, the `RTDSC` instruction can only be executed in Ring 0.",
     TSD, is_timestamp_disabled, disable_timestamp
 }
+cpu_flag! {
+    doc="If set, the CPU cannot execute code in user pages while in Ring 0.",
+    SMEP, is_smep_enabled, enable_smep
+}
+cpu_flag! {
+    doc="If set, the CPU cannot access data in user pages while in Ring 0, \
+        unless the `AC` flag in `%rflags` is set.",
+    SMAP, is_smap_enabled, enable_smap
+}
+cpu_flag! {
+    doc="If set, descriptor table instructions cannot be executed outside \
+        of Ring 0.",
+    UMIP, is_umip_enabled, enable_umip
+}
+cpu_flag! {
+    doc="If set, the low 12 bits of `%cr3` hold a process-context identifier.",
+    PCIDE, is_pcid_enabled, enable_pcid
+}
@@ -0,0 +1,380 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Querying CPU features with the `cpuid` instruction.
+//!
+//! The features the kernel cares about are decoded into a [`CpuFeatures`]
+//! once, and cached, so the `has_` functions don't execute `cpuid` (which
+//! is slow, and traps under a hypervisor) every time they're called.
+//!
+//! [`CpuFeatures`]: struct.CpuFeatures.html
+#![warn(missing_docs)]
+use spin::Once;
+
+use core::{fmt, str};
+
+/// The values of the four registers written by `cpuid`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct CpuId { /// The value of `%eax`
+                   pub eax: u32
+                 , /// The value of `%ebx`
+                   pub ebx: u32
+                 , /// The value of `%ecx`
+                   pub ecx: u32
+                 , /// The value of `%edx`
+                   pub edx: u32
+                 }
+
+/// Execute `cpuid` with the given leaf (`%eax`) and subleaf (`%ecx`).
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuId {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!(  "cpuid"
+            :  "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+            :  "{eax}"(leaf), "{ecx}"(subleaf)
+            :: "volatile" );
+    }
+    CpuId { eax: eax, ebx: ebx, ecx: ecx, edx: edx }
+}
+
+/// Returns the highest basic leaf supported by `cpuid`.
+#[inline]
+pub fn max_leaf() -> u32 { cpuid(0, 0).eax }
+
+/// Returns the basic feature flags (leaf 1).
+#[inline]
+pub fn features() -> CpuId { cpuid(1, 0) }
+
+/// Returns the ID of the current CPU's local APIC.
+///
+/// This is the 32-bit x2APIC ID from leaf `0xb` if it is supported, and the
+/// 8-bit initial APIC ID from leaf 1 otherwise.
+pub fn apic_id() -> u32 {
+    if max_leaf() >= 0xb {
+        let topology = cpuid(0xb, 0);
+        // leaf 0xb is unsupported if it reports no logical processors.
+        if topology.ebx != 0 { return topology.edx }
+    }
+    features().ebx >> 24
+}
+
+/// Returns the structured extended feature flags (leaf 7, subleaf 0).
+///
+/// If leaf 7 is not supported, all flags are clear.
+#[inline]
+pub fn extended_features() -> CpuId {
+    if max_leaf() >= 7 { cpuid(7, 0) } else { CpuId::default() }
+}
+
+/// Returns the extended processor feature flags (leaf `0x8000_0001`).
+///
+/// If that leaf is not supported, all flags are clear.
+#[inline]
+pub fn extended_processor_features() -> CpuId {
+    if cpuid(0x8000_0000, 0).eax >= 0x8000_0001 { cpuid(0x8000_0001, 0) }
+    else { CpuId::default() }
+}
+
+/// Returns the advanced power management flags (leaf `0x8000_0007`).
+///
+/// If that leaf is not supported, all flags are clear.
+#[inline]
+pub fn power_management() -> CpuId {
+    if cpuid(0x8000_0000, 0).eax >= 0x8000_0007 { cpuid(0x8000_0007, 0) }
+    else { CpuId::default() }
+}
+
+/// The manufacturer of a CPU.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Vendor { /// `GenuineIntel`
+                  Intel
+                , /// `AuthenticAMD`
+                  Amd
+                , /// Any other vendor ID string
+                  Other
+                }
+
+/// The features of the CPU that the kernel cares about.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CpuFeatures { /// The manufacturer of the CPU
+                         pub vendor: Vendor
+                       , /// The vendor ID string (from leaf 0)
+                         pub vendor_id: [u8; 12]
+                       , /// The CPU family, including the extended family
+                         pub family: u32
+                       , /// The CPU model, including the extended model
+                         pub model: u32
+                       , /// The CPU stepping
+                         pub stepping: u32
+                       , /// The time stamp counter (`rdtsc`)
+                         pub tsc: bool
+                       , /// A TSC that runs at a constant rate in every
+                         /// power state
+                         pub invariant_tsc: bool
+                       , /// The `rdtscp` instruction
+                         pub rdtscp: bool
+                       , /// SSE
+                         pub sse: bool
+                       , /// SSE2
+                         pub sse2: bool
+                       , /// SSE3
+                         pub sse3: bool
+                       , /// Supplemental SSE3
+                         pub ssse3: bool
+                       , /// SSE4.1
+                         pub sse4_1: bool
+                       , /// SSE4.2
+                         pub sse4_2: bool
+                       , /// AVX
+                         pub avx: bool
+                       , /// AVX2
+                         pub avx2: bool
+                       , /// `xsave`, `xrstor`, and `xgetbv`
+                         pub xsave: bool
+                       , /// The no-execute page bit
+                         pub nx: bool
+                       , /// Process-context identifiers
+                         pub pcid: bool
+                       , /// The `invpcid` instruction
+                         pub invpcid: bool
+                       , /// Supervisor Mode Execution Protection
+                         pub smep: bool
+                       , /// Supervisor Mode Access Prevention
+                         pub smap: bool
+                       , /// User-Mode Instruction Prevention
+                         pub umip: bool
+                       , /// The page attribute table
+                         pub pat: bool
+                       , /// A local APIC
+                         pub apic: bool
+                       , /// The local APIC's x2APIC mode
+                         pub x2apic: bool
+                       , /// The local APIC timer's TSC-deadline mode
+                         pub tsc_deadline: bool
+                       , /// The `rdrand` instruction
+                         pub rdrand: bool
+                       , /// 1 GiB pages
+                         pub huge_pages: bool
+                       }
+
+impl CpuFeatures {
+    /// Decode the features from the values `cpuid` returned for leaf 0
+    /// (`vendor`), leaf 1 (`basic`), leaf 7 (`extended`), leaf
+    /// `0x8000_0001` (`processor`), and leaf `0x8000_0007` (`power`).
+    pub fn from_leaves( vendor: CpuId, basic: CpuId, extended: CpuId
+                      , processor: CpuId, power: CpuId) -> Self {
+        let bit = |reg: u32, n: u32| reg & (1 << n) != 0;
+
+        // the vendor ID string is in %ebx, %edx, %ecx, in that order.
+        let mut vendor_id = [0; 12];
+        for (i, reg) in [vendor.ebx, vendor.edx, vendor.ecx].iter().enumerate() {
+            for j in 0..4 {
+                vendor_id[i * 4 + j] = (reg >> (j * 8)) as u8;
+            }
+        }
+
+        let base_family = (basic.eax >> 8) & 0xf;
+        let base_model = (basic.eax >> 4) & 0xf;
+        // the extended family and model are only used for some families.
+        let family = if base_family == 0xf {
+            base_family + ((basic.eax >> 20) & 0xff)
+        } else { base_family };
+        let model = if base_family == 0x6 || base_family == 0xf {
+            ((basic.eax >> 12) & 0xf0) | base_model
+        } else { base_model };
+
+        let vendor = if &vendor_id == b"GenuineIntel" { Vendor::Intel }
+                     else if &vendor_id == b"AuthenticAMD" { Vendor::Amd }
+                     else { Vendor::Other };
+
+        CpuFeatures { vendor: vendor
+                    , vendor_id: vendor_id
+                    , family: family
+                    , model: model
+                    , stepping: basic.eax & 0xf
+                    , tsc: bit(basic.edx, 4)
+                    , invariant_tsc: bit(power.edx, 8)
+                    , rdtscp: bit(processor.edx, 27)
+                    , sse: bit(basic.edx, 25)
+                    , sse2: bit(basic.edx, 26)
+                    , sse3: bit(basic.ecx, 0)
+                    , ssse3: bit(basic.ecx, 9)
+                    , sse4_1: bit(basic.ecx, 19)
+                    , sse4_2: bit(basic.ecx, 20)
+                    , avx: bit(basic.ecx, 28)
+                    , avx2: bit(extended.ebx, 5)
+                    , xsave: bit(basic.ecx, 26)
+                    , nx: bit(processor.edx, 20)
+                    , pcid: bit(basic.ecx, 17)
+                    , invpcid: bit(extended.ebx, 10)
+                    , smep: bit(extended.ebx, 7)
+                    , smap: bit(extended.ebx, 20)
+                    , umip: bit(extended.ecx, 2)
+                    , pat: bit(basic.edx, 16)
+                    , apic: bit(basic.edx, 9)
+                    , x2apic: bit(basic.ecx, 21)
+                    , tsc_deadline: bit(basic.ecx, 24)
+                    , rdrand: bit(basic.ecx, 30)
+                    , huge_pages: bit(processor.edx, 26)
+                    }
+    }
+
+    /// Detect the features of the current CPU.
+    pub fn detect() -> Self {
+        CpuFeatures::from_leaves( cpuid(0, 0), features(), extended_features()
+                                , extended_processor_features()
+                                , power_management())
+    }
+
+    /// Returns the vendor ID string.
+    #[inline]
+    pub fn vendor_id_str(&self) -> &str {
+        str::from_utf8(&self.vendor_id).unwrap_or("(invalid)")
+    }
+}
+
+impl fmt::Display for CpuFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "{} family {:#x} model {:#x} stepping {}:"
+              , self.vendor_id_str(), self.family, self.model, self.stepping)?;
+        let flags = [ (self.tsc, "tsc"), (self.invariant_tsc, "invariant-tsc")
+                    , (self.rdtscp, "rdtscp")
+                    , (self.sse, "sse"), (self.sse2, "sse2")
+                    , (self.sse3, "sse3"), (self.ssse3, "ssse3")
+                    , (self.sse4_1, "sse4.1"), (self.sse4_2, "sse4.2")
+                    , (self.avx, "avx"), (self.avx2, "avx2")
+                    , (self.xsave, "xsave"), (self.nx, "nx")
+                    , (self.pcid, "pcid"), (self.invpcid, "invpcid")
+                    , (self.smep, "smep"), (self.smap, "smap")
+                    , (self.umip, "umip"), (self.pat, "pat")
+                    , (self.apic, "apic"), (self.x2apic, "x2apic")
+                    , (self.tsc_deadline, "tsc-deadline")
+                    , (self.rdrand, "rdrand"), (self.huge_pages, "1gb-pages")
+                    ];
+        for &(_, name) in flags.iter().filter(|&&(has, _)| has) {
+            write!(f, " {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// The features of the CPU, once they have been detected.
+static CPU_FEATURES: Once<CpuFeatures> = Once::new();
+
+/// Returns the features of the CPU.
+///
+/// They are detected the first time this is called, and cached; every CPU
+/// in the system is assumed to have the same features.
+#[inline]
+pub fn cpu_features() -> &'static CpuFeatures {
+    CPU_FEATURES.call_once(CpuFeatures::detect)
+}
+
+/// Returns true if Supervisor Mode Execution Protection is supported.
+#[inline]
+pub fn has_smep() -> bool { cpu_features().smep }
+
+/// Returns true if Supervisor Mode Access Prevention is supported.
+#[inline]
+pub fn has_smap() -> bool { cpu_features().smap }
+
+/// Returns true if User-Mode Instruction Prevention is supported.
+#[inline]
+pub fn has_umip() -> bool { cpu_features().umip }
+
+/// Returns true if process-context identifiers are supported.
+#[inline]
+pub fn has_pcid() -> bool { cpu_features().pcid }
+
+/// Returns true if the `invpcid` instruction is supported.
+#[inline]
+pub fn has_invpcid() -> bool { cpu_features().invpcid }
+
+/// Returns true if the page attribute table is supported.
+#[inline]
+pub fn has_pat() -> bool { cpu_features().pat }
+
+/// Returns true if the CPU has a local APIC.
+#[inline]
+pub fn has_apic() -> bool { cpu_features().apic }
+
+/// Returns true if the local APIC supports x2APIC mode.
+#[inline]
+pub fn has_x2apic() -> bool { cpu_features().x2apic }
+
+/// Returns true if the CPU has a time stamp counter that runs at a constant
+/// rate in every power state.
+#[inline]
+pub fn has_invariant_tsc() -> bool {
+    let features = cpu_features();
+    features.tsc && features.invariant_tsc
+}
+
+/// Returns true if the local APIC timer supports TSC-deadline mode.
+#[inline]
+pub fn has_tsc_deadline() -> bool { cpu_features().tsc_deadline }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `cpuid` leaf 0 on an Intel CPU.
+    const INTEL: CpuId = CpuId { eax: 0xd
+                               , ebx: 0x756e_6547 // "Genu"
+                               , ecx: 0x6c65_746e // "ntel"
+                               , edx: 0x4965_6e69 // "ineI"
+                               };
+
+    #[test]
+    fn test_vendor() {
+        let features = CpuFeatures::from_leaves( INTEL, CpuId::default()
+                                               , CpuId::default()
+                                               , CpuId::default()
+                                               , CpuId::default());
+        assert_eq!(features.vendor, Vendor::Intel);
+        assert_eq!(features.vendor_id_str(), "GenuineIntel");
+    }
+
+    #[test]
+    fn test_family_and_model() {
+        // a Skylake: family 6, extended model 5, model 0xe, stepping 3.
+        let basic = CpuId { eax: 0x0005_06e3, ..CpuId::default() };
+        let features = CpuFeatures::from_leaves( INTEL, basic
+                                               , CpuId::default()
+                                               , CpuId::default()
+                                               , CpuId::default());
+        assert_eq!(features.family, 6);
+        assert_eq!(features.model, 0x5e);
+        assert_eq!(features.stepping, 3);
+
+        // an AMD Zen: family 0xf + extended family 8, model 1.
+        let basic = CpuId { eax: 0x0080_0f11, ..CpuId::default() };
+        let features = CpuFeatures::from_leaves( INTEL, basic
+                                               , CpuId::default()
+                                               , CpuId::default()
+                                               , CpuId::default());
+        assert_eq!(features.family, 0x17);
+        assert_eq!(features.model, 0x1);
+    }
+
+    #[test]
+    fn test_feature_bits() {
+        let basic = CpuId { ecx: 1 << 26 | 1 << 30, edx: 1 << 25 | 1 << 4
+                          , ..CpuId::default() };
+        let extended = CpuId { ebx: 1 << 7, ..CpuId::default() };
+        let processor = CpuId { edx: 1 << 20 | 1 << 26, ..CpuId::default() };
+        let power = CpuId { edx: 1 << 8, ..CpuId::default() };
+        let features = CpuFeatures::from_leaves( INTEL, basic, extended
+                                               , processor, power);
+        assert!(features.xsave && features.rdrand && features.sse);
+        assert!(features.smep && !features.smap);
+        assert!(features.nx && features.huge_pages && !features.rdtscp);
+        assert!(!features.avx && !features.x2apic);
+        assert!(features.tsc && features.invariant_tsc);
+    }
+}
@@ -0,0 +1,255 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Hardware breakpoints and watchpoints, with the debug registers.
+//!
+//! `%dr0`-`%dr3` hold the addresses of up to four breakpoints, and `%dr7`
+//! controls which of them are enabled, and under which [`Condition`] and
+//! [`Length`] they fire. When one fires, the CPU raises a debug exception
+//! (vector 1), and sets its bit in `%dr6`.
+//!
+//! Execute breakpoints are faults, and fire before the instruction runs, so
+//! the handler must set `RF` in the interrupted `%rflags` to resume. Data
+//! breakpoints are traps, and fire after the access.
+//!
+//! For more information, refer to the _Intel® 64 and IA-32 Architectures
+//! Software Developer’s Manual_, Vol. 3B, section 17.2, "Debug Registers".
+#![warn(missing_docs)]
+
+/// The number of hardware breakpoints.
+pub const N_BREAKPOINTS: usize = 4;
+
+/// When a breakpoint fires.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(usize)]
+pub enum Condition { /// When the instruction at the address is executed
+                     Execute = 0b00
+                   , /// When the address is written to
+                     Write = 0b01
+                   , /// When the address is read from or written to
+                     ReadWrite = 0b11
+                   }
+
+/// How many bytes a breakpoint covers.
+///
+/// The breakpoint's address must be aligned to its length.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(usize)]
+pub enum Length { /// 1 byte
+                  Byte = 0b00
+                , /// 2 bytes
+                  Word = 0b01
+                , /// 8 bytes
+                  Quad = 0b10
+                , /// 4 bytes
+                  Dword = 0b11
+                }
+
+impl Length {
+    /// Returns the `Length` covering `bytes` bytes, if there is one.
+    pub fn from_bytes(bytes: usize) -> Option<Self> {
+        match bytes { 1 => Some(Length::Byte)
+                    , 2 => Some(Length::Word)
+                    , 4 => Some(Length::Dword)
+                    , 8 => Some(Length::Quad)
+                    , _ => None
+                    }
+    }
+
+    /// Returns the number of bytes covered.
+    pub fn bytes(&self) -> usize {
+        match *self { Length::Byte => 1
+                    , Length::Word => 2
+                    , Length::Dword => 4
+                    , Length::Quad => 8
+                    }
+    }
+}
+
+bitflags! {
+    /// Contents of the debug status register, `%dr6`.
+    pub flags Dr6: usize {
+        /// Breakpoint 0 fired
+        const B0 = 1 << 0
+      , /// Breakpoint 1 fired
+        const B1 = 1 << 1
+      , /// Breakpoint 2 fired
+        const B2 = 1 << 2
+      , /// Breakpoint 3 fired
+        const B3 = 1 << 3
+      , /// An instruction accessed a debug register while `GD` was set
+        const BD = 1 << 13
+      , /// Single-step (`TF`) trap
+        const BS = 1 << 14
+      , /// Task switch trap
+        const BT = 1 << 15
+    }
+}
+
+impl Dr6 {
+    /// Returns true if breakpoint `index` fired.
+    #[inline]
+    pub fn hit(&self, index: usize) -> bool {
+        index < N_BREAKPOINTS && self.bits & (1 << index) != 0
+    }
+}
+
+/// Returns `dr7` with breakpoint `index` enabled (locally and globally),
+/// firing under `condition`, and covering `length`.
+///
+/// # Panics
+/// + If `index` is not less than `N_BREAKPOINTS`.
+pub fn dr7_enable( dr7: usize, index: usize
+                 , condition: Condition, length: Length) -> usize {
+    assert!(index < N_BREAKPOINTS, "No such breakpoint!");
+    let control = 16 + index * 4;
+    let cleared = dr7 & !(0b1111 << control);
+    cleared | (0b11 << (index * 2))
+            | (condition as usize) << control
+            | (length as usize) << (control + 2)
+}
+
+/// Returns `dr7` with breakpoint `index` disabled.
+///
+/// # Panics
+/// + If `index` is not less than `N_BREAKPOINTS`.
+pub fn dr7_disable(dr7: usize, index: usize) -> usize {
+    assert!(index < N_BREAKPOINTS, "No such breakpoint!");
+    dr7 & !(0b11 << (index * 2)) & !(0b1111 << (16 + index * 4))
+}
+
+macro_rules! debug_reg {
+    ($doc:expr, $read:ident, $read_asm:tt, $write:ident, $write_asm:tt) => {
+        #[doc=$doc]
+        #[inline]
+        pub unsafe fn $read() -> usize {
+            let value: usize;
+            asm!( $read_asm : "=r"(value) ::: "intel");
+            value
+        }
+
+        #[doc=$doc]
+        #[inline]
+        pub unsafe fn $write(value: usize) {
+            asm!( $write_asm :: "r"(value) :: "intel");
+        }
+    }
+}
+
+debug_reg!( "Breakpoint 0's address."
+          , read_dr0, "mov $0, dr0", write_dr0, "mov dr0, $0");
+debug_reg!( "Breakpoint 1's address."
+          , read_dr1, "mov $0, dr1", write_dr1, "mov dr1, $0");
+debug_reg!( "Breakpoint 2's address."
+          , read_dr2, "mov $0, dr2", write_dr2, "mov dr2, $0");
+debug_reg!( "Breakpoint 3's address."
+          , read_dr3, "mov $0, dr3", write_dr3, "mov dr3, $0");
+debug_reg!( "The debug status register."
+          , read_dr6, "mov $0, dr6", write_dr6, "mov dr6, $0");
+debug_reg!( "The debug control register."
+          , read_dr7, "mov $0, dr7", write_dr7, "mov dr7, $0");
+
+/// Read the address of breakpoint `index`.
+///
+/// # Safety
+/// + Reading a debug register outside of ring 0 is a general protection
+///   fault.
+pub unsafe fn address(index: usize) -> usize {
+    match index { 0 => read_dr0()
+                , 1 => read_dr1()
+                , 2 => read_dr2()
+                , 3 => read_dr3()
+                , _ => panic!("No such breakpoint!")
+                }
+}
+
+/// Set breakpoint `index` at `address`, and enable it on this CPU.
+///
+/// # Returns
+/// + `Ok(())` if the breakpoint was set
+/// + `Err` if `address` isn't aligned to `length`, or `condition` is
+///   `Execute` and `length` isn't `Byte`.
+///
+/// # Safety
+/// + Writing a debug register outside of ring 0 is a general protection
+///   fault.
+/// + Something must handle the debug exceptions.
+pub unsafe fn set( index: usize, address: usize
+                 , condition: Condition, length: Length)
+                 -> Result<(), &'static str> {
+    if index >= N_BREAKPOINTS {
+        return Err("No such breakpoint!")
+    }
+    if address % length.bytes() != 0 {
+        return Err("Breakpoint address isn't aligned to its length!")
+    }
+    if condition == Condition::Execute && length != Length::Byte {
+        return Err("Execute breakpoints must have a length of 1 byte!")
+    }
+    match index { 0 => write_dr0(address)
+                , 1 => write_dr1(address)
+                , 2 => write_dr2(address)
+                , _ => write_dr3(address)
+                }
+    write_dr7(dr7_enable(read_dr7(), index, condition, length));
+    Ok(())
+}
+
+/// Disable breakpoint `index` on this CPU.
+///
+/// # Safety
+/// + The same as `set`.
+pub unsafe fn clear(index: usize) {
+    write_dr7(dr7_disable(read_dr7(), index));
+}
+
+/// Returns which breakpoints fired, from `%dr6`.
+///
+/// # Safety
+/// + The same as `address`.
+#[inline]
+pub unsafe fn status() -> Dr6 { Dr6::from_bits_truncate(read_dr6()) }
+
+/// Clear the status bits in `%dr6`.
+///
+/// The CPU never clears them itself, so the debug exception handler must.
+///
+/// # Safety
+/// + The same as `address`.
+#[inline]
+pub unsafe fn clear_status() { write_dr6(0) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dr7_enable() {
+        let dr7 = dr7_enable(0, 1, Condition::Write, Length::Quad);
+        // L1 and G1, R/W1 = 01, LEN1 = 10
+        assert_eq!(dr7, 0b1100 | 0b1001 << 20);
+        let dr7 = dr7_enable(dr7, 3, Condition::Execute, Length::Byte);
+        assert_eq!(dr7, 0b1100_1100 | 0b1001 << 20);
+    }
+
+    #[test]
+    fn test_dr7_disable() {
+        let dr7 = dr7_enable(0, 0, Condition::ReadWrite, Length::Dword);
+        let dr7 = dr7_enable(dr7, 2, Condition::Write, Length::Word);
+        assert_eq!(dr7_disable(dr7, 0), dr7_enable(0, 2, Condition::Write
+                                                  , Length::Word));
+    }
+
+    #[test]
+    fn test_length_from_bytes() {
+        for &len in [1, 2, 4, 8].iter() {
+            assert_eq!(Length::from_bytes(len).unwrap().bytes(), len);
+        }
+        assert_eq!(Length::from_bytes(3), None);
+    }
+}
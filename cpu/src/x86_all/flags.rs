@@ -101,3 +101,26 @@ pub fn read() -> Flags {
     }
     Flags { bits: result }
 }
+
+/// Set the `AC` flag, allowing supervisor-mode access to user pages.
+///
+/// # Safety
+/// + `stac` causes an invalid opcode exception if SMAP is not supported.
+/// + While `AC` is set, SMAP no longer protects the kernel from accessing
+///   user memory, so it should be cleared again with [`clac`] as soon as
+///   possible.
+///
+/// [`clac`]: fn.clac.html
+#[inline]
+pub unsafe fn stac() {
+    asm!("stac" :::: "volatile");
+}
+
+/// Clear the `AC` flag, disallowing supervisor-mode access to user pages.
+///
+/// # Safety
+/// + `clac` causes an invalid opcode exception if SMAP is not supported.
+#[inline]
+pub unsafe fn clac() {
+    asm!("clac" :::: "volatile");
+}
@@ -0,0 +1,723 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Support for the Advanced Programmable Interrupt Controller (APIC).
+//!
+//! Every CPU has a _local APIC_, which delivers interrupts to that CPU, and
+//! the system has one or more _I/O APICs_, which route external interrupts
+//! to the local APICs. Once the APIC is enabled, the 8259 PICs are masked and
+//! the legacy ISA IRQs are delivered through the I/O APIC instead, on the
+//! same vectors that the PICs were remapped to.
+//!
+//! The local APIC is accessed either through a page of memory-mapped
+//! registers (xAPIC mode), or through MSRs (x2APIC mode), which we prefer
+//! when the CPU supports it. As this crate doesn't know about paging, the
+//! MMIO regions of the local and I/O APICs must be mapped by the caller.
+//!
+//! The ISA IRQs aren't necessarily wired to the I/O APIC input with the same
+//! number: the firmware describes the exceptions (such as the PIT's IRQ 0
+//! usually being wired to input 2) as _interrupt source overrides_ in the
+//! ACPI MADT, which [`isa_routes`] takes into account.
+//!
+//! [`isa_routes`]: fn.isa_routes.html
+//!
+//! For more information, refer to the _Intel® 64 and IA-32 Architectures
+//! Software Developer’s Manual_, Vol. 3A, chapter 10, "Advanced Programmable
+//! Interrupt Controller (APIC)", and the _82093AA I/O Advanced Programmable
+//! Interrupt Controller (IOAPIC)_ datasheet.
+use core::ptr;
+
+use memory::{PAddr, VAddr};
+use spin::{Mutex, Once};
+
+use ::{cpuid, msr};
+use super::pics;
+
+/// Vector that the legacy ISA IRQs start at.
+///
+/// This is the same offset the PICs are remapped to, so IRQ handlers don't
+/// need to care which interrupt controller delivered them.
+pub const IRQ_BASE: u8 = 0x20;
+
+/// Number of legacy ISA IRQs.
+pub const N_LEGACY_IRQS: u8 = 16;
+
+/// Vector for spurious interrupts from the local APIC.
+pub const SPURIOUS_VECTOR: u8 = 0xef;
+
+/// Size of the I/O APIC's MMIO region, in bytes.
+pub const IO_APIC_MMIO_SIZE: usize = 0x20;
+
+/// Size of the local APIC's MMIO region, in bytes.
+pub const LOCAL_APIC_MMIO_SIZE: usize = 0x1000;
+
+/// The first of the MSRs that x2APIC registers are mapped to.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Set in `IA32_APIC_BASE` if this is the bootstrap processor.
+const APIC_BASE_BSP: u64 = 1 << 8;
+/// Set in `IA32_APIC_BASE` to enable x2APIC mode.
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+/// Set in `IA32_APIC_BASE` to enable the local APIC.
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+/// The physical address bits of `IA32_APIC_BASE`.
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Set in the spurious interrupt vector register to enable the local APIC.
+const SVR_ENABLE: u32 = 1 << 8;
+
+/// Maximum number of I/O APICs we keep track of.
+pub const MAX_IO_APICS: usize = 8;
+
+/// Local APIC registers, as offsets into the xAPIC MMIO page.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum Register { /// Local APIC ID
+                    Id                   = 0x020
+                  , /// Local APIC version
+                    Version              = 0x030
+                  , /// Task priority
+                    TaskPriority         = 0x080
+                  , /// End of interrupt (write only)
+                    EndOfInterrupt       = 0x0b0
+                  , /// Spurious interrupt vector
+                    SpuriousVector       = 0x0f0
+                  , /// Error status
+                    ErrorStatus          = 0x280
+                  , /// Interrupt command, low 32 bits
+                    InterruptCommandLow  = 0x300
+                  , /// Interrupt command, high 32 bits (xAPIC only)
+                    InterruptCommandHigh = 0x310
+                  , /// LVT timer
+                    LvtTimer             = 0x320
+                  , /// LVT `LINT0` pin
+                    LvtLint0             = 0x350
+                  , /// LVT `LINT1` pin
+                    LvtLint1             = 0x360
+                  , /// LVT error
+                    LvtError             = 0x370
+                  , /// Timer initial count
+                    TimerInitialCount    = 0x380
+                  , /// Timer current count (read only)
+                    TimerCurrentCount    = 0x390
+                  , /// Timer divide configuration
+                    TimerDivide          = 0x3e0
+                  }
+
+/// Set in a local vector table entry to mask it.
+pub const LVT_MASKED: u32 = 1 << 16;
+
+/// Vector that the local APIC timer interrupt is delivered on.
+pub const TIMER_VECTOR: u8 = 0xee;
+
+/// LVT timer mode: count down once.
+pub const LVT_TIMER_ONESHOT: u32 = 0b00 << 17;
+/// LVT timer mode: count down repeatedly.
+pub const LVT_TIMER_PERIODIC: u32 = 0b01 << 17;
+/// LVT timer mode: fire when the TSC reaches `IA32_TSC_DEADLINE`.
+pub const LVT_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+
+/// Timer divide configuration: divide the bus clock by 16.
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+/// The local APIC timer counts at the bus clock divided by this.
+pub const TIMER_DIVISOR: u32 = 16;
+
+/// Interrupt command delivery mode: `INIT`.
+pub const ICR_INIT: u32 = 0b101 << 8;
+/// Interrupt command delivery mode: `STARTUP`.
+pub const ICR_STARTUP: u32 = 0b110 << 8;
+/// Set in the interrupt command register while an IPI is being sent
+/// (xAPIC only).
+const ICR_SEND_PENDING: u32 = 1 << 12;
+/// Interrupt command level: assert.
+pub const ICR_ASSERT: u32 = 1 << 14;
+
+/// Returns true if the CPU has a local APIC.
+#[inline]
+pub fn is_available() -> bool { cpuid::has_apic() }
+
+/// Returns the physical address of the local APIC's MMIO registers.
+#[inline]
+pub fn base_address() -> PAddr {
+    PAddr::from(unsafe { msr::read(msr::IA32_APIC_BASE) } & APIC_BASE_ADDR_MASK)
+}
+
+/// Returns true if this is the bootstrap processor.
+#[inline]
+pub fn is_bsp() -> bool {
+    unsafe { msr::read(msr::IA32_APIC_BASE) & APIC_BASE_BSP != 0 }
+}
+
+/// A local APIC.
+///
+/// Since every CPU sees its own local APIC at the same address (or through
+/// the same MSRs), one `LocalApic` can be shared by all CPUs.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LocalApic { /// An xAPIC, with its registers mapped at this address
+                     XApic(VAddr)
+                   , /// An x2APIC, with its registers accessed through MSRs
+                     X2Apic
+                   }
+
+impl LocalApic {
+
+    /// Read a local APIC register.
+    pub unsafe fn read(&self, reg: Register) -> u32 {
+        match *self {
+            LocalApic::XApic(base) =>
+                ptr::read_volatile((*base + reg as usize) as *const u32)
+          , LocalApic::X2Apic =>
+                msr::read(X2APIC_MSR_BASE + (reg as u32 >> 4)) as u32
+        }
+    }
+
+    /// Write to a local APIC register.
+    pub unsafe fn write(&self, reg: Register, value: u32) {
+        match *self {
+            LocalApic::XApic(base) =>
+                ptr::write_volatile((*base + reg as usize) as *mut u32, value)
+          , LocalApic::X2Apic =>
+                msr::write(X2APIC_MSR_BASE + (reg as u32 >> 4), value as u64)
+        }
+    }
+
+    /// Returns true if this local APIC is in x2APIC mode.
+    #[inline] pub fn is_x2apic(&self) -> bool { *self == LocalApic::X2Apic }
+
+    /// Returns the ID of the local APIC of the current CPU.
+    pub fn id(&self) -> u32 {
+        let id = unsafe { self.read(Register::Id) };
+        match *self { LocalApic::XApic(_) => id >> 24
+                    , LocalApic::X2Apic => id
+                    }
+    }
+
+    /// Returns the version of the local APIC.
+    #[inline]
+    pub fn version(&self) -> u8 {
+        unsafe { self.read(Register::Version) as u8 }
+    }
+
+    /// Enable the current CPU's local APIC.
+    ///
+    /// Spurious interrupts will be delivered on `spurious_vector`, and the
+    /// local interrupt pins and error interrupt are masked.
+    pub unsafe fn enable(&self, spurious_vector: u8) {
+        self.write(Register::LvtLint0, LVT_MASKED);
+        self.write(Register::LvtLint1, LVT_MASKED);
+        self.write(Register::LvtError, LVT_MASKED);
+        // accept interrupts of every priority
+        self.write(Register::TaskPriority, 0);
+        self.write( Register::SpuriousVector
+                  , SVR_ENABLE | spurious_vector as u32);
+    }
+
+    /// Set the local APIC timer's mode (one of the `LVT_TIMER_` constants),
+    /// and the vector it interrupts on.
+    ///
+    /// In the counter modes, the timer counts down from the count set with
+    /// `set_timer_count` at the bus clock divided by `TIMER_DIVISOR`.
+    pub unsafe fn set_timer(&self, vector: u8, mode: u32) {
+        self.write(Register::TimerDivide, TIMER_DIVIDE_16);
+        self.write(Register::LvtTimer, mode | vector as u32);
+    }
+
+    /// Start the local APIC timer counting down from `count`.
+    ///
+    /// A count of 0 stops the timer.
+    #[inline]
+    pub unsafe fn set_timer_count(&self, count: u32) {
+        self.write(Register::TimerInitialCount, count)
+    }
+
+    /// Returns the local APIC timer's current count.
+    #[inline]
+    pub fn timer_count(&self) -> u32 {
+        unsafe { self.read(Register::TimerCurrentCount) }
+    }
+
+    /// Stop and mask the local APIC timer.
+    pub unsafe fn stop_timer(&self) {
+        self.write(Register::TimerInitialCount, 0);
+        self.write(Register::LvtTimer, LVT_MASKED);
+    }
+
+    /// Signal the end of the interrupt currently being serviced.
+    #[inline]
+    pub unsafe fn end_of_interrupt(&self) {
+        self.write(Register::EndOfInterrupt, 0)
+    }
+
+    /// Send an inter-processor interrupt to the CPU whose local APIC ID is
+    /// `destination`.
+    ///
+    /// `command` is the low 32 bits of the interrupt command register: the
+    /// vector, delivery mode (e.g. `ICR_INIT`), and level. In xAPIC mode,
+    /// this waits until the IPI has been sent.
+    pub unsafe fn send_ipi(&self, destination: u32, command: u32) {
+        match *self {
+            LocalApic::XApic(_) => {
+                self.write(Register::InterruptCommandHigh, destination << 24);
+                self.write(Register::InterruptCommandLow, command);
+                while self.read(Register::InterruptCommandLow)
+                    & ICR_SEND_PENDING != 0 { }
+            }
+          , LocalApic::X2Apic =>
+                // in x2APIC mode, the ICR is a single 64-bit MSR.
+                msr::write( X2APIC_MSR_BASE
+                          + (Register::InterruptCommandLow as u32 >> 4)
+                          , (destination as u64) << 32 | command as u64)
+        }
+    }
+
+    /// Send an `INIT` IPI to the CPU whose local APIC ID is `destination`.
+    #[inline]
+    pub unsafe fn send_init(&self, destination: u32) {
+        self.send_ipi(destination, ICR_INIT | ICR_ASSERT)
+    }
+
+    /// Send a `STARTUP` IPI to the CPU whose local APIC ID is `destination`.
+    ///
+    /// The CPU will start executing in real mode at physical address
+    /// `page << 12`.
+    #[inline]
+    pub unsafe fn send_startup(&self, destination: u32, page: u8) {
+        self.send_ipi(destination, ICR_STARTUP | ICR_ASSERT | page as u32)
+    }
+}
+
+bitflags! {
+    /// Flags in an I/O APIC redirection table entry.
+    ///
+    /// An entry with no flags set delivers its vector to the destination
+    /// local APIC, in physical destination mode, on an active-high,
+    /// edge-triggered input.
+    pub flags RedirectionFlags: u64 {
+        /// Deliver to the lowest-priority CPU in the destination set
+        const LOWEST_PRIORITY = 0b001 << 8
+      , /// Deliver as a system management interrupt
+        const SMI = 0b010 << 8
+      , /// Deliver as a non-maskable interrupt
+        const NMI = 0b100 << 8
+      , /// Deliver as an `INIT` signal
+        const INIT = 0b101 << 8
+      , /// Deliver as an external interrupt (8259 compatible)
+        const EXT_INT = 0b111 << 8
+      , /// Interpret the destination as a logical destination
+        const LOGICAL_DESTINATION = 1 << 11
+      , /// The interrupt input is active low
+        const ACTIVE_LOW = 1 << 13
+      , /// The interrupt input is level triggered
+        const LEVEL_TRIGGERED = 1 << 15
+      , /// The interrupt is masked
+        const MASKED = 1 << 16
+    }
+}
+
+/// An I/O APIC redirection table entry.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RedirectionEntry { /// The vector to deliver
+                              pub vector: u8
+                            , /// How the interrupt is delivered
+                              pub flags: RedirectionFlags
+                            , /// The APIC ID of the destination CPU
+                              pub destination: u8
+                            }
+
+impl RedirectionEntry {
+    /// Returns a new entry delivering `vector` to the CPU with the local
+    /// APIC ID `destination`.
+    #[inline]
+    pub fn new(vector: u8, destination: u8) -> Self {
+        RedirectionEntry { vector: vector
+                         , flags: RedirectionFlags::empty()
+                         , destination: destination
+                         }
+    }
+
+    /// Returns a new masked entry.
+    #[inline]
+    pub fn masked() -> Self {
+        RedirectionEntry { vector: 0, flags: MASKED, destination: 0 }
+    }
+
+    /// Returns the raw bits of this entry.
+    #[inline]
+    pub fn bits(&self) -> u64 {
+        self.vector as u64 | self.flags.bits() | (self.destination as u64) << 56
+    }
+
+    /// Returns the entry represented by `bits`.
+    #[inline]
+    pub fn from_bits(bits: u64) -> Self {
+        RedirectionEntry { vector: bits as u8
+                         , flags: RedirectionFlags::from_bits_truncate(bits)
+                         , destination: (bits >> 56) as u8
+                         }
+    }
+}
+
+/// MPS INTI flags: the polarity bits.
+const MPS_POLARITY_MASK: u16 = 0b11;
+/// MPS INTI flags: the input is active low.
+const MPS_ACTIVE_LOW: u16 = 0b11;
+/// MPS INTI flags: the trigger mode bits.
+const MPS_TRIGGER_MASK: u16 = 0b11 << 2;
+/// MPS INTI flags: the input is level triggered.
+const MPS_LEVEL_TRIGGERED: u16 = 0b11 << 2;
+
+/// How a legacy ISA IRQ is wired to an I/O APIC.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct IsaRoute { /// The global system interrupt the IRQ is wired to
+                      pub gsi: u32
+                    , /// The polarity and trigger mode of the input
+                      pub flags: RedirectionFlags
+                    }
+
+impl IsaRoute {
+    /// Returns the route of an IRQ that isn't overridden: to the GSI with
+    /// the same number, active high and edge triggered, like the ISA bus.
+    #[inline]
+    pub fn identity(irq: u8) -> Self {
+        IsaRoute { gsi: irq as u32, flags: RedirectionFlags::empty() }
+    }
+
+    /// Returns a route to `gsi`, with the polarity and trigger mode in the
+    /// MPS INTI `flags` of an interrupt source override.
+    ///
+    /// Flags that say the input conforms to the bus are the same as the ISA
+    /// bus: active high and edge triggered.
+    pub fn from_mps(gsi: u32, flags: u16) -> Self {
+        let mut route = IsaRoute { gsi: gsi
+                                 , flags: RedirectionFlags::empty() };
+        if flags & MPS_POLARITY_MASK == MPS_ACTIVE_LOW {
+            route.flags.insert(ACTIVE_LOW);
+        }
+        if flags & MPS_TRIGGER_MASK == MPS_LEVEL_TRIGGERED {
+            route.flags.insert(LEVEL_TRIGGERED);
+        }
+        route
+    }
+}
+
+/// Returns where each legacy ISA IRQ is wired, given the interrupt source
+/// overrides from the ACPI MADT, as `(irq, gsi, flags)`.
+///
+/// IRQs that aren't overridden are identity mapped, except for IRQ 2 (the
+/// PIC cascade, which never fires), and IRQs whose GSI another IRQ was
+/// moved to. Those are `None`.
+pub fn isa_routes<I>(overrides: I) -> [Option<IsaRoute>; N_LEGACY_IRQS as usize]
+where I: IntoIterator<Item=(u8, u32, u16)> {
+    let mut routes = [None; N_LEGACY_IRQS as usize];
+    let mut overridden = [false; N_LEGACY_IRQS as usize];
+    for irq in 0..N_LEGACY_IRQS {
+        if irq != 2 {
+            routes[irq as usize] = Some(IsaRoute::identity(irq));
+        }
+    }
+    for (irq, gsi, flags) in overrides {
+        if irq < N_LEGACY_IRQS {
+            routes[irq as usize] = Some(IsaRoute::from_mps(gsi, flags));
+            overridden[irq as usize] = true;
+        }
+    }
+    for irq in 0..N_LEGACY_IRQS as usize {
+        let taken = routes[irq].map(|route| {
+            (0..N_LEGACY_IRQS as usize).any(|other|
+                other != irq && overridden[other]
+                    && routes[other].map(|o| o.gsi) == Some(route.gsi))
+        }).unwrap_or(false);
+        if taken && !overridden[irq] {
+            routes[irq] = None;
+        }
+    }
+    routes
+}
+
+/// I/O APIC register selecting the register accessed through `IOWIN`.
+const IOREGSEL: usize = 0x00;
+/// I/O APIC register window.
+const IOWIN: usize = 0x10;
+
+/// I/O APIC ID register.
+const IOAPICID: u32 = 0x00;
+/// I/O APIC version register.
+const IOAPICVER: u32 = 0x01;
+/// First I/O APIC redirection table register.
+const IOREDTBL: u32 = 0x10;
+
+/// An I/O APIC.
+#[derive(Copy, Clone, Debug)]
+pub struct IoApic { /// The address the I/O APIC's registers are mapped at
+                    base: VAddr
+                  , /// The first global system interrupt handled by this
+                    /// I/O APIC
+                    gsi_base: u32
+                  }
+
+impl IoApic {
+    /// Returns a new `IoApic`.
+    ///
+    /// # Safety
+    /// + `base` must be the address of an I/O APIC's registers, mapped as
+    ///   uncached memory.
+    pub unsafe fn new(base: VAddr, gsi_base: u32) -> Self {
+        IoApic { base: base, gsi_base: gsi_base }
+    }
+
+    unsafe fn read(&self, reg: u32) -> u32 {
+        ptr::write_volatile((*self.base + IOREGSEL) as *mut u32, reg);
+        ptr::read_volatile((*self.base + IOWIN) as *const u32)
+    }
+
+    unsafe fn write(&self, reg: u32, value: u32) {
+        ptr::write_volatile((*self.base + IOREGSEL) as *mut u32, reg);
+        ptr::write_volatile((*self.base + IOWIN) as *mut u32, value);
+    }
+
+    /// Returns the ID of this I/O APIC.
+    #[inline]
+    pub fn id(&self) -> u8 {
+        unsafe { (self.read(IOAPICID) >> 24) as u8 & 0xf }
+    }
+
+    /// Returns the number of entries in this I/O APIC's redirection table.
+    #[inline]
+    pub fn n_entries(&self) -> u32 {
+        unsafe { ((self.read(IOAPICVER) >> 16) & 0xff) + 1 }
+    }
+
+    /// Returns the first global system interrupt handled by this I/O APIC.
+    #[inline] pub fn gsi_base(&self) -> u32 { self.gsi_base }
+
+    /// Returns true if this I/O APIC handles the global system interrupt
+    /// `gsi`.
+    #[inline]
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.n_entries()
+    }
+
+    /// Returns the redirection table entry for `gsi`.
+    pub fn entry(&self, gsi: u32) -> RedirectionEntry {
+        assert!(self.handles(gsi), "GSI {} is not on this I/O APIC!", gsi);
+        let reg = IOREDTBL + (gsi - self.gsi_base) * 2;
+        unsafe {
+            let low = self.read(reg) as u64;
+            let high = self.read(reg + 1) as u64;
+            RedirectionEntry::from_bits(high << 32 | low)
+        }
+    }
+
+    /// Sets the redirection table entry for `gsi`.
+    pub unsafe fn set_entry(&self, gsi: u32, entry: RedirectionEntry) {
+        assert!(self.handles(gsi), "GSI {} is not on this I/O APIC!", gsi);
+        let reg = IOREDTBL + (gsi - self.gsi_base) * 2;
+        let bits = entry.bits();
+        // mask the entry while it's being changed, and write the destination
+        // before the low half, which may unmask it.
+        self.write(reg, MASKED.bits() as u32);
+        self.write(reg + 1, (bits >> 32) as u32);
+        self.write(reg, bits as u32);
+    }
+
+    /// Mask every entry in this I/O APIC's redirection table.
+    pub unsafe fn mask_all(&self) {
+        for gsi in self.gsi_base .. self.gsi_base + self.n_entries() {
+            self.set_entry(gsi, RedirectionEntry::masked());
+        }
+    }
+}
+
+/// The local APIC, once it has been enabled.
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+
+/// The system's I/O APICs.
+static IO_APICS: Mutex<[Option<IoApic>; MAX_IO_APICS]>
+    = Mutex::new([None; MAX_IO_APICS]);
+
+/// Returns the local APIC, if the APIC is in use.
+#[inline]
+pub fn local() -> Option<&'static LocalApic> { LOCAL_APIC.try() }
+
+/// Returns true if interrupts are being delivered by the APIC.
+#[inline]
+pub fn is_enabled() -> bool { local().is_some() }
+
+/// Add an I/O APIC, with every entry masked.
+pub unsafe fn add_io_apic(io_apic: IoApic) -> Result<(), &'static str> {
+    let mut io_apics = IO_APICS.lock();
+    let slot = io_apics.iter_mut()
+                       .find(|slot| slot.is_none())
+                       .ok_or("Too many I/O APICs!")?;
+    io_apic.mask_all();
+    kinfoln!( dots: " . . ", "I/O APIC {} handles GSIs {} to {}"
+            , io_apic.id(), io_apic.gsi_base()
+            , io_apic.gsi_base() + io_apic.n_entries() - 1);
+    *slot = Some(io_apic);
+    Ok(())
+}
+
+/// Set the redirection table entry for the global system interrupt `gsi`.
+pub unsafe fn set_gsi(gsi: u32, entry: RedirectionEntry)
+                     -> Result<(), &'static str> {
+    IO_APICS.lock()
+            .iter()
+            .filter_map(|io_apic| io_apic.as_ref())
+            .find(|io_apic| io_apic.handles(gsi))
+            .map(|io_apic| io_apic.set_entry(gsi, entry))
+            .ok_or("No I/O APIC handles that GSI!")
+}
+
+/// Enable the local APIC, and route the legacy ISA IRQs through the I/O
+/// APICs.
+///
+/// The x2APIC is used if it is supported, in which case `lapic_mmio` is
+/// ignored. Afterwards, the PICs are masked.
+///
+/// # Arguments
+/// + `lapic_mmio`: the address that the local APIC's registers (at
+///   `base_address()`) were mapped at, as uncached memory
+/// + `routes`: where each ISA IRQ is wired, from `isa_routes`
+///
+/// # Safety
+/// + This should only be called once, by the bootstrap processor, with
+///   interrupts disabled, after the PICs have been initialized, and every
+///   I/O APIC has been added with `add_io_apic`.
+pub unsafe fn initialize(lapic_mmio: VAddr, routes: &[Option<IsaRoute>])
+                        -> Result<&'static LocalApic, &'static str> {
+    if !is_available() {
+        return Err("APIC is not supported!")
+    }
+    // check that every IRQ can be routed before the PICs are masked, so
+    // that a missing I/O APIC doesn't leave us without a timer.
+    {
+        let io_apics = IO_APICS.lock();
+        let missing = routes.iter()
+            .filter_map(|route| route.as_ref())
+            .any(|route| !io_apics.iter()
+                                  .filter_map(|io_apic| io_apic.as_ref())
+                                  .any(|io_apic| io_apic.handles(route.gsi)));
+        if missing {
+            return Err("No I/O APIC handles an ISA IRQ's GSI!")
+        }
+    }
+
+    let base = msr::read(msr::IA32_APIC_BASE);
+    let lapic = if cpuid::has_x2apic() {
+        // the xAPIC must be enabled before switching to x2APIC mode.
+        msr::write(msr::IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+        msr::write( msr::IA32_APIC_BASE
+                  , base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+        LocalApic::X2Apic
+    } else {
+        msr::write(msr::IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+        LocalApic::XApic(lapic_mmio)
+    };
+    lapic.enable(SPURIOUS_VECTOR);
+    kinfoln!( dots: " . . ", "Local APIC {} (version {:#x}) ENABLED in {} mode"
+            , lapic.id(), lapic.version()
+            , if lapic.is_x2apic() { "x2APIC" } else { "xAPIC" });
+
+    // the PICs are still wired up, so mask them now that we don't need
+    // them.
+    pics::disable();
+
+    let bsp = lapic.id() as u8;
+    for (irq, route) in routes.iter().enumerate().take(N_LEGACY_IRQS as usize) {
+        if let Some(route) = *route {
+            let entry = RedirectionEntry { vector: IRQ_BASE + irq as u8
+                                         , flags: route.flags
+                                         , destination: bsp
+                                         };
+            set_gsi(route.gsi, entry)?;
+            if route.gsi != irq as u32 {
+                kinfoln!( dots: " . . ", "ISA IRQ {} is wired to GSI {}"
+                        , irq, route.gsi);
+            }
+        }
+    }
+
+    Ok(LOCAL_APIC.call_once(|| lapic))
+}
+
+/// Enable the local APIC of an application processor.
+///
+/// The local APIC is put in the same mode as the bootstrap processor's.
+///
+/// # Safety
+/// + This should be called once by each application processor, with
+///   interrupts disabled, after the bootstrap processor has called
+///   `initialize`.
+pub unsafe fn initialize_ap() -> Result<&'static LocalApic, &'static str> {
+    let lapic = local().ok_or("APIC has not been initialized!")?;
+    let base = msr::read(msr::IA32_APIC_BASE) | APIC_BASE_ENABLE;
+    msr::write(msr::IA32_APIC_BASE, base);
+    if lapic.is_x2apic() {
+        msr::write(msr::IA32_APIC_BASE, base | APIC_BASE_X2APIC);
+    }
+    lapic.enable(SPURIOUS_VECTOR);
+    Ok(lapic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redirection_entry_bits() {
+        let entry = RedirectionEntry { vector: 0x21
+                                     , flags: ACTIVE_LOW | LEVEL_TRIGGERED
+                                     , destination: 3
+                                     };
+        assert_eq!(entry.bits(), 0x0300_0000_0000_a021);
+        assert_eq!(RedirectionEntry::from_bits(entry.bits()), entry);
+    }
+
+    #[test]
+    fn test_masked_entry_is_masked() {
+        assert_eq!(RedirectionEntry::masked().bits(), 1 << 16);
+    }
+
+    #[test]
+    fn test_isa_routes_are_identity_without_overrides() {
+        let routes = isa_routes(None);
+        assert_eq!(routes[0], Some(IsaRoute::identity(0)));
+        assert_eq!(routes[2], None);
+        assert_eq!(routes[15], Some(IsaRoute::identity(15)));
+    }
+
+    #[test]
+    fn test_isa_routes_apply_overrides() {
+        // QEMU's MADT: IRQ 0 is wired to GSI 2, and IRQ 9 (the ACPI SCI) is
+        // active high and level triggered.
+        let routes = isa_routes([(0, 2, 0), (9, 9, 0b1101)].iter().cloned());
+        assert_eq!(routes[0], Some(IsaRoute { gsi: 2
+                                            , flags: RedirectionFlags::empty()
+                                            }));
+        assert_eq!(routes[2], None);
+        assert_eq!(routes[9], Some(IsaRoute { gsi: 9
+                                            , flags: LEVEL_TRIGGERED }));
+    }
+
+    #[test]
+    fn test_isa_routes_free_overridden_gsis() {
+        // IRQ 2 moved to GSI 0 means IRQ 0 can't use GSI 0.
+        let routes = isa_routes(Some((2, 0, 0b1111)));
+        assert_eq!(routes[0], None);
+        assert_eq!(routes[2], Some(IsaRoute { gsi: 0
+                                            , flags: ACTIVE_LOW
+                                                   | LEVEL_TRIGGERED }));
+    }
+
+    #[test]
+    fn test_ipi_commands() {
+        assert_eq!(ICR_INIT | ICR_ASSERT, 0x4500);
+        assert_eq!(ICR_STARTUP | ICR_ASSERT | 0x08, 0x4608);
+    }
+}
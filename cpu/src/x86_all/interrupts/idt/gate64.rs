@@ -49,8 +49,9 @@ pub struct Gate<H = InterruptHandler>
       pub offset_lower: u16
     , /// code segment selector (GDT or LDT)
       pub selector: segment::Selector
-    , /// always zero
-      _zero: u8
+    , /// bits 0 - 2 select a stack from the Interrupt Stack Table,
+      /// the rest are always zero
+      ist: u8
     , /// indicates the gate's type and attributes.
       /// the second half indicates the type:
       ///   + `0b1100`: Call gate
@@ -78,7 +79,7 @@ impl<H> Gate<H> {
     pub const fn absent() -> Self {
        Gate { offset_lower: 0
             , selector: segment::Selector::from_raw(0)
-            , _zero: 0
+            , ist: 0
             , flags: GateFlags { bits:  0b1000_1110 }
             , offset_mid: 0
             , offset_upper: 0
@@ -102,6 +103,24 @@ impl<H> Gate<H> {
         self
     }
 
+    /// Sets the Interrupt Stack Table index for this gate.
+    ///
+    /// When this gate's interrupt fires, the CPU will switch to the stack in
+    /// the TSS's `ist[index - 1]` entry, even if it was already running in
+    /// ring 0. An index of 0 means the stack will not be switched.
+    ///
+    /// # Panics
+    /// + If `index` is greater than 7.
+    #[inline]
+    pub fn set_stack_index(&mut self, index: u8) -> &mut Self {
+        assert!(index <= 7, "IST index must be between 0 and 7!");
+        self.ist = index;
+        self
+    }
+
+    /// Returns the Interrupt Stack Table index for this gate.
+    #[inline] pub fn stack_index(&self) -> u8 { self.ist & 0b111 }
+
 }
 
 
@@ -109,7 +128,7 @@ impl<H> Default for Gate<H> {
     fn default() -> Self {
         Gate { offset_lower: 0
              , selector: segment::Selector::from_raw(0)
-             , _zero: 0
+             , ist: 0
              , flags: GateFlags { bits: 0b1000_1110 }
              , offset_mid: 0
              , offset_upper: 0
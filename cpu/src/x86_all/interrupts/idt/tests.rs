@@ -2,3 +2,23 @@
 #[test] fn test_int_gate_32() { assert_eq!(super::INT_GATE_32.bits, 14)}
 
 #[test] fn test_trap_gate_32() { assert_eq!(super::TRAP_GATE_32.bits, 16)}
+
+#[cfg(target_arch = "x86_64")]
+mod gate64 {
+    use super::super::Gate;
+    use core::mem::size_of;
+
+    #[test] fn test_gate_correct_size() { assert_eq!(size_of::<Gate>(), 16) }
+
+    #[test] fn test_set_stack_index() {
+        let mut gate: Gate = Gate::absent();
+        assert_eq!(gate.stack_index(), 0);
+        gate.set_stack_index(3);
+        assert_eq!(gate.stack_index(), 3);
+    }
+
+    #[test] #[should_panic] fn test_stack_index_out_of_range() {
+        let mut gate: Gate = Gate::absent();
+        gate.set_stack_index(8);
+    }
+}
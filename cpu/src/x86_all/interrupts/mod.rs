@@ -14,6 +14,7 @@
 #![warn(missing_docs)]
 pub mod idt;
 pub mod pics;
+#[cfg(target_arch = "x86_64")] pub mod apic;
 
 use vga::{CONSOLE, Color};
 
@@ -50,8 +51,109 @@ pub struct ExceptionInfo { /// The name of the exception
                            pub source: &'static str
                          }
 
+impl ExceptionInfo {
+    /// Returns true if the exception may be a fault, which is reported
+    /// before the instruction that caused it, so it can be restarted.
+    #[inline] pub fn is_fault(&self) -> bool {
+        self.irq_type.starts_with("Fault")
+    }
+
+    /// Returns true if the exception is a trap, which is reported after the
+    /// instruction that caused it, so the interrupted code can carry on.
+    #[inline] pub fn is_trap(&self) -> bool { self.irq_type == "Trap" }
+
+    /// Returns true if the exception is an abort, which leaves the
+    /// interrupted code in a state it can't be restarted from.
+    #[inline] pub fn is_abort(&self) -> bool { self.irq_type == "Abort" }
+
+    /// Returns true if the vector is reserved, and the CPU shouldn't raise it.
+    #[inline] pub fn is_reserved(&self) -> bool {
+        self.irq_type == "Reserved"
+    }
+}
+
+/// Returns true if the CPU pushes an error code for exception `vector`.
+///
+/// Handlers for these vectors must be `ErrorCodeHandler`s.
+#[inline]
+pub fn has_error_code(vector: usize) -> bool {
+    match vector { 8 | 10 ... 14 | 17 | 30 => true
+                 , _ => false
+                 }
+}
+
+macro_rules! exception {
+    ($name:expr, $mnemonic:expr, $ty:expr, $source:expr) => {
+        ExceptionInfo { name: $name, mnemonic: $mnemonic
+                      , irq_type: $ty, source: $source }
+    }
+}
+
+/// Descriptions of each CPU exception, indexed by vector.
+///
+/// Refer to Table 6-1, "Protected-Mode Exceptions and Interrupts", in the
+/// _Intel® 64 and IA-32 Architectures Software Developer’s Manual_, Vol. 3A.
+pub static EXCEPTIONS: [ExceptionInfo; NUM_EXCEPTIONS] =
+    [ exception!("Divide Error", "#DE", "Fault"
+                , "DIV or IDIV instruction")
+    , exception!("Debug", "#DB", "Fault/Trap"
+                , "Instruction, data, and I/O breakpoints, or single-step")
+    , exception!("Non-Maskable Interrupt", "NMI", "Interrupt"
+                , "Non-maskable external interrupt")
+    , exception!("Breakpoint", "#BP", "Trap"
+                , "INT3 instruction")
+    , exception!("Overflow", "#OF", "Trap"
+                , "INTO instruction")
+    , exception!("BOUND Range Exceeded", "#BR", "Fault"
+                , "BOUND instruction")
+    , exception!("Invalid Opcode", "#UD", "Fault"
+                , "UD2 instruction or reserved opcode")
+    , exception!("Device Not Available", "#NM", "Fault"
+                , "Floating-point or WAIT/FWAIT instruction")
+    , exception!("Double Fault", "#DF", "Abort"
+                , "Any instruction that can generate an exception, a NMI, \
+                   or an INTR")
+    , exception!("Coprocessor Segment Overrun", "-", "Fault"
+                , "Floating-point instruction")
+    , exception!("Invalid TSS", "#TS", "Fault"
+                , "Task switch or TSS access")
+    , exception!("Segment Not Present", "#NP", "Fault"
+                , "Loading segment registers or accessing system \
+                   segments")
+    , exception!("Stack Segment Fault", "#SS", "Fault"
+                , "Stack operations and SS register loads")
+    , exception!("General Protection Fault", "#GP", "Fault"
+                , "Any memory reference or other protection checks")
+    , exception!("Page Fault", "#PF", "Fault"
+                , "Any memory reference")
+    , exception!("Reserved", "-", "Reserved", "Reserved") // 15
+    , exception!("x87 FPU Floating-Point Error", "#MF", "Fault"
+                , "x87 FPU floating-point or WAIT/FWAIT instruction")
+    , exception!("Alignment Check", "#AC", "Fault"
+                , "Any data reference in memory")
+    , exception!("Machine Check", "#MC", "Abort"
+                , "Model-dependent (probably hardware!)")
+    , exception!("SIMD Floating-Point Exception", "#XM", "Fault"
+                , "SSE/SSE2/SSE3 floating-point instructions")
+    , exception!("Virtualization Exception", "#VE", "Fault"
+                , "EPT violations")
+    , exception!("Reserved", "-", "Reserved", "Reserved") // 21
+    , exception!("Reserved", "-", "Reserved", "Reserved") // 22
+    , exception!("Reserved", "-", "Reserved", "Reserved") // 23
+    , exception!("Reserved", "-", "Reserved", "Reserved") // 24
+    , exception!("Reserved", "-", "Reserved", "Reserved") // 25
+    , exception!("Reserved", "-", "Reserved", "Reserved") // 26
+    , exception!("Reserved", "-", "Reserved", "Reserved") // 27
+    , exception!("Reserved", "-", "Reserved", "Reserved") // 28
+    , exception!("Reserved", "-", "Reserved", "Reserved") // 29
+    , exception!("Security Exception", "#SX", "Fault"
+                , "Security-sensitive events under SVM")
+    , exception!("Reserved", "-", "Reserved", "Reserved") // 31
+    ];
+
 bitflags! {
-   flags PageFaultErrorCode: u32 {
+   /// The error code pushed by a page fault.
+   pub flags PageFaultErrorCode: u32 {
        /// If 1, the error was caused by a page that was present.
        /// Otherwise, the page was non-present.
        const PRESENT = 1 << 0
@@ -85,11 +187,49 @@ impl fmt::Display for PageFaultErrorCode {
    }
 }
 
+/// Signal the end of the interrupt on `vector` to the interrupt controller.
+///
+/// If the APIC is enabled, this signals the local APIC, otherwise the PICs.
+/// Interrupt handlers should use this rather than talking to either
+/// controller directly.
+///
+/// # Safety
+///  - This should only be called by interrupt handler functions.
+pub unsafe fn end_of_interrupt(vector: u8) {
+    #[cfg(target_arch = "x86_64")] {
+        if let Some(lapic) = apic::local() {
+            return lapic.end_of_interrupt()
+        }
+    }
+    pics::end_pic_interrupt(vector)
+}
+
+/// Run `f` with interrupts disabled.
+///
+/// If interrupts were enabled, they are enabled again afterwards.
+pub fn without_interrupts<F, R>(f: F) -> R
+where F: FnOnce() -> R {
+    let enabled = ::flags::read().contains(::flags::IF);
+    unsafe { asm!("cli" :::: "volatile"); }
+    let result = f();
+    if enabled {
+        unsafe { asm!("sti" :::: "volatile"); }
+    }
+    result
+}
+
 /// Handler for the system timer interrupt
 pub extern "x86-interrupt" fn timer(_frame: &InterruptFrame) {
-    // do nothing, just signal the pics to end the IRQ
+    // do nothing, just signal the end of the IRQ
     // println!("timer!");
-    unsafe { pics::end_pic_interrupt(0x20); }
+    unsafe { end_of_interrupt(0x20); }
+}
+
+/// Handler for spurious interrupts from the local APIC.
+///
+/// Spurious interrupts aren't in service, so they must not be acknowledged.
+pub extern "x86-interrupt" fn spurious(_frame: &InterruptFrame) {
+    trace!("spurious interrupt");
 }
 
 
@@ -117,8 +257,45 @@ pub extern "x86-interrupt" fn page_fault( frame: &InterruptFrame, error_code: us
 pub extern "x86-interrupt" fn test(_frame: &InterruptFrame) {
    // assert_eq!(state.int_id, 0x80);
    kinfoln!(dots: " . . ", target: "Testing interrupt handling:", "[ OKAY ]");
-   // send the PICs the end interrupt signal
+   // signal the end of the interrupt
    unsafe {
-       pics::end_pic_interrupt(0xff);
+       end_of_interrupt(0xff);
    }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exceptions_indexed_by_vector() {
+        assert_eq!(EXCEPTIONS[0].mnemonic, "#DE");
+        assert_eq!(EXCEPTIONS[8].mnemonic, "#DF");
+        assert_eq!(EXCEPTIONS[14].mnemonic, "#PF");
+        assert_eq!(EXCEPTIONS[19].mnemonic, "#XM");
+        assert_eq!(EXCEPTIONS[30].mnemonic, "#SX");
+    }
+
+    #[test]
+    fn test_exception_classes() {
+        assert!(EXCEPTIONS[14].is_fault());
+        assert!(EXCEPTIONS[1].is_fault());
+        assert!(!EXCEPTIONS[2].is_fault());
+        assert!(EXCEPTIONS[3].is_trap());
+        assert!(EXCEPTIONS[4].is_trap());
+        assert!(!EXCEPTIONS[14].is_trap());
+        assert!(EXCEPTIONS[8].is_abort());
+        assert!(EXCEPTIONS[18].is_abort());
+        assert!(EXCEPTIONS[15].is_reserved());
+        assert!(!EXCEPTIONS[30].is_reserved());
+    }
+
+    #[test]
+    fn test_has_error_code() {
+        let with_codes = [8, 10, 11, 12, 13, 14, 17, 30];
+        for vector in 0..NUM_EXCEPTIONS {
+            assert_eq!( has_error_code(vector)
+                      , with_codes.contains(&vector), "vector {}", vector);
+        }
+    }
+}
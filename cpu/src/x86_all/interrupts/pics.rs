@@ -23,10 +23,10 @@
 use Port;
 use spin::Mutex;
 
-use core::mem::transmute;
-
 /// Starting offset for PIC1
 const OFFSET: u8 = 0x20;
+/// Number of IRQ lines on both PICs
+pub const N_IRQS: u8 = 16;
 /// Command port for the follower PIC (PIC2)
 const FOLLOWER_CMD_PORT: u16 = 0xA0;
 /// Command port for the leader PIC (PIC1)
@@ -49,13 +49,16 @@ enum Command { /// Command to set the PIC to 8086 mode
 
 /// List of IRQs on the x86.
 ///
+/// Each IRQ's value is the interrupt vector it is delivered on. The APIC
+/// delivers the legacy IRQs on the same vectors as the PICs.
+///
 /// See [here](https://en.wikibooks.org/wiki/X86_Assembly/Programmable_Interrupt_Controller) for more info.
 #[repr(u8)]
-#[derive(Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Debug)]
 pub enum IRQ { /// System timer IRQ
                Timer        = OFFSET
              , /// PS/2 keyboard controller
-               PS2Keyboard  = 1 + OFFSET
+               Keyboard     = 1 + OFFSET
              , /// PIC2 cascade IRQ
                Cascade      = 2 + OFFSET
              , /// COM2 serial port
@@ -70,8 +73,14 @@ pub enum IRQ { /// System timer IRQ
                LPT1         = 7 + OFFSET
              , /// CMOS clock
                RTCTimer     = 8 + OFFSET
+             , /// ACPI system control interrupt (usually)
+               ACPI         = 9 + OFFSET
+             , /// Free for peripherals
+               Peripheral10 = 10 + OFFSET
+             , /// Free for peripherals
+               Peripheral11 = 11 + OFFSET
              , /// PS/2 mouse controller
-               PS2Mouse     = 12 + OFFSET
+               Mouse        = 12 + OFFSET
              , /// Floating-point Coprocessor
                FPU          = 13 + OFFSET
              , /// ATA channel 1
@@ -80,6 +89,13 @@ pub enum IRQ { /// System timer IRQ
                SecondaryATA = 15 + OFFSET
              }
 
+impl IRQ {
+    /// Returns the IRQ line number (0 to 15).
+    #[inline] pub fn line(&self) -> u8 { *self as u8 - OFFSET }
+
+    /// Returns the interrupt vector this IRQ is delivered on.
+    #[inline] pub fn vector(&self) -> u8 { *self as u8 }
+}
 
 /// A 8259 Programmable Interrupt Controller.
 pub struct PIC {
@@ -152,20 +168,22 @@ impl PIC {
 }
 
 /// Trait for something which is capable of handling a PIC IRQ
+///
+/// IRQs are identified by their interrupt vector.
 trait IRQHandler {
     /// Returns whether or not this handler handles the given IRQ
-    fn handles(&self, irq: IRQ) -> bool;
+    fn handles(&self, vector: u8) -> bool;
     /// End an interrupt request
-    fn end_interrupt(&self, irq: IRQ);
+    fn end_interrupt(&self, vector: u8);
 }
 
 impl IRQHandler for PIC {
 
-    fn handles(&self, irq: IRQ) -> bool {
-        self.offset <= (irq as u8) && (irq as u8) < self.offset + 8
+    fn handles(&self, vector: u8) -> bool {
+        self.offset <= vector && vector < self.offset + 8
     }
 
-    fn end_interrupt(&self, _: IRQ) {
+    fn end_interrupt(&self, _: u8) {
         let _ = self.send_command(Command::EndIRQ);
     }
 }
@@ -224,16 +242,16 @@ impl BothPICs {
 
 impl IRQHandler for BothPICs {
 
-    fn handles(&self, irq: IRQ) -> bool {
-        self.0.handles(irq) ||
-        self.1.handles(irq)
+    fn handles(&self, vector: u8) -> bool {
+        self.0.handles(vector) ||
+        self.1.handles(vector)
     }
 
-    fn end_interrupt(&self, irq: IRQ) {
-        if self.1.handles(irq) {
-            self.1.end_interrupt(irq);
+    fn end_interrupt(&self, vector: u8) {
+        if self.1.handles(vector) {
+            self.1.end_interrupt(vector);
         }
-        self.0.end_interrupt(irq);
+        self.0.end_interrupt(vector);
     }
 
 }
@@ -255,6 +273,21 @@ pub unsafe fn initialize() {
 
 }
 
+/// Mask every IRQ on both PICs.
+///
+/// This is done when switching to the APIC. The PICs should still have been
+/// initialized first, so that any spurious IRQs they raise arrive on vectors
+/// that don't belong to CPU exceptions.
+///
+/// # Safety
+///  - Once the PICs are masked, no IRQs will be delivered through them.
+pub unsafe fn disable() {
+    let pics = PICS.lock();
+    pics.0.send_data(0xff);
+    pics.1.send_data(0xff);
+    kinfoln!(dots: " . . ", target: "Masking PICs", "[ OKAY ]");
+}
+
 /// If an interrupt is being handled by the PICs, end that interrupt.
 ///
 /// This is called by the interrupt handler at the end of all interrupts.
@@ -264,9 +297,22 @@ pub unsafe fn initialize() {
 ///  - This should only be called by interrupt handler functions.
 pub unsafe fn end_pic_interrupt(interrupt_id: u8) {
     let pics = PICS.lock();
-    let irq: IRQ = transmute(interrupt_id);
 
-    if pics.handles(irq) {
-        pics.end_interrupt(irq)
+    if pics.handles(interrupt_id) {
+        pics.end_interrupt(interrupt_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IRQ;
+
+    #[test]
+    fn test_irq_lines_and_vectors() {
+        assert_eq!(IRQ::Timer.line(), 0);
+        assert_eq!(IRQ::Timer.vector(), 0x20);
+        assert_eq!(IRQ::Keyboard.line(), 1);
+        assert_eq!(IRQ::SecondaryATA.line(), 15);
+        assert_eq!(IRQ::SecondaryATA.vector(), 0x2f);
     }
 }
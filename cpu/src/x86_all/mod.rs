@@ -39,6 +39,8 @@ macro_rules! cpu_flag {
 }
 
 pub mod control_regs;
+pub mod cpuid;
+pub mod debug_regs;
 pub mod segment;
 pub mod dtable;
 pub mod flags;
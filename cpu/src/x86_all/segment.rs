@@ -21,20 +21,71 @@
 
 use core::{fmt, mem};
 use super::{PrivilegeLevel, dtable};
+#[cfg(target_arch = "x86_64")] use ::task::{self, StateDescriptor};
+
 /// The number of entries in the GDT
+///
+/// The TSS descriptor is 16 bytes long, so it takes up two entries.
 #[cfg(target_arch = "x86_64")]
-pub const GDT_SIZE: usize = 2;
+pub const GDT_SIZE: usize = 7;
 
 /// Structure representing a Global Descriptor Table
+///
+/// The order of the user segments is fixed by `sysret`, which loads the user
+/// `%ss` from the entry after the kernel data segment, and the user `%cs`
+/// from the entry after that.
 #[cfg(target_arch = "x86_64")]
 #[repr(C, packed)]
 pub struct Gdt { _null: Descriptor
-               , /// The code segment descriptor
-                 pub code: Descriptor
-            //    , /// The data segment descriptor
-            //      pub data: Descriptor
+               , /// The kernel code segment descriptor
+                 pub kernel_code: Descriptor
+               , /// The kernel data segment descriptor
+                 pub kernel_data: Descriptor
+               , /// The user data segment descriptor
+                 pub user_data: Descriptor
+               , /// The user code segment descriptor
+                 pub user_code: Descriptor
+               , /// The task state segment descriptor
+                 pub tss: StateDescriptor
                }
 
+#[cfg(target_arch = "x86_64")]
+impl Gdt {
+    /// Returns a new GDT with flat kernel and user segments.
+    ///
+    /// The TSS descriptor is left null, as its base address is not known
+    /// until runtime.
+    pub const fn new() -> Self {
+        Gdt { _null: Descriptor::null()
+            , kernel_code: Descriptor::code(PrivilegeLevel::KernelMode)
+            , kernel_data: Descriptor::data(PrivilegeLevel::KernelMode)
+            , user_data: Descriptor::data(PrivilegeLevel::UserMode)
+            , user_code: Descriptor::code(PrivilegeLevel::UserMode)
+            , tss: StateDescriptor::null()
+            }
+    }
+}
+
+/// Selector for the kernel code segment.
+#[cfg(target_arch = "x86_64")]
+pub const KERNEL_CS: Selector = Selector::new(1);
+
+/// Selector for the kernel data segment.
+#[cfg(target_arch = "x86_64")]
+pub const KERNEL_DS: Selector = Selector::new(2);
+
+/// Selector for the user data segment.
+#[cfg(target_arch = "x86_64")]
+pub const USER_DS: Selector = Selector::from_raw(3 << 3 | RPL_RING_3.bits);
+
+/// Selector for the user code segment.
+#[cfg(target_arch = "x86_64")]
+pub const USER_CS: Selector = Selector::from_raw(4 << 3 | RPL_RING_3.bits);
+
+/// Selector for the task state segment.
+#[cfg(target_arch = "x86_64")]
+pub const TSS_SELECTOR: Selector = Selector::new(5);
+
 /// The number of entries in the GDT
 #[cfg(target_arch = "x86")]
 pub const GDT_SIZE: usize = 512;
@@ -60,17 +111,55 @@ impl dtable::DTable for Gdt {
     }
 }
 
+/// The Global Descriptor Table (GDT)
+///
+/// This is used for configuring segmentation. Since we use paging rather than
+/// segmentation for memory protection, we barely _use_ the GDT, but x86
+/// requires that it be properly configured nonetheless, and it is where the
+/// CPU finds the user segments and the TSS.
+///
+/// The boot assembly loads a minimal GDT to enter long mode, which is
+/// replaced with this one by [`initialize`](fn.initialize.html).
+#[cfg(target_arch = "x86_64")]
+pub static mut GDT: Gdt = Gdt::new();
 
-extern {
+/// Load the kernel GDT, reload the segment registers, and load the TSS.
+///
+/// This is used by the bootstrap processor; other CPUs use their own GDT
+/// and TSS, which are loaded with [`load`](fn.load.html).
+///
+/// # Safety
+/// + This should only be called once, during early init, with interrupts
+///   disabled.
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn initialize() {
+    task::init_interrupt_stacks();
+    load(&mut GDT, &task::TSS);
+}
 
-    /// A Global Descriptor Table (GDT)
-    ///
-    /// This is used for configuring segmentation. Since we use paging rather than
-    /// segmentation for memory protection, we never actually _use_ the GDT, but
-    /// x86 requires that it be properly configured nonetheless. So, here it is.
-    #[cfg(target_arch = "x86_64")]
-    #[link_section = ".gdt64"]
-    pub static GDT: Gdt;
+/// Load `gdt`, reload the segment registers, and load `tss`.
+///
+/// The TSS descriptor in `gdt` is pointed at `tss` first.
+///
+/// # Safety
+/// + This should be called with interrupts disabled.
+/// + `tss` must not already be loaded by another CPU, as loading a TSS
+///   marks its descriptor as busy.
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn load(gdt: &'static mut Gdt, tss: &'static task::StateSegment) {
+    use self::dtable::DTable;
+    gdt.tss = StateDescriptor::new(tss);
+    gdt.load();
+    trace!("loaded GDT at {:p}", gdt);
+
+    KERNEL_DS.load_ss();
+    KERNEL_DS.load_ds();
+    KERNEL_DS.load_es();
+    KERNEL_CS.load_cs();
+    trace!("reloaded segment registers, %cs = {}", Selector::from_cs());
+
+    task::load_tr(TSS_SELECTOR);
+    trace!("loaded TSS at {:p}", tss);
 }
 
 bitflags! {
@@ -217,18 +306,18 @@ impl Selector {
     /// Load this selector into the code segment register.
     ///
     /// N.B. that as we cannot `mov` directly to `cs`, we have to do this
-    /// differently. We push the selector and return value onto the stack,
-    /// and use `lret` to reload `cs`.
+    /// differently. We push the selector and return address onto the stack,
+    /// and use `lretq` to reload `cs`.
     #[cfg(target_arch = "x86_64")]
     pub unsafe fn load_cs(&self) {
-        asm!(  "push $0
-                lea rax, [rip + 1]
-                push rax
-                iret
+        asm!(  "pushq $0
+                leaq 1f(%rip), %rax
+                pushq %rax
+                lretq
                 1:"
             :: "r"(self.bits as u64)
             :  "rax", "memory"
-            :  "intel");
+            :  "volatile");
     }
 
 }
@@ -261,50 +350,82 @@ impl fmt::Display for Selector {
 /// application programs.
 ///
 #[repr(C, packed)]
-pub struct Descriptor { /// The last 8 bits of the base address
-                        pub base_high: u8
-                      , /// The next 16 bits are bitflags
-                        pub flags: Flags
-                      , /// The middle 8 bits of the base address
-                        pub base_mid: u8
+pub struct Descriptor { /// the first 16 bits of the segment limit
+                        pub limit: u16
                       , /// The first 16 bits of the base address
                         pub base_low: u16
-                      , /// the segment limit
-                        pub limit: u16
+                      , /// The middle 8 bits of the base address
+                        pub base_mid: u8
+                      , /// The next 16 bits are bitflags
+                        pub flags: Flags
+                      , /// The last 8 bits of the base address
+                        pub base_high: u8
                       }
 
 impl Descriptor {
 
     /// Constructs a new null `Descriptor`
     pub const fn null() -> Self {
-        Descriptor { base_high: 0
-                   , flags: Flags::null()
-                   , base_mid: 0
+        Descriptor { limit: 0
                    , base_low: 0
-                   , limit: 0
+                   , base_mid: 0
+                   , flags: Flags::null()
+                   , base_high: 0
                    }
     }
 
     /// Constructs a new `Descriptor` from a `limit` and a `base` address
     pub fn new(base: u32, limit: u32) -> Self {
-        let (hi, mid, lo): (u8, u8, u16) = unsafe { mem::transmute(base) };
-        let (limit_lo, limit_hi): (u16, u16) = unsafe { mem::transmute(limit) };
-        // I hope this is right...
-        let flags = (limit_hi & 0b1111) << 8;
-
-        Descriptor { base_high: hi
+        let flags = (limit >> 8) as u16 & LIMIT.bits;
+        Descriptor { limit: limit as u16
+                   , base_low: base as u16
+                   , base_mid: (base >> 16) as u8
                    , flags: Flags::from_bits_truncate(flags)
-                   , base_mid: mid
-                   , base_low: lo
-                   , limit: limit_lo
+                   , base_high: (base >> 24) as u8
+                   }
+    }
+
+    /// Constructs a new 64-bit code segment `Descriptor`.
+    ///
+    /// The base and limit are ignored in long mode.
+    pub const fn code(dpl: PrivilegeLevel) -> Self {
+        Descriptor { limit: 0
+                   , base_low: 0
+                   , base_mid: 0
+                   , flags: Flags::from_raw( PRESENT.bits | DESCR_TYPE.bits
+                                           | LENGTH.bits
+                                           | EXECUTE.bits | READ.bits
+                                           | (dpl as u16) << 5 )
+                   , base_high: 0
+                   }
+    }
+
+    /// Constructs a new writable data segment `Descriptor`.
+    ///
+    /// The base and limit are ignored in long mode.
+    pub const fn data(dpl: PrivilegeLevel) -> Self {
+        Descriptor { limit: 0
+                   , base_low: 0
+                   , base_mid: 0
+                   , flags: Flags::from_raw( PRESENT.bits | DESCR_TYPE.bits
+                                           | WRITE.bits
+                                           | (dpl as u16) << 5 )
+                   , base_high: 0
                    }
     }
 
     /// Extract the limit part from the flags and limit fields.
     #[inline]
     pub fn get_limit(&self) -> u32 {
-        // TODO: i hope this is right...
-        self.flags.get_limit_part() & self.limit as u32
+        self.flags.get_limit_part() | self.limit as u32
+    }
+
+    /// Returns the base address of this segment.
+    #[inline]
+    pub fn get_base(&self) -> u32 {
+        (self.base_high as u32) << 24
+            | (self.base_mid as u32) << 16
+            | self.base_low as u32
     }
 
 }
@@ -526,3 +647,45 @@ impl CodeFlags {
         self.contains(CONFORMING)
     }
 }
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod test {
+    use super::*;
+    use core::mem::{size_of, transmute};
+
+    #[test]
+    fn test_descriptor_correct_size() {
+        assert_eq!(size_of::<Descriptor>(), 8);
+        assert_eq!(size_of::<Gdt>(), GDT_SIZE * 8);
+    }
+
+    #[test]
+    fn test_kernel_code_descriptor() {
+        let bits: u64 = unsafe {
+            transmute(Descriptor::code(PrivilegeLevel::KernelMode))
+        };
+        assert_eq!(bits, 0x0020_9a00_0000_0000);
+    }
+
+    #[test]
+    fn test_user_data_descriptor() {
+        let bits: u64 = unsafe {
+            transmute(Descriptor::data(PrivilegeLevel::UserMode))
+        };
+        assert_eq!(bits, 0x0000_f200_0000_0000);
+    }
+
+    #[test]
+    fn test_descriptor_base_and_limit() {
+        let descr = Descriptor::new(0xdead_beef, 0xf_1234);
+        assert_eq!(descr.get_base(), 0xdead_beef);
+        assert_eq!(descr.get_limit(), 0xf_1234);
+    }
+
+    #[test]
+    fn test_user_selectors_have_rpl_3() {
+        assert_eq!(USER_CS.get_rpl(), PrivilegeLevel::UserMode);
+        assert_eq!(USER_DS.get_rpl(), PrivilegeLevel::UserMode);
+        assert_eq!(KERNEL_CS.get_rpl(), PrivilegeLevel::KernelMode);
+    }
+}
@@ -10,7 +10,7 @@
 #![warn(missing_docs)]
 pub mod timestamp {
     //! x86 Timestamp register
-    use core::mem;
+    use super::pit;
 
 
     /// Read the current value of the timestamp counter.
@@ -21,8 +21,9 @@ pub mod timestamp {
     pub unsafe fn rtdsc() -> u64 {
         let (high, low): (u32, u32);
         asm!( "rdtsc"
-            : "={eax}" (low), "={edx}" (high));
-        mem::transmute((high, low))
+            : "={eax}" (low), "={edx}" (high)
+            ::: "volatile");
+        (high as u64) << 32 | low as u64
     }
 
     /// Read the current timestamp, after other instructions have been executed.
@@ -34,17 +35,21 @@ pub mod timestamp {
         let (high, low): (u32, u32);
         asm!( "rdtscp"
             : "={eax}" (low), "={edx}" (high)
-            ::: "volatile");
-        mem::transmute((high, low))
+            : : "ecx"
+            : "volatile");
+        (high as u64) << 32 | low as u64
     }
 
-    /// Returns true if timestamps are currently available.
+    /// Returns `Ok` if timestamps are currently available.
     #[inline]
     pub fn is_available() -> Result<(), &'static str> {
         use ::control_regs::cr4;
+        use ::cpuid::cpu_features;
         use ::PrivilegeLevel;
 
-        if PrivilegeLevel::current_iopl() != PrivilegeLevel::KernelMode {
+        if !cpu_features().tsc {
+            Err("CPU has no timestamp counter.")
+        } else if PrivilegeLevel::current_iopl() != PrivilegeLevel::KernelMode {
             Err("Reading timestamp register requires kernel mode.")
         } else if
             // it's safe to do this since we already know we are in kernel mode.
@@ -63,6 +68,150 @@ pub mod timestamp {
     /// been executed.
     #[inline]
     pub fn wait_get_timestamp() -> Result<u64, &'static str> {
+        if !::cpuid::cpu_features().rdtscp {
+            return Err("CPU does not support rdtscp.")
+        }
         is_available().map(|_| unsafe { rtdscp() })
     }
+
+    /// Measure the frequency of the timestamp counter, in Hz.
+    ///
+    /// The TSC is timed against a 10 millisecond wait on the PIT. This is
+    /// repeated a few times, and the shortest run is used, since anything
+    /// that interrupts a run (such as an SMI) only makes it longer.
+    pub fn calibrate() -> Result<u64, &'static str> {
+        const RUNS: usize = 5;
+        const WAIT_US: u64 = 10_000;
+        is_available()?;
+        (0..RUNS).map(|_| unsafe {
+                     let start = rtdsc();
+                     pit::delay_us(WAIT_US);
+                     rtdsc().wrapping_sub(start)
+                 })
+                 .min()
+                 .map(|cycles| cycles * (1_000_000 / WAIT_US))
+                 .ok_or("Could not calibrate the TSC!")
+    }
+}
+
+pub mod pit {
+    //! The 8253/8254 Programmable Interval Timer (PIT).
+    //!
+    //! Channel 0 is wired to IRQ 0, and is used as the system timer.
+    //! Channel 2 is wired to the PC speaker, but its output can be read back
+    //! through port `0x61`, so it is used for busy-waiting with interrupts
+    //! disabled.
+    use ::Port;
+    use spin::Mutex;
+
+    use core::cmp;
+
+    /// Frequency of the PIT's oscillator, in Hz.
+    pub const FREQUENCY: u32 = 1_193_182;
+
+    /// The largest reload value (a reload value of 0 means 65536).
+    const MAX_DIVISOR: u32 = 0x1_0000;
+
+    /// Command to program channel 0 as a rate generator (mode 2), low byte
+    /// then high byte.
+    const CHANNEL_0_RATE: u8 = 0b0011_0100;
+
+    /// Command to program channel 2 to interrupt on terminal count (mode 0),
+    /// low byte then high byte.
+    const CHANNEL_2_ONESHOT: u8 = 0b1011_0000;
+
+    /// Channel 2 gate (bit 0) and speaker enable (bit 1) in port `0x61`.
+    const SPEAKER_GATE: u8 = 0b11;
+    /// Channel 2 output in port `0x61`.
+    const CHANNEL_2_OUT: u8 = 1 << 5;
+
+    /// Only one CPU may use channel 2 at a time.
+    static CHANNEL_2: Mutex<()> = Mutex::new(());
+
+    /// Returns the divisor for running the PIT at about `hz` Hz.
+    ///
+    /// The result is clamped to the range the PIT supports, 2 to 65536.
+    pub fn divisor(hz: u32) -> u32 {
+        let hz = cmp::max(hz, 1);
+        cmp::min(cmp::max((FREQUENCY + hz / 2) / hz, 2), MAX_DIVISOR)
+    }
+
+    /// Returns the frequency, in Hz, that the PIT runs at with `divisor`.
+    pub fn frequency(divisor: u32) -> u32 {
+        (FREQUENCY + divisor / 2) / divisor
+    }
+
+    /// Program channel 0 to fire IRQ 0 at about `hz` Hz.
+    ///
+    /// Returns the frequency the PIT actually runs at, since only divisors
+    /// of `FREQUENCY` can be used.
+    ///
+    /// # Safety
+    /// + This changes the rate of the system timer.
+    pub unsafe fn set_frequency(hz: u32) -> u32 {
+        let divisor = divisor(hz);
+        Port::<u8>::new(0x43).write(CHANNEL_0_RATE);
+        // a divisor of 65536 is written as 0.
+        let channel_0 = Port::<u8>::new(0x40);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+        frequency(divisor)
+    }
+
+    /// Busy-wait for about `us` microseconds, using channel 2.
+    ///
+    /// This doesn't need interrupts, so it can be used before they are
+    /// enabled.
+    pub fn delay_us(us: u64) {
+        // channel 2's counter is 16 bits, so long waits are split up.
+        const MAX_US: u64 = 50_000;
+        let _lock = CHANNEL_2.lock();
+        let mut remaining = us;
+        while remaining > 0 {
+            let chunk = cmp::min(remaining, MAX_US);
+            one_shot(cmp::max(1, FREQUENCY as u64 * chunk / 1_000_000) as u16);
+            remaining -= chunk;
+        }
+    }
+
+    /// Count `count` PIT cycles down on channel 2, and wait for it to finish.
+    fn one_shot(count: u16) {
+        let control = Port::<u8>::new(0x61);
+        let command = Port::<u8>::new(0x43);
+        let channel_2 = Port::<u8>::new(0x42);
+
+        // stop channel 2, and disconnect it from the speaker.
+        let gate = control.read() & !SPEAKER_GATE;
+        control.write(gate);
+        command.write(CHANNEL_2_ONESHOT);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+        // start counting, and wait for channel 2's output to go high.
+        control.write(gate | 0b01);
+        while control.read() & CHANNEL_2_OUT == 0 { }
+        control.write(gate);
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_divisor() {
+            assert_eq!(divisor(100), 11932);
+            assert_eq!(divisor(1000), 1193);
+            // too slow for the PIT
+            assert_eq!(divisor(1), 0x1_0000);
+            assert_eq!(divisor(0), 0x1_0000);
+            // too fast for the PIT
+            assert_eq!(divisor(FREQUENCY), 2);
+        }
+
+        #[test]
+        fn test_frequency() {
+            assert_eq!(frequency(divisor(100)), 100);
+            assert_eq!(frequency(divisor(1000)), 1000);
+            assert_eq!(frequency(0x1_0000), 18);
+        }
+    }
 }
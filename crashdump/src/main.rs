@@ -0,0 +1,446 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Pretty-prints the crash dumps the kernel writes to COM1.
+//!
+//! Usage: `crashdump [SERIAL LOG]`
+//!
+//! Reads a serial log (from `make debug` or `make exception`, for example),
+//! or standard input if no file is given, and prints every crash dump in it.
+//! The format is described in the kernel's `arch::x86_64::crashdump` module.
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::process;
+
+const BEGIN_MARKER: &str = "=== SOS CRASH DUMP BEGIN ===";
+const END_MARKER: &str = "=== SOS CRASH DUMP END ===";
+/// The newest version of the format this understands.
+const VERSION: u32 = 2;
+/// The first version that escapes panic messages and log lines.
+const ESCAPED_SINCE: u32 = 2;
+
+/// Why the kernel crashed.
+#[derive(Debug, PartialEq)]
+enum Reason { Exception { vector: u8
+                        , mnemonic: String
+                        , kind: String
+                        , name: String
+                        }
+            , Panic { file: String, line: u32, message: String }
+            }
+
+/// A frame in the backtrace.
+#[derive(Debug, PartialEq)]
+struct Frame { addr: u64
+             , symbol: Option<String>
+             }
+
+/// An area in the memory map.
+#[derive(Debug, PartialEq)]
+struct Area { start: u64
+            , end: u64
+            , kind: String
+            }
+
+/// A parsed crash dump.
+#[derive(Debug, Default)]
+struct Dump { version: u32
+            , reason: Option<Reason>
+            , source: Option<String>
+            , error_code: Option<u64>
+            , registers: Vec<(String, u64)>
+            , control_regs: Vec<(String, u64)>
+            , frames: Vec<Frame>
+            , log: Vec<String>
+            , memory: Vec<Area>
+            , kernel: Option<(u64, u64)>
+            , heap: Option<(u64, u64)>
+            , /// Lines that couldn't be parsed, and why
+              errors: Vec<String>
+            }
+
+/// Splits a line into its first `n` fields and the rest of the line.
+fn fields(line: &str, n: usize) -> Result<(Vec<&str>, &str), String> {
+    let mut fields = Vec::with_capacity(n);
+    let mut rest = line;
+    for _ in 0..n {
+        let (field, tail) = match rest.find(' ') {
+            Some(i) => (&rest[..i], &rest[i + 1..])
+          , None => (rest, "")
+        };
+        if field.is_empty() {
+            return Err(format!("expected {} fields", n + 1))
+        }
+        fields.push(field);
+        rest = tail;
+    }
+    Ok((fields, rest))
+}
+
+fn parse_hex(s: &str) -> Result<u64, String> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    u64::from_str_radix(digits, 16)
+        .map_err(|e| format!("bad hex number {:?}: {}", s, e))
+}
+
+/// Undo the kernel's escaping of backslashes and newlines.
+fn unescape(s: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue
+        }
+        match chars.next() {
+            Some('n') => out.push('\n')
+          , Some('\\') => out.push('\\')
+          , Some(c) => return Err(format!("bad escape \\{}", c))
+          , None => return Err("trailing backslash".to_string())
+        }
+    }
+    Ok(out)
+}
+
+fn parse_range(rest: &str) -> Result<(u64, u64), String> {
+    let (fields, _) = fields(rest, 2)?;
+    Ok((parse_hex(fields[0])?, parse_hex(fields[1])?))
+}
+
+impl Dump {
+    /// Parse the lines between a dump's begin and end markers.
+    fn parse<'a, I>(lines: I) -> Self
+    where I: Iterator<Item = &'a str> {
+        let mut dump = Dump::default();
+        for line in lines {
+            let line = line.trim_end();
+            if line.is_empty() { continue }
+            if let Err(why) = dump.parse_line(line) {
+                dump.errors.push(format!("{}: {:?}", why, line));
+            }
+        }
+        dump
+    }
+
+    /// Returns the text at the end of a panic or log line, unescaped if
+    /// the dump's version escapes it.
+    fn text(&self, s: &str) -> Result<String, String> {
+        if self.version >= ESCAPED_SINCE { unescape(s) }
+        else { Ok(s.to_string()) }
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let (keyword, rest) = match line.find(' ') {
+            Some(i) => (&line[..i], &line[i + 1..])
+          , None => (line, "")
+        };
+        match keyword {
+            "version" => {
+                self.version = rest.parse()
+                                   .map_err(|e| format!("bad version: {}", e))?;
+                if self.version > VERSION {
+                    return Err(format!( "version {} is newer than {}"
+                                      , self.version, VERSION))
+                }
+            }
+          , "exception" => {
+                let (f, name) = fields(rest, 3)?;
+                self.reason = Some(Reason::Exception {
+                    vector: f[0].parse()
+                                .map_err(|e| format!("bad vector: {}", e))?
+                  , mnemonic: f[1].to_string()
+                  , kind: f[2].to_string()
+                  , name: name.to_string()
+                });
+            }
+          , "source" => self.source = Some(rest.to_string())
+          , "error_code" => self.error_code = Some(parse_hex(rest)?)
+          , "panic" => {
+                let (f, message) = fields(rest, 2)?;
+                self.reason = Some(Reason::Panic {
+                    file: f[0].to_string()
+                  , line: f[1].parse()
+                              .map_err(|e| format!("bad line number: {}", e))?
+                  , message: self.text(message)?
+                });
+            }
+          , "reg" | "cr" => {
+                let (f, _) = fields(rest, 2)?;
+                let reg = (f[0].to_string(), parse_hex(f[1])?);
+                if keyword == "reg" { self.registers.push(reg) }
+                else { self.control_regs.push(reg) }
+            }
+          , "frame" => {
+                let (f, symbol) = fields(rest, 2)?;
+                self.frames.push(Frame {
+                    addr: parse_hex(f[1])?
+                  , symbol: if symbol == "???" || symbol.is_empty() { None }
+                            else { Some(symbol.to_string()) }
+                });
+            }
+          , "log" => {
+                let line = self.text(rest)?;
+                self.log.push(line);
+            }
+          , "mem" => {
+                let (f, kind) = fields(rest, 2)?;
+                self.memory.push(Area { start: parse_hex(f[0])?
+                                      , end: parse_hex(f[1])?
+                                      , kind: kind.to_string()
+                                      });
+            }
+          , "kernel" => self.kernel = Some(parse_range(rest)?)
+          , "heap" => self.heap = Some(parse_range(rest)?)
+          , _ => return Err("unknown line".to_string())
+        }
+        Ok(())
+    }
+
+    fn register(&self, name: &str) -> Option<u64> {
+        self.registers.iter().chain(self.control_regs.iter())
+            .find(|&(n, _)| n == name)
+            .map(|&(_, value)| value)
+    }
+}
+
+/// Find and parse every crash dump in a serial log.
+///
+/// A dump that was cut off before its end marker is parsed as far as it
+/// goes.
+fn find_dumps(log: &str) -> Vec<Dump> {
+    let mut dumps = Vec::new();
+    let mut lines = log.lines();
+    while lines.by_ref().any(|l| l.trim() == BEGIN_MARKER) {
+        let body = lines.by_ref()
+                        .take_while(|l| l.trim() != END_MARKER)
+                        .collect::<Vec<_>>();
+        dumps.push(Dump::parse(body.into_iter()));
+    }
+    dumps
+}
+
+/// Describes a page fault error code.
+fn page_fault_cause(code: u64) -> String {
+    format!( "{} {} in {} mode{}{}"
+           , if code & 1 != 0 { "protection violation" }
+             else { "page not present" }
+           , if code & (1 << 4) != 0 { "fetching an instruction" }
+             else if code & (1 << 1) != 0 { "writing" }
+             else { "reading" }
+           , if code & (1 << 2) != 0 { "user" } else { "kernel" }
+           , if code & (1 << 3) != 0 { ", reserved bit set" } else { "" }
+           , if code & (1 << 5) != 0 { ", protection key" } else { "" })
+}
+
+impl fmt::Display for Dump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.reason {
+            Some(Reason::Exception { vector, ref mnemonic, ref kind, ref name }) =>
+                writeln!( f, "{} ({}, vector {}, {})"
+                        , name, mnemonic, vector, kind)?
+          , Some(Reason::Panic { ref file, line, ref message }) =>
+                writeln!(f, "panic at {}:{}: {}", file, line, message)?
+          , None => writeln!(f, "crash (no reason recorded)")?
+        }
+        if let Some(ref source) = self.source {
+            writeln!(f, "  caused by: {}", source)?;
+        }
+        if let Some(code) = self.error_code {
+            write!(f, "  error code: {:#x}", code)?;
+            match self.reason {
+                Some(Reason::Exception { vector: 14, .. }) => {
+                    write!(f, " ({}", page_fault_cause(code))?;
+                    if let Some(cr2) = self.register("cr2") {
+                        write!(f, " at {:#x}", cr2)?;
+                    }
+                    writeln!(f, ")")?;
+                }
+              , _ => writeln!(f)?
+            }
+        }
+
+        if !self.registers.is_empty() || !self.control_regs.is_empty() {
+            writeln!(f, "\nRegisters:")?;
+            for &(ref name, value) in self.registers.iter()
+                                          .chain(self.control_regs.iter()) {
+                writeln!(f, "  {:>6}: {:#018x}", name, value)?;
+            }
+        }
+
+        if !self.frames.is_empty() {
+            writeln!(f, "\nBacktrace:")?;
+            for (i, frame) in self.frames.iter().enumerate() {
+                writeln!( f, "  {:>2}: {:#018x} in {}", i, frame.addr
+                        , frame.symbol.as_ref().map(|s| &s[..])
+                                      .unwrap_or("???"))?;
+            }
+        }
+
+        if !self.log.is_empty() {
+            writeln!(f, "\nRecent log:")?;
+            for line in &self.log {
+                writeln!(f, "  {}", line)?;
+            }
+        }
+
+        if !self.memory.is_empty() || self.kernel.is_some()
+                                   || self.heap.is_some() {
+            writeln!(f, "\nMemory:")?;
+            for area in &self.memory {
+                writeln!( f, "  {:#018x} - {:#018x}  {:>5} KiB  {}"
+                        , area.start, area.end
+                        , (area.end - area.start + 1) / 1024, area.kind)?;
+            }
+            if let Some((start, end)) = self.kernel {
+                writeln!(f, "  kernel: {:#x} - {:#x}", start, end)?;
+            }
+            if let Some((start, end)) = self.heap {
+                writeln!(f, "  heap:   {:#x} - {:#x}", start, end)?;
+            }
+        }
+
+        if !self.errors.is_empty() {
+            writeln!(f, "\nUnparsed lines:")?;
+            for error in &self.errors {
+                writeln!(f, "  {}", error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn main() {
+    let mut log = String::new();
+    let result = match env::args().nth(1) {
+        Some(path) => File::open(&path).and_then(|mut f| {
+            f.read_to_string(&mut log)
+        })
+      , None => io::stdin().read_to_string(&mut log)
+    };
+    if let Err(why) = result {
+        eprintln!("crashdump: couldn't read the serial log: {}", why);
+        process::exit(2);
+    }
+
+    let dumps = find_dumps(&log);
+    if dumps.is_empty() {
+        eprintln!("crashdump: no crash dumps found");
+        process::exit(1);
+    }
+    for (i, dump) in dumps.iter().enumerate() {
+        if i > 0 { println!("\n{}\n", "-".repeat(78)); }
+        print!("{}", dump);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &str = "\
+[ 1.000 ][ INFO ] sos_kernel: some output before the crash
+=== SOS CRASH DUMP BEGIN ===
+version 1
+exception 14 #PF Fault Page Fault
+source Any memory reference
+error_code 0x2
+reg rip 0xffffffff80101234
+reg rbp 0x12ff0
+cr cr2 0xdeadbeef
+frame 0 0xffffffff80101234 sos_kernel::kernel_main+0x34
+frame 1 0xffffffff80100010 ???
+log [ 0.500 ][ INFO ] sos_kernel: hello world
+mem 0x0 0x9fbff Available
+kernel 0x100000 0x1fffff
+heap 0x200000 0x2fffff
+=== SOS CRASH DUMP END ===
+[ 2.000 ][ INFO ] sos_kernel: this isn't in the dump
+";
+
+    #[test]
+    fn test_find_dumps() {
+        let dumps = find_dumps(LOG);
+        assert_eq!(dumps.len(), 1);
+        let dump = &dumps[0];
+        assert!(dump.errors.is_empty(), "{:?}", dump.errors);
+        assert_eq!(dump.version, 1);
+        assert_eq!(dump.reason, Some(Reason::Exception {
+            vector: 14
+          , mnemonic: "#PF".to_string()
+          , kind: "Fault".to_string()
+          , name: "Page Fault".to_string()
+        }));
+        assert_eq!(dump.error_code, Some(0x2));
+        assert_eq!(dump.register("rip"), Some(0xffffffff80101234));
+        assert_eq!(dump.register("cr2"), Some(0xdeadbeef));
+        assert_eq!(dump.frames[0].symbol.as_ref().unwrap()
+                  , "sos_kernel::kernel_main+0x34");
+        assert_eq!(dump.frames[1].symbol, None);
+        assert_eq!(dump.log, vec![ "[ 0.500 ][ INFO ] sos_kernel: hello world"
+                                     .to_string() ]);
+        assert_eq!(dump.memory, vec![ Area { start: 0, end: 0x9fbff
+                                           , kind: "Available".to_string() }
+                                    ]);
+        assert_eq!(dump.heap, Some((0x200000, 0x2fffff)));
+    }
+
+    #[test]
+    fn test_panic_message_keeps_spaces() {
+        let dump = Dump::parse("panic src/main.rs 42 oh no: it broke\r"
+                                  .lines());
+        assert_eq!(dump.reason, Some(Reason::Panic {
+            file: "src/main.rs".to_string()
+          , line: 42
+          , message: "oh no: it broke".to_string()
+        }));
+    }
+
+    #[test]
+    fn test_multi_line_panic_message() {
+        let dumps = find_dumps("=== SOS CRASH DUMP BEGIN ===\nversion 2\n\
+                                panic src/main.rs 7 page table:\\n  \
+                                PML4 0x1000\\nC:\\\\\n\
+                                log a\\\\n b\n\
+                                reg rip 0x10\n\
+                                === SOS CRASH DUMP END ===\n");
+        assert_eq!(dumps.len(), 1);
+        let dump = &dumps[0];
+        assert!(dump.errors.is_empty(), "{:?}", dump.errors);
+        assert_eq!(dump.reason, Some(Reason::Panic {
+            file: "src/main.rs".to_string()
+          , line: 7
+          , message: "page table:\n  PML4 0x1000\nC:\\".to_string()
+        }));
+        assert_eq!(dump.log, vec![ "a\\n b".to_string() ]);
+        assert_eq!(dump.register("rip"), Some(0x10));
+    }
+
+    #[test]
+    fn test_bad_escape() {
+        let dump = Dump::parse("version 2\nlog oops\\x".lines());
+        assert!(dump.log.is_empty());
+        assert_eq!(dump.errors.len(), 1);
+    }
+
+    #[test]
+    fn test_truncated_dump() {
+        let dumps = find_dumps("=== SOS CRASH DUMP BEGIN ===\nreg rip 0x10\n\
+                                reg rsp 0xzz\n");
+        assert_eq!(dumps.len(), 1);
+        assert_eq!(dumps[0].register("rip"), Some(0x10));
+        assert_eq!(dumps[0].errors.len(), 1);
+    }
+
+    #[test]
+    fn test_page_fault_cause() {
+        assert_eq!( page_fault_cause(0b111)
+                  , "protection violation writing in user mode");
+    }
+}
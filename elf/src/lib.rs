@@ -80,6 +80,7 @@ macro_rules! impl_getters {
 pub mod section;
 pub mod file;
 pub mod program;
+pub mod symbol;
 
 /// An ELF section header.
 pub type Section<W> = section::Header<Word = W>;
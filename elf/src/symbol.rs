@@ -0,0 +1,90 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! ELF symbol tables.
+//!
+//! Refer to the Symbol Table [entry] in Section 4 of the ELF standard
+//! for more information.
+//!
+//! [entry]: http://www.sco.com/developers/gabi/latest/ch4.symtab.html
+use super::section::StrTable;
+
+use core::{mem, slice};
+
+/// Raw representation of an ELF64 symbol table entry.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct Symbol64 { /// Index of the symbol's name in the string table
+                      name_offset: u32
+                    , /// The symbol's type and binding
+                      info: u8
+                    , other: u8
+                    , /// Index of the section the symbol is defined in
+                      section_index: u16
+                    , /// The symbol's value; for functions, its address
+                      value: u64
+                    , /// The size of the object the symbol refers to
+                      size: u64
+                    }
+
+/// The type of a symbol, from the low four bits of `st_info`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Type { /// `STT_NOTYPE`
+                NoType
+              , /// `STT_OBJECT`: a data object, such as a variable
+                Object
+              , /// `STT_FUNC`: a function, or other executable code
+                Function
+              , /// `STT_SECTION`: a section
+                Section
+              , /// `STT_FILE`: the name of a source file
+                File
+              , /// Any other type
+                Other(u8)
+              }
+
+impl Symbol64 {
+    impl_getters! {
+        pub fn name_offset(&self) -> usize;
+        pub fn section_index(&self) -> u16;
+        pub fn value(&self) -> u64;
+        pub fn size(&self) -> u64;
+    }
+
+    /// Returns the symbol's type.
+    #[inline] pub fn get_type(&self) -> Type {
+        match self.info & 0xf { 0 => Type::NoType
+                              , 1 => Type::Object
+                              , 2 => Type::Function
+                              , 3 => Type::Section
+                              , 4 => Type::File
+                              , ty => Type::Other(ty)
+                              }
+    }
+
+    /// Returns true if `addr` is within the object the symbol refers to.
+    #[inline] pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.value && addr - self.value < self.size
+    }
+
+    /// Look up the name of this symbol in the string table `strtab`.
+    #[inline] pub fn name<'a>(&self, strtab: &'a StrTable<'a>)
+                             -> Option<&'a str> {
+        strtab.at_index(self.name_offset())
+    }
+}
+
+/// Interpret `length` bytes at `address` as a table of ELF64 symbols.
+///
+/// # Safety
+/// + `address` must point to a symbol table `length` bytes long, which
+///   lives for `'a`.
+pub unsafe fn table<'a>(address: usize, length: usize) -> &'a [Symbol64] {
+    slice::from_raw_parts( address as *const Symbol64
+                         , length / mem::size_of::<Symbol64>())
+}
@@ -1,5 +1,7 @@
-use super::table::{Table, PML4Level};
+use super::table::{Table, PML4Level, ENTRY_ADDR_MASK};
+use super::pcid::{self, Pcid};
 use cpu::control_regs::cr3::{read, write};
+use memory::{PAddr, PhysicalPage};
 pub use cpu::control_regs::cr3::*;
 
 /// Returns the current Page Meta-Level 4 table
@@ -11,7 +13,8 @@ pub use cpu::control_regs::cr3::*;
 #[cfg(target_arch = "x86_64")]
 #[inline]
 pub unsafe fn current_pml4() -> *mut Table<PML4Level> {
-    read().as_mut_ptr::<Table<PML4Level>>()
+    let cr3: u64 = read().into();
+    PAddr::from(cr3 & ENTRY_ADDR_MASK).as_mut_ptr::<Table<PML4Level>>()
 }
 
 /// Sets the current Page Meta-Level 4 Table
@@ -23,3 +26,24 @@ pub unsafe fn current_pml4() -> *mut Table<PML4Level> {
 pub unsafe fn set_pml4(pml4: Table<PML4Level>) {
     write(pml4.frame().base_addr())
 }
+
+/// Switch to the PML4 table in `frame`, tagged with `pcid`.
+///
+/// If PCIDs are enabled, the TLB entries tagged with `pcid` are preserved,
+/// unless other page tables share it, or PCIDs have been freed since this
+/// CPU's TLB was last flushed. Otherwise, this flushes the TLB as usual.
+///
+/// # Safety
+/// + Control registers should generally not be modified during normal
+///   operation.
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn switch_to(frame: PhysicalPage, pcid: Pcid) {
+    let addr: u64 = frame.base_addr().into();
+    if pcid::is_enabled() {
+        pcid::flush_freed();
+        let no_flush = if pcid.is_unique() { pcid::CR3_NO_FLUSH } else { 0 };
+        write(PAddr::from(addr | pcid.bits() | no_flush))
+    } else {
+        write(PAddr::from(addr))
+    }
+}
@@ -0,0 +1,220 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Access to the memory that page tables live in.
+//!
+//! On real hardware, page tables are reached through the recursive PML4
+//! entry, the active table is selected by `%cr3`, and changes have to be
+//! flushed from the TLB. The [`Mmu`] trait abstracts over all of this, so
+//! that the paging code can also run against the [`SoftMmu`], a software
+//! model of the x86_64 MMU backed by any [`PhysicalMemory`], such as a
+//! buffer on the host when running `cargo test`.
+//!
+//! [`Mmu`]: trait.Mmu.html
+//! [`SoftMmu`]: struct.SoftMmu.html
+//! [`PhysicalMemory`]: trait.PhysicalMemory.html
+use memory::{PAddr, Page, PhysicalPage, VAddr, VirtualPage, PAGE_SIZE};
+
+use super::pcid::Pcid;
+use super::table::*;
+
+/// How the paging code reaches page tables and controls the MMU.
+pub trait Mmu {
+    /// Returns a pointer to the active PML4 table, as seen through the
+    /// recursive entry.
+    fn pml4(&self) -> *mut Table<PML4Level>;
+
+    /// Returns a pointer to the table referenced by entry `i` of `table`.
+    ///
+    /// The entry must be present and must not map a huge page.
+    fn next_table<L: Sublevel>(&self, table: &Table<L>, i: usize)
+                              -> *mut Table<L::Next>;
+
+    /// Returns a pointer through which the kernel can access `vaddr`.
+    ///
+    /// `vaddr` must be mapped in the active page table.
+    fn ptr(&self, vaddr: VAddr) -> *mut u8;
+
+    /// Returns the frame containing the PML4 table selected by `%cr3`.
+    fn current_frame(&self) -> PhysicalPage;
+
+    /// Select the PML4 table in `frame`, tagged with `pcid`.
+    ///
+    /// # Returns
+    /// + the previously selected PML4 frame and PCID.
+    unsafe fn switch_to(&mut self, frame: PhysicalPage, pcid: Pcid)
+                       -> (PhysicalPage, Pcid);
+
+    /// Invalidate `page` in the TLB for the current address space.
+    unsafe fn invlpg(&self, page: VirtualPage);
+
+    /// Invalidate the TLB for the current address space.
+    unsafe fn flush_all(&self);
+
+    /// Invalidate the TLB entries tagged with `pcid`, if it isn't current.
+    unsafe fn flush_pcid(&self, pcid: Pcid);
+}
+
+/// The hardware MMU, with page tables reached through the recursive mapping.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Recursive;
+
+impl Mmu for Recursive {
+    #[inline]
+    fn pml4(&self) -> *mut Table<PML4Level> { PML4_PTR }
+
+    #[inline]
+    fn next_table<L: Sublevel>(&self, table: &Table<L>, i: usize)
+                              -> *mut Table<L::Next> {
+        let table_addr = table as *const _ as usize;
+        ((table_addr << 9) | (i << 12)) as *mut _
+    }
+
+    #[inline]
+    fn ptr(&self, vaddr: VAddr) -> *mut u8 { vaddr.as_mut_ptr() }
+
+    #[inline]
+    fn current_frame(&self) -> PhysicalPage {
+        // this is safe to execute; we are in kernel mode
+        unsafe { super::cr3::current_pagetable_frame() }
+    }
+
+    unsafe fn switch_to(&mut self, frame: PhysicalPage, pcid: Pcid)
+                       -> (PhysicalPage, Pcid) {
+        let prev = (self.current_frame(), Pcid::current());
+        super::cr3::switch_to(frame, pcid);
+        prev
+    }
+
+    #[inline]
+    unsafe fn invlpg(&self, page: VirtualPage) {
+        use super::tlb::Flush;
+        page.invlpg()
+    }
+
+    #[inline]
+    unsafe fn flush_all(&self) { super::tlb::flush_all() }
+
+    #[inline]
+    unsafe fn flush_pcid(&self, pcid: Pcid) {
+        if pcid != Pcid::current() { pcid.flush() }
+    }
+}
+
+/// Physical memory that can be accessed directly.
+pub trait PhysicalMemory {
+    /// Returns a pointer to the start of `frame`.
+    fn frame_ptr(&self, frame: PhysicalPage) -> *mut u8;
+}
+
+/// A software model of the x86_64 MMU.
+///
+/// Virtual addresses are translated by walking the page tables in
+/// `memory`, starting from the modelled `%cr3`, exactly as the hardware
+/// would, including through the recursive entry. The model has no TLB, so
+/// flushes do nothing.
+#[derive(Debug)]
+pub struct SoftMmu<P> { /// The physical memory that page tables live in
+                        memory: P
+                      , /// The modelled `%cr3` frame
+                        cr3: PhysicalPage
+                      , /// The modelled `%cr3` PCID
+                        pcid: Pcid
+                      }
+
+impl<P: PhysicalMemory> SoftMmu<P> {
+    /// Returns a new `SoftMmu` with the PML4 table in `cr3` active.
+    pub fn new(memory: P, cr3: PhysicalPage) -> Self {
+        SoftMmu { memory: memory, cr3: cr3, pcid: Pcid::BOOT }
+    }
+
+    /// Returns the physical memory backing this MMU.
+    #[inline] pub fn memory(&self) -> &P { &self.memory }
+
+    /// Returns the table in `frame`.
+    #[inline]
+    fn table<L: TableLevel>(&self, frame: PhysicalPage) -> &Table<L> {
+        unsafe { &*(self.memory.frame_ptr(frame) as *const Table<L>) }
+    }
+
+    /// Translate `vaddr` the way the hardware would.
+    ///
+    /// # Returns
+    /// + `Some(PAddr)` if `vaddr` is mapped in the active page table
+    /// + `None` if accessing `vaddr` would page fault.
+    pub fn translate(&self, vaddr: VAddr) -> Option<PAddr> {
+        const HUGE_1G_MASK: usize = (1 << 30) - 1;
+        const HUGE_2M_MASK: usize = (1 << 21) - 1;
+        let addr = vaddr.as_usize();
+        let page = VirtualPage { number: addr >> 12 };
+        self.table::<PML4Level>(self.cr3)[page]
+            .get_frame()
+            .and_then(|frame| {
+                let entry = &self.table::<PDPTLevel>(frame)[page];
+                if entry.is_huge() {
+                    return entry.get_frame()
+                                .map(|frame| (frame, addr & HUGE_1G_MASK))
+                }
+                entry.get_frame().and_then(|frame| {
+                    let entry = &self.table::<PDLevel>(frame)[page];
+                    if entry.is_huge() {
+                        return entry.get_frame()
+                                    .map(|frame| (frame, addr & HUGE_2M_MASK))
+                    }
+                    entry.get_frame().and_then(|frame|
+                        self.table::<PTLevel>(frame)[page]
+                            .get_frame()
+                            .map(|frame|
+                                (frame, addr & (PAGE_SIZE as usize - 1))))
+                })
+            })
+            .map(|(frame, offset)| frame.base_addr() + offset as u64)
+    }
+}
+
+impl<P: PhysicalMemory> Mmu for SoftMmu<P> {
+    fn pml4(&self) -> *mut Table<PML4Level> {
+        self.ptr(VAddr::from(PML4_VADDR as usize)) as *mut _
+    }
+
+    fn next_table<L: Sublevel>(&self, table: &Table<L>, i: usize)
+                              -> *mut Table<L::Next> {
+        // on hardware, this goes through the recursive entry one level
+        // further up; when every recursive entry points to its own table,
+        // that's the same as following the entry directly.
+        let frame = table[i].get_frame()
+                            .expect("SoftMmu: next table is not present");
+        self.memory.frame_ptr(frame) as *mut _
+    }
+
+    fn ptr(&self, vaddr: VAddr) -> *mut u8 {
+        let paddr = self.translate(vaddr)
+                        .unwrap_or_else(||
+                            panic!("SoftMmu: page fault at {:#x}", vaddr));
+        let frame = PhysicalPage::containing(paddr);
+        let offset = *paddr - *frame.base_addr();
+        unsafe { self.memory.frame_ptr(frame).offset(offset as isize) }
+    }
+
+    #[inline]
+    fn current_frame(&self) -> PhysicalPage { self.cr3 }
+
+    unsafe fn switch_to(&mut self, frame: PhysicalPage, pcid: Pcid)
+                       -> (PhysicalPage, Pcid) {
+        let prev = (self.cr3, self.pcid);
+        self.cr3 = frame;
+        self.pcid = pcid;
+        prev
+    }
+
+    #[inline] unsafe fn invlpg(&self, _page: VirtualPage) { }
+
+    #[inline] unsafe fn flush_all(&self) { }
+
+    #[inline] unsafe fn flush_pcid(&self, _pcid: Pcid) { }
+}
@@ -12,14 +12,16 @@
 //! page table is called the Page Meta-Level 4 (PML4) table, followed by
 //! the Page Directory Pointer Table (PDPT), Page Directory (PD) table, and
 //! finally the bottom-level Page Table (PT).
-use core::{fmt, ops};
-use core::ptr::Unique;
+use core::{fmt, mem, ops};
 
 use alloc::FrameAllocator;
+use elf::section::Type as SectionType;
 use memory::{Addr, PAGE_SIZE, PAddr, Page, PhysicalPage, VAddr, VirtualPage};
 use params::InitParams;
 use ::Mapper;
 
+use self::mmu::{Mmu, Recursive};
+use self::pcid::Pcid;
 use self::table::*;
 use self::temp::TempPage;
 
@@ -27,19 +29,27 @@ pub mod table;
 pub mod tlb;
 pub mod temp;
 pub mod cr3;
+pub mod mmu;
+pub mod pat;
+pub mod pcid;
+pub mod walk;
+
+#[cfg(test)] mod test;
+
 #[derive(Debug)]
-pub struct ActivePageTable { pml4: ActivePML4 }
+pub struct ActivePageTable<M = Recursive>
+where M: Mmu { pml4: ActivePML4<M> }
 
-impl ops::Deref for ActivePageTable {
-    type Target = ActivePML4;
+impl<M: Mmu> ops::Deref for ActivePageTable<M> {
+    type Target = ActivePML4<M>;
 
-    fn deref(&self) -> &ActivePML4 {
+    fn deref(&self) -> &ActivePML4<M> {
         &self.pml4
     }
 }
 
-impl ops::DerefMut for ActivePageTable {
-    fn deref_mut(&mut self) -> &mut ActivePML4 {
+impl<M: Mmu> ops::DerefMut for ActivePageTable<M> {
+    fn deref_mut(&mut self) -> &mut ActivePML4<M> {
         &mut self.pml4
     }
 }
@@ -48,6 +58,16 @@ impl ActivePageTable {
     pub unsafe fn new() -> ActivePageTable {
         ActivePageTable { pml4: ActivePML4::new() }
     }
+}
+
+impl<M: Mmu> ActivePageTable<M> {
+    /// Returns the `ActivePageTable` reached through the given `Mmu`.
+    ///
+    /// # Safety
+    /// + The active PML4 table must have a valid recursive entry.
+    pub unsafe fn with_mmu(mmu: M) -> Self {
+        ActivePageTable { pml4: ActivePML4 { mmu: mmu } }
+    }
 
     /// Execute a closure with the recursive mapping temporarily changed to a
     /// new page table
@@ -55,14 +75,10 @@ impl ActivePageTable {
                    , table: &mut InactivePageTable
                    , temp_page: &mut temp::TempPage
                    , f: F)
-    where F: FnOnce(&mut ActivePML4) {
-        use self::tlb::flush_all;
+    where F: FnOnce(&mut ActivePML4<M>) {
         {
             // back up the current PML4 frame
-            let prev_pml4_frame = unsafe {
-                // this is safe to execute; we are in kernel mode
-                cr3::current_pagetable_frame()
-            };
+            let prev_pml4_frame = self.mmu.current_frame();
 
             // map temporary_page to current p4 table
             let pml4 = temp_page.map_to_table(prev_pml4_frame.clone(), self);
@@ -71,7 +87,7 @@ impl ActivePageTable {
             self.pml4_mut()[511].set(table.pml4_frame, PRESENT | WRITABLE);
             unsafe {
                 // this is safe to execute; we are in kernel mode
-                flush_all();
+                self.mmu.flush_all();
             }
 
             // execute the closure
@@ -82,7 +98,10 @@ impl ActivePageTable {
 
             unsafe {
                 // this is safe to execute; we are in kernel mode
-                flush_all();
+                self.mmu.flush_all();
+                // the closure may have changed mappings that the TLB still
+                // holds under the inactive table's PCID.
+                self.mmu.flush_pcid(table.pcid);
             }
         }
         temp_page.unmap(self);
@@ -91,6 +110,9 @@ impl ActivePageTable {
 
     /// Replace the current `ActivePageTable` with the given `InactivePageTable`
     ///
+    /// If PCIDs are enabled, the TLB is not flushed, since the new table's
+    /// entries are tagged with its own PCID.
+    ///
     /// # Arguments
     /// + `new_table`: the `InactivePageTable` that will replace the current
     ///                `ActivePageTable`.
@@ -100,16 +122,19 @@ impl ActivePageTable {
     pub fn replace_with(&mut self, new_table: InactivePageTable)
                        -> InactivePageTable {
         unsafe {
-            trace!("replacing {:?} with {:?}", self, new_table);
+            trace!("replacing active table with {:?}", new_table);
             // this is safe to execute; we are in kernel mode
-            let old_pml4_frame = cr3::current_pagetable_frame();
-            trace!("current pml4 frame is {:?}", old_pml4_frame);
-
-            cr3::set_pagetable_frame(new_table.pml4_frame);
-            trace!("set new pml4 frame to {:?}", new_table.pml4_frame);
+            let (old_pml4_frame, old_pcid)
+                = self.mmu.switch_to(new_table.pml4_frame, new_table.pcid);
+            trace!("previous pml4 frame was {:?}, {}", old_pml4_frame, old_pcid);
+            trace!( "set new pml4 frame to {:?}, {}"
+                  , new_table.pml4_frame, new_table.pcid);
+            // the new table is active now, so it keeps its PCID.
+            mem::forget(new_table);
 
             InactivePageTable {
                 pml4_frame: old_pml4_frame
+              , pcid: old_pcid
             }
         }
     }
@@ -118,43 +143,49 @@ impl ActivePageTable {
 
 /// Struct representing the currently active PML4 instance.
 ///
-/// The `ActivePML4` is a `Unique` reference to a PML4-level page table. It's
+/// The `ActivePML4` is a unique reference to a PML4-level page table. It's
 /// unique because, well, there can only be one active PML4 at a given time.
 ///
-pub struct ActivePML4(Unique<Table<PML4Level>>);
-impl fmt::Debug for ActivePML4 {
+/// The `Mmu` determines how the PML4 and the tables below it are reached;
+/// on real hardware, this is through the recursive mapping.
+pub struct ActivePML4<M = Recursive>
+where M: Mmu { mmu: M }
+
+impl<M: Mmu> fmt::Debug for ActivePML4<M> {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Active {:?}", unsafe { self.0.as_ref() })
+        write!(f, "Active {:?}", self.pml4())
     }
 }
 /// The active PML4 table is the single point of entry for page mapping.
-impl Mapper for ActivePML4 {
+impl<M: Mmu> Mapper for ActivePML4<M> {
     type Flags = EntryFlags;
 
     fn translate(&self, vaddr: VAddr) -> Option<PAddr> {
         let offset = *vaddr % PAGE_SIZE as usize;
         self.translate_page(Page::containing(vaddr))
-            .map(|frame| PAddr::from(frame.number + offset as u64) )
+            .map(|frame| frame.base_addr() + offset as u64)
     }
 
     fn translate_page(&self, page: VirtualPage) -> Option<PhysicalPage> {
-        let pdpt = self.pml4().next_table(page);
+        let mmu = &self.mmu;
+        let pdpt = self.pml4().next_table(page, mmu);
 
         let huge_page = || {
             pdpt.and_then(|pdpt|
                 pdpt[page]
-                    .do_huge(PDLevel::index_of(page) + PTLevel::index_of(page))
+                    .do_huge( PDLevel::index_of(page) * N_ENTRIES
+                            + PTLevel::index_of(page))
                     .or_else(|| {
-                        pdpt.next_table(page).and_then(|pd|
+                        pdpt.next_table(page, mmu).and_then(|pd|
                             pd[page].do_huge(PTLevel::index_of(page))
                         )
                     })
                 )
         };
 
-        pdpt.and_then(|pdpt| pdpt.next_table(page))
-            .and_then(|pd| pd.next_table(page))
+        pdpt.and_then(|pdpt| pdpt.next_table(page, mmu))
+            .and_then(|pd| pd.next_table(page, mmu))
             .and_then(|pt| pt[page].get_frame())
             .or_else(huge_page)
     }
@@ -174,14 +205,15 @@ impl Mapper for ActivePML4 {
         // let addr = page.base();
 
         // access or create all the lower-level page tables.
+        let (pml4, mmu) = self.pml4_and_mmu();
         let mut page_table // get the PML4
-            = self.pml4_mut()
+            = pml4
                   // get or create the PDPT table at the page's PML4 index
-                  .create_next(page, alloc)
+                  .create_next(page, alloc, mmu)
                   // get or create the PD table at the page's PDPT index
-                  .create_next(page, alloc)
+                  .create_next(page, alloc, mmu)
                   // get or create the page table at the  page's PD table index
-                  .create_next(page, alloc);
+                  .create_next(page, alloc, mmu);
         trace!(" . . Map: Got page table");
         // check if the page at that index is not currently in use, as we
         // cannot map a page which is currently in use.
@@ -220,30 +252,9 @@ impl Mapper for ActivePML4 {
     /// All freed frames are returned to the given `FrameAllocator`.
     fn unmap<A>(&mut self, page: VirtualPage, alloc: &mut A)
     where A: FrameAllocator {
-        use self::tlb::Flush;
         trace!("unmapping {:?}", page);
-        assert!(self.translate_page(page).is_some());
-
-        // get the page table entry corresponding to the page.
-        let entry
-            =  &mut self.pml4_mut()
-                        .next_table_mut(page)
-                        .and_then(|pdpt| pdpt.next_table_mut(page))
-                        .and_then(|pd| pd.next_table_mut(page))
-                        .expect("Could not unmap, huge pages not supported!")
-                  [page];        // index the entry from the table
-        trace!("got page table entry for {:?}", page);
-        // get the pointed frame for the page table entry.
-        let frame = entry.get_frame()
-                         .expect("Could not unmap page that was not mapped!");
-        trace!("page table entry for {:?} points to {:?}", page, frame);
-        // mark the page table entry as unused
-        entry.set_unused();
-        trace!("set page table entry for {:?} as unused", page);
-        // deallocate the frame and flush the translation lookaside buffer
-        // this is safe because we're in kernel mode
-        unsafe { page.invlpg() };
-        trace!("flushed TLB");
+        let frame = self.unmap_entry(page);
+        // deallocate the frame
         unsafe {
             // this is hopefully safe because nobody else should be using an
             // allocated page frame
@@ -259,23 +270,97 @@ impl Mapper for ActivePML4 {
 impl ActivePML4 {
 
     pub unsafe fn new() -> Self {
-        ActivePML4(Unique::new(PML4_PTR))
+        ActivePML4 { mmu: Recursive }
     }
+}
+
+impl<M: Mmu> ActivePML4<M> {
 
     fn pml4(&self) -> &Table<PML4Level> {
-        unsafe { self.0.as_ref() }
+        unsafe { &*self.mmu.pml4() }
     }
 
     fn pml4_mut(&mut self) -> &mut Table<PML4Level> {
-        unsafe { self.0.as_mut() }
+        unsafe { &mut *self.mmu.pml4() }
     }
 
+    /// Borrow the PML4 table mutably, along with the `Mmu` needed to reach
+    /// the tables below it.
+    fn pml4_and_mmu(&mut self) -> (&mut Table<PML4Level>, &M) {
+        let pml4 = unsafe { &mut *self.mmu.pml4() };
+        (pml4, &self.mmu)
+    }
+
+    /// Returns the `Mmu` used to reach this page table.
+    #[inline]
+    pub fn mmu(&self) -> &M { &self.mmu }
+
     /// Returns true if the given page is mapped.
     #[inline]
     pub fn is_mapped(&self, page: &VirtualPage) -> bool {
          self.translate_page(*page).is_some()
     }
 
+    /// Clear the page table entry for `page` and flush it from the TLB.
+    ///
+    /// Unlike `unmap`, this does not deallocate the frame that `page` was
+    /// mapped to, so it may be used for frames that were never allocated,
+    /// such as device memory.
+    ///
+    /// # Returns
+    /// + the `PhysicalPage` that `page` was mapped to.
+    pub fn unmap_entry(&mut self, page: VirtualPage) -> PhysicalPage {
+        assert!(self.translate_page(page).is_some());
+
+        let (pml4, mmu) = self.pml4_and_mmu();
+        // get the page table entry corresponding to the page.
+        let entry
+            =  &mut pml4.page_table_mut_for(page, mmu)
+                        .expect("Could not unmap, huge pages not supported!")
+                  [page];        // index the entry from the table
+        trace!("got page table entry for {:?}", page);
+        // get the pointed frame for the page table entry.
+        let frame = entry.get_frame()
+                         .expect("Could not unmap page that was not mapped!");
+        trace!("page table entry for {:?} points to {:?}", page, frame);
+        // mark the page table entry as unused
+        entry.set_unused();
+        trace!("set page table entry for {:?} as unused", page);
+        // flush the translation lookaside buffer
+        // this is safe because we're in kernel mode
+        unsafe { mmu.invlpg(page) };
+        trace!("flushed TLB");
+        frame
+    }
+
+    /// Set or clear the present bit of the page table entry for `page`, and
+    /// flush it from the TLB.
+    ///
+    /// The entry keeps the frame it points to, so a page that is made not
+    /// present, such as a guard page, can be made present again later.
+    ///
+    /// # Returns
+    /// + `Ok(())` if the entry was changed
+    /// + `Err` if `page` has no page table entry, or is part of a huge page.
+    pub fn set_present(&mut self, page: VirtualPage, present: bool)
+                      -> Result<(), &'static str> {
+        let (pml4, mmu) = self.pml4_and_mmu();
+        let entry
+            = &mut pml4.page_table_mut_for(page, mmu)
+                       .ok_or("Page is not mapped, or is in a huge page!")?
+                  [page];
+        if entry.is_unused() {
+            return Err("Page was never mapped!")
+        }
+        let frame = PhysicalPage::containing(entry.get_addr());
+        let mut flags = entry.flags();
+        flags.set_present(present);
+        entry.set(frame, flags);
+        // this is safe because we're in kernel mode
+        unsafe { mmu.invlpg(page) };
+        Ok(())
+    }
+
 
 }
 
@@ -283,13 +368,16 @@ impl ActivePML4 {
 #[derive(Debug)]
 pub struct InactivePageTable {
     pml4_frame: PhysicalPage
+  , /// The PCID tagging this table's TLB entries
+    pcid: Pcid
 }
 
 impl InactivePageTable {
-    pub fn new( frame: PhysicalPage
-              , active_table: &mut ActivePageTable
-              , temp: &mut TempPage)
-              -> Self {
+    pub fn new<M>( frame: PhysicalPage
+                 , active_table: &mut ActivePageTable<M>
+                 , temp: &mut TempPage)
+                 -> Self
+    where M: Mmu {
         {
             trace!("Mapping page {} to frame {}", temp.number, frame.number);
             let table = temp.map_to_table(frame.clone(), active_table);
@@ -302,7 +390,22 @@ impl InactivePageTable {
         temp.unmap(active_table);
         trace!(" . . Unmapped temp page.");
 
-        InactivePageTable { pml4_frame: frame }
+        InactivePageTable { pml4_frame: frame, pcid: Pcid::allocate() }
+    }
+
+    /// Returns the PCID assigned to this page table.
+    #[inline]
+    pub fn pcid(&self) -> Pcid { self.pcid }
+
+    /// Returns the frame containing this table's PML4.
+    #[inline]
+    pub fn pml4_frame(&self) -> PhysicalPage { self.pml4_frame }
+}
+
+impl Drop for InactivePageTable {
+    fn drop(&mut self) {
+        // this is safe; the table isn't active, so nothing uses its PCID.
+        unsafe { self.pcid.free() }
     }
 }
 
@@ -348,16 +451,36 @@ where A: FrameAllocator {
 pub fn kernel_remap<A>(params: &InitParams, alloc: &mut A)
                        -> Result<ActivePageTable, &'static str>
 where A: FrameAllocator {
+    // old page table
+    let current_table = unsafe { ActivePageTable::new() };
+    trace!("Got current page table.");
+    kernel_remap_with(current_table, params, alloc)
+}
+
+/// Remaps the kernel using 4KiB pages, starting from `current_table`.
+///
+/// This is `kernel_remap` for any `Mmu`.
+pub fn kernel_remap_with<A, M>( mut current_table: ActivePageTable<M>
+                              , params: &InitParams
+                              , alloc: &mut A)
+                              -> Result<ActivePageTable<M>, &'static str>
+where A: FrameAllocator
+    , M: Mmu {
+    // refuse to map any kernel section as both writable and executable;
+    // every section the kernel needs to modify should be NX.
+    // check this before allocating anything, so nothing leaks on error.
+    if params.elf_sections()
+             .filter(|s| s.is_allocated())
+             .any(|s| s.is_writable() && s.is_executable()) {
+        return Err("Kernel ELF section is both writable and executable!")
+    }
+
     // create a  temporary page for switching page tables
     // page number chosen fairly arbitrarily.
     const TEMP_PAGE_NUMBER: usize = 0xfacade;
     let mut temp_page = TempPage::new(TEMP_PAGE_NUMBER, alloc);
     trace!("Created temporary page.");
 
-    // old and new page tables
-    let mut current_table = unsafe { ActivePageTable::new() };
-    trace!("Got current page table.");
-
     let mut new_table = unsafe {
         InactivePageTable::new(
              alloc.allocate().map_err(|_| { "Out of physical pages!" })?
@@ -393,10 +516,36 @@ where A: FrameAllocator {
             }
         }
 
+        // map the symbol and string tables read-only, so that backtraces
+        // can look up symbols. they aren't allocated sections, so the
+        // bootloader may have packed several of them into one page.
+        let tables
+            = params.elf_sections()
+                    .filter(|s| !s.is_allocated() && s.length() > 0)
+                    .filter(|s| match s.get_type() {
+                        Ok(SectionType::SymbolTable) |
+                        Ok(SectionType::StringTable) => true
+                      , _ => false
+                    });
+
+        for section in tables {
+            kinfoln!( dots: " . . . ", "Identity mapping {}", section);
+            let start_frame = PhysicalPage::containing(section.address());
+            let end_frame = PhysicalPage::containing(section.end_address() - 1)
+                          + 1;
+            for frame in start_frame .. end_frame {
+                let page = VirtualPage::containing(
+                    VAddr::from(*frame.base_addr() as usize));
+                if !pml4.is_mapped(&page) {
+                    pml4.identity_map(frame, PRESENT | NO_EXECUTE, alloc)
+                }
+            }
+        }
+
         // remap VGA buffer
         kinfoln!( dots: " . . ", "Identity mapping VGA buffer" );
         let vga_buffer_frame = PhysicalPage::containing(PAddr::from(0xb8000));
-        pml4.identity_map(vga_buffer_frame, WRITABLE, alloc);
+        pml4.identity_map(vga_buffer_frame, WRITABLE | NO_EXECUTE, alloc);
 
         // remap Multiboot info
         kinfoln!( dots: " . . ", "Identity mapping multiboot info" );
@@ -404,7 +553,7 @@ where A: FrameAllocator {
         let multiboot_end = PhysicalPage::from(params.multiboot_end());
 
         for frame in multiboot_start .. multiboot_end {
-            pml4.identity_map(frame, PRESENT, alloc)
+            pml4.identity_map(frame, PRESENT | NO_EXECUTE, alloc)
         }
 
     });
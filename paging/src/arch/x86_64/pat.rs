@@ -0,0 +1,320 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Page Attribute Table and cache-type control.
+//!
+//! The caching behaviour of a page is selected by the `PAT`, `NO_CACHE`
+//! (`PCD`) and `WRITE_THROUGH` (`PWT`) bits of its page table entry, which
+//! together form a 3-bit index into the `IA32_PAT` MSR. We program the PAT
+//! so that the first four entries match the power-on defaults, and so that
+//! the fifth selects write-combining, which the defaults lack:
+//!
+//! | index | `PAT` | `PCD` | `PWT` | type |
+//! |-------|-------|-------|-------|------|
+//! | 0     | 0     | 0     | 0     | WB   |
+//! | 1     | 0     | 0     | 1     | WT   |
+//! | 2     | 0     | 1     | 0     | UC-  |
+//! | 3     | 0     | 1     | 1     | UC   |
+//! | 4     | 1     | 0     | 0     | WC   |
+//!
+//! The `PAT` bit is bit 7 of a 4KiB page table entry, but bit 12 of a huge
+//! page entry, where bit 7 is `HUGE_PAGE`. `CacheType`s should therefore
+//! only be converted to `EntryFlags` for 4KiB pages.
+use core::{fmt, mem, ops};
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use spin::Mutex;
+
+use alloc::FrameAllocator;
+use memory::{PAddr, Page, PhysicalPage, VAddr, VirtualPage, PAGE_SIZE};
+use cpu::{cpuid, interrupts, msr};
+
+use super::ActivePageTable;
+use super::table::*;
+use ::Mapper;
+
+/// The memory types that may be selected for a page.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CacheType { /// Write-back: the default for normal memory
+                     WriteBack
+                   , /// Write-through: writes update memory immediately
+                     WriteThrough
+                   , /// Uncacheable, but may be overridden to WC by MTRRs
+                     UncachedMinus
+                   , /// Uncacheable: for device registers
+                     Uncached
+                   , /// Write-combining: for framebuffers
+                     WriteCombining
+                   }
+
+impl CacheType {
+    /// Returns the index of this type in the PAT.
+    #[inline]
+    pub fn pat_index(&self) -> u8 {
+        match *self { CacheType::WriteBack => 0
+                    , CacheType::WriteThrough => 1
+                    , CacheType::UncachedMinus => 2
+                    , CacheType::Uncached => 3
+                    , CacheType::WriteCombining => 4
+                    }
+    }
+
+    /// Returns the memory type encoding of this type in the PAT MSR.
+    #[inline]
+    fn encoding(&self) -> u64 {
+        match *self { CacheType::Uncached => 0x00
+                    , CacheType::WriteCombining => 0x01
+                    , CacheType::WriteThrough => 0x04
+                    , CacheType::WriteBack => 0x06
+                    , CacheType::UncachedMinus => 0x07
+                    }
+    }
+}
+
+impl fmt::Display for CacheType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match *self { CacheType::WriteBack => "WB"
+                          , CacheType::WriteThrough => "WT"
+                          , CacheType::UncachedMinus => "UC-"
+                          , CacheType::Uncached => "UC"
+                          , CacheType::WriteCombining => "WC"
+                          })
+    }
+}
+
+/// Converts a `CacheType` to the flags for a 4KiB page table entry.
+impl From<CacheType> for EntryFlags {
+    fn from(cache: CacheType) -> Self {
+        let index = cache.pat_index();
+        let mut flags = EntryFlags::empty();
+        if index & 0b001 != 0 { flags.insert(WRITE_THROUGH) }
+        if index & 0b010 != 0 { flags.insert(NO_CACHE) }
+        // in a 4KiB page table entry, bit 7 is the PAT bit.
+        if index & 0b100 != 0 { flags.insert(HUGE_PAGE) }
+        flags
+    }
+}
+
+/// The PAT entries we program, in index order.
+const PAT_ENTRIES: [CacheType; 8] = [ CacheType::WriteBack
+                                    , CacheType::WriteThrough
+                                    , CacheType::UncachedMinus
+                                    , CacheType::Uncached
+                                    , CacheType::WriteCombining
+                                    , CacheType::WriteThrough
+                                    , CacheType::UncachedMinus
+                                    , CacheType::Uncached
+                                    ];
+
+/// Program the `IA32_PAT` MSR with our cache-type layout.
+///
+/// # Returns
+/// + `Ok(())` if the PAT was programmed
+/// + `Err` if the CPU doesn't support the PAT.
+///
+/// # Safety
+/// + This should be done before any page is mapped with a `CacheType` other
+///   than `WriteBack`, as the meaning of the PAT index bits changes.
+pub unsafe fn initialize() -> Result<(), &'static str> {
+    use super::tlb::flush_all;
+    if !cpuid::has_pat() {
+        return Err("Page attribute table is not supported!")
+    }
+    let pat = PAT_ENTRIES.iter()
+                         .enumerate()
+                         .fold(0, |pat, (i, ty)| pat | ty.encoding() << (i * 8));
+    trace!("IA32_PAT = {:#x}", pat);
+    msr::write(msr::IA32_PAT, pat);
+    flush_all();
+    Ok(())
+}
+
+/// Base address of the virtual address range used for `ioremap`.
+const IOREMAP_BASE: usize = 0xffff_fe00_0000_0000;
+
+/// Number of pages in the virtual address range used for `ioremap` (64GiB).
+const IOREMAP_PAGES: usize = 1 << 24;
+
+/// Number of pages of the `ioremap` range that have been handed out.
+///
+/// When a mapping is unmapped, its pages are only given back if they're the
+/// last ones that were handed out, so a mapping that's unmapped while a
+/// later one is still mapped leaves a hole that is never reused.
+//  TODO: this is fine for the handful of devices we map at boot, but we
+//        should have a real virtual address allocator eventually.
+static IOREMAP_NEXT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// How many dropped mappings can be waiting to be unmapped.
+const MAX_DROPPED: usize = 32;
+
+/// The pages of dropped `IoMapping`s that haven't been unmapped yet, as the
+/// index of the first page in the `ioremap` range, and the number of pages.
+///
+/// A mapping doesn't have the page table when it's dropped, so it leaves
+/// its pages here for the next `ioremap` or `unmap_dropped` to unmap. This
+/// is locked by `Drop`, which may run anywhere, so it must only be locked
+/// with interrupts disabled.
+static DROPPED: Mutex<[Option<(usize, usize)>; MAX_DROPPED]>
+    = Mutex::new([None; MAX_DROPPED]);
+
+/// A mapping of device memory, which is unmapped when dropped.
+///
+/// Since dropping a mapping doesn't have the page table, the pages are
+/// only unmapped by the next call to `ioremap` or `unmap_dropped`; pass the
+/// mapping to `iounmap` to unmap it straight away, or `leak` it to keep it
+/// mapped for as long as the kernel runs.
+#[derive(Debug)]
+pub struct IoMapping { /// The virtual address of the first mapped byte
+                       vaddr: VAddr
+                     , /// The number of bytes requested
+                       len: usize
+                     , /// The pages that were mapped
+                       pages: ops::Range<VirtualPage>
+                     , cache: CacheType
+                     }
+
+impl IoMapping {
+    /// Returns the virtual address that the physical address was mapped at.
+    #[inline] pub fn vaddr(&self) -> VAddr { self.vaddr }
+
+    /// Returns the length of the mapping, in bytes.
+    #[inline] pub fn len(&self) -> usize { self.len }
+
+    /// Returns the cache type of the mapping.
+    #[inline] pub fn cache_type(&self) -> CacheType { self.cache }
+
+    /// Returns a pointer to the start of the mapping.
+    #[inline] pub fn as_ptr<T>(&self) -> *const T { *self.vaddr as *const T }
+
+    /// Returns a mutable pointer to the start of the mapping.
+    #[inline] pub fn as_mut_ptr<T>(&self) -> *mut T { *self.vaddr as *mut T }
+
+    /// Keep the mapping for as long as the kernel runs.
+    ///
+    /// # Returns
+    /// + the virtual address that the physical address was mapped at.
+    pub fn leak(self) -> VAddr {
+        let vaddr = self.vaddr;
+        mem::forget(self);
+        vaddr
+    }
+
+    /// Returns the index of the mapping's first page in the `ioremap`
+    /// range, and its number of pages.
+    fn ioremap_pages(&self) -> (usize, usize) {
+        let base = VirtualPage::containing(VAddr::from(IOREMAP_BASE));
+        ( self.pages.start.number - base.number
+        , self.pages.end.number - self.pages.start.number )
+    }
+}
+
+impl Drop for IoMapping {
+    fn drop(&mut self) {
+        let pages = self.ioremap_pages();
+        let deferred = interrupts::without_interrupts(|| {
+            DROPPED.lock().iter_mut()
+                   .find(|slot| slot.is_none())
+                   .map(|slot| *slot = Some(pages))
+                   .is_some()
+        });
+        if deferred {
+            trace!("dropped {:?}", self);
+        } else {
+            warn!("Too many dropped ioremap mappings, leaking {:?}", self);
+        }
+    }
+}
+
+/// Unmap `count` pages from page `first` of the `ioremap` range, and give
+/// them back if they were the last ones handed out.
+fn unmap_pages(first: usize, count: usize, table: &mut ActivePageTable) {
+    let start = VirtualPage::containing(VAddr::from(IOREMAP_BASE)) + first;
+    for page in start .. start + count {
+        table.unmap_entry(page);
+    }
+    let _ = IOREMAP_NEXT.compare_exchange( first + count, first
+                                         , Ordering::Relaxed
+                                         , Ordering::Relaxed);
+}
+
+/// Unmap every `IoMapping` that has been dropped from `table`, which must be
+/// the page table they were mapped in.
+pub fn unmap_dropped(table: &mut ActivePageTable) {
+    let dropped = interrupts::without_interrupts(|| {
+        mem::replace(&mut *DROPPED.lock(), [None; MAX_DROPPED])
+    });
+    // mappings are usually dropped in the opposite order to the one they
+    // were made in, so unmapping the last dropped first gives back as many
+    // pages as possible.
+    for &(first, count) in dropped.iter().rev()
+                                  .filter_map(|pages| pages.as_ref()) {
+        unmap_pages(first, count, table);
+    }
+}
+
+/// Map `len` bytes of device memory at `paddr` with the given cache type.
+///
+/// The mapping is writable and not executable, and is unmapped again after
+/// the returned `IoMapping` is dropped. The frames being mapped are not
+/// taken from or returned to `alloc`, which is only used to allocate page
+/// tables.
+///
+/// Any mappings that have been dropped are unmapped from `table` first.
+pub fn ioremap<A>( paddr: PAddr, len: usize, cache: CacheType
+                 , table: &mut ActivePageTable, alloc: &mut A)
+                 -> Result<IoMapping, &'static str>
+where A: FrameAllocator {
+    if len == 0 {
+        return Err("Cannot ioremap zero bytes!")
+    }
+    unmap_dropped(table);
+    let start_frame = PhysicalPage::containing(paddr);
+    let offset = (*paddr - *start_frame.base_addr()) as usize;
+    let n_pages = (offset + len + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+
+    // only take the pages if they're all there, so that a request that's
+    // too big doesn't use up the rest of the range.
+    let mut first = IOREMAP_NEXT.load(Ordering::Relaxed);
+    loop {
+        if n_pages > IOREMAP_PAGES - first {
+            return Err("Out of ioremap address space!")
+        }
+        match IOREMAP_NEXT.compare_exchange_weak( first, first + n_pages
+                                                , Ordering::Relaxed
+                                                , Ordering::Relaxed) {
+            Ok(_) => break
+          , Err(next) => first = next
+        }
+    }
+    let start_page = VirtualPage::containing(VAddr::from(IOREMAP_BASE))
+                   + first;
+    let pages = start_page .. start_page + n_pages;
+
+    let flags = EntryFlags::from(cache) | WRITABLE | NO_EXECUTE;
+    for (page, frame) in pages.clone().zip(start_frame .. start_frame + n_pages) {
+        table.map(page, frame, flags, alloc);
+    }
+
+    let mapping = IoMapping { vaddr: start_page.base() + offset
+                            , len: len
+                            , pages: pages
+                            , cache: cache
+                            };
+    trace!("ioremap: {:#x} -> {:?}", paddr, mapping);
+    Ok(mapping)
+}
+
+/// Unmap a mapping made by `ioremap` from `table`, which must be the page
+/// table it was mapped in, without waiting for the next `ioremap`.
+///
+/// The frames that were mapped aren't freed, since they're device memory.
+pub fn iounmap(mapping: IoMapping, table: &mut ActivePageTable) {
+    let (first, count) = mapping.ioremap_pages();
+    unmap_pages(first, count, table);
+    trace!("unmapped {:?}", mapping);
+    mem::forget(mapping);
+}
@@ -0,0 +1,266 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Process-context identifiers.
+//!
+//! When `CR4.PCIDE` is set, the low 12 bits of `%cr3` hold a PCID, and the
+//! TLB tags its entries with the PCID of the address space they came from.
+//! This means that switching page tables no longer has to flush the TLB:
+//! entries belonging to other address spaces just stop matching.
+//!
+//! Each [`InactivePageTable`] is assigned a PCID when it is created, and
+//! gives it back when it is dropped. If all 4094 of the PCIDs that are
+//! handed out are in use, new page tables share [`Pcid::SHARED`], which is
+//! flushed whenever it is switched to, since the TLB can't tell its page
+//! tables apart.
+//!
+//! A freed PCID may still have entries in the TLB of any CPU that ran its
+//! address space. Rather than interrupting every CPU when one is freed, a
+//! count of freed PCIDs is kept, and each CPU flushes its whole TLB before
+//! it next switches page tables if that count has changed since its last
+//! flush. A PCID is only handed out again after the count is bumped, so no
+//! CPU can reach its new address space through stale entries.
+//!
+//! [`InactivePageTable`]: ../struct.InactivePageTable.html
+//! [`Pcid::SHARED`]: struct.Pcid.html#associatedconstant.SHARED
+use core::fmt;
+use core::sync::atomic::{ AtomicBool, AtomicUsize, Ordering
+                        , ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT };
+use spin::Mutex;
+
+use cpu::control_regs::cr4;
+use cpu::{cpuid, interrupts};
+
+/// Number of PCIDs that fit in the low 12 bits of `%cr3`.
+const N_PCIDS: usize = 1 << 12;
+
+/// Mask to apply to `%cr3` to isolate the PCID.
+pub const CR3_PCID_MASK: u64 = (N_PCIDS as u64) - 1;
+
+/// Set this bit when writing `%cr3` to preserve the new PCID's TLB entries.
+pub const CR3_NO_FLUSH: u64 = 1 << 63;
+
+/// True if `CR4.PCIDE` has been set.
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// True if the `invpcid` instruction is supported.
+static HAS_INVPCID: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// The PCIDs that are in use.
+static IN_USE: Mutex<PcidSet> = Mutex::new(PcidSet::new());
+
+/// The most CPUs whose TLB flushes can be kept track of.
+const MAX_CPUS: usize = 64;
+
+/// The number of PCIDs freed so far.
+static FREED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The value of `FREED` when each CPU last flushed its TLB.
+static FLUSHED: Mutex<FlushLog> = Mutex::new(FlushLog::new());
+
+/// A set of PCIDs, one bit each.
+///
+/// `Pcid::BOOT` and `Pcid::SHARED` are never in the set, since they're
+/// never handed out by `take`.
+pub struct PcidSet([u64; N_PCIDS / 64]);
+
+impl PcidSet {
+    /// Returns an empty set.
+    pub const fn new() -> Self { PcidSet([0; N_PCIDS / 64]) }
+
+    /// Returns true if `pcid` is in the set.
+    #[inline] pub fn contains(&self, pcid: Pcid) -> bool {
+        let n = pcid.0 as usize;
+        self.0[n / 64] & (1 << (n % 64)) != 0
+    }
+
+    /// Add the lowest PCID that isn't in the set, other than `Pcid::BOOT`
+    /// and `Pcid::SHARED`, and return it.
+    ///
+    /// # Returns
+    /// + `None` if every other PCID is in the set.
+    pub fn take(&mut self) -> Option<Pcid> {
+        let free = (Pcid::BOOT.0 + 1 .. Pcid::SHARED.0)
+            .map(Pcid)
+            .find(|&pcid| !self.contains(pcid));
+        if let Some(Pcid(n)) = free {
+            self.0[n as usize / 64] |= 1 << (n as usize % 64);
+        }
+        free
+    }
+
+    /// Remove `pcid` from the set.
+    #[inline] pub fn give_back(&mut self, pcid: Pcid) {
+        let n = pcid.0 as usize;
+        self.0[n / 64] &= !(1 << (n % 64));
+    }
+}
+
+/// Keeps track of how many PCIDs had been freed when each CPU last flushed
+/// its TLB.
+pub struct FlushLog { cpus: [(u32, usize); MAX_CPUS]
+                    , len: usize
+                    }
+
+impl FlushLog {
+    /// Returns a log that hasn't seen any CPUs yet.
+    pub const fn new() -> Self {
+        FlushLog { cpus: [(0, 0); MAX_CPUS], len: 0 }
+    }
+
+    /// Record that the CPU whose APIC ID is `cpu` has seen `freed` PCIDs
+    /// freed.
+    ///
+    /// # Returns
+    /// + `true` if that CPU must flush its TLB, because PCIDs have been
+    ///   freed since it last did, or because it hasn't been seen before.
+    pub fn catch_up(&mut self, cpu: u32, freed: usize) -> bool {
+        if let Some(entry) = self.cpus[..self.len].iter_mut()
+                                                  .find(|e| e.0 == cpu) {
+            let stale = entry.1 != freed;
+            entry.1 = freed;
+            return stale
+        }
+        // CPUs that don't fit in the log just flush every time.
+        if self.len < MAX_CPUS {
+            self.cpus[self.len] = (cpu, freed);
+            self.len += 1;
+        }
+        true
+    }
+}
+
+/// A process-context identifier.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Pcid(u16);
+
+impl Pcid {
+    /// The PCID used at boot, and by all page tables if PCIDs are disabled.
+    pub const BOOT: Pcid = Pcid(0);
+
+    /// The PCID shared by the page tables created while every other PCID
+    /// is in use.
+    pub const SHARED: Pcid = Pcid(N_PCIDS as u16 - 1);
+
+    /// Allocate a PCID for a new address space.
+    ///
+    /// If PCIDs are not enabled, this always returns `Pcid::BOOT`. If every
+    /// PCID is in use, this returns `Pcid::SHARED`.
+    pub fn allocate() -> Pcid {
+        if !is_enabled() { return Pcid::BOOT }
+        IN_USE.lock().take().unwrap_or_else(|| {
+            trace!("out of PCIDs, using {}", Pcid::SHARED);
+            Pcid::SHARED
+        })
+    }
+
+    /// Give back a PCID from `allocate`, once its address space is gone,
+    /// so that it can be handed out again.
+    ///
+    /// Every CPU flushes its TLB before it next switches page tables, so
+    /// the PCID's stale entries are gone from a CPU before that CPU can
+    /// switch to it again (see [`flush_freed`]).
+    ///
+    /// # Safety
+    /// + No CPU may be using the PCID, and it mustn't be freed twice.
+    ///
+    /// [`flush_freed`]: fn.flush_freed.html
+    pub unsafe fn free(self) {
+        if self == Pcid::BOOT || self == Pcid::SHARED { return }
+        // this has to happen before the PCID can be handed out again.
+        FREED.fetch_add(1, Ordering::SeqCst);
+        IN_USE.lock().give_back(self);
+    }
+
+    /// Returns true if switching to this PCID can keep the TLB entries
+    /// tagged with it.
+    ///
+    /// This is false for `Pcid::SHARED`, whose entries may be some other
+    /// page table's.
+    #[inline]
+    pub fn is_unique(&self) -> bool { *self != Pcid::SHARED }
+
+    /// Returns the PCID of the current address space.
+    #[inline]
+    pub fn current() -> Pcid {
+        use cpu::control_regs::cr3;
+        // this is safe; we are in kernel mode.
+        let cr3: u64 = unsafe { cr3::read() }.into();
+        Pcid((cr3 & CR3_PCID_MASK) as u16)
+    }
+
+    /// Returns the bits to set in `%cr3` for this PCID.
+    #[inline]
+    pub fn bits(&self) -> u64 { self.0 as u64 }
+
+    /// Invalidate all non-global TLB entries tagged with this PCID.
+    ///
+    /// # Safety
+    /// + Causes a general protection fault if not executed in kernel mode.
+    pub unsafe fn flush(&self) {
+        use super::tlb::{flush_all, flush_all_contexts, invpcid, Invalidate};
+        if has_invpcid() {
+            invpcid(Invalidate::SingleContext, *self, 0)
+        } else if *self == Pcid::current() {
+            flush_all()
+        } else {
+            flush_all_contexts()
+        }
+    }
+}
+
+impl fmt::Display for Pcid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PCID {}", self.0)
+    }
+}
+
+/// Returns true if PCIDs are enabled.
+#[inline]
+pub fn is_enabled() -> bool { ENABLED.load(Ordering::Relaxed) }
+
+/// Returns true if the `invpcid` instruction may be used.
+#[inline]
+pub fn has_invpcid() -> bool { HAS_INVPCID.load(Ordering::Relaxed) }
+
+/// Flush the current CPU's TLB, for every PCID, if any PCIDs have been
+/// freed since it was last flushed.
+///
+/// This must be called before switching page tables, so that a reused
+/// PCID can't reach the entries its previous address space left behind.
+///
+/// # Safety
+/// + Causes a general protection fault if not executed in kernel mode.
+pub unsafe fn flush_freed() {
+    use super::tlb::flush_all_contexts;
+    let freed = FREED.load(Ordering::SeqCst);
+    if freed == 0 { return }
+    let cpu = cpuid::apic_id();
+    // the log is also taken when switching page tables, so don't let an
+    // interrupt do that while it's locked.
+    interrupts::without_interrupts(|| {
+        if FLUSHED.lock().catch_up(cpu, freed) { flush_all_contexts() }
+    })
+}
+
+/// Enable PCIDs, if the CPU supports them.
+///
+/// # Returns
+/// + `true` if `CR4.PCIDE` is now set
+/// + `false` if PCIDs are unsupported.
+///
+/// # Safety
+/// + The current PCID must be 0, as is the case at boot. Setting
+///   `CR4.PCIDE` otherwise causes a general protection fault.
+pub unsafe fn enable() -> bool {
+    if !cpuid::has_pcid() { return false }
+    cr4::enable_pcid(true);
+    HAS_INVPCID.store(cpuid::has_invpcid(), Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+    true
+}
@@ -9,6 +9,7 @@
 use alloc::FrameAllocator;
 use ::elf;
 use memory::{Addr, PAGE_SIZE, PAddr, Page, PhysicalPage, VAddr, VirtualPage};
+use super::mmu::Mmu;
 
 use core::marker::PhantomData;
 use core::ops::{Index, IndexMut};
@@ -28,6 +29,15 @@ pub const PML4_PTR: *mut Table<PML4Level> = PML4_VADDR as *mut _;
 /// Mask to apply to a page table entry to isolate the flags
 pub const ENTRY_FLAGS_MASK: u64 = (PAGE_SIZE as u64 - 1) as u64;
 
+/// Mask to apply to a page table entry to isolate the physical address
+///
+/// This excludes the low flag bits as well as bits 52-63, which hold the
+/// no-execute bit and bits reserved for software use.
+pub const ENTRY_ADDR_MASK: u64 = 0x000fffff_fffff000;
+
+/// Index of the recursive entry in the PML4 table
+pub const RECURSIVE_INDEX: usize = N_ENTRIES - 1;
+
 /// A page table
 #[repr(C)]
 pub struct Table<L>
@@ -130,18 +140,21 @@ impl Sublevel for PDLevel {
 
 impl Table<PML4Level> {
     #[inline]
-    pub fn page_table_for(&self, page: VirtualPage) -> Option<&Table<PTLevel>> {
-        self.next_table(page)
-            .and_then(|pdpt| pdpt.next_table(page))
-            .and_then(|pd| pd.next_table(page))
+    pub fn page_table_for<M>(&self, page: VirtualPage, mmu: &M)
+                            -> Option<&Table<PTLevel>>
+    where M: Mmu {
+        self.next_table(page, mmu)
+            .and_then(|pdpt| pdpt.next_table(page, mmu))
+            .and_then(|pd| pd.next_table(page, mmu))
     }
 
     #[inline]
-    pub fn page_table_mut_for(&mut self, page: VirtualPage)
-                             -> Option<&mut Table<PTLevel>> {
-        self.next_table_mut(page)
-            .and_then(|pdpt| pdpt.next_table_mut(page))
-            .and_then(|pd| pd.next_table_mut(page))
+    pub fn page_table_mut_for<M>(&mut self, page: VirtualPage, mmu: &M)
+                                -> Option<&mut Table<PTLevel>>
+    where M: Mmu {
+        self.next_table_mut(page, mmu)
+            .and_then(|pdpt| pdpt.next_table_mut(page, mmu))
+            .and_then(|pd| pd.next_table_mut(page, mmu))
     }
 }
 
@@ -192,13 +205,13 @@ impl<L: TableLevel> Table<L>  {
 impl<L: Sublevel> Table<L> {
 
 
-    /// Returns the address of the next table, or None if none exists.
+    /// Returns a pointer to the next table, or None if none exists.
     #[inline]
-    fn next_table_addr(&self, i: usize) -> Option<VAddr> {
+    fn next_table_ptr<M: Mmu>(&self, i: usize, mmu: &M)
+                             -> Option<*mut Table<L::Next>> {
         let flags = self[i].flags();
         if flags.contains(PRESENT) && !flags.contains(HUGE_PAGE) {
-            let table_addr = self as *const _ as usize;
-            Some(VAddr::from(table_addr << 9) | (i << 12))
+            Some(mmu.next_table(self, i))
         } else {
             None
         }
@@ -206,29 +219,33 @@ impl<L: Sublevel> Table<L> {
 
     /// Returns the next table, or `None` if none exists
     #[inline]
-    pub fn next_table<I>(&self, i: I) -> Option<&Table<L::Next>>
-    where L: IndexOf<I> {
-        self.next_table_addr(L::index_of(i))
-            .map(|table_addr| unsafe { &*(table_addr.as_ptr()) })
+    pub fn next_table<I, M>(&self, i: I, mmu: &M) -> Option<&Table<L::Next>>
+    where L: IndexOf<I>
+        , M: Mmu {
+        self.next_table_ptr(L::index_of(i), mmu)
+            .map(|table| unsafe { &*table })
     }
 
     /// Mutably borrows the next table.
     #[inline]
-    pub fn next_table_mut<I>(&self, i: I) -> Option<& mut Table<L::Next>>
+    pub fn next_table_mut<I, M>(&mut self, i: I, mmu: &M)
+                               -> Option<&mut Table<L::Next>>
     where L: IndexOf<I>
-        , I: fmt::Debug {
+        , I: fmt::Debug
+        , M: Mmu {
         trace!("{:?}, {:?}", self, i);
-        self.next_table_addr(L::index_of(i))
-            .map(|table_addr| unsafe { &mut *(table_addr.as_mut_ptr()) })
+        self.next_table_ptr(L::index_of(i), mmu)
+            .map(|table| unsafe { &mut *table })
     }
 
 
     /// Returns the next table, creating it if it does not exist.
-    pub fn create_next<A>(&mut self, i: VirtualPage, alloc: &mut A)
-                         -> &mut Table<L::Next>
-    where A: FrameAllocator {
+    pub fn create_next<A, M>(&mut self, i: VirtualPage, alloc: &mut A, mmu: &M)
+                            -> &mut Table<L::Next>
+    where A: FrameAllocator
+        , M: Mmu {
         //println!("in create_next");
-        if self.next_table(i).is_none() {
+        if self.next_table(i, mmu).is_none() {
             assert!( !self[i].is_huge()
                    , "Couldn't create next table: huge pages not \
                       currently supported.");
@@ -243,10 +260,10 @@ impl<L: Sublevel> Table<L> {
 
             self[i].set(frame, PRESENT | WRITABLE);
             //println!("setted.");
-            self.next_table_mut(i).unwrap().zero();
+            self.next_table_mut(i, mmu).unwrap().zero();
             trace!("zeroed");
         }
-        self.next_table_mut(i).unwrap()
+        self.next_table_mut(i, mmu).unwrap()
     }
 }
 
@@ -359,7 +376,7 @@ impl Entry {
     /// Returns the physical address pointed to by this page table entry
     #[inline]
     pub fn get_addr(&self) -> PAddr {
-        PAddr::from(self.0 & PML4_VADDR)
+        PAddr::from(self.0 & ENTRY_ADDR_MASK)
     }
 
     /// Returns the frame in memory pointed to by this page table entry.
@@ -374,7 +391,7 @@ impl Entry {
 
     pub fn set(&mut self, frame: PhysicalPage, flags: EntryFlags) {
         let addr: u64 = frame.base_addr().into();
-        assert!(addr & !0x000fffff_fffff000 == 0);
+        assert!(addr & !ENTRY_ADDR_MASK == 0);
         self.0 = addr | flags.bits();
     }
 
@@ -4,6 +4,7 @@ use alloc::{AllocResult, AllocErr, Layout, FrameAllocator};
 use core::ops;
 
 use super::ActivePageTable;
+use super::mmu::Mmu;
 use super::table::{Table, PTLevel};
 use ::Mapper;
 
@@ -46,32 +47,38 @@ impl TempPage {
     ///
     /// # Returns
     /// + The `VAddr` of the mapped page.
-    pub fn map_to( &mut self
-                 , frame: PhysicalPage
-                 , table: &mut ActivePageTable)
-                 -> VAddr {
+    pub fn map_to<M>( &mut self
+                    , frame: PhysicalPage
+                    , table: &mut ActivePageTable<M>)
+                    -> VAddr
+    where M: Mmu {
         //assert!( !table.is_mapped(self)
                 //, "Cannot map {:?}, as it is already mapped", self);
-        use super::table::WRITABLE;
+        use super::table::{WRITABLE, NO_EXECUTE};
         trace!(" . . TempPage::map_to({:?})", frame);
-        table.map(self.page, frame, WRITABLE, &mut self.frames);
+        table.map(self.page, frame, WRITABLE | NO_EXECUTE, &mut self.frames);
         self.page.base()
     }
 
-    pub fn map_to_table( &mut self
-                       , frame: PhysicalPage
-                       , table: &mut ActivePageTable)
-                       -> &mut Table<PTLevel> {
-       unsafe {
-           &mut *(self.map_to(frame, table).as_mut_ptr::<Table<PTLevel>>())
-       }
-   }
+    pub fn map_to_table<M>( &mut self
+                          , frame: PhysicalPage
+                          , table: &mut ActivePageTable<M>)
+                          -> &mut Table<PTLevel>
+    where M: Mmu {
+        let vaddr = self.map_to(frame, table);
+        unsafe { &mut *(table.mmu().ptr(vaddr) as *mut Table<PTLevel>) }
+    }
 
-    pub fn unmap(&mut self, table: &mut ActivePageTable) {
+    /// Unmap the `TempPage`.
+    ///
+    /// The frame it was mapped to is not deallocated, as it still belongs to
+    /// whoever asked for it to be mapped.
+    pub fn unmap<M>(&mut self, table: &mut ActivePageTable<M>)
+    where M: Mmu {
         trace!("unmapping temp page {:?}", self);
         // assert!( table.is_mapped(self)
         //         , "Cannot unmap {:?}, as it is not mapped", self);
-        table.unmap(self.page, &mut self.frames);
+        table.unmap_entry(self.page);
         trace!("temp page unmapped");
     }
 }
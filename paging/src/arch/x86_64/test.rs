@@ -0,0 +1,646 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Tests for the page tables, run against a `SoftMmu` on the host.
+use super::*;
+use super::mmu::{PhysicalMemory, SoftMmu};
+use super::walk::{Mapping, PageSize};
+
+use alloc::{AllocResult, AllocErr, Layout};
+use elf::section::{HeaderRepr, Sections};
+use memory::FrameRange;
+
+use std::vec::Vec;
+
+/// Number of the first frame of fake physical memory (at 1MiB).
+const FIRST_FRAME: u64 = 0x100;
+/// Number of frames of fake physical memory.
+const N_FRAMES: usize = 256;
+
+/// A virtual address in the second PDPT entry of the first PML4 entry.
+const ADDR: usize = 0x4000_0000;
+
+#[repr(C)]
+struct Frame([u64; N_ENTRIES]);
+
+/// Fake physical memory, backed by a buffer on the host.
+struct FakeMemory { _frames: Vec<Frame>
+                  , base: *mut Frame
+                  }
+
+impl FakeMemory {
+    fn new() -> Self {
+        let mut frames: Vec<Frame>
+            = (0..N_FRAMES).map(|_| Frame([0; N_ENTRIES])).collect();
+        let base = frames.as_mut_ptr();
+        FakeMemory { _frames: frames, base: base }
+    }
+}
+
+impl PhysicalMemory for FakeMemory {
+    fn frame_ptr(&self, frame: PhysicalPage) -> *mut u8 {
+        assert!( frame.number >= FIRST_FRAME &&
+                 frame.number < FIRST_FRAME + N_FRAMES as u64
+               , "{:?} is not in fake physical memory", frame);
+        let i = (frame.number - FIRST_FRAME) as isize;
+        unsafe { self.base.offset(i) as *mut u8 }
+    }
+}
+
+/// A frame allocator handing out the frames of fake physical memory, in
+/// order, so that ranges of frames are contiguous.
+///
+/// Deallocated frames are recorded, but never handed out again.
+struct FakeFrames { next: u64
+                  , freed: Vec<PhysicalPage>
+                  }
+
+impl FakeFrames {
+    fn new() -> Self { FakeFrames { next: FIRST_FRAME, freed: Vec::new() } }
+
+    /// Returns the number of frames allocated so far.
+    fn allocated(&self) -> usize { (self.next - FIRST_FRAME) as usize }
+}
+
+impl FrameAllocator for FakeFrames {
+    unsafe fn allocate(&mut self) -> AllocResult<PhysicalPage> {
+        self.allocate_range(1).map(|range| range.start)
+    }
+
+    unsafe fn deallocate(&mut self, frame: PhysicalPage) {
+        self.freed.push(frame);
+    }
+
+    unsafe fn allocate_range(&mut self, num: usize)
+                            -> AllocResult<FrameRange> {
+        if num <= N_FRAMES - self.allocated() {
+            let start = self.next;
+            self.next += num as u64;
+            Ok(frame(start) .. frame(self.next))
+        } else {
+            Err(AllocErr::Exhausted {
+                request: Layout::from_size_align( num * PAGE_SIZE as usize
+                                                , PAGE_SIZE as usize)
+            })
+        }
+    }
+
+    unsafe fn deallocate_range(&mut self, range: FrameRange) {
+        for frame in range {
+            self.deallocate(frame);
+        }
+    }
+}
+
+type TestTable = ActivePageTable<SoftMmu<FakeMemory>>;
+
+/// Returns an active page table containing only the recursive entry.
+fn boot() -> (TestTable, FakeFrames) {
+    let mut frames = FakeFrames::new();
+    let pml4_frame = unsafe { frames.allocate().unwrap() };
+    let memory = FakeMemory::new();
+    unsafe {
+        let pml4 = &mut *(memory.frame_ptr(pml4_frame)
+                            as *mut Table<PML4Level>);
+        pml4[RECURSIVE_INDEX].set(pml4_frame, PRESENT | WRITABLE);
+        let table = ActivePageTable::with_mmu(SoftMmu::new(memory, pml4_frame));
+        (table, frames)
+    }
+}
+
+#[inline]
+fn frame(number: u64) -> PhysicalPage { PhysicalPage { number: number } }
+
+#[inline]
+fn page_at(addr: usize) -> VirtualPage {
+    VirtualPage::containing(VAddr::from(addr))
+}
+
+#[inline]
+fn pml4_vaddr() -> VAddr { VAddr::from(PML4_VADDR as usize) }
+
+// -- recursive mapping --------------------------------------------------------
+
+#[test]
+fn test_recursive_entry_maps_pml4() {
+    let (table, _) = boot();
+    let pml4_frame = table.mmu().current_frame();
+    assert_eq!( table.mmu().translate(pml4_vaddr())
+              , Some(pml4_frame.base_addr()));
+    assert_eq!( table.mmu().pml4() as *mut u8
+              , table.mmu().memory().frame_ptr(pml4_frame));
+}
+
+#[test]
+fn test_child_tables_are_reachable_through_recursive_addresses() {
+    let (mut table, mut frames) = boot();
+    let page = page_at(ADDR);
+    table.map(page, frame(0x5000), WRITABLE, &mut frames);
+
+    let (pml4, mmu) = table.pml4_and_mmu();
+    let pdpt_frame = pml4[page].get_frame().unwrap();
+    let pdpt = pml4.next_table(page, mmu).unwrap();
+    let pd_frame = pdpt[page].get_frame().unwrap();
+    let pd = pdpt.next_table(page, mmu).unwrap();
+    let pt_frame = pd[page].get_frame().unwrap();
+
+    // these are the addresses the hardware would use to reach each table
+    // through the recursive entry.
+    let pdpt_vaddr = 0xffff_ffff_ffe0_0000 | ((ADDR >> 27) & 0x001f_f000);
+    let pd_vaddr = 0xffff_ffff_c000_0000 | ((ADDR >> 18) & 0x3fff_f000);
+    let pt_vaddr = 0xffff_ff80_0000_0000 | ((ADDR >> 9) & 0x7f_ffff_f000);
+
+    assert_eq!( mmu.translate(VAddr::from(pdpt_vaddr))
+              , Some(pdpt_frame.base_addr()));
+    assert_eq!( mmu.translate(VAddr::from(pd_vaddr))
+              , Some(pd_frame.base_addr()));
+    assert_eq!( mmu.translate(VAddr::from(pt_vaddr))
+              , Some(pt_frame.base_addr()));
+}
+
+#[test]
+fn test_new_tables_are_zeroed_and_writable() {
+    let (mut table, mut frames) = boot();
+    let page = page_at(ADDR);
+    table.map(page, frame(0x5000), EntryFlags::empty(), &mut frames);
+
+    let (pml4, mmu) = table.pml4_and_mmu();
+    assert_eq!(pml4[page].flags(), PRESENT | WRITABLE);
+    let pdpt = pml4.next_table(page, mmu).unwrap();
+    assert_eq!(pdpt[page].flags(), PRESENT | WRITABLE);
+    let pd = pdpt.next_table(page, mmu).unwrap();
+    let pt = pd.next_table(page, mmu).unwrap();
+    // only the entry for `page` is in use
+    for i in 0..N_ENTRIES {
+        assert_eq!(pt[i].is_unused(), i != PTLevel::index_of(page));
+    }
+}
+
+// -- map, translate and unmap -------------------------------------------------
+
+#[test]
+fn test_unmapped_page_does_not_translate() {
+    let (table, _) = boot();
+    assert_eq!(table.translate(VAddr::from(ADDR)), None);
+    assert_eq!(table.mmu().translate(VAddr::from(ADDR)), None);
+    assert!(!table.is_mapped(&page_at(ADDR)));
+}
+
+#[test]
+fn test_map_translates_with_offset() {
+    let (mut table, mut frames) = boot();
+    table.map(page_at(ADDR), frame(0x5000), WRITABLE, &mut frames);
+
+    let vaddr = VAddr::from(ADDR + 0x123);
+    let expected = frame(0x5000).base_addr() + 0x123;
+    assert_eq!(table.translate(vaddr), Some(expected));
+    assert_eq!(table.mmu().translate(vaddr), Some(expected));
+    assert_eq!(table.translate_page(page_at(ADDR)), Some(frame(0x5000)));
+}
+
+#[test]
+fn test_map_higher_half_page() {
+    let (mut table, mut frames) = boot();
+    let addr = 0xffff_8000_0020_0000;
+    table.map(page_at(addr), frame(0x5000), WRITABLE, &mut frames);
+    assert_eq!( table.mmu().translate(VAddr::from(addr + 8))
+              , Some(frame(0x5000).base_addr() + 8));
+}
+
+#[test]
+fn test_identity_map_translates_to_itself() {
+    let (mut table, mut frames) = boot();
+    table.identity_map(frame(0xb8), WRITABLE, &mut frames);
+    assert_eq!( table.translate(VAddr::from(0xb8000))
+              , Some(PAddr::from(0xb8000)));
+}
+
+#[test]
+fn test_map_allocates_intermediate_tables_once() {
+    let (mut table, mut frames) = boot();
+    let page = page_at(ADDR);
+    let before = frames.allocated();
+
+    table.map(page, frame(0x5000), WRITABLE, &mut frames);
+    assert_eq!(frames.allocated() - before, 3);
+
+    table.map(page + 1, frame(0x5001), WRITABLE, &mut frames);
+    assert_eq!(frames.allocated() - before, 3);
+}
+
+#[test]
+#[should_panic]
+fn test_map_twice_panics() {
+    let (mut table, mut frames) = boot();
+    table.map(page_at(ADDR), frame(0x5000), WRITABLE, &mut frames);
+    table.map(page_at(ADDR), frame(0x5001), WRITABLE, &mut frames);
+}
+
+#[test]
+fn test_unmap_clears_mapping_and_frees_frame() {
+    let (mut table, mut frames) = boot();
+    let page = page_at(ADDR);
+    table.map(page, frame(0x5000), WRITABLE, &mut frames);
+    table.unmap(page, &mut frames);
+
+    assert_eq!(table.translate_page(page), None);
+    assert_eq!(table.mmu().translate(VAddr::from(ADDR)), None);
+    assert_eq!(frames.freed, vec![frame(0x5000)]);
+}
+
+#[test]
+fn test_unmap_entry_does_not_free_frame() {
+    let (mut table, mut frames) = boot();
+    let page = page_at(ADDR);
+    table.map(page, frame(0x5000), WRITABLE, &mut frames);
+
+    assert_eq!(table.unmap_entry(page), frame(0x5000));
+    assert_eq!(table.translate_page(page), None);
+    assert!(frames.freed.is_empty());
+}
+
+#[test]
+#[should_panic]
+fn test_unmap_unmapped_page_panics() {
+    let (mut table, mut frames) = boot();
+    table.unmap(page_at(ADDR), &mut frames);
+}
+
+#[test]
+fn test_set_present_keeps_frame() {
+    let (mut table, mut frames) = boot();
+    let page = page_at(ADDR);
+    table.map(page, frame(0x5000), WRITABLE, &mut frames);
+
+    assert!(table.set_present(page, false).is_ok());
+    assert_eq!(table.translate_page(page), None);
+    assert!(frames.freed.is_empty());
+
+    assert!(table.set_present(page, true).is_ok());
+    assert_eq!(table.translate_page(page), Some(frame(0x5000)));
+}
+
+#[test]
+fn test_set_present_unmapped_page_fails() {
+    let (mut table, mut frames) = boot();
+    table.map(page_at(ADDR), frame(0x5000), WRITABLE, &mut frames);
+    let next = page_at(ADDR + PAGE_SIZE as usize);
+    assert!(table.set_present(next, true).is_err());
+    assert!(table.set_present(page_at(0x10_0000_0000), true).is_err());
+}
+
+// -- huge pages ---------------------------------------------------------------
+
+/// Map a 2MiB huge page at `ADDR` to the frames starting at `start`.
+fn map_2m(table: &mut TestTable, frames: &mut FakeFrames, start: u64) {
+    let page = page_at(ADDR);
+    let (pml4, mmu) = table.pml4_and_mmu();
+    let pd = pml4.create_next(page, frames, mmu)
+                 .create_next(page, frames, mmu);
+    pd[page].set(frame(start), PRESENT | WRITABLE | HUGE_PAGE);
+}
+
+/// Map a 1GiB huge page at `ADDR` to the frames starting at `start`.
+fn map_1g(table: &mut TestTable, frames: &mut FakeFrames, start: u64) {
+    let page = page_at(ADDR);
+    let (pml4, mmu) = table.pml4_and_mmu();
+    let pdpt = pml4.create_next(page, frames, mmu);
+    pdpt[page].set(frame(start), PRESENT | WRITABLE | HUGE_PAGE);
+}
+
+#[test]
+fn test_translate_2m_huge_page() {
+    let (mut table, mut frames) = boot();
+    map_2m(&mut table, &mut frames, 0x4_0000);
+
+    let page = page_at(ADDR) + 7;
+    assert_eq!(table.translate_page(page), Some(frame(0x4_0007)));
+
+    let vaddr = VAddr::from(ADDR + 7 * PAGE_SIZE as usize + 0x42);
+    let expected = frame(0x4_0007).base_addr() + 0x42;
+    assert_eq!(table.translate(vaddr), Some(expected));
+    assert_eq!(table.mmu().translate(vaddr), Some(expected));
+}
+
+#[test]
+fn test_translate_1g_huge_page() {
+    let (mut table, mut frames) = boot();
+    map_1g(&mut table, &mut frames, 0x8_0000);
+
+    let offset = 3 * N_ENTRIES + 5;
+    let page = page_at(ADDR) + offset;
+    assert_eq!( table.translate_page(page)
+              , Some(frame(0x8_0000 + offset as u64)));
+
+    let vaddr = page.base() + 0x42;
+    let expected = frame(0x8_0000 + offset as u64).base_addr() + 0x42;
+    assert_eq!(table.translate(vaddr), Some(expected));
+    assert_eq!(table.mmu().translate(vaddr), Some(expected));
+}
+
+#[test]
+fn test_huge_page_has_no_next_table() {
+    let (mut table, mut frames) = boot();
+    map_2m(&mut table, &mut frames, 0x4_0000);
+    let page = page_at(ADDR);
+    let (pml4, mmu) = table.pml4_and_mmu();
+    let pd = pml4.next_table(page, mmu)
+                 .and_then(|pdpt| pdpt.next_table(page, mmu))
+                 .unwrap();
+    assert!(pd[page].is_huge());
+    assert!(pd.next_table(page, mmu).is_none());
+}
+
+#[test]
+fn test_set_present_inside_huge_page_fails() {
+    let (mut table, mut frames) = boot();
+    map_2m(&mut table, &mut frames, 0x4_0000);
+    assert!(table.set_present(page_at(ADDR) + 1, false).is_err());
+    assert_eq!(table.translate_page(page_at(ADDR) + 1), Some(frame(0x4_0001)));
+}
+
+#[test]
+#[should_panic]
+fn test_map_inside_huge_page_panics() {
+    let (mut table, mut frames) = boot();
+    map_2m(&mut table, &mut frames, 0x4_0000);
+    table.map(page_at(ADDR) + 1, frame(0x5000), WRITABLE, &mut frames);
+}
+
+// -- walking ------------------------------------------------------------------
+
+#[test]
+fn test_mappings_coalesce_contiguous_pages() {
+    let (mut table, mut frames) = boot();
+    let base = page_at(ADDR);
+    for i in 0..4 {
+        table.map( base + i, frame(0x5000 + i as u64)
+                 , WRITABLE | NO_EXECUTE, &mut frames);
+    }
+    table.map(base + 10, frame(0x6000), NO_EXECUTE, &mut frames);
+
+    let mappings: Vec<Mapping> = table.mappings().collect();
+    assert_eq!(mappings.len(), 2);
+
+    assert_eq!(mappings[0].pages, base .. base + 4);
+    assert_eq!(mappings[0].frames, frame(0x5000) .. frame(0x5004));
+    assert_eq!(mappings[0].size, PageSize::Size4K);
+    assert!(mappings[0].is_writable());
+    assert!(!mappings[0].is_executable());
+
+    assert_eq!(mappings[1].pages, base + 10 .. base + 11);
+    assert!(!mappings[1].is_writable());
+}
+
+#[test]
+fn test_mappings_split_on_discontiguous_frames() {
+    let (mut table, mut frames) = boot();
+    let base = page_at(ADDR);
+    table.map(base, frame(0x5000), NO_EXECUTE, &mut frames);
+    table.map(base + 1, frame(0x5002), NO_EXECUTE, &mut frames);
+    assert_eq!(table.mappings().count(), 2);
+}
+
+#[test]
+fn test_mappings_report_huge_pages() {
+    let (mut table, mut frames) = boot();
+    map_2m(&mut table, &mut frames, 0x4_0000);
+
+    let mappings: Vec<Mapping> = table.mappings().collect();
+    assert_eq!(mappings.len(), 1);
+    assert_eq!(mappings[0].size, PageSize::Size2M);
+    assert_eq!( mappings[0].pages
+              , page_at(ADDR) .. page_at(ADDR) + N_ENTRIES);
+}
+
+#[test]
+fn test_mappings_use_effective_flags() {
+    let (mut table, mut frames) = boot();
+    // intermediate tables are never user accessible, so neither is the page
+    table.map( page_at(ADDR), frame(0x5000)
+             , USER_ACCESSIBLE | NO_EXECUTE, &mut frames);
+    let mapping = table.mappings().next().unwrap();
+    assert!(!mapping.is_user());
+}
+
+#[test]
+fn test_check_wx_finds_writable_executable_mappings() {
+    let (mut table, mut frames) = boot();
+    let base = page_at(ADDR);
+    table.map(base, frame(0x5000), WRITABLE | NO_EXECUTE, &mut frames);
+    assert!(table.check_wx().is_ok());
+
+    table.map(base + 1, frame(0x6000), WRITABLE, &mut frames);
+    match table.check_wx() {
+        Err(mapping) => assert_eq!(mapping.pages, base + 1 .. base + 2)
+      , Ok(()) => panic!("W+X mapping was not detected")
+    }
+}
+
+// -- inactive tables ----------------------------------------------------------
+
+#[test]
+fn test_using_maps_into_inactive_table() {
+    let (mut table, mut frames) = boot();
+    let boot_frame = table.mmu().current_frame();
+    let mut temp = TempPage::new(0xfacade, &mut frames);
+    let new_frame = unsafe { frames.allocate().unwrap() };
+    let mut inactive = InactivePageTable::new(new_frame, &mut table, &mut temp);
+    let page = page_at(ADDR);
+
+    table.using(&mut inactive, &mut temp, |pml4| {
+        pml4.map(page, frame(0x5000), WRITABLE, &mut frames)
+    });
+
+    // the mapping is not in the active table, and the recursive entry was
+    // pointed back at the active table.
+    assert_eq!(table.translate_page(page), None);
+    assert_eq!( table.mmu().translate(pml4_vaddr())
+              , Some(boot_frame.base_addr()));
+    assert!(!table.is_mapped(&*temp));
+
+    let old = table.replace_with(inactive);
+    assert_eq!(old.pml4_frame(), boot_frame);
+    assert_eq!(table.mmu().current_frame(), new_frame);
+    assert_eq!(table.translate_page(page), Some(frame(0x5000)));
+    assert_eq!( table.mmu().translate(pml4_vaddr())
+              , Some(new_frame.base_addr()));
+}
+
+#[test]
+fn test_temp_page_unmap_does_not_free_frame() {
+    let (mut table, mut frames) = boot();
+    let mut temp = TempPage::new(0xfacade, &mut frames);
+    let new_frame = unsafe { frames.allocate().unwrap() };
+    let _ = InactivePageTable::new(new_frame, &mut table, &mut temp);
+    assert!(!table.is_mapped(&*temp));
+    assert!(frames.freed.is_empty());
+}
+
+// -- kernel remapping ---------------------------------------------------------
+
+/// Expands to a raw ELF64 section header.
+macro_rules! section {
+    ($flags:expr, $address:expr, $length:expr) => {
+        // name offset 0, type SHT_PROGBITS
+        [1 << 32, $flags, $address, 0, $length, 0, 0x1000, 0]
+    }
+}
+
+/// Expands to a raw ELF64 section header for a table that isn't loaded.
+macro_rules! table_section {
+    ($ty:expr, $address:expr, $length:expr) => {
+        [$ty << 32, 0, $address, 0, $length, 0, 8, 0]
+    }
+}
+
+const SHT_SYMTAB: u64 = 2;
+const SHT_STRTAB: u64 = 3;
+
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+
+static KERNEL_SECTIONS: [[u64; 8]; 3]
+    = [ section!(SHF_ALLOC | SHF_EXECINSTR, 0x30_0000, 0x3000)  // .text
+      , section!(SHF_ALLOC, 0x30_3000, 0x1000)                  // .rodata
+        // .data, which holds the boot PML4
+      , section!(SHF_ALLOC | SHF_WRITE, FIRST_FRAME << 12, 0x1000)
+      ];
+
+static SYMBOL_SECTIONS: [[u64; 8]; 3]
+    = [ section!(SHF_ALLOC | SHF_EXECINSTR, 0x30_0000, 0x1000)  // .text
+        // .symtab and .strtab, sharing a page
+      , table_section!(SHT_SYMTAB, 0x50_0000, 0x1800)
+      , table_section!(SHT_STRTAB, 0x50_1800, 0x900)
+      ];
+
+static WX_SECTIONS: [[u64; 8]; 1]
+    = [ section!(SHF_ALLOC | SHF_WRITE | SHF_EXECINSTR, 0x30_0000, 0x1000) ];
+
+fn params(sections: &'static [[u64; 8]]) -> InitParams {
+    let first = unsafe { &*(sections.as_ptr() as *const HeaderRepr<u64>) };
+    InitParams { multiboot_start: Some(PAddr::from(0x40_0000))
+               , multiboot_end: Some(PAddr::from(0x40_1000))
+               , elf_sections: Some(Sections::new( first
+                                                 , sections.len() as u32
+                                                 , 64))
+               , ..Default::default()
+               }
+}
+
+#[test]
+fn test_kernel_remap_switches_to_new_table() {
+    let (table, mut frames) = boot();
+    let boot_frame = table.mmu().current_frame();
+    let table = kernel_remap_with(table, &params(&KERNEL_SECTIONS), &mut frames)
+        .expect("kernel remap failed");
+
+    let current = table.mmu().current_frame();
+    assert!(current != boot_frame);
+    assert_eq!( table.mmu().translate(pml4_vaddr())
+              , Some(current.base_addr()));
+
+    // sections are identity mapped
+    assert_eq!(table.translate_page(page_at(0x30_2000)), Some(frame(0x302)));
+    assert_eq!(table.translate_page(page_at(0x30_3000)), Some(frame(0x303)));
+    // as are the VGA buffer and multiboot info
+    assert_eq!(table.translate_page(page_at(0xb_8000)), Some(frame(0xb8)));
+    assert_eq!(table.translate_page(page_at(0x40_0000)), Some(frame(0x400)));
+
+    // the old PML4 became a guard page, and its frame was freed
+    assert_eq!(table.translate_page(page_at(FIRST_FRAME as usize << 12)), None);
+    assert_eq!(frames.freed, vec![boot_frame]);
+}
+
+#[test]
+fn test_kernel_remap_uses_section_flags() {
+    let (table, mut frames) = boot();
+    let table = kernel_remap_with(table, &params(&KERNEL_SECTIONS), &mut frames)
+        .expect("kernel remap failed");
+
+    let find = |addr: usize| {
+        table.mappings()
+             .find(|m| m.pages.start <= page_at(addr) &&
+                       page_at(addr) < m.pages.end)
+             .expect("address is not mapped")
+    };
+    let text = find(0x30_0000);
+    assert!(text.is_executable() && !text.is_writable());
+    let rodata = find(0x30_3000);
+    assert!(!rodata.is_executable() && !rodata.is_writable());
+    let vga = find(0xb_8000);
+    assert!(!vga.is_executable() && vga.is_writable());
+
+    assert!(table.check_wx().is_ok());
+}
+
+#[test]
+fn test_kernel_remap_refuses_wx_sections() {
+    let (table, mut frames) = boot();
+    assert!(kernel_remap_with(table, &params(&WX_SECTIONS), &mut frames)
+                .is_err());
+}
+
+#[test]
+fn test_kernel_remap_maps_symbol_tables() {
+    let (table, mut frames) = boot();
+    let table = kernel_remap_with(table, &params(&SYMBOL_SECTIONS), &mut frames)
+        .expect("kernel remap failed");
+
+    for &addr in [0x50_0000, 0x50_1000, 0x50_2000].iter() {
+        assert_eq!( table.translate_page(page_at(addr))
+                  , Some(frame((addr >> 12) as u64)));
+        let mapping = table.mappings()
+                           .find(|m| m.pages.start <= page_at(addr) &&
+                                     page_at(addr) < m.pages.end)
+                           .expect("address is not mapped");
+        assert!(!mapping.is_executable() && !mapping.is_writable());
+    }
+    assert_eq!(table.translate_page(page_at(0x50_3000)), None);
+}
+
+// -- PCIDs --------------------------------------------------------------------
+
+#[test]
+fn test_pcid_set_reuses_freed_pcids() {
+    use super::pcid::{Pcid, PcidSet};
+    let mut set = PcidSet::new();
+    let first = set.take().unwrap();
+    let second = set.take().unwrap();
+    assert!(first != Pcid::BOOT && second != first);
+    set.give_back(first);
+    assert!(!set.contains(first));
+    assert_eq!(set.take(), Some(first));
+}
+
+#[test]
+fn test_pcid_set_never_hands_out_shared_pcid() {
+    use super::pcid::{Pcid, PcidSet};
+    let mut set = PcidSet::new();
+    let first = set.take().unwrap();
+    while let Some(pcid) = set.take() {
+        assert!(pcid != Pcid::BOOT && pcid != Pcid::SHARED);
+    }
+    assert!(set.contains(first) && !set.contains(Pcid::SHARED));
+    assert!(first.is_unique() && !Pcid::SHARED.is_unique());
+}
+
+#[test]
+fn test_flush_log_flushes_cpus_behind_on_freed_pcids() {
+    use super::pcid::FlushLog;
+    let mut log = FlushLog::new();
+    // CPUs flush the first time they're seen, and then only once more PCIDs
+    // have been freed.
+    assert!(log.catch_up(0, 1));
+    assert!(!log.catch_up(0, 1));
+    assert!(log.catch_up(1, 1));
+    assert!(log.catch_up(0, 2));
+    assert!(!log.catch_up(0, 2));
+    assert!(log.catch_up(1, 2));
+}
@@ -1,8 +1,12 @@
 use memory::VAddr;
 use super::{Page, VirtualPage};
+use super::pcid::{self, Pcid};
 
 /// Invalidate the TLB completely by reloading the CR3 register.
 ///
+/// If PCIDs are enabled, this only invalidates the entries tagged with the
+/// current PCID.
+///
 /// # Safety
 /// + Causes a general protection fault if not executed in kernel mode.
 pub unsafe fn flush_all() {
@@ -10,13 +14,73 @@ pub unsafe fn flush_all() {
     cr3::write(cr3::read());
 }
 
+/// Invalidate every TLB entry, for every PCID, including global entries.
+///
+/// # Safety
+/// + Causes a general protection fault if not executed in kernel mode.
+pub unsafe fn flush_all_contexts() {
+    use cpu::control_regs::cr4;
+    if pcid::has_invpcid() {
+        invpcid(Invalidate::AllContextsGlobal, Pcid::BOOT, 0)
+    } else {
+        // any write to %cr4 that changes PGE invalidates the entire TLB, so
+        // toggle it and then put it back.
+        let flags = cr4::read();
+        cr4::write(flags ^ cr4::PGE);
+        cr4::write(flags);
+    }
+}
+
+/// The kinds of invalidation performed by `invpcid`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u64)]
+pub enum Invalidate { /// A single address, tagged with a given PCID
+                      Address = 0
+                    , /// Every non-global entry tagged with a given PCID
+                      SingleContext = 1
+                    , /// Every entry, including global entries
+                      AllContextsGlobal = 2
+                    , /// Every non-global entry
+                      AllContexts = 3
+                    }
+
+/// Invalidate TLB entries using the `invpcid` instruction.
+///
+/// # Arguments
+/// + `kind`: what to invalidate
+/// + `pcid`: the PCID to invalidate entries for, if `kind` requires one
+/// + `addr`: the address to invalidate, if `kind` is `Address`
+///
+/// # Safety
+/// + Causes a general protection fault if not executed in kernel mode.
+/// + Causes an invalid opcode exception if `invpcid` is not supported.
+pub unsafe fn invpcid(kind: Invalidate, pcid: Pcid, addr: u64) {
+    let descriptor: [u64; 2] = [pcid.bits(), addr];
+    asm!( "invpcid $0, [$1]"
+        :
+        : "r" (kind as u64), "r" (&descriptor)
+        : "memory"
+        : "intel", "volatile" );
+}
+
 /// Something which may be flushed from the TLB
-pub trait Flush {
+pub trait Flush: Sized {
     /// Invalidate this object in the TLB using the `invlpg` instruction.
     ///
     /// # Safety
     /// + Causes a general protection fault if not executed in kernel mode.
     unsafe fn invlpg(self);
+
+    /// Invalidate this object in the TLB for the address space with the
+    /// given PCID.
+    ///
+    /// `invlpg` only affects the current PCID, so if `pcid` is not current,
+    /// this uses `invpcid` if available, or else flushes that whole address
+    /// space.
+    ///
+    /// # Safety
+    /// + Causes a general protection fault if not executed in kernel mode.
+    unsafe fn flush_in(self, pcid: Pcid);
     //
     // /// Invalidate this object in the TLB instruction if we are in kernel mode.
     // ///
@@ -46,6 +110,16 @@ impl Flush for VAddr {
              : "memory"
              : "intel", "volatile" );
     }
+
+    unsafe fn flush_in(self, pcid: Pcid) {
+        if pcid == Pcid::current() {
+            self.invlpg()
+        } else if pcid::has_invpcid() {
+            invpcid(Invalidate::Address, pcid, *self as u64)
+        } else {
+            pcid.flush()
+        }
+    }
 }
 
 impl Flush for VirtualPage {
@@ -53,4 +127,9 @@ impl Flush for VirtualPage {
     unsafe fn invlpg(self) {
         self.base().invlpg()
     }
+
+    #[inline]
+    unsafe fn flush_in(self, pcid: Pcid) {
+        self.base().flush_in(pcid)
+    }
 }
@@ -0,0 +1,372 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Walking the page tables.
+//!
+//! This module provides an iterator over the mappings in the currently
+//! active page tables. Runs of contiguous entries with identical flags are
+//! coalesced into a single [`Mapping`], so that e.g. a kernel section mapped
+//! with hundreds of 4KiB pages is reported as one line.
+//!
+//! The [`Dump`] formatter prints the mappings in a table similar to
+//! `/proc/pid/maps` on Linux, which is considerably more pleasant to read
+//! than a pile of hand-written `trace!` calls when chasing mapping bugs.
+//!
+//! [`Mapping`]: struct.Mapping.html
+//! [`Dump`]: struct.Dump.html
+use memory::{PAGE_SIZE, PAGE_SHIFT, FrameRange, PageRange, VirtualPage};
+
+use core::{fmt, iter};
+
+use super::{ActivePageTable, ActivePML4, InactivePageTable};
+use super::mmu::Mmu;
+use super::table::*;
+use super::temp::TempPage;
+
+/// Number of 4KiB pages addressable by a four-level page table.
+const N_PAGES: usize = 1 << 36;
+
+/// Bits to set in a page number in the higher half of the address space.
+///
+/// Virtual addresses must be canonical, so bits 48-63 are copies of bit 47.
+const HIGHER_HALF: usize = 0xffff_0000_0000_0000 >> PAGE_SHIFT;
+
+/// The size of the page mapped by a page table entry.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PageSize { /// A 4KiB page, mapped by a page table entry
+                    Size4K
+                  , /// A 2MiB page, mapped by a page directory entry
+                    Size2M
+                  , /// A 1GiB page, mapped by a page directory pointer entry
+                    Size1G
+                  }
+
+impl PageSize {
+    /// Returns the number of 4KiB pages in a page of this size.
+    #[inline]
+    pub fn n_pages(&self) -> usize {
+        match *self { PageSize::Size4K => 1
+                    , PageSize::Size2M => N_ENTRIES
+                    , PageSize::Size1G => N_ENTRIES * N_ENTRIES
+                    }
+    }
+
+    /// Returns the size of a page of this size, in bytes.
+    #[inline]
+    pub fn bytes(&self) -> u64 {
+        self.n_pages() as u64 * PAGE_SIZE
+    }
+}
+
+impl fmt::Display for PageSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match *self { PageSize::Size4K => "4K"
+                          , PageSize::Size2M => "2M"
+                          , PageSize::Size1G => "1G"
+                          })
+    }
+}
+
+/// A run of contiguous virtual pages mapped to contiguous frames.
+///
+/// Every page in a `Mapping` has the same size and the same effective flags.
+/// The flags are _effective_ flags: a page is only writable or user
+/// accessible if every table on the path to it is, and it is not
+/// executable if any table on the path to it sets `NO_EXECUTE`.
+#[derive(Clone, Debug)]
+pub struct Mapping { /// The virtual pages in this mapping
+                     pub pages: PageRange
+                   , /// The physical frames that the pages are mapped to
+                     pub frames: FrameRange
+                   , /// The effective flags of every page in this mapping
+                     pub flags: EntryFlags
+                   , /// The size of the page table entries in this mapping
+                     pub size: PageSize
+                   }
+
+impl Mapping {
+
+    /// Returns true if this mapping is writable.
+    #[inline] pub fn is_writable(&self) -> bool {
+        self.flags.contains(WRITABLE)
+    }
+
+    /// Returns true if this mapping is executable.
+    #[inline] pub fn is_executable(&self) -> bool {
+        !self.flags.contains(NO_EXECUTE)
+    }
+
+    /// Returns true if this mapping is accessible from user mode.
+    #[inline] pub fn is_user(&self) -> bool {
+        self.flags.contains(USER_ACCESSIBLE)
+    }
+
+    /// Returns true if this mapping is both writable and executable.
+    #[inline] pub fn is_wx(&self) -> bool {
+        self.is_writable() && self.is_executable()
+    }
+
+    /// Returns true if `next` directly follows this mapping and can be
+    /// merged into it.
+    fn continues_with(&self, next: &Mapping) -> bool {
+        self.pages.end == next.pages.start &&
+        self.frames.end == next.frames.start &&
+        self.size == next.size &&
+        self.flags & !ignored() == next.flags & !ignored()
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use memory::Page;
+        write!( f, "{:016x}-{:016x} r{}{}{}{} {:016x} {:>8} x {}"
+              , *self.pages.start.base()
+              , *self.pages.end.base()
+              , if self.is_writable() { 'w' } else { '-' }
+              , if self.is_executable() { 'x' } else { '-' }
+              , if self.is_user() { 'u' } else { '-' }
+              , if self.flags.contains(GLOBAL) { 'g' } else { '-' }
+              , *self.frames.start.base_addr()
+              , (self.pages.end.number - self.pages.start.number)
+                    / self.size.n_pages()
+              , self.size)
+    }
+}
+
+/// An iterator over the [`Mapping`]s in the active page tables.
+///
+/// Mappings are returned in order of ascending virtual address. The
+/// recursive PML4 entry is not walked.
+///
+/// [`Mapping`]: struct.Mapping.html
+pub struct Mappings<'a, M>
+where M: Mmu + 'a { pml4: &'a Table<PML4Level>
+                  , mmu: &'a M
+                  , /// Index of the next 4KiB page to examine
+                    next: usize
+                  , /// A leaf entry that didn't fit in the last run
+                    pending: Option<Mapping>
+                  }
+
+impl<'a, M: Mmu> Mappings<'a, M> {
+
+    fn new(pml4: &'a Table<PML4Level>, mmu: &'a M) -> Self {
+        Mappings { pml4: pml4, mmu: mmu, next: 0, pending: None }
+    }
+
+    /// Advance the cursor to the start of the next page of `size`
+    #[inline]
+    fn skip(&mut self, size: PageSize) {
+        let n = size.n_pages();
+        self.next = (self.next / n + 1) * n;
+    }
+
+    /// Returns a `Mapping` for the leaf entry at the cursor and advances
+    /// the cursor past it.
+    fn leaf(&mut self, entry: &Entry, parents: EntryFlags, size: PageSize)
+           -> Option<Mapping> {
+        let number = if self.next & (N_PAGES >> 1) != 0 {
+                self.next | HIGHER_HALF
+            } else {
+                self.next
+            };
+        let page = VirtualPage { number: number };
+        let n_pages = size.n_pages();
+        self.skip(size);
+        entry.get_frame().map(|frame|
+            Mapping { pages: page .. page + n_pages
+                    , frames: frame .. frame + n_pages
+                    , flags: effective_flags(parents, entry.flags())
+                    , size: size
+                    })
+    }
+
+    /// Returns the next individual leaf entry, without coalescing.
+    fn next_leaf(&mut self) -> Option<Mapping> {
+        while self.next < N_PAGES {
+            let i = self.next;
+            let (i4, i3, i2, i1) = ( index::<PML4Level>(i)
+                                   , index::<PDPTLevel>(i)
+                                   , index::<PDLevel>(i)
+                                   , index::<PTLevel>(i) );
+            if i4 == RECURSIVE_INDEX {
+                // the recursive entry is the last entry in the PML4, so we
+                // are done walking.
+                self.next = N_PAGES;
+                break;
+            }
+
+            let (pml4, mmu) = (self.pml4, self.mmu);
+            let pdpt = match pml4.next_table(i4, mmu) {
+                Some(table) => table
+              , None => { self.next = (i4 + 1) << PML4Level::PAGE_SHIFT_AMOUNT;
+                          continue }
+            };
+            let flags = inherit(inherited(), pml4[i4].flags());
+
+            let entry = &pdpt[i3];
+            if !entry.flags().is_present() {
+                self.skip(PageSize::Size1G);
+                continue
+            } else if entry.is_huge() {
+                return self.leaf(entry, flags, PageSize::Size1G)
+            }
+            let pd = pdpt.next_table(i3, mmu)
+                         .expect("present, non-huge PDPT entry has no table");
+            let flags = inherit(flags, entry.flags());
+
+            let entry = &pd[i2];
+            if !entry.flags().is_present() {
+                self.skip(PageSize::Size2M);
+                continue
+            } else if entry.is_huge() {
+                return self.leaf(entry, flags, PageSize::Size2M)
+            }
+            let pt = pd.next_table(i2, mmu)
+                       .expect("present, non-huge PD entry has no table");
+            let flags = inherit(flags, entry.flags());
+
+            let entry = &pt[i1];
+            if entry.flags().is_present() {
+                return self.leaf(entry, flags, PageSize::Size4K)
+            }
+            self.skip(PageSize::Size4K);
+        }
+        None
+    }
+}
+
+impl<'a, M: Mmu> Iterator for Mappings<'a, M> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        let mut run = match self.pending.take() {
+            Some(mapping) => mapping
+          , None => match self.next_leaf() {
+                Some(mapping) => mapping
+              , None => return None
+            }
+        };
+        while let Some(leaf) = self.next_leaf() {
+            if run.continues_with(&leaf) {
+                run.pages.end = leaf.pages.end;
+                run.frames.end = leaf.frames.end;
+            } else {
+                self.pending = Some(leaf);
+                break;
+            }
+        }
+        Some(run)
+    }
+}
+
+/// Returns the index into a table of level `L` for the 4KiB page number `i`.
+#[inline]
+fn index<L: TableLevel>(i: usize) -> usize {
+    (i >> L::PAGE_SHIFT_AMOUNT) & L::INDEX_MASK
+}
+
+/// Flags that are only in effect if they are set at every level of the
+/// page table hierarchy.
+#[inline]
+fn inherited() -> EntryFlags { WRITABLE | USER_ACCESSIBLE }
+
+/// Flags that are set by the CPU and don't make two mappings different.
+#[inline]
+fn ignored() -> EntryFlags { ACCESSED | DIRTY }
+
+/// Combine the flags of a parent table entry with those of its child.
+#[inline]
+fn inherit(parents: EntryFlags, entry: EntryFlags) -> EntryFlags {
+    (parents & entry & inherited()) | ((parents | entry) & NO_EXECUTE)
+}
+
+/// Returns the effective flags of a leaf entry under the given parents.
+#[inline]
+fn effective_flags(parents: EntryFlags, leaf: EntryFlags) -> EntryFlags {
+    (leaf & !inherited() & !NO_EXECUTE) | inherit(parents, leaf)
+}
+
+/// Formats the mappings in the active page tables as a table.
+///
+/// Each line has the form
+///
+/// ```text
+/// <start vaddr>-<end vaddr> <perms> <start paddr> <n entries> x <page size>
+/// ```
+///
+/// where `<perms>` is `r`, followed by `w`, `x`, `u` (user accessible) and
+/// `g` (global), or `-` in place of each flag that isn't set.
+pub struct Dump<'a, M>(&'a Table<PML4Level>, &'a M)
+where M: Mmu + 'a;
+
+impl<'a, M: Mmu> fmt::Display for Dump<'a, M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for mapping in Mappings::new(self.0, self.1) {
+            writeln!(f, "{}", mapping)?;
+        }
+        Ok(())
+    }
+}
+
+/// An iterator over the mappings that are both writable and executable.
+pub type WxViolations<'a, M>
+    = iter::Filter<Mappings<'a, M>, fn(&Mapping) -> bool>;
+
+impl<M: Mmu> ActivePML4<M> {
+
+    /// Returns an iterator over the mappings in this page table.
+    pub fn mappings(&self) -> Mappings<M> {
+        Mappings::new(self.pml4(), &self.mmu)
+    }
+
+    /// Returns a formatter that dumps the mappings in this page table.
+    #[inline]
+    pub fn dump(&self) -> Dump<M> {
+        Dump(self.pml4(), &self.mmu)
+    }
+
+    /// Returns an iterator over all writable and executable mappings.
+    pub fn wx_violations(&self) -> WxViolations<M> {
+        fn is_wx(mapping: &Mapping) -> bool { mapping.is_wx() }
+        self.mappings().filter(is_wx as fn(&Mapping) -> bool)
+    }
+
+    /// Check that no mapping in this page table is writable and executable.
+    ///
+    /// Each violating mapping is logged as a warning.
+    ///
+    /// # Returns
+    /// + `Ok(())` if there are no writable and executable mappings
+    /// + `Err(Mapping)` containing the first such mapping, otherwise.
+    pub fn check_wx(&self) -> Result<(), Mapping> {
+        let mut first = None;
+        for mapping in self.wx_violations() {
+            warn!("W+X mapping: {}", mapping);
+            if first.is_none() { first = Some(mapping); }
+        }
+        first.map_or(Ok(()), Err)
+    }
+}
+
+impl<M: Mmu> ActivePageTable<M> {
+
+    /// Walk the mappings in an `InactivePageTable`.
+    ///
+    /// The inactive table is temporarily made the target of the recursive
+    /// mapping (see [`using`]) while `f` runs.
+    ///
+    /// [`using`]: struct.ActivePageTable.html#method.using
+    pub fn walk_inactive<F>( &mut self
+                           , table: &mut InactivePageTable
+                           , temp_page: &mut TempPage
+                           , f: F)
+    where F: FnOnce(Mappings<M>) {
+        self.using(table, temp_page, |pml4| f(pml4.mappings()))
+    }
+}
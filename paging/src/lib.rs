@@ -18,8 +18,9 @@
 
 #[macro_use] extern crate bitflags;
 #[macro_use] extern crate log;
-#[macro_use] extern crate vga;
+#[cfg(not(test))] #[macro_use] extern crate vga;
 extern crate spin;
+#[cfg(test)] #[macro_use] extern crate std;
 
 extern crate util;
 extern crate memory;
@@ -28,6 +29,16 @@ extern crate cpu;
 extern crate elf;
 extern crate params;
 
+/// There's no VGA console when running tests on the host, so `kinfoln!`
+/// just logs.
+#[cfg(test)]
+macro_rules! kinfoln {
+    ( dots: $dots:expr, target: $target:expr, $status:expr ) => {
+        info!(target: $target, $status)
+    };
+    ( dots: $dots:expr, $($args:tt)* ) => { info!( $($args)* ) };
+}
+
 pub mod arch;
 pub mod stack;
 pub use self::arch::{kernel_remap, test_paging};
@@ -33,7 +33,7 @@ impl StackAllocator for PageRange {
                       , num_pages: usize) -> AllocResult<Stack>
     where A: FrameAllocator {
         use memory::{PAGE_SIZE, Page};
-        use arch::table::WRITABLE;
+        use arch::table::{WRITABLE, NO_EXECUTE};
         let exhausted = || {
             AllocErr::Exhausted {
                 request: Layout::from_size_align( PAGE_SIZE as usize * num_pages
@@ -63,7 +63,7 @@ impl StackAllocator for PageRange {
             *self = working_pages;
 
             for page in start_page .. end_page {
-                page_table.map_to_any(page, WRITABLE, frames);
+                page_table.map_to_any(page, WRITABLE | NO_EXECUTE, frames);
             }
 
             let stack_top = end_page.end_address();
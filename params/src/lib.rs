@@ -70,6 +70,9 @@ pub struct InitParams {
     , /// Map of elf sections
     // todo: construct using convert::From<multiboot>
      pub elf_sections: Option<ElfSections>
+  , /// The physical address of the ACPI Root System Description Pointer,
+    /// if one was found.
+    pub acpi_rsdp: Option<PAddr>
 }
 
 impl Default for InitParams {
@@ -90,6 +93,7 @@ impl Default for InitParams {
                    , multiboot_end: None
                    , mem_map: ArrayVec::<[mem::Area; MAX_MEM_AREAS]>::new()
                    , elf_sections: None
+                   , acpi_rsdp: None
                    }
     }
 }
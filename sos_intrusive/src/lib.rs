@@ -20,7 +20,8 @@
 //! kernel subsystems which require structures such as lists prior to
 //! the initialization of the kernel heap.
 //!
-//! This crate currently provides an intrusive linked-list implementation.
+//! This crate currently provides an intrusive linked-list implementation,
+//! and an intrusive balanced binary search tree.
 //!
 //! # Features
 //! + `use-std`: use the Rust standard library (`std`), rather than `core`.
@@ -37,6 +38,8 @@ pub mod rawlink;
 pub use rawlink::RawLink;
 pub mod list;
 pub use list::List;
+pub mod tree;
+pub use tree::Tree;
 
 #[cfg(test)]
 extern crate std;
@@ -235,6 +235,31 @@ where T: OwnedRef<N>
         }
     }
 
+    /// Removes an element from anywhere in the list, and returns it.
+    ///
+    /// Since the element knows its neighbours, this doesn't have to search
+    /// the list for it.
+    ///
+    /// # Unsafe due to
+    ///   - Dereferencing a raw pointer
+    ///   - `node` must be an element of this list, and not of another list
+    pub unsafe fn remove(&mut self, node: *mut N) -> T {
+        // `RawLink<N>` is only `Copy` if `N` is, so the links are resolved
+        // to raw pointers, and rebuilt from them.
+        let prev = (*node).prev_mut().take().as_raw();
+        let next = (*node).next_mut().take().as_raw();
+        match prev.as_mut() {
+            None => self.head = RawLink::from_raw(next)
+          , Some(prev) => *prev.next_mut() = RawLink::from_raw(next)
+        }
+        match next.as_mut() {
+            None => self.tail = RawLink::from_raw(prev)
+          , Some(next) => *next.prev_mut() = RawLink::from_raw(prev)
+        }
+        self.length -= 1;
+        T::from_raw(node)
+    }
+
     /// Borrows the element at the front of the list
     ///
     /// # Returns
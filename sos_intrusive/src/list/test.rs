@@ -198,7 +198,50 @@ mod boxed {
         assert_eq!(list.pop_back(), None);
     }
 
+    #[test]
+    fn test_remove() {
+        let mut list = TestList::new();
+
+        list.push_back(Box::new(NumberedNode::new(0)));
+        list.push_back(Box::new(NumberedNode::new(1)));
+        list.push_back(Box::new(NumberedNode::new(2)));
+        list.push_back(Box::new(NumberedNode::new(3)));
+
+        let middle = unsafe { list.front_mut().unwrap().next().as_raw() };
+        assert_eq!(unsafe { list.remove(middle) }.number, 1);
+        assert_eq!(list.len(), 3);
+
+        let front = list.front_mut().unwrap() as *mut NumberedNode;
+        assert_eq!(unsafe { list.remove(front) }.number, 0);
+        assert_eq!(list.front().unwrap().number, 2);
 
+        let back = list.back_mut().unwrap() as *mut NumberedNode;
+        assert_eq!(unsafe { list.remove(back) }.number, 3);
+        assert_eq!(list.front().unwrap().number, 2);
+        assert_eq!(list.back().unwrap().number, 2);
+
+        let last = list.front_mut().unwrap() as *mut NumberedNode;
+        assert_eq!(unsafe { list.remove(last) }.number, 2);
+        assert!(list.is_empty());
+        assert_eq!(list.len(), 0);
+        assert_eq!(list.pop_front(), None);
+    }
+
+    #[test]
+    fn push_after_remove() {
+        let mut list = TestList::new();
+
+        list.push_back(Box::new(NumberedNode::new(0)));
+        list.push_back(Box::new(NumberedNode::new(1)));
+
+        let back = list.back_mut().unwrap() as *mut NumberedNode;
+        let node = unsafe { list.remove(back) };
+        list.push_front(node);
+
+        assert_eq!(list.pop_front().unwrap().number, 1);
+        assert_eq!(list.pop_front().unwrap().number, 0);
+        assert!(list.is_empty());
+    }
 }
 
 // mod mut_ptr {
@@ -0,0 +1,311 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! An intrusive balanced binary search tree.
+//!
+//! The tree is an AVL tree: the heights of the two subtrees of any node
+//! differ by at most one, so finding, inserting, and removing elements take
+//! `O(log n)` time. Each element holds its own [`Links`] to its parent and
+//! children, so, like the intrusive list, the tree never allocates.
+//!
+//! Elements are ordered by the key returned by [`TreeNode::key`]. Elements
+//! with equal keys are kept in the order they were inserted in.
+//!
+//! [`Links`]: struct.Links.html
+//! [`TreeNode::key`]: trait.TreeNode.html#tymethod.key
+use super::rawlink::RawLink;
+use super::list::OwnedRef;
+
+use core::cmp;
+use core::marker::PhantomData;
+#[cfg(test)] mod test;
+
+/// The links from an element of a tree to its parent and children.
+#[derive(Debug)]
+pub struct Links<N> { parent: RawLink<N>
+                    , left: RawLink<N>
+                    , right: RawLink<N>
+                    , /// The height of the subtree rooted at this element
+                      height: usize
+                    }
+
+impl<N> Links<N> {
+    /// Returns new `Links` for an element that isn't in a tree.
+    pub const fn new() -> Self {
+        Links { parent: RawLink::none()
+              , left: RawLink::none()
+              , right: RawLink::none()
+              , height: 0
+              }
+    }
+}
+
+impl<N> Default for Links<N> {
+    fn default() -> Self { Self::new() }
+}
+
+/// This trait defines an element of an intrusive tree.
+///
+/// An element must be capable of providing its key, and mutable and
+/// immutable references to its `Links`. The key must not change while the
+/// element is in a tree.
+pub trait TreeNode: Sized {
+    type Key: Ord;
+
+    fn key(&self) -> Self::Key;
+
+    fn links(&self) -> &Links<Self>;
+    fn links_mut(&mut self) -> &mut Links<Self>;
+}
+
+/// An intrusive AVL tree.
+///
+/// It stores a pointer to the root of the tree, the number of elements, and
+/// a `PhantomData` marker for the tree's `OwnedRef` type.
+pub struct Tree<T, N>
+where T: OwnedRef<N>
+    , N: TreeNode {
+    root: RawLink<N>
+  , _ty_marker: PhantomData<T>
+  , length: usize
+}
+
+#[inline]
+unsafe fn links<'a, N: TreeNode>(node: *mut N) -> &'a mut Links<N> {
+    (*node).links_mut()
+}
+
+#[inline]
+unsafe fn parent<N: TreeNode>(node: *mut N) -> *mut N {
+    links(node).parent.as_raw()
+}
+
+#[inline]
+unsafe fn left<N: TreeNode>(node: *mut N) -> *mut N {
+    links(node).left.as_raw()
+}
+
+#[inline]
+unsafe fn right<N: TreeNode>(node: *mut N) -> *mut N {
+    links(node).right.as_raw()
+}
+
+/// Returns the height of the subtree rooted at `node`, which is 0 if it is
+/// empty.
+#[inline]
+unsafe fn height<N: TreeNode>(node: *mut N) -> usize {
+    node.as_ref().map(|node| node.links().height).unwrap_or(0)
+}
+
+/// Recalculate the height of `node` from its children's.
+#[inline]
+unsafe fn update_height<N: TreeNode>(node: *mut N) {
+    links(node).height = 1 + cmp::max(height(left(node)), height(right(node)));
+}
+
+/// Returns the leftmost element of the subtree rooted at `node`.
+unsafe fn leftmost<N: TreeNode>(mut node: *mut N) -> *mut N {
+    while !left(node).is_null() {
+        node = left(node);
+    }
+    node
+}
+
+impl<T, N> Tree<T, N>
+where T: OwnedRef<N>
+    , N: TreeNode {
+
+    /// Construct a new `Tree<T, N>` with zero elements
+    pub const fn new() -> Self {
+        Tree { root: RawLink::none()
+             , _ty_marker: PhantomData
+             , length: 0 }
+    }
+
+    /// Returns the number of elements in the tree
+    #[inline] pub fn len(&self) -> usize { self.length }
+
+    /// Returns true if the tree is empty.
+    #[inline] pub fn is_empty(&self) -> bool { self.root.is_none() }
+
+    /// Borrows the element with the smallest key, as an `Option`
+    ///
+    /// # Returns
+    ///   - `Some(&N)` if the tree has elements
+    ///   - `None` if the tree is empty.
+    pub fn first(&self) -> Option<&N> {
+        unsafe {
+            self.root.resolve_mut()
+                .map(|root| &*leftmost(root))
+        }
+    }
+
+    /// Insert an element into the tree, after any elements with equal keys.
+    pub fn insert(&mut self, mut item: T) {
+        unsafe {
+            let node = item.get_mut() as *mut N;
+            *links(node) = Links::new();
+            links(node).height = 1;
+            item.take();
+            self.length += 1;
+
+            let key = (*node).key();
+            let mut parent = match self.root.resolve_mut() {
+                None => { self.root = RawLink::from_raw(node); return }
+              , Some(root) => root as *mut N
+            };
+            loop {
+                let go_left = key < (*parent).key();
+                let child = if go_left { &mut links(parent).left }
+                            else { &mut links(parent).right };
+                match child.resolve_mut() {
+                    Some(next) => parent = next
+                  , None => {
+                        *child = RawLink::from_raw(node);
+                        break
+                    }
+                }
+            }
+            links(node).parent = RawLink::from_raw(parent);
+            self.rebalance(parent);
+        }
+    }
+
+    /// Removes and returns the element with the smallest key.
+    ///
+    /// # Returns
+    ///   - `Some(T)` if the tree is not empty
+    ///   - `None` if the tree is empty
+    pub fn pop_first(&mut self) -> Option<T> {
+        unsafe {
+            self.root.resolve_mut()
+                .map(|root| leftmost(root))
+                .map(|first| self.remove(first))
+        }
+    }
+
+    /// Removes an element from anywhere in the tree, and returns it.
+    ///
+    /// # Unsafe due to
+    ///   - Dereferencing a raw pointer
+    ///   - `node` must be an element of this tree, and not of another tree
+    pub unsafe fn remove(&mut self, node: *mut N) -> T {
+        let (above, l, r) = (parent(node), left(node), right(node));
+
+        // the lowest element whose subtree's height may have changed.
+        let lowest = if !l.is_null() && !r.is_null() {
+            // replace the node with its successor, which is the leftmost
+            // element of its right subtree.
+            let next = leftmost(r);
+            let lowest = if next == r {
+                next
+            } else {
+                // the successor has no left child, so move its right child
+                // up to where it was.
+                let (next_parent, next_right) = (parent(next), right(next));
+                links(next_parent).left = RawLink::from_raw(next_right);
+                if !next_right.is_null() {
+                    links(next_right).parent = RawLink::from_raw(next_parent);
+                }
+                links(next).right = RawLink::from_raw(r);
+                links(r).parent = RawLink::from_raw(next);
+                next_parent
+            };
+            links(next).left = RawLink::from_raw(l);
+            links(l).parent = RawLink::from_raw(next);
+            links(next).parent = RawLink::from_raw(above);
+            links(next).height = links(node).height;
+            self.replace_child(above, node, next);
+            lowest
+        } else {
+            let child = if l.is_null() { r } else { l };
+            if !child.is_null() {
+                links(child).parent = RawLink::from_raw(above);
+            }
+            self.replace_child(above, node, child);
+            above
+        };
+        if !lowest.is_null() {
+            self.rebalance(lowest);
+        }
+
+        *links(node) = Links::new();
+        self.length -= 1;
+        T::from_raw(node)
+    }
+
+    /// Make `new` the child of `parent` that `old` was, or the root, if
+    /// `parent` is null.
+    unsafe fn replace_child(&mut self, parent: *mut N, old: *mut N
+                           , new: *mut N) {
+        if parent.is_null() {
+            self.root = RawLink::from_raw(new);
+        } else if left(parent) == old {
+            links(parent).left = RawLink::from_raw(new);
+        } else {
+            links(parent).right = RawLink::from_raw(new);
+        }
+    }
+
+    /// Rotate the subtree rooted at `node` to the left, and return its new
+    /// root, which was `node`'s right child.
+    unsafe fn rotate_left(&mut self, node: *mut N) -> *mut N {
+        let (above, top) = (parent(node), right(node));
+        let inner = left(top);
+        links(node).right = RawLink::from_raw(inner);
+        if !inner.is_null() {
+            links(inner).parent = RawLink::from_raw(node);
+        }
+        links(top).left = RawLink::from_raw(node);
+        links(node).parent = RawLink::from_raw(top);
+        links(top).parent = RawLink::from_raw(above);
+        self.replace_child(above, node, top);
+        update_height(node);
+        update_height(top);
+        top
+    }
+
+    /// Rotate the subtree rooted at `node` to the right, and return its new
+    /// root, which was `node`'s left child.
+    unsafe fn rotate_right(&mut self, node: *mut N) -> *mut N {
+        let (above, top) = (parent(node), left(node));
+        let inner = right(top);
+        links(node).left = RawLink::from_raw(inner);
+        if !inner.is_null() {
+            links(inner).parent = RawLink::from_raw(node);
+        }
+        links(top).right = RawLink::from_raw(node);
+        links(node).parent = RawLink::from_raw(top);
+        links(top).parent = RawLink::from_raw(above);
+        self.replace_child(above, node, top);
+        update_height(node);
+        update_height(top);
+        top
+    }
+
+    /// Restore the balance of every subtree from `node` up to the root,
+    /// after `node`'s subtree has changed height.
+    unsafe fn rebalance(&mut self, mut node: *mut N) {
+        while !node.is_null() {
+            update_height(node);
+            let (l, r) = (left(node), right(node));
+            if height(l) > height(r) + 1 {
+                if height(left(l)) < height(right(l)) {
+                    self.rotate_left(l);
+                }
+                node = self.rotate_right(node);
+            } else if height(r) > height(l) + 1 {
+                if height(right(r)) < height(left(r)) {
+                    self.rotate_right(r);
+                }
+                node = self.rotate_left(node);
+            }
+            node = parent(node);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+use super::*;
+
+use std::boxed::Box;
+use std::vec::Vec;
+
+#[derive(Debug)]
+pub struct KeyedNode {
+    pub key: usize,
+    pub number: usize,
+    links: Links<KeyedNode>,
+}
+
+impl KeyedNode {
+    pub fn new(key: usize, number: usize) -> Self {
+        KeyedNode {
+            key: key,
+            number: number,
+            links: Links::new(),
+        }
+    }
+}
+
+impl TreeNode for KeyedNode {
+    type Key = usize;
+
+    fn key(&self) -> usize { self.key }
+
+    fn links(&self) -> &Links<Self> { &self.links }
+
+    fn links_mut(&mut self) -> &mut Links<Self> { &mut self.links }
+}
+
+type TestTree = Tree<Box<KeyedNode>, KeyedNode>;
+
+/// Checks the AVL invariants and parent links of the subtree rooted at
+/// `node`, and returns its height.
+fn check_subtree(node: &KeyedNode, parent: *mut KeyedNode) -> usize {
+    let links = node.links();
+    assert_eq!(unsafe { links.parent.as_raw() }, parent);
+    let this = node as *const KeyedNode as *mut KeyedNode;
+    let left = unsafe { links.left.resolve() }
+        .map(|left| {
+            assert!(left.key <= node.key);
+            check_subtree(left, this)
+        })
+        .unwrap_or(0);
+    let right = unsafe { links.right.resolve() }
+        .map(|right| {
+            assert!(right.key >= node.key);
+            check_subtree(right, this)
+        })
+        .unwrap_or(0);
+    assert!(left <= right + 1 && right <= left + 1
+           , "node {} is unbalanced: {} vs {}", node.key, left, right);
+    let height = 1 + ::core::cmp::max(left, right);
+    assert_eq!(links.height, height);
+    height
+}
+
+fn check(tree: &TestTree) {
+    match unsafe { tree.root.resolve() } {
+        Some(root) => { check_subtree(root, ::core::ptr::null_mut()); }
+      , None => assert_eq!(tree.len(), 0)
+    }
+}
+
+fn drain(tree: &mut TestTree) -> Vec<usize> {
+    let mut keys = Vec::new();
+    while let Some(node) = tree.pop_first() {
+        check(tree);
+        keys.push(node.key);
+    }
+    keys
+}
+
+#[test]
+fn empty() {
+    let mut tree = TestTree::new();
+    assert!(tree.is_empty());
+    assert_eq!(tree.len(), 0);
+    assert!(tree.first().is_none());
+    assert!(tree.pop_first().is_none());
+}
+
+#[test]
+fn first_is_smallest() {
+    let mut tree = TestTree::new();
+
+    tree.insert(box KeyedNode::new(5, 0));
+    assert_eq!(tree.first().unwrap().key, 5);
+    tree.insert(box KeyedNode::new(8, 0));
+    assert_eq!(tree.first().unwrap().key, 5);
+    tree.insert(box KeyedNode::new(2, 0));
+    assert_eq!(tree.first().unwrap().key, 2);
+
+    assert!(!tree.is_empty());
+    assert_eq!(tree.len(), 3);
+}
+
+#[test]
+fn balanced_after_ascending_inserts() {
+    let mut tree = TestTree::new();
+    for key in 0..100 {
+        tree.insert(box KeyedNode::new(key, 0));
+        check(&tree);
+    }
+    // a balanced tree of 100 elements is at most 1.44 * log2(100) high.
+    assert!(unsafe { tree.root.resolve() }.unwrap().links().height <= 9);
+    assert_eq!(drain(&mut tree), (0..100).collect::<Vec<_>>());
+}
+
+#[test]
+fn balanced_after_descending_inserts() {
+    let mut tree = TestTree::new();
+    for key in (0..100).rev() {
+        tree.insert(box KeyedNode::new(key, 0));
+        check(&tree);
+    }
+    assert_eq!(drain(&mut tree), (0..100).collect::<Vec<_>>());
+}
+
+#[test]
+fn pops_in_key_order() {
+    let mut tree = TestTree::new();
+    // insert the keys in a scrambled order.
+    for i in 0..64 {
+        tree.insert(box KeyedNode::new((i * 37) % 64, 0));
+        check(&tree);
+    }
+    assert_eq!(tree.len(), 64);
+    assert_eq!(drain(&mut tree), (0..64).collect::<Vec<_>>());
+    assert!(tree.is_empty());
+}
+
+#[test]
+fn equal_keys_are_fifo() {
+    let mut tree = TestTree::new();
+    for number in 0..10 {
+        tree.insert(box KeyedNode::new(1, number));
+        tree.insert(box KeyedNode::new(number % 3, 100));
+        check(&tree);
+    }
+    let mut numbers = Vec::new();
+    while let Some(node) = tree.pop_first() {
+        if node.key == 1 && node.number < 100 {
+            numbers.push(node.number);
+        }
+    }
+    assert_eq!(numbers, (0..10).collect::<Vec<_>>());
+}
+
+#[test]
+fn remove_from_anywhere() {
+    let mut tree = TestTree::new();
+    let mut nodes = Vec::new();
+    for i in 0..50 {
+        let mut node = box KeyedNode::new((i * 7) % 50, i);
+        nodes.push(&mut *node as *mut KeyedNode);
+        tree.insert(node);
+    }
+    // remove every other element, in insertion order, so that elements
+    // with zero, one, and two children are all removed.
+    for (i, &node) in nodes.iter().enumerate().filter(|&(i, _)| i % 2 == 0) {
+        let removed = unsafe { tree.remove(node) };
+        assert_eq!(removed.number, i);
+        assert!(removed.links().parent.is_none());
+        check(&tree);
+    }
+    assert_eq!(tree.len(), 25);
+
+    let keys = drain(&mut tree);
+    let mut expected = (0..50).filter(|i| i % 2 == 1)
+                              .map(|i| (i * 7) % 50)
+                              .collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(keys, expected);
+}
+
+#[test]
+fn insert_after_remove() {
+    let mut tree = TestTree::new();
+    for key in 0..8 {
+        tree.insert(box KeyedNode::new(key, 0));
+    }
+    let first = tree.first().unwrap() as *const KeyedNode as *mut KeyedNode;
+    let mut node = unsafe { tree.remove(first) };
+    node.key = 20;
+    tree.insert(node);
+    check(&tree);
+
+    assert_eq!(tree.len(), 8);
+    let mut expected = (1..8).collect::<Vec<_>>();
+    expected.push(20);
+    assert_eq!(drain(&mut tree), expected);
+}
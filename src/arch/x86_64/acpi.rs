@@ -0,0 +1,65 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Finding and reading the ACPI tables.
+use acpi::{rsdp, PhysicalMemory, Tables};
+use alloc::FrameAllocator;
+use memory::PAddr;
+use paging::arch::ActivePageTable;
+use paging::arch::pat::{ioremap, CacheType};
+
+use core::slice;
+
+use super::bda;
+
+/// Scans the BIOS areas for the ACPI RSDP.
+///
+/// This reads the first KiB of the EBDA and the BIOS ROM area directly, so
+/// it must be called while the boot page tables (which identity map the
+/// low memory) are still in use.
+pub fn scan_for_rsdp() -> Option<PAddr> {
+    let scan = |start: usize, len: usize| {
+        let area = unsafe { slice::from_raw_parts(start as *const u8, len) };
+        rsdp::scan(area).map(|offset| PAddr::from((start + offset) as u64))
+    };
+    bda::ebda_address()
+        .and_then(|ebda| scan(ebda, rsdp::EBDA_SEARCH_LEN))
+        .or_else(|| scan( rsdp::BIOS_AREA_START as usize
+                        , (rsdp::BIOS_AREA_END - rsdp::BIOS_AREA_START)
+                          as usize))
+}
+
+/// Reads physical memory by mapping it with `ioremap`.
+struct Mapper<'t, A: 't> { table: &'t mut ActivePageTable
+                         , alloc: &'t mut A
+                         }
+
+impl<'t, A> PhysicalMemory<'static> for Mapper<'t, A>
+where A: FrameAllocator {
+    fn read(&mut self, addr: PAddr, len: usize)
+           -> Result<&'static [u8], &'static str> {
+        // the tables stay mapped for as long as the kernel runs.
+        let vaddr = ioremap( addr, len, CacheType::WriteBack
+                           , self.table, self.alloc)?
+                        .leak();
+        let bytes = unsafe { slice::from_raw_parts(*vaddr as *const u8, len) };
+        // TODO: tables that are read more than once (e.g. the header and
+        //       then the whole table) are mapped more than once, which
+        //       wastes a little of the ioremap range.
+        Ok(bytes)
+    }
+}
+
+/// Parses the ACPI tables pointed to by the RSDP at `rsdp`.
+pub fn tables<A>( rsdp: PAddr
+                , table: &mut ActivePageTable
+                , alloc: &mut A)
+                -> Result<Tables<'static>, &'static str>
+where A: FrameAllocator {
+    Tables::parse(rsdp, &mut Mapper { table: table, alloc: alloc })
+}
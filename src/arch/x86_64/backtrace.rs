@@ -0,0 +1,202 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Stack backtraces, by walking frame pointers.
+//!
+//! The kernel is built with frame pointers, so every function's `%rbp`
+//! points at the caller's saved `%rbp`, with the return address just above
+//! it. The entry points zero `%rbp` before calling into Rust, which ends
+//! the chain.
+//!
+//! Since a backtrace is usually taken when something has already gone
+//! wrong, the walker checks that each frame looks sane before reading it,
+//! and stops at the first one that doesn't. Frames are read with an entry
+//! in the exception table (see `extable`), so a frame pointer into memory
+//! that isn't mapped ends the backtrace, rather than faulting again.
+//!
+//! Addresses are resolved against the kernel's symbol table, once
+//! `symbols::initialize()` has been called, and printed as `function+offset`.
+use super::symbols::{self, Symbol};
+
+use core::{fmt, mem};
+use log::LogLevel;
+
+/// The most frames a backtrace will walk.
+pub const MAX_DEPTH: usize = 32;
+
+/// The furthest apart two consecutive frames may be, in bytes.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// An iterator over the return addresses on the stack.
+#[derive(Copy, Clone, Debug)]
+pub struct Frames { rbp: usize
+                  , depth: usize
+                  }
+
+impl Frames {
+    /// Walk the stack starting from the frame that `rbp` points at.
+    ///
+    /// # Safety
+    /// + The exception handlers must be installed, so that reading a frame
+    ///   that isn't mapped is caught.
+    pub unsafe fn new(rbp: usize) -> Self {
+        Frames { rbp: rbp, depth: 0 }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let rbp = self.rbp;
+        if rbp == 0 || rbp % mem::align_of::<usize>() != 0
+                    || self.depth >= MAX_DEPTH {
+            return None
+        }
+        // this is safe; if `rbp` doesn't point at a mapped frame, the reads
+        // fail rather than fault.
+        let ret_addr = rbp.wrapping_add(mem::size_of::<usize>());
+        let frame = unsafe { (read_word(rbp), read_word(ret_addr)) };
+        let (caller_rbp, ret) = match frame {
+            (Some(caller_rbp), Some(ret)) if ret != 0 => (caller_rbp, ret)
+          , _ => return None
+        };
+        // the stack grows down, so the caller's frame must be above ours.
+        self.rbp = if caller_rbp > rbp && caller_rbp - rbp <= MAX_FRAME_SIZE {
+            caller_rbp
+        } else { 0 };
+        self.depth += 1;
+        Some(ret)
+    }
+}
+
+/// Returns the word at `addr`, or `None` if reading it faults.
+#[inline(never)]
+unsafe fn read_word(addr: usize) -> Option<usize> {
+    let value: usize;
+    let faulted: usize;
+    // if the `mov` faults, the exception handler resumes at `2:`, with
+    // `%rdx` still 1.
+    asm!("1: mov (%rsi), %rax
+          xor %edx, %edx
+          2:
+          .pushsection .ex_table, \"a\"
+          .balign 8
+          .quad 1b, 2b
+          .popsection"
+        : "={rax}"(value), "={rdx}"(faulted)
+        : "{rsi}"(addr), "{rdx}"(1usize)
+        :
+        : "volatile");
+    if faulted == 0 { Some(value) } else { None }
+}
+
+/// A frame in a backtrace.
+#[derive(Copy, Clone, Debug)]
+pub struct Frame { /// The address execution was at, or will return to
+                   pub addr: usize
+                 , /// The function containing `addr`, if it could be found
+                   pub symbol: Option<Symbol>
+                 }
+
+impl Frame {
+    /// Returns the frame for the instruction at `rip`.
+    pub fn at(rip: usize) -> Self {
+        Frame { addr: rip, symbol: symbols::resolve(rip) }
+    }
+
+    /// Returns the frame for the return address `ret`.
+    ///
+    /// The return address is just after the `call`, which may be the start
+    /// of the next function, so this looks up the byte before it.
+    pub fn returning_to(ret: usize) -> Self {
+        let symbol = symbols::resolve(ret - 1).map(|s| {
+            Symbol { offset: s.offset + 1, .. s }
+        });
+        Frame { addr: ret, symbol: symbol }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.symbol {
+            Some(ref symbol) => write!(f, "{:#018x} in {}", self.addr, symbol)
+          , None => write!(f, "{:#018x} in ???", self.addr)
+        }
+    }
+}
+
+/// An iterator over the `Frame`s of a backtrace.
+pub struct Backtrace { rip: Option<usize>
+                     , frames: Frames
+                     }
+
+impl Backtrace {
+    /// Trace the stack from `rip`, in the function whose frame `rbp` points
+    /// at. If `rip` is `None`, the trace starts at the return address in
+    /// that frame.
+    ///
+    /// # Safety
+    /// + The same as `Frames::new`.
+    pub unsafe fn new(rip: Option<usize>, rbp: usize) -> Self {
+        Backtrace { rip: rip, frames: Frames::new(rbp) }
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        match self.rip.take() {
+            Some(rip) => Some(Frame::at(rip))
+          , None => self.frames.next().map(Frame::returning_to)
+        }
+    }
+}
+
+/// Returns the current frame pointer.
+#[inline(always)]
+pub fn current_rbp() -> usize {
+    let rbp: usize;
+    unsafe { asm!("mov $0, rbp" : "=r"(rbp) ::: "intel"); }
+    rbp
+}
+
+/// Returns the frame pointer of the code an interrupt handler interrupted.
+///
+/// The handler's prologue saves the interrupted `%rbp` first thing, so it
+/// is what the handler's own frame pointer points at.
+///
+/// # Safety
+/// + This must be inlined into an interrupt handler.
+#[inline(always)]
+pub unsafe fn interrupted_rbp() -> usize {
+    *(current_rbp() as *const usize)
+}
+
+/// Log the backtrace from `rip` and `rbp` at `level`.
+///
+/// # Safety
+/// + The same as `Backtrace::new`.
+pub unsafe fn log(level: LogLevel, rip: Option<usize>, rbp: usize) {
+    log!(level, "backtrace:");
+    for (i, frame) in Backtrace::new(rip, rbp).enumerate() {
+        log!(level, "  {:>2}: {}", i, frame);
+    }
+}
+
+/// Write the backtrace from `rip` and `rbp` to `w`.
+///
+/// # Safety
+/// + The same as `Backtrace::new`.
+pub unsafe fn print(w: &mut fmt::Write, rip: Option<usize>, rbp: usize) {
+    let _ = write!(w, "\nBacktrace:\n");
+    for (i, frame) in Backtrace::new(rip, rbp).enumerate() {
+        let _ = write!(w, "{:>2}: {}\n", i, frame);
+    }
+}
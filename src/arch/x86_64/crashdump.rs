@@ -0,0 +1,235 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Crash dumps over serial.
+//!
+//! When the kernel panics, or takes an exception it can't recover from, it
+//! writes a crash dump to COM1, so that it can be read after the screen has
+//! scrolled away. The `crashdump` tool in the repository pretty-prints it.
+//!
+//! A dump is a run of lines between `BEGIN_MARKER` and `END_MARKER`. Each
+//! line is a keyword, followed by fields separated by single spaces; the
+//! last field may contain spaces. Panic messages and log lines are written
+//! with backslashes escaped as `\\` and newlines as `\n`, so that each
+//! one stays on its own line.
+//!
+//! ```text
+//! version <n>
+//! exception <vector> <mnemonic> <type> <name>
+//! source <what causes the exception>
+//! error_code <hex>
+//! panic <file> <line> <message>
+//! reg <name> <hex>
+//! cr <name> <hex>
+//! frame <n> <hex address> <function+offset, or ???>
+//! log <line>
+//! mem <hex start> <hex end> <type>
+//! kernel <hex start> <hex end>
+//! heap <hex start> <hex end>
+//! ```
+use super::backtrace::{self, Backtrace};
+use super::drivers::serial::{self, Serial};
+use super::multiboot2::MemMapTag;
+use cpu::context::InterruptFrame;
+use cpu::control_regs;
+use cpu::interrupts::EXCEPTIONS;
+use logger;
+use memory::PAddr;
+
+use core::fmt::{self, Arguments, Write};
+use spin::{MutexGuard, Once};
+
+/// The first line of a crash dump.
+pub const BEGIN_MARKER: &'static str = "=== SOS CRASH DUMP BEGIN ===";
+/// The last line of a crash dump.
+pub const END_MARKER: &'static str = "=== SOS CRASH DUMP END ===";
+/// The version of the crash dump format.
+pub const VERSION: u32 = 2;
+
+/// How many times to try to take the COM1 lock, before writing without it.
+const LOCK_TRIES: usize = 1_000_000;
+
+/// What the kernel knows about memory, for the memory map summary.
+struct MemorySummary { areas: &'static MemMapTag
+                     , kernel: (PAddr, PAddr)
+                     , heap: (PAddr, PAddr)
+                     }
+
+static MEMORY: Once<MemorySummary> = Once::new();
+
+/// Why the kernel crashed.
+#[derive(Copy, Clone)]
+pub enum Reason<'a> { /// An exception it can't recover from
+                      Exception { vector: usize, error_code: Option<usize> }
+                    , /// A panic
+                      Panic { message: Arguments<'a>
+                            , file: &'static str
+                            , line: usize
+                            }
+                    }
+
+/// Record the memory map, for the summary in crash dumps.
+pub fn set_memory_map( areas: &'static MemMapTag
+                     , kernel: (PAddr, PAddr)
+                     , heap: (PAddr, PAddr)) {
+    MEMORY.call_once(|| MemorySummary { areas: areas
+                                      , kernel: kernel
+                                      , heap: heap
+                                      });
+}
+
+/// Somewhere to write a crash dump: COM1, or a handle on it that doesn't
+/// take the lock, if whoever holds it isn't letting go.
+enum Port { Locked(MutexGuard<'static, Serial>)
+          , Unlocked(Serial)
+          }
+
+impl Port {
+    fn take() -> Self {
+        for _ in 0..LOCK_TRIES {
+            if let Some(port) = serial::COM1.try_lock() {
+                return Port::Locked(port)
+            }
+        }
+        // this is safe enough; we're never giving control back to whoever
+        // holds the lock.
+        Port::Unlocked(unsafe { serial::com1_unlocked() })
+    }
+
+    fn writer(&mut self) -> &mut Write {
+        match *self { Port::Locked(ref mut port) => &mut **port
+                    , Port::Unlocked(ref mut port) => port
+                    }
+    }
+}
+
+/// Writes to another writer, escaping backslashes and newlines.
+struct Escaped<'a>(&'a mut Write);
+
+impl<'a> Write for Escaped<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut rest = s;
+        while let Some(i) = rest.find(|c: char| c == '\\' || c == '\n') {
+            self.0.write_str(&rest[..i])?;
+            self.0.write_str(if rest.as_bytes()[i] == b'\n' { "\\n" }
+                             else { "\\\\" })?;
+            rest = &rest[i + 1..];
+        }
+        self.0.write_str(rest)
+    }
+}
+
+/// Write a line that starts with `prefix` and ends with `text`, escaped.
+fn write_escaped(w: &mut Write, prefix: Arguments, text: Arguments)
+                -> fmt::Result {
+    w.write_fmt(prefix)?;
+    Escaped(&mut *w).write_fmt(text)?;
+    w.write_str("\n")
+}
+
+/// Write a crash dump to COM1.
+///
+/// `frame` is the interrupted code's interrupt frame, if the crash was an
+/// exception, and `rbp` is the frame pointer to start the backtrace from.
+///
+/// # Safety
+/// + `rbp` must be a frame pointer on a mapped stack, or 0.
+pub unsafe fn write( reason: Reason, frame: Option<&InterruptFrame>
+                   , rbp: usize) {
+    let mut port = Port::take();
+    let _ = write_to(port.writer(), reason, frame, rbp);
+}
+
+unsafe fn write_to( w: &mut Write, reason: Reason
+                  , frame: Option<&InterruptFrame>, rbp: usize)
+                  -> fmt::Result {
+    write!(w, "\n{}\nversion {}\n", BEGIN_MARKER, VERSION)?;
+
+    // -- what happened -------------------------------------------------------
+    match reason {
+        Reason::Exception { vector, error_code } => {
+            match EXCEPTIONS.get(vector) {
+                Some(info) => write!( w, "exception {} {} {} {}\nsource {}\n"
+                                    , vector, info.mnemonic, info.irq_type
+                                    , info.name, info.source)?
+              , None => write!(w, "exception {} - - Unknown\n", vector)?
+            }
+            if let Some(code) = error_code {
+                write!(w, "error_code {:#x}\n", code)?;
+            }
+        }
+      , Reason::Panic { message, file, line } =>
+            write_escaped(w, format_args!("panic {} {} ", file, line), message)?
+    }
+
+    // -- registers -----------------------------------------------------------
+    let rip = match frame {
+        Some(frame) => {
+            write!(w, "reg rip {:#x}\n", frame.rip as usize)?;
+            write!(w, "reg cs {:#x}\n", frame.cs.bits())?;
+            write!(w, "reg rflags {:#x}\n", frame.rflags.bits())?;
+            write!(w, "reg rsp {:#x}\n", frame.rsp as usize)?;
+            write!(w, "reg ss {:#x}\n", frame.ss.bits())?;
+            Some(frame.rip as usize)
+        }
+      , None => {
+            let rsp: usize;
+            asm!("mov $0, rsp" : "=r"(rsp) ::: "intel");
+            write!(w, "reg rsp {:#x}\n", rsp)?;
+            None
+        }
+    };
+    write!(w, "reg rbp {:#x}\n", rbp)?;
+    let crs = control_regs::dump();
+    write!( w, "cr cr0 {:#x}\ncr cr2 {:#x}\ncr cr3 {:#x}\ncr cr4 {:#x}\n"
+          , crs.cr0.bits(), crs.cr2, crs.cr3, crs.cr4.bits())?;
+
+    // -- backtrace -----------------------------------------------------------
+    for (i, frame) in Backtrace::new(rip, rbp).enumerate() {
+        match frame.symbol {
+            Some(symbol) => write!(w, "frame {} {:#x} {}\n", i, frame.addr
+                                  , symbol)?
+          , None => write!(w, "frame {} {:#x} ???\n", i, frame.addr)?
+        }
+    }
+
+    // -- recent log lines ----------------------------------------------------
+    let mut result = Ok(());
+    logger::recent_lines(|line| if result.is_ok() {
+        result = write_escaped( w, format_args!("log ")
+                              , format_args!("{}", line));
+    });
+    result?;
+
+    // -- memory map ----------------------------------------------------------
+    if let Some(memory) = MEMORY.try() {
+        for area in memory.areas.areas() {
+            write!( w, "mem {:#x} {:#x} {:?}\n"
+                  , area.base, area.address(), area.ty)?;
+        }
+        write!(w, "kernel {:#x} {:#x}\n", memory.kernel.0, memory.kernel.1)?;
+        write!(w, "heap {:#x} {:#x}\n", memory.heap.0, memory.heap.1)?;
+    }
+
+    write!(w, "{}\n", END_MARKER)
+}
+
+/// Handle a panic: print a backtrace on the panic screen, and write a crash
+/// dump.
+///
+/// `arch_init` registers this with the panic handler.
+pub fn panic_hook( console: &mut Write, message: Arguments
+                 , file: &'static str, line: usize) {
+    let rbp = backtrace::current_rbp();
+    // this is safe; we're tracing our own stack.
+    unsafe {
+        backtrace::print(console, None, rbp);
+        write(Reason::Panic { message: message, file: file, line: line }
+             , None, rbp);
+    }
+}
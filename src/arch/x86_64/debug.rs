@@ -0,0 +1,187 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Kernel watchpoints and breakpoints, using the debug registers.
+//!
+//! [`watch`] sets a hardware watchpoint; when the memory is touched, the
+//! debug exception handler logs which watchpoint fired and a backtrace, and
+//! the kernel carries on. `int3` breakpoints are handled the same way.
+//!
+//! The debug registers belong to each CPU, so setting a watchpoint only
+//! loads it on the calling CPU. Other CPUs pick up the current watchpoints
+//! when they call [`load`].
+//!
+//! [`watch`]: fn.watch.html
+//! [`load`]: fn.load.html
+use super::backtrace;
+use cpu::context::InterruptFrame;
+use cpu::debug_regs::{self, Condition, Length, N_BREAKPOINTS};
+use cpu::flags::RF;
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use log::LogLevel;
+use spin::Mutex;
+
+/// What kind of access a watchpoint fires on.
+pub type Kind = Condition;
+
+/// A hardware watchpoint.
+#[derive(Copy, Clone, Debug)]
+pub struct Watchpoint { /// The address being watched
+                        pub addr: usize
+                      , /// How many bytes are watched
+                        pub len: Length
+                      , /// What kind of access fires the watchpoint
+                        pub kind: Kind
+                      }
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} of {} bytes at {:#x}", self.kind, self.len.bytes()
+              , self.addr)
+    }
+}
+
+/// The watchpoints, indexed by debug register.
+static WATCHPOINTS: Mutex<[Option<Watchpoint>; N_BREAKPOINTS]>
+    = Mutex::new([None; N_BREAKPOINTS]);
+
+/// The number of watchpoint hits since boot.
+static HITS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Returns the number of watchpoint hits since boot.
+#[inline]
+pub fn hits() -> usize { HITS.load(Ordering::Relaxed) }
+
+/// Watch `len` bytes at `addr` for accesses of `kind`.
+///
+/// # Returns
+/// + `Ok(id)` with the watchpoint's ID, to pass to `unwatch`
+/// + `Err` if all the debug registers are in use, `len` isn't 1, 2, 4, or
+///   8, or `addr` isn't aligned to `len`.
+pub fn watch(addr: usize, len: usize, kind: Kind)
+            -> Result<usize, &'static str> {
+    let len = Length::from_bytes(len)
+        .ok_or("Watchpoints must be 1, 2, 4, or 8 bytes long!")?;
+    let mut watchpoints = WATCHPOINTS.lock();
+    let id = watchpoints.iter().position(Option::is_none)
+        .ok_or("All the debug registers are in use!")?;
+    // this is safe; we're in kernel mode, and the debug exception handler
+    // is installed.
+    unsafe { debug_regs::set(id, addr, kind, len)?; }
+    let watchpoint = Watchpoint { addr: addr, len: len, kind: kind };
+    watchpoints[id] = Some(watchpoint);
+    debug!("set watchpoint {}: {}", id, watchpoint);
+    Ok(id)
+}
+
+/// Remove the watchpoint `id`, on this CPU.
+pub fn unwatch(id: usize) -> Result<(), &'static str> {
+    let mut watchpoints = WATCHPOINTS.lock();
+    if id >= N_BREAKPOINTS || watchpoints[id].is_none() {
+        return Err("No such watchpoint!")
+    }
+    watchpoints[id] = None;
+    // this is safe; we're in kernel mode.
+    unsafe { debug_regs::clear(id) };
+    Ok(())
+}
+
+/// Load the current watchpoints into this CPU's debug registers.
+///
+/// # Safety
+/// + The IDT must be loaded on this CPU.
+pub unsafe fn load() {
+    let watchpoints = WATCHPOINTS.lock();
+    for (id, slot) in watchpoints.iter().enumerate() {
+        match *slot {
+            Some(ref w) => {
+                // the watchpoint was already checked when it was set.
+                let _ = debug_regs::set(id, w.addr, w.kind, w.len);
+            }
+          , None => debug_regs::clear(id)
+        }
+    }
+}
+
+/// Handle a debug exception.
+///
+/// # Safety
+/// + This must be called from the debug exception handler, with the
+///   interrupted `frame` and frame pointer `rbp`.
+pub unsafe fn handle_debug_exception(frame: &mut InterruptFrame, rbp: usize) {
+    let status = debug_regs::status();
+    debug_regs::clear_status();
+    let mut hit = false;
+    for id in (0..N_BREAKPOINTS).filter(|&id| status.hit(id)) {
+        hit = true;
+        HITS.fetch_add(1, Ordering::Relaxed);
+        // don't spin on the lock; the exception may have interrupted
+        // `watch` itself.
+        let watchpoint = WATCHPOINTS.try_lock().and_then(|w| w[id]);
+        match watchpoint {
+            Some(w) => {
+                warn!("watchpoint {} ({}) hit at {:p}", id, w, frame.rip)
+            }
+          , None => warn!("debug register {} hit at {:p}", id, frame.rip)
+        }
+    }
+    if !hit {
+        warn!("debug exception at {:p}: {:?}", frame.rip, status);
+    }
+    backtrace::log(LogLevel::Warn, Some(frame.rip as usize), rbp);
+    // execute breakpoints fire before the instruction runs, so `RF` has to
+    // be set for it to run, rather than fire again. data breakpoints ignore
+    // `RF`, so it's always set.
+    frame.rflags.insert(RF);
+}
+
+/// Handle an `int3` breakpoint.
+///
+/// `int3` is a trap, so `frame.rip` is the instruction after it.
+///
+/// # Safety
+/// + The same as `handle_debug_exception`.
+pub unsafe fn handle_breakpoint(frame: &InterruptFrame, rbp: usize) {
+    warn!("breakpoint at {:p}", frame.rip.offset(-1));
+    backtrace::log(LogLevel::Warn, Some(frame.rip as usize), rbp);
+}
+
+/// Set a write watchpoint, write to it, and check that it fired.
+#[cfg(feature = "boot-selftest")]
+pub fn test() -> Result<(), &'static str> {
+    use core::ptr;
+    static mut WATCHED: u64 = 0;
+    let addr = unsafe { &mut WATCHED as *mut u64 };
+    let id = watch(addr as usize, 8, Condition::Write)?;
+    let before = hits();
+    unsafe { ptr::write_volatile(addr, 0xdead_beef) };
+    unwatch(id)?;
+    if hits() > before { Ok(()) }
+    else { Err("write watchpoint didn't fire!") }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watch_rejects_bad_lengths() {
+        // the length is checked before any debug register is touched.
+        for &len in [0, 3, 5, 16].iter() {
+            assert!(watch(0x1000, len, Condition::Write).is_err());
+        }
+    }
+
+    #[test]
+    fn test_unwatch_unknown_watchpoints() {
+        assert!(unwatch(0).is_err());
+        assert!(unwatch(N_BREAKPOINTS).is_err());
+    }
+}
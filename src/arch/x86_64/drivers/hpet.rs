@@ -0,0 +1,222 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! High Precision Event Timer (HPET) driver.
+//!
+//! The HPET has a main counter, which counts up at a fixed frequency, and a
+//! number of comparators (timers), which interrupt when the main counter
+//! reaches their value. It is found through the ACPI HPET table.
+//!
+//! Timer 0 is used as a clock event device. Its interrupt is routed through
+//! the I/O APIC to `VECTOR`, rather than with legacy replacement, so the PIT
+//! keeps running.
+//!
+//! For more information, refer to the
+//! [IA-PC HPET Specification](https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/software-developers-hpet-spec-1-0a.pdf).
+use acpi;
+use acpi::AddressSpace;
+use alloc::FrameAllocator;
+use cpu::interrupts::apic::{self, RedirectionEntry};
+use memory::{PAddr, VAddr};
+use paging::arch::ActivePageTable;
+use paging::arch::pat::{ioremap, CacheType};
+use spin::Once;
+use time::Duration;
+use time::clock_event::{self, ClockEventDevice, Features, ONESHOT, PERIODIC};
+
+use core::ptr;
+
+/// Vector that HPET timer 0 interrupts are delivered on.
+pub const VECTOR: u8 = 0x30;
+
+/// Size of the HPET's register block, in bytes.
+const MMIO_SIZE: usize = 0x400;
+
+/// The longest period the HPET may have, in femtoseconds (100ns).
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+const FS_PER_NS: u64 = 1_000_000;
+
+/// General capabilities and ID register
+const CAPABILITIES: usize = 0x000;
+/// General configuration register
+const CONFIG: usize = 0x010;
+/// Main counter value register
+const MAIN_COUNTER: usize = 0x0f0;
+
+/// Set in `CONFIG` to start the main counter.
+const ENABLE: u64 = 1 << 0;
+
+/// Timer `n`'s configuration and capabilities register
+#[inline] fn timer_config(n: usize) -> usize { 0x100 + 0x20 * n }
+/// Timer `n`'s comparator value register
+#[inline] fn timer_comparator(n: usize) -> usize { 0x108 + 0x20 * n }
+
+/// Set in a timer's config to enable its interrupt.
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+/// Set in a timer's config to put it in periodic mode.
+const TIMER_PERIODIC: u64 = 1 << 3;
+/// Set in a timer's config if it supports periodic mode.
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+/// Set in a timer's config to allow setting a periodic timer's accumulator.
+const TIMER_VAL_SET: u64 = 1 << 6;
+/// A timer's I/O APIC routing bits.
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+
+/// The High Precision Event Timer.
+#[derive(Debug)]
+pub struct Hpet { /// The address the HPET's registers are mapped at
+                  base: VAddr
+                , /// The main counter's period, in femtoseconds
+                  period_fs: u64
+                , /// Whether timer 0 supports periodic mode
+                  periodic: bool
+                }
+
+static HPET: Once<Hpet> = Once::new();
+
+impl Hpet {
+    #[inline]
+    unsafe fn read(&self, reg: usize) -> u64 {
+        ptr::read_volatile((*self.base + reg) as *const u64)
+    }
+
+    #[inline]
+    unsafe fn write(&self, reg: usize, value: u64) {
+        ptr::write_volatile((*self.base + reg) as *mut u64, value)
+    }
+
+    /// Returns the main counter's frequency, in Hz.
+    #[inline]
+    pub fn frequency(&self) -> u64 { 1_000_000_000_000_000 / self.period_fs }
+
+    /// Returns the value of the main counter.
+    #[inline]
+    pub fn counter(&self) -> u64 { unsafe { self.read(MAIN_COUNTER) } }
+
+    /// Returns the number of main counter ticks in `duration`.
+    #[inline]
+    fn ticks(&self, duration: Duration) -> u64 {
+        duration.as_nanos() * FS_PER_NS / self.period_fs
+    }
+}
+
+impl ClockEventDevice for Hpet {
+    fn name(&self) -> &'static str { "HPET" }
+
+    fn features(&self) -> Features {
+        if self.periodic { ONESHOT | PERIODIC } else { ONESHOT }
+    }
+
+    fn rating(&self) -> u32 { 50 }
+
+    fn min_delta(&self) -> Duration {
+        // the comparator has to be written before the main counter passes
+        // it, or the interrupt is missed until the counter wraps.
+        Duration::from_micros(10)
+    }
+
+    fn max_delta(&self) -> Duration {
+        // stay within what a 32-bit comparator can do.
+        Duration::from_nanos(u32::max_value() as u64 * self.period_fs
+                             / FS_PER_NS)
+    }
+
+    unsafe fn set_periodic(&self, period: Duration)
+                          -> Result<(), &'static str> {
+        if !self.periodic {
+            return Err("HPET timer 0 doesn't support periodic mode!")
+        }
+        let ticks = self.ticks(period);
+        let config = self.read(timer_config(0));
+        self.write( timer_config(0)
+                  , config | TIMER_INT_ENABLE | TIMER_PERIODIC
+                           | TIMER_VAL_SET);
+        // with `TIMER_VAL_SET`, the first write sets the comparator, and the
+        // second sets the period.
+        self.write(timer_comparator(0), self.counter() + ticks);
+        self.write(timer_comparator(0), ticks);
+        Ok(())
+    }
+
+    unsafe fn set_next_event(&self, delta: Duration)
+                            -> Result<(), &'static str> {
+        let config = self.read(timer_config(0)) & !TIMER_PERIODIC;
+        self.write(timer_config(0), config | TIMER_INT_ENABLE);
+        self.write(timer_comparator(0), self.counter() + self.ticks(delta));
+        Ok(())
+    }
+
+    unsafe fn shutdown(&self) {
+        let config = self.read(timer_config(0));
+        self.write(timer_config(0), config & !TIMER_INT_ENABLE);
+    }
+}
+
+/// Returns the HPET, if it has been initialized.
+#[inline]
+pub fn hpet() -> Option<&'static Hpet> { HPET.try() }
+
+/// Map and start the HPET described by the ACPI HPET `table`, and register
+/// it as a clock event device.
+///
+/// # Safety
+/// + This should be called once, by the bootstrap processor, after the
+///   APIC has been enabled.
+pub unsafe fn initialize<A>( table: &acpi::Hpet
+                           , page_table: &mut ActivePageTable
+                           , alloc: &mut A)
+                           -> Result<&'static Hpet, &'static str>
+where A: FrameAllocator {
+    let address = table.base_address();
+    if address.space != AddressSpace::SystemMemory {
+        return Err("HPET registers aren't in memory!")
+    }
+    let lapic = apic::local().ok_or("APIC is not enabled!")?;
+    let mapping = ioremap( PAddr::from(address.address), MMIO_SIZE
+                         , CacheType::Uncached, page_table, alloc)?;
+    let base = mapping.vaddr();
+
+    let capabilities = ptr::read_volatile((*base + CAPABILITIES) as *const u64);
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        return Err("HPET has an invalid period!")
+    }
+    let hpet = Hpet { base: base
+                    , period_fs: period_fs
+                    , periodic: false
+                    };
+
+    // route timer 0 to the first I/O APIC pin it can use past the ISA IRQs.
+    let timer = hpet.read(timer_config(0));
+    let routes = timer >> 32;
+    let gsi = (16..32).find(|&gsi| routes & (1 << gsi) != 0)
+                      .ok_or("HPET timer 0 can't be routed to the I/O APIC!")?;
+    apic::set_gsi(gsi, RedirectionEntry::new(VECTOR, lapic.id() as u8))?;
+    let timer = (timer & !(TIMER_ROUTE_MASK | TIMER_INT_ENABLE | TIMER_PERIODIC))
+              | (gsi as u64) << TIMER_ROUTE_SHIFT;
+    hpet.write(timer_config(0), timer);
+
+    // restart the main counter from zero. this comes after everything that
+    // can fail, so that a failed probe never leaves it stopped.
+    let config = hpet.read(CONFIG);
+    hpet.write(CONFIG, config & !ENABLE);
+    hpet.write(MAIN_COUNTER, 0);
+    hpet.write(CONFIG, config | ENABLE);
+    // the HPET's registers stay mapped for as long as the kernel runs.
+    mapping.leak();
+
+    let hpet = HPET.call_once(|| Hpet { periodic: timer & TIMER_PERIODIC_CAP
+                                                  != 0
+                                      , ..hpet });
+    kinfoln!( dots: " . . ", "HPET at {:#x} runs at {} Hz; timer 0 uses GSI {}"
+            , address.address, hpet.frequency(), gsi);
+    clock_event::register(hpet);
+    Ok(hpet)
+}
@@ -0,0 +1,153 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Local APIC timer driver.
+//!
+//! Every CPU's local APIC has a timer, which counts down at the bus clock
+//! divided by `apic::TIMER_DIVISOR`, and interrupts on `apic::TIMER_VECTOR`
+//! when it reaches zero. Since the bus clock's frequency isn't known, the
+//! timer is calibrated against the PIT.
+//!
+//! If the CPU supports it (and the TSC has been calibrated), one-shot events
+//! use _TSC-deadline_ mode instead, where the timer fires when the TSC
+//! reaches the value written to `IA32_TSC_DEADLINE`. This is more precise,
+//! and doesn't depend on the calibration.
+use cpu::{cpuid, msr};
+use cpu::interrupts::apic::{self, LocalApic, TIMER_VECTOR, LVT_TIMER_ONESHOT,
+                            LVT_TIMER_PERIODIC, LVT_TIMER_TSC_DEADLINE};
+use cpu::timer::{pit, timestamp};
+use spin::Once;
+use time::{self, Duration};
+use time::clock_event::{self, ClockEventDevice, Features, ONESHOT, PERIODIC,
+                        PER_CPU};
+
+/// How long to count for when calibrating the timer, in microseconds.
+const CALIBRATION_US: u64 = 10_000;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// The local APIC timer.
+#[derive(Debug)]
+pub struct LapicTimer { /// The local APIC
+                        lapic: &'static LocalApic
+                      , /// How many times per second the timer counts down
+                        hz: u64
+                      , /// Whether one-shot events use TSC-deadline mode
+                        tsc_deadline: bool
+                      }
+
+static TIMER: Once<LapicTimer> = Once::new();
+
+impl LapicTimer {
+    /// Returns the number of timer counts in `duration`, up to the most the
+    /// timer can count down from.
+    fn counts(&self, duration: Duration) -> u32 {
+        let counts = duration.as_secs() * self.hz
+                   + duration.subsec_nanos() as u64 * self.hz / NANOS_PER_SEC;
+        // a count of 0 would stop the timer, rather than fire at once.
+        if counts > u32::max_value() as u64 { u32::max_value() }
+        else if counts == 0 { 1 }
+        else { counts as u32 }
+    }
+
+    /// Returns the frequency the timer counts down at, in Hz.
+    #[inline] pub fn frequency(&self) -> u64 { self.hz }
+
+    /// Returns true if one-shot events use TSC-deadline mode.
+    #[inline] pub fn uses_tsc_deadline(&self) -> bool { self.tsc_deadline }
+}
+
+impl ClockEventDevice for LapicTimer {
+    fn name(&self) -> &'static str {
+        if self.tsc_deadline { "local APIC timer (TSC-deadline)" }
+        else { "local APIC timer" }
+    }
+
+    fn features(&self) -> Features { ONESHOT | PERIODIC | PER_CPU }
+
+    fn rating(&self) -> u32 { if self.tsc_deadline { 150 } else { 100 } }
+
+    fn min_delta(&self) -> Duration { Duration::from_micros(1) }
+
+    fn max_delta(&self) -> Duration {
+        // stay within what the counter can do, even in TSC-deadline mode, so
+        // that converting to TSC cycles can't overflow.
+        Duration::from_nanos(u32::max_value() as u64 * NANOS_PER_SEC
+                             / self.hz)
+    }
+
+    unsafe fn set_periodic(&self, period: Duration)
+                          -> Result<(), &'static str> {
+        self.lapic.set_timer(TIMER_VECTOR, LVT_TIMER_PERIODIC);
+        self.lapic.set_timer_count(self.counts(period));
+        Ok(())
+    }
+
+    unsafe fn set_next_event(&self, delta: Duration)
+                            -> Result<(), &'static str> {
+        if self.tsc_deadline {
+            let cycles = time::tsc_cycles(delta)
+                .ok_or("TSC is not calibrated!")?;
+            self.lapic.set_timer(TIMER_VECTOR, LVT_TIMER_TSC_DEADLINE);
+            // the switch to TSC-deadline mode has to be seen before the
+            // deadline is written, or the write may be ignored.
+            asm!("mfence" :::: "volatile");
+            msr::write( msr::IA32_TSC_DEADLINE
+                      , timestamp::rtdsc().wrapping_add(cycles));
+        } else {
+            self.lapic.set_timer(TIMER_VECTOR, LVT_TIMER_ONESHOT);
+            self.lapic.set_timer_count(self.counts(delta));
+        }
+        Ok(())
+    }
+
+    unsafe fn shutdown(&self) {
+        if self.tsc_deadline {
+            msr::write(msr::IA32_TSC_DEADLINE, 0);
+        }
+        self.lapic.stop_timer();
+    }
+}
+
+/// Returns the local APIC timer, if it has been initialized.
+#[inline]
+pub fn timer() -> Option<&'static LapicTimer> { TIMER.try() }
+
+/// Count the local APIC timer's frequency against the PIT, in Hz.
+unsafe fn calibrate(lapic: &LocalApic) -> Result<u64, &'static str> {
+    // mask the timer while it's counting, so it doesn't interrupt us if
+    // it runs out.
+    lapic.set_timer(TIMER_VECTOR, apic::LVT_MASKED | LVT_TIMER_ONESHOT);
+    lapic.set_timer_count(u32::max_value());
+    pit::delay_us(CALIBRATION_US);
+    let counted = u32::max_value() - lapic.timer_count();
+    lapic.stop_timer();
+    match counted as u64 * 1_000_000 / CALIBRATION_US {
+        0 => Err("local APIC timer didn't count!")
+      , hz => Ok(hz)
+    }
+}
+
+/// Calibrate the local APIC timer, and register it as a clock event device.
+///
+/// # Safety
+/// + This should be called once, by the bootstrap processor, after the APIC
+///   has been enabled and `time::initialize()` has been called.
+pub unsafe fn initialize() -> Result<&'static LapicTimer, &'static str> {
+    let lapic = apic::local().ok_or("APIC is not enabled!")?;
+    let hz = calibrate(lapic)?;
+    let tsc_deadline = cpuid::has_tsc_deadline() && time::tsc_hz().is_some();
+    let timer = TIMER.call_once(|| LapicTimer { lapic: lapic
+                                              , hz: hz
+                                              , tsc_deadline: tsc_deadline
+                                              });
+    kinfoln!( dots: " . . ", "Local APIC timer runs at {} Hz{}", hz
+            , if tsc_deadline { "; using TSC-deadline mode" } else { "" });
+    clock_event::register(timer);
+    Ok(timer)
+}
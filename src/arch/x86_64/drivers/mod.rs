@@ -6,5 +6,7 @@
 //  Released under the terms of the MIT license. See `LICENSE` in the root
 //  directory of this repository for more information.
 //
+pub mod hpet;
+pub mod lapic_timer;
 pub mod serial;
 pub mod vga;
@@ -65,6 +65,21 @@ lazy_static! {
 
 
 
+/// Returns a handle on COM1 that doesn't go through its lock.
+///
+/// # Safety
+/// + This is only for writing crash dumps, when whoever holds the lock on
+///   `COM1` will never run again. Anything written through it may be
+///   interleaved with output from the lock holder.
+pub unsafe fn com1_unlocked() -> Serial {
+    // the port was already set up when `COM1` was, so don't reset it.
+    Serial(bda::ports::com1().map(|port| {
+        SerialPort { data_port: Port::<u8>::new(port)
+                   , status_port: Port::<u8>::new(port + 5)
+                   }
+    }))
+}
+
 /// A serial port
 pub struct SerialPort { data_port: Port<u8>
                       , status_port: Port<u8>
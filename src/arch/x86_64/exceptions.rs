@@ -0,0 +1,315 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Handling CPU exceptions.
+//!
+//! Each of the 32 exception vectors has a stub in the IDT, which calls
+//! [`dispatch`] with its vector. `dispatch` tries, in order:
+//!
+//!  1. the handler registered for that vector with [`set_handler`], if any;
+//!  2. the kernel exception table, if the exception is a fault in kernel
+//!     code that has a fixup (see `extable`);
+//!  3. for traps, logging the exception and carrying on.
+//!
+//! Anything else is fatal: the exception is reported on the console, with a
+//! backtrace, a crash dump is written to COM1, and the CPU halts.
+//!
+//! # Examples
+//! ```ignore
+//! fn invalid_opcode(e: &mut Exception) -> ExceptionResult {
+//!     // ... emulate the instruction ...
+//!     e.frame.rip = unsafe { e.frame.rip.offset(2) };
+//!     ExceptionResult::Handled
+//! }
+//!
+//! exceptions::set_handler(exceptions::INVALID_OPCODE, invalid_opcode)?;
+//! ```
+//!
+//! [`dispatch`]: fn.dispatch.html
+//! [`set_handler`]: fn.set_handler.html
+use super::backtrace;
+use super::crashdump::{self, Reason};
+use super::extable;
+use super::percpu::KernelGs;
+use cpu::context::InterruptFrame;
+use cpu::interrupts::{self, ExceptionInfo, ErrorCodeHandler, InterruptHandler
+                     , EXCEPTIONS, NUM_EXCEPTIONS};
+use cpu::interrupts::idt::{Gate, Idt};
+use spin::Mutex;
+use vga::{CONSOLE, Color, Terminal};
+
+/// Vector of the debug exception (`#DB`).
+pub const DEBUG: usize = 1;
+/// Vector of the non-maskable interrupt (`NMI`).
+pub const NMI: usize = 2;
+/// Vector of the breakpoint exception (`#BP`).
+pub const BREAKPOINT: usize = 3;
+/// Vector of the invalid opcode exception (`#UD`).
+pub const INVALID_OPCODE: usize = 6;
+/// Vector of the device not available exception (`#NM`).
+pub const DEVICE_NOT_AVAILABLE: usize = 7;
+/// Vector of the double fault exception (`#DF`).
+pub const DOUBLE_FAULT: usize = 8;
+/// Vector of the page fault exception (`#PF`).
+pub const PAGE_FAULT: usize = 14;
+/// Vector of the machine check exception (`#MC`).
+pub const MACHINE_CHECK: usize = 18;
+/// Vector of the SIMD floating-point exception (`#XM`).
+pub const SIMD_FP_EXCEPTION: usize = 19;
+
+/// How many times `fatal` tries to take the console lock, before reporting
+/// the exception only in the crash dump.
+const CONSOLE_TRIES: usize = 1_000_000;
+
+/// What an exception handler did with an exception.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExceptionResult { /// The exception was dealt with; resume the
+                           /// interrupted code at `frame.rip`
+                           Handled
+                         , /// Carry on as if there were no handler
+                           NotHandled
+                         }
+
+/// An exception being handled.
+pub struct Exception<'a> { /// The exception's vector
+                           pub vector: usize
+                         , /// The error code, for vectors that push one
+                           pub error_code: Option<usize>
+                         , /// The interrupted code's state, which is
+                           /// restored when the handler returns
+                           pub frame: &'a mut InterruptFrame
+                         , /// The interrupted code's frame pointer
+                           pub rbp: usize
+                         }
+
+impl<'a> Exception<'a> {
+    /// Returns the description of this exception.
+    #[inline] pub fn info(&self) -> &'static ExceptionInfo {
+        &EXCEPTIONS[self.vector]
+    }
+
+    /// Returns true if the exception was raised in user mode.
+    #[inline] pub fn is_from_user(&self) -> bool { self.frame.is_from_user() }
+
+    /// Returns true if the interrupted code can be resumed without a
+    /// handler doing anything: that is, if the exception is a trap.
+    ///
+    /// Aborts, faults in user mode (since there is nothing to kill yet),
+    /// and faults in kernel code without a fixup are fatal.
+    #[inline] pub fn is_recoverable(&self) -> bool { self.info().is_trap() }
+}
+
+/// A handler for an exception vector.
+///
+/// Handlers are called with interrupts disabled, on the stack the exception
+/// arrived on.
+pub type ExceptionHandler = fn(&mut Exception) -> ExceptionResult;
+
+/// The handler registered for each exception vector.
+///
+/// This is locked by exception handlers, so it must only be locked with
+/// interrupts disabled.
+static HANDLERS: Mutex<[Option<ExceptionHandler>; NUM_EXCEPTIONS]>
+    = Mutex::new([None; NUM_EXCEPTIONS]);
+
+/// Register `handler` to be called when exception `vector` is raised,
+/// before the default handling.
+///
+/// # Returns
+/// + `Ok(())` if the handler was registered
+/// + `Err` if `vector` isn't an exception, or already has a handler.
+pub fn set_handler(vector: usize, handler: ExceptionHandler)
+                  -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let slot = handlers.get_mut(vector)
+                           .ok_or("That vector is not an exception!")?;
+        if slot.is_some() {
+            return Err("That exception already has a handler!")
+        }
+        *slot = Some(handler);
+        Ok(())
+    })
+}
+
+/// Remove the handler registered for exception `vector`.
+///
+/// # Returns
+/// + `Ok(())` if the handler was removed
+/// + `Err` if `vector` has no handler.
+pub fn clear_handler(vector: usize) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        HANDLERS.lock().get_mut(vector)
+                .and_then(|slot| slot.take())
+                .map(|_| ())
+                .ok_or("That exception has no handler!")
+    })
+}
+
+/// Handle exception `vector`.
+fn dispatch( vector: usize, frame: &InterruptFrame
+           , error_code: Option<usize>, rbp: usize) {
+    // NMIs, double faults, and machine checks can arrive between the
+    // `syscall` entry point and its `swapgs`, or between the `swapgs` and
+    // `sysretq` on the way out, where the code segment is the kernel's but
+    // the `%gs` base is still the user's.
+    let _gs = unsafe {
+        match vector {
+            NMI | DOUBLE_FAULT | MACHINE_CHECK => KernelGs::paranoid()
+          , _ => KernelGs::enter(frame)
+        }
+    };
+    // handlers may change the frame the interrupted code resumes with.
+    let frame = unsafe {
+        &mut *(frame as *const InterruptFrame as *mut InterruptFrame)
+    };
+    let mut exception = Exception { vector: vector
+                                  , error_code: error_code
+                                  , frame: frame
+                                  , rbp: rbp
+                                  };
+
+    // if the handlers are locked, this CPU was registering one when the
+    // exception arrived, so carry on without.
+    let handler = HANDLERS.try_lock().and_then(|handlers| handlers[vector]);
+    if let Some(handler) = handler {
+        if handler(&mut exception) == ExceptionResult::Handled {
+            return
+        }
+    }
+
+    let info = exception.info();
+    if !exception.is_from_user() && info.is_fault() {
+        if let Some(fixup) = extable::search(exception.frame.rip as usize) {
+            trace!( "{} at {:p}, resuming at {:#x}"
+                  , info.name, exception.frame.rip, fixup);
+            exception.frame.rip = fixup as *const u8;
+            return
+        }
+    }
+
+    if exception.is_recoverable() {
+        warn!("{} ({}) at {:p}", info.name, info.mnemonic, exception.frame.rip);
+        return
+    }
+
+    fatal(&exception)
+}
+
+/// Report an exception that can't be recovered from, and halt.
+///
+/// If the console is locked, which it will be if the exception happened
+/// while printing, it's only reported in the crash dump.
+fn fatal(exception: &Exception) -> ! {
+    let frame = &*exception.frame;
+    if let Some(mut console) = (0..CONSOLE_TRIES)
+                                   .filter_map(|_| CONSOLE.try_lock())
+                                   .next() {
+        report(&mut *console, exception);
+    }
+    unsafe {
+        crashdump::write( Reason::Exception { vector: exception.vector
+                                            , error_code: exception.error_code
+                                            }
+                        , Some(frame), exception.rbp);
+    }
+    loop { }
+}
+
+/// Print an exception that can't be recovered from, and a backtrace, on the
+/// console.
+fn report(console: &mut Terminal, exception: &Exception) {
+    use cpu::control_regs::cr2;
+    use cpu::interrupts::PageFaultErrorCode;
+    use core::fmt::Write;
+
+    let info = exception.info();
+    let frame = &*exception.frame;
+    let _ = write!( console.set_colors(Color::White, Color::Blue)
+                  , "EVERYTHING IS FINE: {} ({}, {}) at {:p}\n\
+                     Source: {}.\n"
+                  , info.name, info.mnemonic, info.irq_type, frame.rip
+                  , info.source);
+    if let Some(code) = exception.error_code {
+        let _ = write!(console, "Error code: {:#x}\n", code);
+    }
+    match exception.vector {
+        PAGE_FAULT => {
+            let _ = write!( console, "Accessing {:#x}\n{}\n"
+                          , unsafe { cr2::read() }
+                          , PageFaultErrorCode::from_bits_truncate(
+                                exception.error_code.unwrap_or(0) as u32));
+        }
+      , SIMD_FP_EXCEPTION => {
+            let _ = write!( console, "{}\n"
+                          , unsafe { ::cpu::fpu::mxcsr() });
+        }
+      , _ => {}
+    }
+    let _ = write!(console, "This is fine.\n\n{:?}", *frame);
+    unsafe {
+        backtrace::print(console, Some(frame.rip as usize), exception.rbp);
+    }
+}
+
+macro_rules! exception_stubs {
+    ( $($vector:expr => $name:ident),+ ;
+      $($code_vector:expr => $code_name:ident),+ ) => {
+        $(
+            #[doc(hidden)]
+            extern "x86-interrupt" fn $name(frame: &InterruptFrame) {
+                // this must be expanded here, so that the backtrace starts
+                // from the interrupted code's frame.
+                let rbp = unsafe { backtrace::interrupted_rbp() };
+                dispatch($vector, frame, None, rbp)
+            }
+        )+
+        $(
+            #[doc(hidden)]
+            extern "x86-interrupt" fn $code_name( frame: &InterruptFrame
+                                                , error_code: usize) {
+                let rbp = unsafe { backtrace::interrupted_rbp() };
+                dispatch($code_vector, frame, Some(error_code), rbp)
+            }
+        )+
+
+        /// The stubs for vectors without error codes.
+        static STUBS: [(usize, InterruptHandler); NUM_EXCEPTIONS - 8]
+            = [ $(($vector, $name as InterruptHandler)),+ ];
+    }
+}
+
+exception_stubs! {
+    0 => exception_0, 1 => exception_1, 2 => exception_2,
+    3 => exception_3, 4 => exception_4, 5 => exception_5,
+    6 => exception_6, 7 => exception_7, 9 => exception_9,
+    15 => exception_15, 16 => exception_16, 18 => exception_18,
+    19 => exception_19, 20 => exception_20, 21 => exception_21,
+    22 => exception_22, 23 => exception_23, 24 => exception_24,
+    25 => exception_25, 26 => exception_26, 27 => exception_27,
+    28 => exception_28, 29 => exception_29, 31 => exception_31;
+    8 => exception_8, 10 => exception_10, 11 => exception_11,
+    12 => exception_12, 13 => exception_13, 14 => exception_14,
+    17 => exception_17, 30 => exception_30
+}
+
+/// Point every exception gate in the IDT at its dispatch stub.
+pub fn install_stubs(idt: &mut Idt) {
+    for &(vector, stub) in STUBS.iter() {
+        idt[vector] = Gate::from(stub);
+    }
+    idt.double_fault = Gate::from(exception_8 as ErrorCodeHandler);
+    idt.invalid_tss = Gate::from(exception_10 as ErrorCodeHandler);
+    idt.segment_not_present = Gate::from(exception_11 as ErrorCodeHandler);
+    idt.stack_segment_fault = Gate::from(exception_12 as ErrorCodeHandler);
+    idt.general_protection_fault
+        = Gate::from(exception_13 as ErrorCodeHandler);
+    idt.page_fault = Gate::from(exception_14 as ErrorCodeHandler);
+    idt.alignment_check = Gate::from(exception_17 as ErrorCodeHandler);
+    idt.security_exception = Gate::from(exception_30 as ErrorCodeHandler);
+}
@@ -0,0 +1,64 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The kernel exception table.
+//!
+//! Some kernel code is expected to fault, such as copying from a user
+//! pointer that may not be mapped. Rather than checking every address first,
+//! the instruction that may fault is given a _fixup_: an address to resume
+//! at if it does. The exception handler looks up the faulting `%rip` here,
+//! and if there's a fixup for it, returns there instead of crashing.
+//!
+//! Each entry is a pair of addresses, placed in the `.ex_table` section by
+//! the assembly that needs it:
+//!
+//! ```ignore
+//! 1:  rep movsb           // the instruction that may fault
+//! 2:                      // where to resume if it does
+//!     .pushsection .ex_table, "a"
+//!     .balign 8
+//!     .quad 1b, 2b
+//!     .popsection
+//! ```
+//!
+//! The linker script collects the entries between `__ex_table_start` and
+//! `__ex_table_end`.
+use core::{mem, slice};
+
+/// An entry in the exception table.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct Entry { /// The address of the instruction that may fault
+                   pub insn: usize
+                 , /// The address to resume at if it does
+                   pub fixup: usize
+                 }
+
+extern {
+    static __ex_table_start: Entry;
+    static __ex_table_end: Entry;
+}
+
+/// Returns every entry in the exception table.
+pub fn entries() -> &'static [Entry] {
+    // this is safe; the linker script puts nothing but entries between
+    // the two symbols.
+    unsafe {
+        let start = &__ex_table_start as *const Entry;
+        let end = &__ex_table_end as *const Entry;
+        slice::from_raw_parts(start, (end as usize - start as usize)
+                                     / mem::size_of::<Entry>())
+    }
+}
+
+/// Returns the fixup for the instruction at `addr`, if there is one.
+pub fn search(addr: usize) -> Option<usize> {
+    entries().iter()
+             .find(|entry| entry.insn == addr)
+             .map(|entry| entry.fixup)
+}
@@ -0,0 +1,160 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Switching the FPU, SSE, and AVX state between tasks.
+//!
+//! Each task that uses the FPU has an [`FpuArea`] to save its state in. The
+//! context switch calls [`switch`], which either saves and restores the
+//! state right away (_eager_ switching), or just sets `TS` in `%cr0`, so
+//! that the next task's first FPU instruction raises a Device Not Available
+//! exception, and [`handle_device_not_available`] switches the state then
+//! (_lazy_ switching). Lazy switching is cheaper when few tasks use the FPU.
+//!
+//! The task whose state is in the FPU registers is the CPU's _owner_; with
+//! lazy switching, it may not be the task that is running.
+//!
+//! [`FpuArea`]: struct.FpuArea.html
+//! [`switch`]: fn.switch.html
+//! [`handle_device_not_available`]: fn.handle_device_not_available.html
+use cpu::control_regs::cr0;
+use cpu::fpu::{self, FpuState, STATE_ALIGN};
+use collections::vec::Vec;
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+/// When the FPU state is switched.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Policy { /// Save and restore the state on every context switch
+                  Eager
+                , /// Save and restore the state when the next task first
+                  /// uses the FPU
+                  Lazy
+                }
+
+/// True if lazy switching is in use.
+static LAZY: AtomicBool = ATOMIC_BOOL_INIT;
+
+percpu! {
+    /// The state that is loaded in this CPU's FPU registers.
+    static OWNER: Cell<FpuState> = Cell::new(FpuState::none());
+    /// The state of the task running on this CPU.
+    static CURRENT: Cell<FpuState> = Cell::new(FpuState::none());
+}
+
+/// A heap-allocated save area for a task's FPU, SSE, and AVX state.
+///
+/// This is sized for the state components enabled at boot, so it must not
+/// be created before `initialize()` is called.
+pub struct FpuArea { _buf: Vec<u8>
+                   , state: FpuState
+                   }
+
+impl FpuArea {
+    /// Allocate a save area, in the state after `fninit`.
+    pub fn new() -> Self {
+        let mut buf: Vec<u8> = Vec::with_capacity( fpu::state_size()
+                                                 + STATE_ALIGN);
+        let area = (buf.as_mut_ptr() as usize + STATE_ALIGN - 1)
+                 & !(STATE_ALIGN - 1);
+        // this is safe; `buf` has room for an aligned area, and lives as
+        // long as the `FpuState` does.
+        let state = unsafe { FpuState::new(area as *mut u8) };
+        FpuArea { _buf: buf, state: state }
+    }
+
+    /// Returns the `FpuState` that saves to this area.
+    #[inline]
+    pub fn state(&self) -> FpuState { self.state }
+}
+
+impl Drop for FpuArea {
+    fn drop(&mut self) {
+        // make sure nothing saves to the area once it's freed.
+        let state = self.state;
+        OWNER.with(|owner| if owner.get() == state {
+            owner.set(FpuState::none())
+        });
+        CURRENT.with(|current| if current.get() == state {
+            current.set(FpuState::none())
+        });
+    }
+}
+
+/// Enable the FPU on this CPU.
+///
+/// The bootstrap processor chooses the switching `policy`; the other CPUs
+/// pass `None`, and use the same one.
+///
+/// # Safety
+/// + This must be called once on each CPU, before anything uses the FPU.
+pub unsafe fn initialize(policy: Option<Policy>)
+                        -> Result<fpu::XFeatures, &'static str> {
+    if let Some(policy) = policy {
+        LAZY.store(policy == Policy::Lazy, Ordering::Relaxed);
+    }
+    fpu::initialize()
+}
+
+/// Returns the switching policy in use.
+#[inline]
+pub fn policy() -> Policy {
+    if LAZY.load(Ordering::Relaxed) { Policy::Lazy } else { Policy::Eager }
+}
+
+/// Switch the FPU state from the task `prev` to the task `next`.
+///
+/// Tasks that don't use the FPU have an empty `FpuState`.
+///
+/// # Safety
+/// + This must be called with interrupts disabled, by the context switch,
+///   with `prev` being the state of the task that was running.
+pub unsafe fn switch(prev: FpuState, next: FpuState) {
+    CURRENT.with(|current| current.set(next));
+    match policy() {
+        Policy::Eager => OWNER.with(|owner| {
+            fpu::clts();
+            if !prev.is_none() && owner.get() == prev { prev.save() }
+            if !next.is_none() { next.restore() }
+            owner.set(next);
+        })
+      , Policy::Lazy => OWNER.with(|owner| {
+            // if `next` still owns the FPU, its state hasn't changed.
+            if !next.is_none() && owner.get() == next { fpu::clts() }
+            else { cr0::set_task_switched(true) }
+        })
+    }
+}
+
+/// Handle a Device Not Available exception.
+///
+/// This gives the FPU to the running task, saving the previous owner's state.
+///
+/// # Returns
+/// + `Ok(())` if the task can use the FPU now
+/// + `Err` if the running task has no `FpuState`, or lazy switching is not
+///   in use.
+pub fn handle_device_not_available() -> Result<(), &'static str> {
+    if policy() != Policy::Lazy {
+        return Err("FPU used while it was disabled!")
+    }
+    let current = CURRENT.with(|current| current.get());
+    if current.is_none() {
+        return Err("FPU used by a task with no FPU state!")
+    }
+    OWNER.with(|owner| unsafe {
+        fpu::clts();
+        let prev = owner.get();
+        if prev != current {
+            if !prev.is_none() { prev.save() }
+            current.restore();
+            owner.set(current);
+        }
+    });
+    Ok(())
+}
@@ -7,134 +7,121 @@
 //  directory of this repository for more information.
 //
 
-use cpu::interrupts::pics;
+use super::debug;
+use super::drivers::hpet;
+use super::exceptions::{self, Exception, ExceptionResult};
+use super::fpu;
+use super::irq::{self, IrqResult, IRQ};
+use super::percpu::KernelGs;
+use super::syscall;
+use cpu::interrupts::{apic, pics};
 use cpu::interrupts::idt::{Gate, Idt};
 
 use cpu::context::InterruptFrame;
 use cpu::dtable::DTable;
+use cpu::task;
 
+use acpi::Madt;
+use alloc::FrameAllocator;
+use memory::PAGE_SIZE;
+use paging::arch::ActivePageTable;
+use paging::arch::pat::{ioremap, CacheType};
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// The number of timer IRQs since interrupts were enabled.
+static TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Returns the number of timer IRQs since interrupts were enabled.
+#[inline]
+pub fn ticks() -> usize { TICKS.load(Ordering::Relaxed) }
 
 //==--------------------------------------------------------------------------==
 // Top-level interrupt handling
 
 /// Initialize interrupt handling.
 ///
-/// This function initializes the PICs, populates the IDT with interrupt
-/// handlers, loads the IDT pointer, and enables interrupts.
+/// This function remaps the PICs, registers the timer and keyboard IRQ
+/// handlers and the debug, breakpoint, and FPU exception handlers, populates
+/// the IDT with interrupt handlers, and loads the IDT pointer. Interrupts
+/// stay disabled until `enable()` is called, so the APIC and the other CPUs
+/// can be brought up first.
 ///
 /// This is called from the kernel during the init process.
-// TODO: make the result returned by this meaningful?
 #[inline]
-pub unsafe fn initialize() -> Result<(), ()>{
+pub unsafe fn initialize() -> Result<(), &'static str> {
 
     pics::initialize();
-   // TODO: consider loading double-fault handler before anything else in case
-   //       a double fault occurs during init?
+    irq::register_irq(IRQ::Timer, timer)?;
+    irq::register_irq(IRQ::Keyboard, keyboard)?;
+    exceptions::set_handler(exceptions::DEBUG, debug_exception)?;
+    exceptions::set_handler(exceptions::BREAKPOINT, breakpoint)?;
+    exceptions::set_handler( exceptions::DEVICE_NOT_AVAILABLE
+                           , device_not_available)?;
+    // the double fault, NMI, and machine check handlers run on their own
+    // IST stacks (installed in the TSS along with the GDT), so a kernel stack
+    // overflow ends up in the double fault handler, rather than a triple
+    // fault.
     IDT.load();         // Load the IDT pointer
     //
     // debug!("Testing interrupt handling");
     // asm!("int $0" :: "N" (0xff));
-
-    Idt::enable_interrupts(); // enable interrupts
     Ok(())
 
 }
 
-macro_rules! exception_inner {
-    ($title:expr, $kind:expr, $source:expr, $f:expr) => {
-        use vga::{CONSOLE, Color};
-        use core::fmt::Write;
-        let _ = write!( CONSOLE.lock()
-                               .set_colors(Color::White, Color::Blue)
-                      , "EVERYTHING IS FINE: {}{} at {:p}\n\
-                         Source: {}.\nThis is fine.\n\n\
-                         {:?}"
-                         , $title, $kind
-                         , (*$f).rip
-                         , $source
-                         , *$f);
-    };
-    ($title:expr, $kind:expr, $source:expr, $f:expr, $e:expr) => {
-        use vga::{CONSOLE, Color};
-        use core::fmt::Write;
-        let _ = write!( CONSOLE.lock()
-                               .set_colors(Color::White, Color::Blue)
-                      , "EVERYTHING IS FINE: {}{} at {:p}\n\
-                         Source: {}.\n
-                         Error code: {:x}\nThis is fine.\n\n\
-                         {:?}"
-                         , $title, $kind
-                         , (*$f).rip
-                         , $source
-                         , $e
-                         , *$f);
-    };
+/// Enable interrupts on this CPU.
+///
+/// # Safety
+/// + `initialize()` must have been called first.
+/// + IRQ handlers lock the console, so interrupts should only be enabled
+///   once nothing on this CPU is going to hold it for long.
+#[inline]
+pub unsafe fn enable() {
+    kinfoln!(dots: " . ", target: "Enabling interrupts", "[ OKAY ]");
+    Idt::enable_interrupts();
 }
 
-macro_rules! exceptions {
-    ( fault: $name:ident, $title:expr, $source:expr, $($tail:tt)* ) => {
-        #[doc=$title]
-        extern "x86-interrupt" fn $name(frame: &InterruptFrame) {
-            exception_inner! ($title, "Fault", $source, frame);
-            loop {}
-        }
-
-        exceptions! {  $($tail)* }
-    };
-     ( fault (code): $name:ident, $title:expr, $source:expr, $($tail:tt)* ) => {
-        #[doc=$title]
-        extern "x86-interrupt" fn $name( frame: &InterruptFrame
-                                       , error_code: usize) {
-           exception_inner! ($title, "Fault", $source, frame, error_code);
-           loop {}
-       }
-       exceptions! { $($tail)* }
-   };
-     ( trap: $name:ident, $title:expr, $source:expr, $($tail:tt)* ) => {
-         #[doc=$title]
-         extern "x86-interrupt" fn $name(frame: &InterruptFrame) {
-             exception_inner! ($title, "Trap", $source, frame);
-         }
-
-         exceptions! { $($tail)* }
-     };
-      ( ) => {};
+/// Load the IDT on an application processor.
+///
+/// The IDT is shared by every CPU, but each CPU has to load it.
+pub unsafe fn initialize_ap() { IDT.load(); }
 
-}
-exceptions! {
-    fault: divide_by_zero, "Divide by Zero Error",
-           "DIV or IDIV instruction",
-    fault: nmi, "Non-Maskable Interrupt",
-          "Non-maskable external interrupt",
-    trap: overflow, "Overflow", "INTO instruction",
-    fault: bound_exceeded, "BOUND range exceeded",
-          "BOUND instruction",
-    fault: undefined_opcode, "Undefined Opcode",
-           "UD2 instruction or reserved opcode",
-    fault: device_not_available, "Device Not Available"
-         , "Floating-point or WAIT/FWAIT instruction \
-            (no math coprocessor)",
-    fault (code): double_fault, "Double Fault"
-         , "Any instruction that can generate an exception, a NMI, or \
-            an INTR",
-    fault (code): invalid_tss, "Invalid TSS"
-         , "Task switch or TSS access",
-    fault (code): segment_not_present, "Segment Not Present"
-         , "Loading segment registers or accessing \
-            system segments",
-    fault (code): general_protection_fault, "General Protection Fault"
-         , "Any memory reference or other protection checks",
-    fault (code): stack_segment_fault, "Stack Segment Fault"
-         , "Stack operations and SS register loads",
-    fault: floating_point_error
-         , "x87 FPU Floating-Point Error (Math Fault)"
-         , "x87 FPU floating-point or WAIT/FWAIT instruction",
-    fault: machine_check, "Machine Check"
-         , "Model-dependent (probably hardware!)",
-    fault (code): alignment_check, "Alignment Check"
-         , "Any data reference in memory",
-    fault: simd_fp_exception, "SIMD Floating-Point Exception"
-         , "SSE/SSE2/SSE3 floating-point instructions",
+/// Switch from the PICs to the local and I/O APICs.
+///
+/// This maps the local APIC and every I/O APIC in the MADT as uncached
+/// memory, enables the local APIC, routes the legacy IRQs through the I/O
+/// APICs as the MADT's interrupt source overrides say, and masks the PICs.
+/// If the APIC is unavailable, the PICs stay in use.
+///
+/// This should be called after `initialize()`, so that the PICs have been
+/// remapped before they are masked.
+pub unsafe fn initialize_apic<A>( madt: &Madt
+                                , table: &mut ActivePageTable
+                                , alloc: &mut A)
+                                -> Result<(), &'static str>
+where A: FrameAllocator {
+    if !apic::is_available() {
+        return Err("APIC is not supported!")
+    }
+    if madt.io_apics().next().is_none() {
+        return Err("ACPI MADT lists no I/O APICs!")
+    }
+    let lapic = ioremap( apic::base_address(), PAGE_SIZE as usize
+                       , CacheType::Uncached, table, alloc)?;
+    for io_apic in madt.io_apics() {
+        let mapping = ioremap( io_apic.address, apic::IO_APIC_MMIO_SIZE
+                             , CacheType::Uncached, table, alloc)?;
+        apic::add_io_apic(apic::IoApic::new( mapping.vaddr()
+                                           , io_apic.gsi_base))?;
+        // the APIC registers stay mapped for as long as the kernel runs.
+        mapping.leak();
+    }
+    let routes = apic::isa_routes(madt.interrupt_overrides()
+                                      .map(|o| (o.source, o.gsi, o.flags)));
+    apic::initialize(lapic.vaddr(), &routes)?;
+    lapic.leak();
+    Ok(())
 }
 
 lazy_static! {
@@ -145,31 +132,22 @@ lazy_static! {
         // TODO: log each handler as it's added to the IDT? that way we can
         //       trace faults occurring during IDT population (if any)
         //          - eliza, 5/22/2017
-        idt.divide_by_zero = Gate::from(divide_by_zero as InterruptHandler);
-        idt.nmi = Gate::from(nmi as InterruptHandler);
-        idt.overflow = Gate::from(overflow as InterruptHandler);
+        // every exception goes through `exceptions::dispatch`.
+        exceptions::install_stubs(&mut idt);
+        idt.nmi.set_stack_index(task::NMI_IST);
         idt.overflow.set_trap();
-        idt.bound_exceeded = Gate::from(bound_exceeded as InterruptHandler);
-        idt.undefined_opcode = Gate::from(undefined_opcode as InterruptHandler);
-        idt.device_not_available = Gate::from(device_not_available as InterruptHandler);
-        idt.double_fault = Gate::from(double_fault as ErrorCodeHandler);
-        idt.invalid_tss = Gate::from(invalid_tss as ErrorCodeHandler);
-        idt.segment_not_present = Gate::from(segment_not_present as ErrorCodeHandler);
-        idt.stack_segment_fault = Gate::from(stack_segment_fault as ErrorCodeHandler);
-        idt.general_protection_fault = Gate::from(general_protection_fault as ErrorCodeHandler);
-        idt.page_fault = Gate::from(page_fault as ErrorCodeHandler);
-
-        idt.floating_point_error = Gate::from(floating_point_error as InterruptHandler);
-        idt.alignment_check = Gate::from(alignment_check as ErrorCodeHandler);
-        idt.machine_check = Gate::from(machine_check as InterruptHandler);
-        idt.simd_fp_exception = Gate::from(simd_fp_exception as InterruptHandler);
-
-        idt.breakpoint = Gate::from(breakpoint as InterruptHandler);
-        idt.page_fault = Gate::from(page_fault as ErrorCodeHandler);
-
-        idt.interrupts[0x20 - 32] = Gate::from(timer as InterruptHandler);
-        idt.interrupts[0x21 - 32] = Gate::from(keyboard as InterruptHandler);
+        idt.double_fault.set_stack_index(task::DOUBLE_FAULT_IST);
+        idt.machine_check.set_stack_index(task::MACHINE_CHECK_IST);
+
+        irq::install_stubs(&mut idt);
+        syscall::install_int80(&mut idt);
         idt.interrupts[0xff - 32] = Gate::from(test as InterruptHandler);
+        idt.interrupts[apic::SPURIOUS_VECTOR as usize - 32]
+            = Gate::from(spurious as InterruptHandler);
+        idt.interrupts[hpet::VECTOR as usize - 32]
+            = Gate::from(hpet_timer as InterruptHandler);
+        idt.interrupts[apic::TIMER_VECTOR as usize - 32]
+            = Gate::from(lapic_timer as InterruptHandler);
 
         kinfoln!( dots: " . . ", target: "Adding interrupt handlers to IDT"
                 , "[ OKAY ]");
@@ -178,31 +156,63 @@ lazy_static! {
 }
 
 
-#[no_mangle] #[inline(never)]
-pub extern "x86-interrupt" fn keyboard(_frame: &InterruptFrame) {
-    use io::keyboard;
-
-    // println!("keyboard happened");
-    if let Some(input) = keyboard::read_char() {
-        if input == '\r' {
-            println!("");
-        } else {
-            print!("{}", input);
+/// Handler for the system timer IRQ.
+fn timer(_frame: &InterruptFrame) -> IrqResult {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    IrqResult::Handled
+}
+
+/// Handler for the HPET's clock event interrupts.
+extern "x86-interrupt" fn hpet_timer(frame: &InterruptFrame) {
+    let _gs = unsafe { KernelGs::enter(frame) };
+    ::time::clock_event::handle_event();
+    unsafe { ::cpu::interrupts::end_of_interrupt(hpet::VECTOR) }
+    ::thread::irq_exit(frame);
+}
+
+/// Handler for the local APIC timer's clock event interrupts.
+extern "x86-interrupt" fn lapic_timer(frame: &InterruptFrame) {
+    let _gs = unsafe { KernelGs::enter(frame) };
+    ::time::clock_event::handle_event();
+    unsafe { ::cpu::interrupts::end_of_interrupt(apic::TIMER_VECTOR) }
+    ::thread::irq_exit(frame);
+}
+
+/// Handler for Device Not Available exceptions.
+///
+/// These are raised by the first FPU instruction after a lazy FPU switch,
+/// so this gives the FPU to the running task.
+fn device_not_available(_exception: &mut Exception) -> ExceptionResult {
+    match fpu::handle_device_not_available() {
+        Ok(()) => ExceptionResult::Handled
+      , Err(why) => {
+            error!("Device Not Available: {}", why);
+            ExceptionResult::NotHandled
         }
     }
-   // send the PICs the end interrupt signal
-   unsafe {
-       pics::end_pic_interrupt(0x21);
-   }
 }
 
-#[no_mangle] #[inline(never)]
-pub extern "x86-interrupt" fn breakpoint(frame: &InterruptFrame) {
-    println!("Breakpoint! Frame: {:#?}", frame);
-   // send the PICs the end interrupt signal
-   unsafe {
-       pics::end_pic_interrupt(0x03);
-   }
+/// Handler for the PS/2 keyboard IRQ.
+fn keyboard(_frame: &InterruptFrame) -> IrqResult {
+    ::io::keyboard::handle_irq();
+    IrqResult::Handled
+}
+
+/// Handler for debug exceptions, raised by the debug registers.
+///
+/// This logs the watchpoint that fired, and resumes.
+fn debug_exception(exception: &mut Exception) -> ExceptionResult {
+    // the handler resumes by setting `RF` in the frame it returns to.
+    unsafe { debug::handle_debug_exception(exception.frame, exception.rbp) }
+    ExceptionResult::Handled
+}
+
+/// Handler for `int3` breakpoints.
+fn breakpoint(exception: &mut Exception) -> ExceptionResult {
+    unsafe { debug::handle_breakpoint(exception.frame, exception.rbp) }
+    // breakpoints are exceptions, rather than IRQs, so there's no interrupt
+    // controller to signal.
+    ExceptionResult::Handled
 }
 
 /// Empty dummy handler for undefined interrupts.
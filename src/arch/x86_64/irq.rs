@@ -0,0 +1,189 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Dispatching hardware IRQs to handlers registered at runtime.
+//!
+//! Each of the 16 legacy IRQ vectors has a stub in the IDT, which calls
+//! [`dispatch`] with its IRQ line. `dispatch` counts the IRQ, calls every
+//! handler registered for that line (so lines may be shared between
+//! devices), and then signals the end of the interrupt to whichever
+//! interrupt controller is in use, so handlers don't have to. Finally, it
+//! switches threads, if a handler (such as the scheduler's timer tick)
+//! decided that the interrupted thread should be preempted.
+//!
+//! # Examples
+//! ```ignore
+//! fn keyboard(_frame: &InterruptFrame) -> IrqResult {
+//!     // ... read from the keyboard ...
+//!     IrqResult::Handled
+//! }
+//!
+//! irq::register_irq(IRQ::Keyboard, keyboard)?;
+//! ```
+//!
+//! [`dispatch`]: fn.dispatch.html
+use super::percpu::KernelGs;
+use cpu::context::InterruptFrame;
+use cpu::interrupts::{self, InterruptHandler, NUM_EXCEPTIONS};
+use cpu::interrupts::apic::IRQ_BASE;
+use cpu::interrupts::idt::{Gate, Idt};
+use cpu::interrupts::pics::N_IRQS;
+use spin::Mutex;
+
+pub use cpu::interrupts::pics::IRQ;
+
+/// Maximum number of handlers that may share an IRQ line.
+pub const MAX_SHARED_HANDLERS: usize = 4;
+
+/// What an IRQ handler did with an IRQ.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IrqResult { /// The IRQ came from this handler's device
+                     Handled
+                   , /// The IRQ came from another device on the same line
+                     NotMine
+                   }
+
+/// A handler for an IRQ.
+///
+/// Handlers are called with interrupts disabled, and must not signal the end
+/// of the interrupt themselves.
+pub type IrqHandler = fn(&InterruptFrame) -> IrqResult;
+
+/// The handlers and counters for an IRQ line.
+#[derive(Copy, Clone)]
+struct Line { /// The handlers registered for this line
+              handlers: [Option<IrqHandler>; MAX_SHARED_HANDLERS]
+            , /// The number of times this IRQ has fired
+              count: usize
+            , /// The number of times no handler handled this IRQ
+              unhandled: usize
+            }
+
+impl Line {
+    const fn new() -> Self {
+        Line { handlers: [None; MAX_SHARED_HANDLERS]
+             , count: 0
+             , unhandled: 0
+             }
+    }
+}
+
+/// Every IRQ line, indexed by line number.
+///
+/// This is locked by IRQ handlers, so it must only be locked with interrupts
+/// disabled.
+static LINES: Mutex<[Line; N_IRQS as usize]>
+    = Mutex::new([Line::new(); N_IRQS as usize]);
+
+/// Register `handler` to be called when `irq` fires.
+///
+/// Other handlers registered for the same IRQ are still called.
+///
+/// # Returns
+/// + `Ok(())` if the handler was registered
+/// + `Err` if `irq` already has `MAX_SHARED_HANDLERS` handlers.
+pub fn register_irq(irq: IRQ, handler: IrqHandler)
+                   -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut lines = LINES.lock();
+        let slot = lines[irq.line() as usize].handlers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("Too many handlers share that IRQ!")?;
+        *slot = Some(handler);
+        Ok(())
+    })
+}
+
+/// Stop calling `handler` when `irq` fires.
+///
+/// # Returns
+/// + `Ok(())` if the handler was unregistered
+/// + `Err` if `handler` was not registered for `irq`.
+pub fn unregister_irq(irq: IRQ, handler: IrqHandler)
+                     -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut lines = LINES.lock();
+        let slot = lines[irq.line() as usize].handlers
+            .iter_mut()
+            .find(|slot| slot.map(|h| h as usize) == Some(handler as usize))
+            .ok_or("Handler is not registered for that IRQ!")?;
+        *slot = None;
+        Ok(())
+    })
+}
+
+/// Returns the number of times `irq` has fired.
+pub fn count(irq: IRQ) -> usize {
+    interrupts::without_interrupts(|| LINES.lock()[irq.line() as usize].count)
+}
+
+/// Returns the number of times `irq` fired without any handler handling it.
+pub fn unhandled_count(irq: IRQ) -> usize {
+    interrupts::without_interrupts(||
+        LINES.lock()[irq.line() as usize].unhandled)
+}
+
+/// Call the handlers registered for the IRQ `line`, and end the interrupt.
+fn dispatch(line: u8, frame: &InterruptFrame) {
+    let _gs = unsafe { KernelGs::enter(frame) };
+
+    // copy the handlers out, so the lock isn't held while they run.
+    let handlers = {
+        let mut lines = LINES.lock();
+        lines[line as usize].count += 1;
+        lines[line as usize].handlers
+    };
+    // every handler is called, since more than one device on a shared line
+    // may need attention.
+    let handled = handlers.iter()
+                          .filter_map(|handler| *handler)
+                          .fold(false, |handled, handler|
+                                handler(frame) == IrqResult::Handled
+                                || handled);
+    if !handled {
+        LINES.lock()[line as usize].unhandled += 1;
+        trace!("unhandled IRQ {}", line);
+    }
+
+    unsafe { interrupts::end_of_interrupt(IRQ_BASE + line) }
+
+    // now that the IRQ has ended, the handlers may have decided that
+    // another thread should run.
+    ::thread::irq_exit(frame);
+}
+
+macro_rules! irq_stubs {
+    ( $($line:expr => $name:ident),+ ) => {
+        $(
+            #[doc(hidden)]
+            extern "x86-interrupt" fn $name(frame: &InterruptFrame) {
+                dispatch($line, frame)
+            }
+        )+
+
+        /// The vector stubs, indexed by IRQ line.
+        static STUBS: [InterruptHandler; N_IRQS as usize]
+            = [ $($name as InterruptHandler),+ ];
+    }
+}
+
+irq_stubs! {
+    0 => irq_0, 1 => irq_1, 2 => irq_2, 3 => irq_3,
+    4 => irq_4, 5 => irq_5, 6 => irq_6, 7 => irq_7,
+    8 => irq_8, 9 => irq_9, 10 => irq_10, 11 => irq_11,
+    12 => irq_12, 13 => irq_13, 14 => irq_14, 15 => irq_15
+}
+
+/// Point the IDT gates for the legacy IRQ vectors at the dispatch stubs.
+pub fn install_stubs(idt: &mut Idt) {
+    let base = IRQ_BASE as usize - NUM_EXCEPTIONS;
+    for (line, stub) in STUBS.iter().enumerate() {
+        idt.interrupts[base + line] = Gate::from(*stub);
+    }
+}
@@ -8,8 +8,21 @@
 //
 //! `x86_64` architecture-specific implementation.
 // pub mod cpu;
+#[macro_use] pub mod percpu;
+pub mod acpi;
+pub mod backtrace;
+pub mod crashdump;
+pub mod smp;
+pub mod debug;
 pub mod drivers;
+pub mod exceptions;
+pub mod extable;
+pub mod fpu;
 pub mod interrupts;
+pub mod irq;
+pub mod symbols;
+pub mod syscall;
+pub mod usercopy;
 
 #[path = "../x86_all/bda.rs"] pub mod bda;
 #[path = "../x86_all/multiboot2.rs"] pub mod multiboot2;
@@ -52,6 +65,7 @@ pub unsafe extern "C" fn long_mode_init() {
           mov es, ax
           mov fs, ax
           mov gs, ax
+          xor rbp, rbp
           call arch_init"
         :::: "intel");
 
@@ -66,7 +80,7 @@ pub unsafe extern "C" fn long_mode_init() {
 /// bad problem and not go to space today.
 #[no_mangle]
 pub extern "C" fn arch_init(multiboot_addr: PAddr) {
-    use cpu::{control_regs, msr};
+    use cpu::{control_regs, cpuid, msr};
     use elf;
     use params::{InitParams, mem};
 
@@ -76,6 +90,17 @@ pub extern "C" fn arch_init(multiboot_addr: PAddr) {
     ::logger::initialize()
         .expect("Could not initialize logger!");
 
+    // -- Replace the boot GDT ------------------------------------------------
+    // this is safe; we're still single-threaded and interrupts are disabled.
+    unsafe {
+        ::cpu::segment::initialize();
+        // point %gs at the bootstrap processor's per-CPU data.
+        smp::init_bsp();
+    }
+    kinfoln!(dots: " . ", "Loaded kernel GDT, TSS, and per-CPU data");
+
+    // -- Detect CPU features -------------------------------------------------
+    kinfoln!(dots: " . ", "CPU: {}", cpuid::cpu_features());
 
     // -- Unpack multiboot tag ------------------------------------------------
     kinfoln!( dots: " . "
@@ -122,6 +147,14 @@ pub extern "C" fn arch_init(multiboot_addr: PAddr) {
     kinfoln!( dots: " . . ", "Kernel begins at {:#p} and ends at {:#p}."
             , kernel_begin, kernel_end );
 
+    // -- Load the kernel symbol table for backtraces -------------------------
+    match symbols::initialize(elf_sections_tag) {
+        Ok(n) => kinfoln!(dots: " . ", "Loaded {} kernel symbols", n)
+      , Err(why) => kinfoln!( dots: " . "
+                            , "Backtraces will have no symbols: {}", why)
+    }
+    ::vga::panic::set_hook(crashdump::panic_hook);
+
     let multiboot_end = multiboot_addr + boot_info.length as u64;
 
     kinfoln!( dots: " . . ", "Multiboot info begins at {:#x} and ends at {:#x}."
@@ -139,6 +172,15 @@ pub extern "C" fn arch_init(multiboot_addr: PAddr) {
                             , ..Default::default()
                         };
 
+    // -- Find the ACPI RSDP ---------------------------------------------------
+    // prefer the bootloader's copy; otherwise, scan the BIOS areas while
+    // they're still identity mapped.
+    params.acpi_rsdp = boot_info.acpi_rsdp().or_else(acpi::scan_for_rsdp);
+    match params.acpi_rsdp {
+        Some(rsdp) => kinfoln!(dots: " . ", "Found ACPI RSDP at {:#p}", rsdp)
+      , None => kinfoln!(dots: " . ", "ACPI RSDP not found")
+    }
+
     // Extract the memory map tag from the multiboot info
     let mem_map = boot_info.mem_map()
                            .expect("Memory map tag required!");
@@ -149,11 +191,14 @@ pub extern "C" fn arch_init(multiboot_addr: PAddr) {
         let a: mem::Area = area.into();
         if a.is_usable == true { params.mem_map.push(a); }
     }
+    crashdump::set_memory_map( mem_map
+                             , (params.kernel_base, params.kernel_top)
+                             , (params.heap_base, params.heap_top));
 
      //-- enable flags needed for paging ------------------------------------
      unsafe {
-        //  control_regs::cr0::enable_write_protect(true);
-        //  kinfoln!(dots: " . ", "Page write protect ENABED" );
+        control_regs::cr0::enable_write_protect(true);
+        kinfoln!(dots: " . ", "Page write protect ENABLED" );
 
         let efer = msr::read(msr::IA32_EFER);
         trace!("EFER = {:#x}", efer);
@@ -163,6 +208,46 @@ pub extern "C" fn arch_init(multiboot_addr: PAddr) {
         kinfoln!(dots: " . ", "Page no execute bit ENABLED");
      }
 
+    //-- enable supervisor mode protections ---------------------------------
+    unsafe {
+        use cpu::control_regs::cr4;
+        if cpuid::has_smep() {
+            cr4::enable_smep(true);
+            kinfoln!( dots: " . "
+                    , "Supervisor mode execution protection ENABLED");
+        } else {
+            kinfoln!( dots: " . "
+                    , "Supervisor mode execution protection UNSUPPORTED");
+        }
+        if cpuid::has_smap() {
+            cr4::enable_smap(true);
+            kinfoln!(dots: " . ", "Supervisor mode access prevention ENABLED");
+        } else {
+            kinfoln!( dots: " . "
+                    , "Supervisor mode access prevention UNSUPPORTED");
+        }
+        if cpuid::has_umip() {
+            cr4::enable_umip(true);
+            kinfoln!(dots: " . ", "User mode instruction prevention ENABLED");
+        } else {
+            kinfoln!( dots: " . "
+                    , "User mode instruction prevention UNSUPPORTED");
+        }
+
+        // this is safe; we are still using the boot page tables, so the
+        // current PCID is 0.
+        if ::paging::arch::pcid::enable() {
+            kinfoln!(dots: " . ", "Process-context identifiers ENABLED");
+        } else {
+            kinfoln!(dots: " . ", "Process-context identifiers UNSUPPORTED");
+        }
+
+        match ::paging::arch::pat::initialize() {
+            Ok(()) => kinfoln!(dots: " . ", "Page attribute table PROGRAMMED")
+          , Err(why) => kinfoln!(dots: " . ", "{}", why)
+        }
+    }
+
     kinfoln!(dots: " . ", "Transferring to `kernel_init()`.");
     ::kernel_init(&params);
 }
@@ -0,0 +1,225 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Per-CPU variables.
+//!
+//! Per-CPU variables are declared with the [`percpu!`] macro, which places
+//! them in the `.percpu` linker section. That section is only a template:
+//! each CPU gets its own copy of it when it starts (the BSP's copy is
+//! reserved in `.bss` by the linker script, and the APs' are allocated on
+//! the heap). A [`PerCpu`] is accessed at the same offset in the current
+//! CPU's copy, which is found through the [`Cpu`] at `%gs:0`.
+//!
+//! # `swapgs`
+//! While the kernel is running, the `%gs` base points at the current CPU's
+//! `Cpu`. When returning to user mode, `swapgs` exchanges it with the user
+//! `%gs` base in `IA32_KERNEL_GS_BASE`, and the next entry into the kernel
+//! has to swap it back before anything calls [`this_cpu`]:
+//!
+//!  + `syscall` entry always comes from user mode, and always swaps.
+//!  + interrupt handlers use [`KernelGs::enter`], which swaps only if the
+//!    interrupted code was running in user mode.
+//!  + NMI, machine check, and double fault handlers can interrupt the
+//!    kernel before its own `swapgs`, so they use [`KernelGs::paranoid`],
+//!    which checks the `%gs` base itself.
+//!
+//! Dropping the returned [`KernelGs`] swaps back, if needed.
+//!
+//! [`percpu!`]: ../../macro.percpu.html
+//! [`PerCpu`]: struct.PerCpu.html
+//! [`Cpu`]: ../smp/struct.Cpu.html
+//! [`this_cpu`]: fn.this_cpu.html
+//! [`KernelGs`]: struct.KernelGs.html
+//! [`KernelGs::enter`]: struct.KernelGs.html#method.enter
+//! [`KernelGs::paranoid`]: struct.KernelGs.html#method.paranoid
+use super::smp::{self, Cpu};
+use cpu::{cpuid, interrupts, msr};
+use cpu::context::InterruptFrame;
+
+use core::cell::UnsafeCell;
+use core::ptr;
+
+extern {
+    /// Start of the `.percpu` template section
+    static __percpu_start: u8;
+    /// End of the `.percpu` template section
+    static __percpu_end: u8;
+    /// The BSP's copy of the `.percpu` section
+    static mut __percpu_bsp: u8;
+}
+
+/// Declare per-CPU variables.
+///
+/// Each variable is a `static` [`PerCpu`], placed in the `.percpu` section,
+/// so every CPU gets its own copy, starting out with the given value.
+///
+/// # Examples
+/// ```ignore
+/// percpu! {
+///     /// Number of timer ticks seen by this CPU
+///     static TICKS: Cell<u64> = Cell::new(0);
+/// }
+///
+/// TICKS.with(|ticks| ticks.set(ticks.get() + 1));
+/// ```
+///
+/// [`PerCpu`]: arch/percpu/struct.PerCpu.html
+#[macro_export]
+macro_rules! percpu {
+    ( $(#[$attr:meta])* static $name:ident: $ty:ty = $init:expr; $($tail:tt)* ) => {
+        $(#[$attr])*
+        #[link_section = ".percpu"]
+        static $name: $crate::arch::percpu::PerCpu<$ty>
+            = $crate::arch::percpu::PerCpu::new($init);
+        percpu! { $($tail)* }
+    };
+    ( $(#[$attr:meta])* pub static $name:ident: $ty:ty = $init:expr; $($tail:tt)* ) => {
+        $(#[$attr])*
+        #[link_section = ".percpu"]
+        pub static $name: $crate::arch::percpu::PerCpu<$ty>
+            = $crate::arch::percpu::PerCpu::new($init);
+        percpu! { $($tail)* }
+    };
+    () => {};
+}
+
+/// Returns the size of the `.percpu` section, in bytes.
+#[inline]
+pub fn size() -> usize {
+    unsafe {
+        &__percpu_end as *const u8 as usize
+            - &__percpu_start as *const u8 as usize
+    }
+}
+
+/// Returns the address of the BSP's copy of the `.percpu` section.
+#[inline]
+pub fn bsp_area() -> *mut u8 {
+    unsafe { &mut __percpu_bsp as *mut u8 }
+}
+
+/// Copy the initial values of the per-CPU variables to `area`.
+///
+/// # Safety
+/// + `area` must be valid for `size()` bytes, and must be aligned at least
+///   as strictly as the `.percpu` section (to a page).
+pub unsafe fn initialize_area(area: *mut u8) {
+    ptr::copy_nonoverlapping(&__percpu_start as *const u8, area, size());
+}
+
+/// Returns the current CPU's `Cpu`.
+///
+/// This relies on the kernel's `%gs` base being loaded whenever kernel code
+/// runs (see the module-level docs), and must not be called before
+/// `smp::init_bsp()`.
+#[inline]
+pub fn this_cpu() -> &'static Cpu {
+    unsafe {
+        let cpu: *const Cpu;
+        asm!(  "mov $0, gs:[0]"
+            :  "=r"(cpu)
+            ::: "intel", "volatile");
+        &*cpu
+    }
+}
+
+/// A per-CPU variable.
+///
+/// These should be declared with the [`percpu!`] macro; a `PerCpu` that is
+/// not in the `.percpu` section doesn't have per-CPU copies.
+///
+/// [`percpu!`]: ../../macro.percpu.html
+pub struct PerCpu<T> { value: UnsafeCell<T> }
+
+// each CPU only ever accesses its own copy of the value, either through a
+// shared reference (if `T: Sync`) or with interrupts disabled.
+unsafe impl<T> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(value: T) -> Self {
+        PerCpu { value: UnsafeCell::new(value) }
+    }
+
+    /// Returns a pointer to the current CPU's copy of this variable.
+    #[inline]
+    pub fn as_ptr(&'static self) -> *mut T {
+        let offset = self as *const Self as usize
+                   - unsafe { &__percpu_start as *const u8 as usize };
+        (this_cpu().percpu_area() as usize + offset) as *mut T
+    }
+
+    /// Calls `f` with the current CPU's copy of this variable.
+    ///
+    /// Interrupts are disabled while `f` runs, so nothing else can access
+    /// the value, and we can't be moved to another CPU.
+    #[inline]
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where F: FnOnce(&T) -> R {
+        interrupts::without_interrupts(|| f(unsafe { &*self.as_ptr() }))
+    }
+}
+
+impl<T: Sync> PerCpu<T> {
+    /// Returns the current CPU's copy of this variable.
+    ///
+    /// If we move to another CPU, the reference still points at the copy
+    /// belonging to the CPU we started on, which is why `T` has to be
+    /// `Sync`.
+    #[inline]
+    pub fn get(&'static self) -> &'static T {
+        unsafe { &*self.as_ptr() }
+    }
+}
+
+/// Makes sure the kernel's `%gs` base is loaded while handling an interrupt.
+///
+/// When this is dropped, the `%gs` base it replaced is swapped back.
+#[must_use]
+pub struct KernelGs { swapped: bool }
+
+impl KernelGs {
+    /// Swap in the kernel's `%gs` base if `frame` came from user mode.
+    ///
+    /// # Safety
+    /// + This must be called before anything else in the interrupt handler
+    ///   uses `%gs`, and the result must live until the handler returns.
+    #[inline]
+    pub unsafe fn enter(frame: &InterruptFrame) -> Self {
+        let swapped = frame.is_from_user();
+        if swapped { msr::swapgs() }
+        KernelGs { swapped: swapped }
+    }
+
+    /// Swap in the kernel's `%gs` base if it isn't loaded.
+    ///
+    /// Unlike `enter`, this doesn't trust the interrupted code segment, so
+    /// it's correct even if we interrupted the kernel between its entry
+    /// point and its `swapgs`. It doesn't take any locks, so it may be used
+    /// from NMI handlers.
+    ///
+    /// # Safety
+    /// + The same as `enter`.
+    pub unsafe fn paranoid() -> Self {
+        let gs_base = msr::read(msr::IA32_GS_BASE);
+        let swapped = match smp::cpu_with_apic_id(cpuid::apic_id()) {
+            Some(cpu) => gs_base != cpu as *const Cpu as u64
+            // if this CPU isn't online yet, it hasn't been to user mode.
+          , None => false
+        };
+        if swapped { msr::swapgs() }
+        KernelGs { swapped: swapped }
+    }
+}
+
+impl Drop for KernelGs {
+    #[inline]
+    fn drop(&mut self) {
+        if self.swapped { unsafe { msr::swapgs() } }
+    }
+}
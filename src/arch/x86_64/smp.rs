@@ -0,0 +1,511 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Symmetric multiprocessing.
+//!
+//! At boot, only the _bootstrap processor_ (BSP) is running. The other CPUs,
+//! the _application processors_ (APs), are listed in the ACPI MADT, and are
+//! started by sending each one an `INIT` IPI followed by two `STARTUP` IPIs.
+//! A `STARTUP` IPI starts the AP in real mode at the start of a page below
+//! 1 MiB, so we copy a small trampoline there, which switches the AP to long
+//! mode with the kernel's page tables and calls [`ap_main`].
+//!
+//! Each CPU has a [`Cpu`] structure, which `%gs` points at, and its own copy
+//! of the per-CPU variables (see the [`percpu`] module). APs also get their
+//! own GDT, TSS, kernel stack, and interrupt stacks. The BSP keeps using the
+//! ones it loaded in `arch_init`.
+//!
+//! For more information, refer to the _Intel® 64 and IA-32 Architectures
+//! Software Developer’s Manual_, Vol. 3A, section 8.4, "Multiple-Processor
+//! (MP) Initialization".
+//!
+//! [`ap_main`]: fn.ap_main.html
+//! [`Cpu`]: struct.Cpu.html
+//! [`percpu`]: ../percpu/index.html
+use super::percpu::{self, this_cpu};
+use acpi::Madt;
+use acpi::madt::Processor;
+use alloc::FrameAllocator;
+use collections::vec::Vec;
+use cpu::{msr, segment, task};
+use cpu::timer::pit::delay_us;
+use cpu::control_regs::{cr0, cr3, cr4};
+use cpu::interrupts::apic;
+use cpu::segment::Gdt;
+use cpu::task::StateSegment;
+use memory::{PAddr, Page, PhysicalPage, VAddr, VirtualPage, PAGE_SIZE};
+use paging::Mapper;
+use paging::arch::ActivePageTable;
+use paging::arch::pat::{ioremap, iounmap, CacheType};
+use paging::arch::table::PRESENT;
+use thread::stack::Stack;
+
+use core::{mem, ptr};
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// Maximum number of CPUs we support.
+///
+/// This is the number of bits in the online CPU mask.
+pub const MAX_CPUS: usize = 64;
+
+/// Physical address that the AP trampoline is copied to.
+///
+/// This must be page-aligned and below 1 MiB, and must match the address
+/// the trampoline is assembled for. The frame allocator never hands out
+/// frames this low.
+pub const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+/// Size of each application processor's kernel stack, in bytes.
+pub const AP_STACK_SIZE: usize = 4096 * 8;
+
+/// Per-CPU data.
+///
+/// Each CPU's `%gs` base points at its `Cpu`. The system call entry stub
+/// finds `kernel_stack` and `user_stack` at fixed offsets from `%gs`, so the
+/// first three fields must not be moved.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Cpu { /// Pointer to this `Cpu`, so it can be found at `%gs:0`
+                 this: *const Cpu
+               , /// The top of the stack to run system calls on (`%gs:8`)
+                 kernel_stack: VAddr
+               , /// The user stack pointer, while entering a system call
+                 /// (`%gs:16`)
+                 user_stack: u64
+               , /// The logical CPU number (0 for the BSP)
+                 pub id: usize
+               , /// The ID of this CPU's local APIC
+                 pub apic_id: u32
+               , /// This CPU's copy of the per-CPU variables
+                 percpu: *mut u8
+               }
+
+// the fields that aren't `Sync` are `this` and `percpu`, which are never
+// changed once the `Cpu` is in use.
+unsafe impl Sync for Cpu {}
+
+impl Cpu {
+    const fn new( id: usize, apic_id: u32, percpu: *mut u8
+                , kernel_stack: VAddr) -> Self {
+        Cpu { this: ptr::null()
+            , kernel_stack: kernel_stack
+            , user_stack: 0
+            , id: id
+            , apic_id: apic_id
+            , percpu: percpu
+            }
+    }
+
+    /// Returns the top of the stack that system calls run on.
+    #[inline]
+    pub fn kernel_stack(&self) -> VAddr { self.kernel_stack }
+
+    /// Returns the address of this CPU's copy of the per-CPU variables.
+    #[inline]
+    pub fn percpu_area(&self) -> *mut u8 { self.percpu }
+
+    /// Point `%gs` at this `Cpu`.
+    ///
+    /// There's no user `%gs` yet, so `IA32_KERNEL_GS_BASE` is cleared.
+    unsafe fn load(&'static mut self) {
+        self.this = self as *const Cpu;
+        msr::write(msr::IA32_GS_BASE, self.this as u64);
+        msr::write(msr::IA32_KERNEL_GS_BASE, 0);
+    }
+}
+
+/// The bootstrap processor's `Cpu`.
+///
+/// This is a static, so that `%gs` can be set up before the heap is.
+static mut BSP: Cpu = Cpu::new(0, 0, ptr::null_mut(), VAddr::new(0));
+
+/// Every CPU that has come online, indexed by CPU number.
+///
+/// Each entry is written once, by its CPU, before that CPU's bit in `ONLINE`
+/// is set, and only read after the bit is set, so this doesn't need a lock
+/// (which means it's safe to use from NMI handlers).
+static mut CPUS: [Option<&'static Cpu>; MAX_CPUS] = [None; MAX_CPUS];
+
+/// Bitmask of the CPUs that are online.
+static ONLINE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The CPU number of the last application processor to get through the
+/// trampoline.
+///
+/// Once an AP has, the trampoline's stack and argument can be changed for
+/// the next one.
+static LEFT_TRAMPOLINE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Returns the `Cpu` with the CPU number `id`, if it is online.
+#[inline]
+pub fn cpu(id: usize) -> Option<&'static Cpu> {
+    if is_online(id) { unsafe { CPUS[id] } } else { None }
+}
+
+/// Returns the online `Cpu` whose local APIC has the ID `apic_id`, if any.
+pub fn cpu_with_apic_id(apic_id: u32) -> Option<&'static Cpu> {
+    (0..MAX_CPUS).filter_map(cpu)
+                 .find(|cpu| cpu.apic_id == apic_id)
+}
+
+/// Returns true if the CPU with the CPU number `id` is online.
+#[inline]
+pub fn is_online(id: usize) -> bool {
+    id < MAX_CPUS && ONLINE.load(Ordering::Acquire) & (1 << id) != 0
+}
+
+/// Returns the number of CPUs that are online.
+#[inline]
+pub fn n_online() -> usize {
+    ONLINE.load(Ordering::Acquire).count_ones() as usize
+}
+
+/// Add `cpu` to the online CPUs.
+fn set_online(cpu: &'static Cpu) {
+    unsafe { CPUS[cpu.id] = Some(cpu); }
+    ONLINE.fetch_or(1 << cpu.id, Ordering::Release);
+}
+
+/// Point the bootstrap processor's `%gs` at its `Cpu`, and mark it online.
+///
+/// The BSP's system calls, and interrupts from user mode, run on the boot
+/// stack.
+///
+/// # Safety
+/// + This should be called once, by the bootstrap processor, during early
+///   init.
+pub unsafe fn init_bsp() {
+    let stack = VAddr::from(super::STACK_TOP as usize);
+    BSP.kernel_stack = stack;
+    task::TSS.rsp[0] = stack;
+    BSP.percpu = percpu::bsp_area();
+    percpu::initialize_area(BSP.percpu);
+    BSP.load();
+    set_online(&BSP);
+}
+
+// -- The AP trampoline ------------------------------------------------------
+//
+// The trampoline is assembled to run at `TRAMPOLINE_ADDR` (0x8000), and is
+// copied there before the APs are started. It loads a temporary GDT, enters
+// protected mode, enables PAE, loads `%cr3`, sets `EFER.LME` and `EFER.NXE`,
+// and enables paging, which puts the CPU in long mode. Then it loads the
+// stack pointer, and calls the entry point with one argument.
+//
+// Since `%cr3` is loaded in protected mode, the PML4 must be below 4 GiB.
+global_asm!(r#"
+    .pushsection .text.ap_trampoline, "ax"
+    .global ap_trampoline_start
+    .global ap_trampoline_end
+    .global ap_trampoline_cr3
+    .global ap_trampoline_stack
+    .global ap_trampoline_entry
+    .global ap_trampoline_arg
+
+    .code16
+ap_trampoline_start:
+    cli
+    cld
+    xorw %ax, %ax
+    movw %ax, %ds
+    lgdtl (ap_trampoline_gdt_ptr - ap_trampoline_start + 0x8000)
+    movl %cr0, %eax
+    orl $1, %eax
+    movl %eax, %cr0
+    ljmpl $0x08, $(ap_trampoline_32 - ap_trampoline_start + 0x8000)
+
+    .code32
+ap_trampoline_32:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movl %cr4, %eax
+    orl $(1 << 5), %eax
+    movl %eax, %cr4
+    movl (ap_trampoline_cr3 - ap_trampoline_start + 0x8000), %eax
+    movl %eax, %cr3
+    movl $0xc0000080, %ecx
+    rdmsr
+    orl $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+    movl %cr0, %eax
+    orl $0x80010000, %eax
+    movl %eax, %cr0
+    ljmpl $0x18, $(ap_trampoline_64 - ap_trampoline_start + 0x8000)
+
+    .code64
+ap_trampoline_64:
+    xorw %ax, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movq (ap_trampoline_stack - ap_trampoline_start + 0x8000), %rsp
+    movq (ap_trampoline_arg - ap_trampoline_start + 0x8000), %rdi
+    movq (ap_trampoline_entry - ap_trampoline_start + 0x8000), %rax
+    xorq %rbp, %rbp
+    callq *%rax
+1:  hlt
+    jmp 1b
+
+    .align 8
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff
+    .quad 0x00cf92000000ffff
+    .quad 0x00209a0000000000
+ap_trampoline_gdt_ptr:
+    .word ap_trampoline_gdt_ptr - ap_trampoline_gdt - 1
+    .long ap_trampoline_gdt - ap_trampoline_start + 0x8000
+
+    .align 8
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_arg:
+    .quad 0
+ap_trampoline_end:
+    .popsection
+"#);
+
+extern {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_arg: u8;
+}
+
+/// Returns the offset of `symbol` from the start of the trampoline.
+#[inline]
+fn trampoline_offset(symbol: &'static u8) -> usize {
+    unsafe {
+        symbol as *const u8 as usize
+            - &ap_trampoline_start as *const u8 as usize
+    }
+}
+
+/// Everything an application processor needs to start up.
+struct ApStart { /// The AP's per-CPU data
+                 cpu: Cpu
+               , /// The AP's GDT
+                 gdt: Gdt
+               , /// The AP's TSS
+                 tss: StateSegment
+               , /// The bootstrap processor's `%cr0`
+                 cr0: cr0::Flags
+               , /// The bootstrap processor's `%cr4`
+                 cr4: cr4::Flags
+               , /// The bootstrap processor's `IA32_EFER`
+                 efer: u64
+               , /// The bootstrap processor's `IA32_PAT`
+                 pat: u64
+               }
+
+/// Move `value` to the heap, and never free it.
+fn leak<T>(value: T) -> &'static mut T {
+    let mut vec = Vec::with_capacity(1);
+    vec.push(value);
+    let ptr = vec.as_mut_ptr();
+    mem::forget(vec);
+    unsafe { &mut *ptr }
+}
+
+/// Allocate and initialize a copy of the per-CPU variables on the heap.
+fn allocate_percpu() -> *mut u8 {
+    // the copy has to be aligned like the `.percpu` section is.
+    let len = percpu::size() + PAGE_SIZE as usize;
+    let mut area: Vec<u64> = Vec::with_capacity(len / 8 + 1);
+    let base = (area.as_mut_ptr() as usize + PAGE_SIZE as usize - 1)
+             & !(PAGE_SIZE as usize - 1);
+    mem::forget(area);
+    unsafe { percpu::initialize_area(base as *mut u8); }
+    base as *mut u8
+}
+
+/// Allocate a stack of `size` bytes, with a guard page made in `table`, and
+/// return its top.
+///
+/// The stack is never freed, since its CPU runs on it from now on.
+fn allocate_stack(size: usize, table: &mut ActivePageTable)
+                  -> Result<VAddr, &'static str> {
+    Stack::new_in(size, table).map(Stack::leak)
+}
+
+/// Allocate an AP's kernel stack and interrupt stacks, and return the top
+/// of each.
+///
+/// If one can't be allocated, the ones before it are leaked, since stacks
+/// can't be freed until the kernel's page table is handed over.
+fn allocate_stacks(table: &mut ActivePageTable)
+                   -> Result< (VAddr, [VAddr; task::N_IST_STACKS])
+                            , &'static str> {
+    let stack = allocate_stack(AP_STACK_SIZE, table)?;
+    let mut ist = [VAddr::from(0); task::N_IST_STACKS];
+    for top in ist.iter_mut() {
+        *top = allocate_stack(task::IST_STACK_SIZE, table)?;
+    }
+    Ok((stack, ist))
+}
+
+/// Entry point for application processors, called by the trampoline.
+extern "C" fn ap_main(start: &'static mut ApStart) -> ! {
+    LEFT_TRAMPOLINE.store(start.cpu.id, Ordering::Release);
+    unsafe {
+        // the trampoline only set the bits it needed to reach long mode.
+        cr0::write(start.cr0);
+        cr4::write(start.cr4);
+        msr::write(msr::IA32_EFER, start.efer);
+        msr::write(msr::IA32_PAT, start.pat);
+
+        segment::load(&mut start.gdt, &start.tss);
+        super::interrupts::initialize_ap();
+        start.cpu.load();
+        super::syscall::initialize();
+        super::debug::load();
+    }
+    let cpu = this_cpu();
+
+    if let Err(why) = unsafe { super::fpu::initialize(None) } {
+        kinfoln!( dots: " . . ", "CPU {} could not enable the FPU: {}"
+                , cpu.id, why);
+    }
+
+    match unsafe { apic::initialize_ap() } {
+        Ok(_) => {
+            set_online(cpu);
+            kinfoln!( dots: " . . ", "CPU {} (local APIC {}) is online"
+                    , cpu.id, cpu.apic_id);
+        }
+      , Err(why) => kinfoln!( dots: " . . ", "CPU {} failed to start: {}"
+                            , cpu.id, why)
+    }
+
+    // there's nothing for APs to do yet.
+    loop {
+        unsafe { asm!("cli; hlt" :::: "volatile"); }
+    }
+}
+
+/// Start the application processors listed in the ACPI `madt`.
+///
+/// The APIC must already be enabled, and `init_bsp` must have been called.
+///
+/// # Returns
+/// + `Ok(n)` with the number of CPUs now online
+/// + `Err` if the APs could not be started.
+///
+/// # Safety
+/// + This should only be called once, by the bootstrap processor.
+pub unsafe fn initialize<A>( madt: &Madt
+                           , table: &mut ActivePageTable
+                           , alloc: &mut A)
+                           -> Result<usize, &'static str>
+where A: FrameAllocator {
+    let lapic = apic::local().ok_or("APIC is not enabled!")?;
+
+    let cr3 = cr3::current_pagetable_frame().base_addr();
+    if *cr3 > u32::max_value() as u64 {
+        return Err("Kernel PML4 is above 4 GiB!")
+    }
+
+    // -- copy the trampoline below 1 MiB ------------------------------------
+    let frame = PhysicalPage::containing(PAddr::from(TRAMPOLINE_ADDR));
+    let trampoline = ioremap( frame.base_addr(), PAGE_SIZE as usize
+                            , CacheType::WriteBack, table, alloc)?;
+    let len = trampoline_offset(&ap_trampoline_end);
+    ptr::copy_nonoverlapping( &ap_trampoline_start as *const u8
+                            , trampoline.as_mut_ptr::<u8>(), len);
+    let base = trampoline.as_mut_ptr::<u8>();
+    let set = move |symbol: &'static u8, value: u64| {
+        let field = base.offset(trampoline_offset(symbol) as isize);
+        ptr::write_volatile(field as *mut u64, value);
+    };
+    set(&ap_trampoline_cr3, *cr3);
+    set(&ap_trampoline_entry, ap_main as usize as u64);
+
+    // the trampoline keeps running at the same address once paging is
+    // enabled, so it has to be identity mapped (and executable).
+    table.identity_map(frame, PRESENT, alloc);
+
+    let bsp = lapic.id();
+    BSP.apic_id = bsp;
+    let mut next_id = 1;
+    for Processor { apic_id, .. } in madt.processors()
+                                          .filter(|p| p.is_enabled
+                                                   && p.apic_id != bsp) {
+        if next_id >= MAX_CPUS {
+            kinfoln!( dots: " . . ", "Ignoring CPUs past the first {}"
+                    , MAX_CPUS);
+            break
+        }
+        let id = next_id;
+        next_id += 1;
+
+        let (stack, ist) = match allocate_stacks(table) {
+            Ok(stacks) => stacks
+          , Err(why) => {
+                kinfoln!( dots: " . . "
+                        , "Could not allocate stacks for CPU {}: {}", id, why);
+                break
+            }
+        };
+        let percpu = allocate_percpu();
+        let start = leak(ApStart { cpu: Cpu::new(id, apic_id, percpu, stack)
+                                 , gdt: Gdt::new()
+                                 , tss: StateSegment::new()
+                                 , cr0: cr0::read()
+                                 , cr4: cr4::read()
+                                 , efer: msr::read(msr::IA32_EFER)
+                                 , pat: msr::read(msr::IA32_PAT)
+                                 });
+        for (index, &top) in (1..).zip(ist.iter()) {
+            start.tss.set_ist(index, top);
+        }
+        // interrupts from user mode and system calls use the same stack.
+        start.tss.rsp[0] = stack;
+        set(&ap_trampoline_stack, *stack as u64);
+        set(&ap_trampoline_arg, start as *mut ApStart as u64);
+
+        // INIT-SIPI-SIPI
+        lapic.send_init(apic_id);
+        delay_us(10_000);
+        for _ in 0..2 {
+            if is_online(id) { break }
+            lapic.send_startup(apic_id, (TRAMPOLINE_ADDR >> 12) as u8);
+            delay_us(200);
+        }
+        // give the AP up to 100ms to come online.
+        for _ in 0..100 {
+            if is_online(id) { break }
+            delay_us(1000);
+        }
+        if !is_online(id) {
+            kinfoln!( dots: " . . ", "CPU {} (local APIC {}) did not start!"
+                    , id, apic_id);
+            if LEFT_TRAMPOLINE.load(Ordering::Acquire) != id {
+                // the AP may still be in the trampoline, and would pick up
+                // the next AP's stack and argument, so put it back in the
+                // wait-for-SIPI state.
+                lapic.send_init(apic_id);
+                delay_us(10_000);
+            }
+            // don't reuse its CPU number, in case it starts up later, or
+            // free its stacks, which it may be running on.
+        }
+    }
+
+    table.unmap_entry(VirtualPage::containing(
+        VAddr::from(TRAMPOLINE_ADDR as usize)));
+    iounmap(trampoline, table);
+    Ok(n_online())
+}
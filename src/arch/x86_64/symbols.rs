@@ -0,0 +1,191 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Looking up kernel symbols, for backtraces.
+//!
+//! The bootloader loads the kernel's `.symtab` and `.strtab` sections along
+//! with the rest of the kernel, and the Multiboot 2 ELF sections tag tells
+//! us where. `kernel_remap` keeps them mapped, read-only.
+//!
+//! Rust symbol names are mangled; [`Demangle`] formats them the way they
+//! appear in the source.
+//!
+//! [`Demangle`]: struct.Demangle.html
+use super::multiboot2::ElfSectionsTag;
+use elf::section::{StrTable, Type};
+use elf::symbol::{self, Symbol64, Type as SymbolType};
+
+use core::{fmt, slice};
+use spin::Once;
+
+/// The kernel's symbol table.
+struct SymbolTable { symbols: &'static [Symbol64]
+                   , strings: StrTable<'static>
+                   }
+
+static SYMBOLS: Once<SymbolTable> = Once::new();
+
+/// A function symbol that an address was resolved to.
+#[derive(Copy, Clone, Debug)]
+pub struct Symbol { /// The function's (mangled) name
+                    pub name: &'static str
+                  , /// How far into the function the address is, in bytes
+                    pub offset: usize
+                  }
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{:#x}", Demangle(self.name), self.offset)
+    }
+}
+
+/// Find the kernel's symbol table in the ELF `sections`.
+///
+/// # Returns
+/// + `Ok(n)` with the number of symbols
+/// + `Err` if the kernel has no symbol table.
+pub fn initialize(sections: &'static ElfSectionsTag)
+                 -> Result<usize, &'static str> {
+    let symtab = (0..sections.n_sections)
+        .filter_map(|i| sections.section(i))
+        .find(|s| s.get_type() == Ok(Type::SymbolTable))
+        .ok_or("Kernel has no symbol table!")?;
+    // a symbol table's `sh_link` is the index of its string table.
+    let strtab = match sections.section(symtab.link()) {
+        Some(s) if s.get_type() == Ok(Type::StringTable) => s
+      , _ => return Err("Kernel symbol table has no string table!")
+    };
+    // this is safe; the bootloader loaded both sections, and they stay
+    // mapped for as long as the kernel runs.
+    let table = unsafe {
+        SymbolTable {
+            symbols: symbol::table( *symtab.address() as usize
+                                  , symtab.length())
+          , strings: StrTable::from(slice::from_raw_parts(
+                *strtab.address() as usize as *const u8
+              , strtab.length()))
+        }
+    };
+    Ok(SYMBOLS.call_once(|| table).symbols.len())
+}
+
+/// Returns the function containing `addr`, if there is a symbol for it.
+pub fn resolve(addr: usize) -> Option<Symbol> {
+    let table = match SYMBOLS.try() {
+        Some(table) => table
+      , None => return None
+    };
+    table.symbols.iter()
+         .find(|s| s.get_type() == SymbolType::Function &&
+                   s.contains(addr as u64))
+         .and_then(|s| s.name(&table.strings).map(|name| {
+             Symbol { name: name, offset: addr - s.value() as usize }
+         }))
+}
+
+/// Formats a mangled Rust symbol name as a path, like
+/// `kernel::arch::interrupts::page_fault`.
+///
+/// Names that aren't mangled are written as they are.
+pub struct Demangle<'a>(pub &'a str);
+
+impl<'a> Demangle<'a> {
+    /// Split the mangled name into its path components.
+    ///
+    /// Returns `None` if the name isn't a well-formed mangled name.
+    fn components(&self) -> Option<Components<'a>> {
+        let name = self.0;
+        if name.starts_with("_ZN") && name.ends_with('E') {
+            Some(Components(&name[3..name.len() - 1]))
+        } else { None }
+    }
+}
+
+/// An iterator over the length-prefixed components of a mangled name.
+#[derive(Clone)]
+struct Components<'a>(&'a str);
+
+impl<'a> Iterator for Components<'a> {
+    type Item = Result<&'a str, ()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() { return None }
+        let digits = self.0.bytes().take_while(|&b| b >= b'0' && b <= b'9')
+                         .count();
+        let len = self.0[..digits].parse::<usize>();
+        Some(match len {
+            Ok(len) if digits + len <= self.0.len() => {
+                let component = &self.0[digits..digits + len];
+                self.0 = &self.0[digits + len..];
+                Ok(component)
+            }
+          , _ => { self.0 = ""; Err(()) }
+        })
+    }
+}
+
+/// Returns true if `component` is the hash rustc appends to symbol names.
+fn is_hash(component: &str) -> bool {
+    component.len() == 17 && component.starts_with('h') &&
+    component[1..].bytes().all(|b| (b >= b'0' && b <= b'9') ||
+                                   (b >= b'a' && b <= b'f'))
+}
+
+/// Write a path component, replacing the escapes rustc uses for characters
+/// that can't appear in symbol names.
+fn write_component(f: &mut fmt::Formatter, component: &str) -> fmt::Result {
+    let mut rest = if component.starts_with("_$") { &component[1..] }
+                   else { component };
+    while !rest.is_empty() {
+        if rest.starts_with("..") {
+            f.write_str("::")?;
+            rest = &rest[2..];
+        } else if rest.starts_with('$') {
+            let end = match rest[1..].find('$') {
+                Some(end) => end + 2
+              , None => return f.write_str(rest)
+            };
+            let escaped = match &rest[1..end - 1] {
+                "SP" => "@", "BP" => "*", "RF" => "&", "LT" => "<"
+              , "GT" => ">", "LP" => "(", "RP" => ")", "C" => ","
+              , "u7e" => "~", "u20" => " ", "u27" => "'", "u5b" => "["
+              , "u5d" => "]", "u7b" => "{", "u7d" => "}", "u3b" => ";"
+              , "u2b" => "+", "u22" => "\""
+              , _ => &rest[..end]
+            };
+            f.write_str(escaped)?;
+            rest = &rest[end..];
+        } else {
+            let end = rest.find(|c| c == '$' || c == '.')
+                          .unwrap_or(rest.len());
+            // a lone `.` is written as it is.
+            let end = if end == 0 { 1 } else { end };
+            f.write_str(&rest[..end])?;
+            rest = &rest[end..];
+        }
+    }
+    Ok(())
+}
+
+impl<'a> fmt::Display for Demangle<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let components = match self.components() {
+            Some(ref c) if c.clone().all(|c| c.is_ok()) => c.clone()
+          , _ => return f.write_str(self.0)
+        };
+        let n = components.clone().count();
+        for (i, component) in components.enumerate() {
+            // the checks above mean this can't fail.
+            let component = component.unwrap_or("");
+            if i + 1 == n && is_hash(component) { break }
+            if i > 0 { f.write_str("::")?; }
+            write_component(f, component)?;
+        }
+        Ok(())
+    }
+}
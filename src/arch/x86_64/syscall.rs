@@ -0,0 +1,309 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! System calls.
+//!
+//! User code makes a system call with the `syscall` instruction, with the
+//! system call number in `%rax`, and up to six arguments in `%rdi`, `%rsi`,
+//! `%rdx`, `%r10`, `%r8`, and `%r9`. The result is returned in `%rax`: a
+//! negative value is an error number (see [`Error`]).
+//!
+//! `syscall` doesn't switch stacks, so the entry stub swaps in the kernel's
+//! `%gs` base, and uses it to find the current CPU's kernel stack (see
+//! [`Cpu`]). It saves the caller's [`Registers`], and calls
+//! [`syscall_dispatch`], which looks the system call up in a table.
+//!
+//! System calls run with interrupts disabled. The kernel stack is the CPU's
+//! rather than the calling thread's, so a timer tick that switched threads
+//! partway through a system call would let another thread's system call
+//! overwrite it. System calls are short, so this costs little until threads
+//! have kernel stacks of their own.
+//!
+//! For testing, system calls can also be made with `int 0x80`, with the same
+//! registers.
+//!
+//! [`Error`]: enum.Error.html
+//! [`Cpu`]: ../smp/struct.Cpu.html
+//! [`Registers`]: ../../../cpu/struct.Registers.html
+//! [`syscall_dispatch`]: fn.syscall_dispatch.html
+use super::percpu::this_cpu;
+use super::usercopy::copy_from_user;
+use cpu::{msr, PrivilegeLevel, Registers};
+use cpu::flags::{AC, DF, IF, TF};
+use cpu::interrupts::InterruptHandler;
+use cpu::interrupts::idt::{Gate, Idt};
+use time;
+
+use core::{mem, str};
+
+/// The vector of the `int 0x80` system call gate.
+pub const INT80_VECTOR: u8 = 0x80;
+
+/// Write a string to the console: `(buf: *const u8, len: usize) -> len`
+pub const SYS_WRITE_CONSOLE: u64 = 0;
+/// Returns the time since boot, in nanoseconds: `() -> nanos`
+pub const SYS_UPTIME: u64 = 1;
+/// Returns the number of the CPU the caller is running on: `() -> cpu`
+pub const SYS_CPU_ID: u64 = 2;
+
+/// The longest string `SYS_WRITE_CONSOLE` will write.
+pub const MAX_WRITE_LEN: usize = 256;
+
+/// An error returned by a system call.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error { /// An address argument wasn't valid user memory
+                 BadAddress
+               , /// Some other argument wasn't valid
+                 InvalidArgument
+               , /// There is no system call with that number
+                 NoSuchSyscall
+               }
+
+impl Error {
+    /// Returns the error number user code sees (negated, in `%rax`).
+    ///
+    /// These are the same as Linux's, so that they're familiar.
+    pub fn errno(&self) -> u64 {
+        match *self { Error::BadAddress => 14
+                    , Error::InvalidArgument => 22
+                    , Error::NoSuchSyscall => 38
+                    }
+    }
+}
+
+/// The arguments to a system call.
+pub type Args = [u64; 6];
+
+/// A system call handler.
+pub type Syscall = fn(Args) -> Result<u64, Error>;
+
+/// Every system call, indexed by number.
+static SYSCALLS: [Syscall; 3] = [ write_console
+                                , uptime
+                                , cpu_id
+                                ];
+
+fn write_console(args: Args) -> Result<u64, Error> {
+    let len = args[1] as usize;
+    if len > MAX_WRITE_LEN {
+        return Err(Error::InvalidArgument)
+    }
+    let mut buf = [0u8; MAX_WRITE_LEN];
+    unsafe { copy_from_user(&mut buf[..len], args[0] as *const u8) }
+        .map_err(|_| Error::BadAddress)?;
+    let s = str::from_utf8(&buf[..len])
+               .map_err(|_| Error::InvalidArgument)?;
+    print!("{}", s);
+    Ok(len as u64)
+}
+
+fn uptime(_args: Args) -> Result<u64, Error> {
+    Ok(time::uptime().as_nanos())
+}
+
+fn cpu_id(_args: Args) -> Result<u64, Error> {
+    Ok(this_cpu().id as u64)
+}
+
+/// Call the system call that `regs` asks for, and put the result in
+/// `regs.rax`.
+///
+/// This is called by the entry stubs, with the kernel's `%gs` base loaded.
+#[no_mangle]
+pub extern "C" fn syscall_dispatch(regs: &mut Registers) {
+    let number = regs.rax;
+    let args = [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9];
+    let result = SYSCALLS.get(number as usize)
+                         .ok_or(Error::NoSuchSyscall)
+                         .and_then(|syscall| syscall(args));
+    regs.rax = match result {
+        Ok(value) => value
+      , Err(why) => {
+            trace!("system call {} failed: {:?}", number, why);
+            why.errno().wrapping_neg()
+        }
+    };
+}
+
+/// Entry point of the `syscall` instruction.
+///
+/// `syscall` leaves the user `%rip` in `%rcx` and `%rflags` in `%r11`, and
+/// clears the `%rflags` bits in `IA32_FMASK`: `IF`, `TF`, `DF`, and `AC`, so
+/// the kernel runs with SMAP on and string instructions going up. `IF` stays
+/// clear until `sysretq` restores the user's `%rflags`, since the kernel
+/// stack is shared by every thread on this CPU.
+///
+/// THIS FUNCTION IS NAKED. DO NOT CALL IT NORMALLY.
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn syscall_entry() {
+    // `%gs:8` is the current CPU's kernel stack, and `%gs:16` is scratch
+    // space for the user stack pointer (see `smp::Cpu`). the registers are
+    // pushed in the order `Registers::push` uses, so `%rsp` points at a
+    // `Registers` once they're all pushed.
+    //
+    // `sysretq` faults in kernel mode if `%rcx` isn't canonical, so user
+    // memory must never be mapped in the last page below the canonical hole.
+    asm!("swapgs
+          mov gs:[16], rsp
+          mov rsp, gs:[8]
+          push qword ptr gs:[16]
+
+          push rax
+          push rcx
+          push rdx
+          push r8
+          push r9
+          push r10
+          push r11
+          push rdi
+          push rsi
+
+          mov rdi, rsp
+          call syscall_dispatch
+
+          pop rsi
+          pop rdi
+          pop r11
+          pop r10
+          pop r9
+          pop r8
+          pop rdx
+          pop rcx
+          pop rax
+
+          pop rsp
+          swapgs
+          sysretq"
+        :::: "intel", "volatile");
+}
+
+/// Entry point of the `int 0x80` system call gate.
+///
+/// The CPU has already switched to the kernel stack (if we came from user
+/// mode) and pushed an interrupt frame, so this only has to swap `%gs` if
+/// the caller was in user mode.
+///
+/// Unlike `syscall`, an interrupt gate leaves `DF` and `AC` as the caller
+/// set them, so the stub clears them itself before calling into Rust: Rust
+/// code assumes `DF` is clear, and a set `AC` would turn SMAP off.
+///
+/// THIS FUNCTION IS NAKED. DO NOT CALL IT NORMALLY.
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn int80_entry() {
+    // `[rsp + 8]` is the caller's `%cs`; its low bits are the RPL. `clac`
+    // is an invalid opcode on CPUs without SMAP, so it's only run if SMAP
+    // is enabled in `%cr4` (bit 21); `%rax` has been saved by then.
+    asm!("test qword ptr [rsp + 8], 3
+          jz .Lint80_enter_from_kernel
+          swapgs
+      .Lint80_enter_from_kernel:
+          cld
+          push rax
+          push rcx
+          push rdx
+          push r8
+          push r9
+          push r10
+          push r11
+          push rdi
+          push rsi
+
+          mov rax, cr4
+          test eax, 1 << 21
+          jz .Lint80_no_smap
+          clac
+      .Lint80_no_smap:
+          mov rdi, rsp
+          call syscall_dispatch
+
+          pop rsi
+          pop rdi
+          pop r11
+          pop r10
+          pop r9
+          pop r8
+          pop rdx
+          pop rcx
+          pop rax
+
+          test qword ptr [rsp + 8], 3
+          jz .Lint80_return_to_kernel
+          swapgs
+      .Lint80_return_to_kernel:
+          iretq"
+        :::: "intel", "volatile");
+}
+
+/// Point the `int 0x80` gate at its entry stub, and let user code use it.
+pub fn install_int80(idt: &mut Idt) {
+    // the stub handles the interrupt frame itself.
+    let handler: InterruptHandler = unsafe {
+        mem::transmute(int80_entry as unsafe extern "C" fn())
+    };
+    idt.interrupts[INT80_VECTOR as usize - 32] = Gate::from(handler);
+    idt.interrupts[INT80_VECTOR as usize - 32]
+        .set_dpl(PrivilegeLevel::UserMode);
+}
+
+/// Enable the `syscall` instruction on this CPU.
+///
+/// # Safety
+/// + `%gs` must point at this CPU's `Cpu`.
+pub unsafe fn initialize() {
+    msr::enable_syscall( syscall_entry as usize as u64
+                       , (IF | TF | DF | AC).bits() as u64);
+}
+
+/// Make a system call with `int 0x80` from the kernel, and check the result.
+#[cfg(feature = "boot-selftest")]
+pub fn test() -> Result<(), &'static str> {
+    let cpu: u64;
+    unsafe {
+        asm!(  "int 0x80"
+            :  "={rax}"(cpu)
+            :  "{rax}"(SYS_CPU_ID)
+            :  "memory"
+            :  "intel", "volatile");
+    }
+    if cpu == this_cpu().id as u64 { Ok(()) }
+    else { Err("int 0x80 returned the wrong CPU number!") }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Dispatch system call `number` with `args`, and return `%rax`.
+    fn call(number: u64, args: Args) -> u64 {
+        let mut regs = Registers::empty();
+        regs.rax = number;
+        regs.rdi = args[0];
+        regs.rsi = args[1];
+        syscall_dispatch(&mut regs);
+        regs.rax
+    }
+
+    #[test]
+    fn test_no_such_syscall() {
+        assert_eq!( call(SYSCALLS.len() as u64, [0; 6])
+                  , Error::NoSuchSyscall.errno().wrapping_neg());
+    }
+
+    #[test]
+    fn test_write_console_errors() {
+        let too_long = [0x80_0000_0000, MAX_WRITE_LEN as u64 + 1, 0, 0, 0, 0];
+        assert_eq!( call(SYS_WRITE_CONSOLE, too_long)
+                  , Error::InvalidArgument.errno().wrapping_neg());
+        // the kernel's memory isn't user memory.
+        let kernel = [0x10_0000, 4, 0, 0, 0, 0];
+        assert_eq!( call(SYS_WRITE_CONSOLE, kernel)
+                  , Error::BadAddress.errno().wrapping_neg());
+    }
+}
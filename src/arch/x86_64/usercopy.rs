@@ -0,0 +1,165 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Copying data to and from user memory.
+//!
+//! When Supervisor Mode Access Prevention (SMAP) is enabled, the kernel
+//! faults if it touches a user page, unless the `AC` flag is set. These
+//! helpers set `AC` only for the duration of the copy, so that any other
+//! access to user memory from the kernel is still caught.
+//!
+//! The copy itself has an entry in the exception table (see `extable`), so
+//! if the user memory isn't mapped, the copy stops and returns an error,
+//! rather than the kernel crashing.
+//!
+//! The kernel lives in the lower half, too: the first PML4 entry maps the
+//! identity-mapped low memory, which holds the kernel image and the heap.
+//! So user memory starts at the second PML4 entry, and any address below
+//! that is refused before it's touched.
+use cpu::control_regs::cr4;
+use cpu::flags::{stac, clac};
+
+/// The lowest user address: the start of the second PML4 entry, since the
+/// first maps the kernel.
+const USER_START: usize = 0x0000_0080_0000_0000;
+/// The first address past the lower (user) half of the address space.
+const USER_END: usize = 0x0000_8000_0000_0000;
+
+/// Guard that allows the kernel to access user pages while it is alive.
+///
+/// If SMAP is enabled, `AC` is set when the guard is created and cleared
+/// again when it is dropped. Otherwise, this does nothing.
+struct UserAccess { smap: bool }
+
+impl UserAccess {
+    #[inline]
+    fn new() -> Self {
+        // this is safe; we are in kernel mode.
+        let smap = unsafe { cr4::is_smap_enabled() };
+        if smap { unsafe { stac() } }
+        UserAccess { smap: smap }
+    }
+}
+
+impl Drop for UserAccess {
+    #[inline]
+    fn drop(&mut self) {
+        if self.smap { unsafe { clac() } }
+    }
+}
+
+/// Returns an error if `len` bytes at `addr` are not all in user memory.
+#[inline]
+fn check_user_range(addr: usize, len: usize) -> Result<(), &'static str> {
+    match addr.checked_add(len) {
+        Some(end) if addr >= USER_START && end <= USER_END => Ok(())
+      , _ => Err("Address range is not in user memory!")
+    }
+}
+
+/// Copy `len` bytes from `src` to `dst`, stopping if either faults.
+///
+/// Returns the number of bytes that weren't copied.
+#[inline(never)]
+unsafe fn copy_with_fixup(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    let remaining: usize;
+    let _dst_end: usize;
+    let _src_end: usize;
+    // if `rep movsb` faults, the page fault handler resumes at `2:`, with
+    // `%rcx` counting the bytes that are left.
+    asm!("1: rep movsb
+          2:
+          .pushsection .ex_table, \"a\"
+          .balign 8
+          .quad 1b, 2b
+          .popsection"
+        : "={rcx}"(remaining), "={rdi}"(_dst_end), "={rsi}"(_src_end)
+        : "{rcx}"(len), "{rdi}"(dst), "{rsi}"(src)
+        : "memory"
+        : "volatile");
+    remaining
+}
+
+/// Copy `dst.len()` bytes from the user address `src` into `dst`.
+///
+/// # Returns
+/// + `Ok(())` if every byte was copied
+/// + `Err` if the range isn't in user memory, or part of it isn't mapped.
+///
+/// # Safety
+/// + If the copy faults partway through, the start of `dst` has been
+///   overwritten.
+pub unsafe fn copy_from_user(dst: &mut [u8], src: *const u8)
+                            -> Result<(), &'static str> {
+    check_user_range(src as usize, dst.len())?;
+    let _access = UserAccess::new();
+    match copy_with_fixup(dst.as_mut_ptr(), src, dst.len()) {
+        0 => Ok(())
+      , _ => Err("User memory is not mapped!")
+    }
+}
+
+/// Copy the bytes in `src` to the user address `dst`.
+///
+/// # Returns
+/// + `Ok(())` if every byte was copied
+/// + `Err` if the range isn't in user memory, or part of it isn't mapped
+///   and writable.
+///
+/// # Safety
+/// + If the copy faults partway through, some of the user memory has been
+///   overwritten.
+pub unsafe fn copy_to_user(dst: *mut u8, src: &[u8])
+                          -> Result<(), &'static str> {
+    check_user_range(dst as usize, src.len())?;
+    let _access = UserAccess::new();
+    match copy_with_fixup(dst, src.as_ptr(), src.len()) {
+        0 => Ok(())
+      , _ => Err("User memory is not mapped!")
+    }
+}
+
+/// Copy from a user address that isn't mapped, and from kernel memory, and
+/// check that both fail rather than crashing or leaking.
+#[cfg(feature = "boot-selftest")]
+pub fn test() -> Result<(), &'static str> {
+    // nothing is mapped this high in the lower half.
+    const UNMAPPED: usize = USER_END - 0x1000_0000;
+    static SECRET: [u8; 16] = [0xaa; 16];
+    let mut buf = [0u8; 16];
+    if unsafe { copy_from_user(&mut buf, UNMAPPED as *const u8) }.is_ok() {
+        return Err("copy from unmapped user memory succeeded!")
+    }
+    if unsafe { copy_from_user(&mut buf, SECRET.as_ptr()) }.is_ok() {
+        return Err("copy from kernel memory succeeded!")
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_range() {
+        assert!(check_user_range(USER_START, 16).is_ok());
+        assert!(check_user_range(USER_END - 16, 16).is_ok());
+        assert!(check_user_range(USER_START - 1, 16).is_err());
+        assert!(check_user_range(USER_END - 16, 17).is_err());
+        assert!(check_user_range(usize::max_value(), 2).is_err());
+    }
+
+    #[test]
+    fn test_copy_from_kernel_memory() {
+        // the range is checked before anything is copied.
+        static SECRET: [u8; 16] = [0xaa; 16];
+        let mut buf = [0u8; 16];
+        assert!(unsafe { copy_from_user(&mut buf, SECRET.as_ptr()) }.is_err());
+        assert_eq!(buf, [0u8; 16]);
+    }
+}
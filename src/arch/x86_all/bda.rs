@@ -14,6 +14,19 @@
 
 type Word = u16;
 
+/// Address of the word containing the real-mode segment of the EBDA.
+const EBDA_SEGMENT_ADDR: usize = 0x040e;
+
+/// Returns the physical address of the Extended BIOS Data Area, if the BIOS
+/// reported one.
+#[inline]
+pub fn ebda_address() -> Option<usize> {
+    match unsafe { *(EBDA_SEGMENT_ADDR as *const Word) } {
+        0 => None
+      , segment => Some((segment as usize) << 4)
+    }
+}
+
 pub mod ports {
     use super::Word;
     const PORTS_ADDR: usize = 0x0400;
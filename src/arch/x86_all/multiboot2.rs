@@ -11,6 +11,7 @@
 //! Consult the [Multiboot Specification](http://nongnu.askapache.com/grub/phcoder/multiboot.pdf)
 //! for more information.
 use memory::{PAddr, PhysicalPage, FrameRange};
+use elf::Section;
 use elf::section::{Sections, HeaderRepr as SectionHeader};
 use params::mem;
 
@@ -123,6 +124,23 @@ impl Info {
             })
     }
 
+    /// Finds the bootloader's copy of the ACPI RSDP.
+    ///
+    /// The ACPI 2.0 RSDP is preferred over the ACPI 1.0 RSDP, if the
+    /// bootloader passed both.
+    ///
+    ///  # Returns
+    ///  - `Some(PAddr)` with the address of the copied RSDP, if an ACPI tag
+    ///    could be found
+    ///  - `None` if no ACPI tag could be found.
+    #[inline]
+    pub fn acpi_rsdp(&'static self) -> Option<PAddr> {
+        self.get_tag(TagType::ACPINewRSDP)
+            .or_else(|| self.get_tag(TagType::ACPIOldRSDP))
+            // the RSDP follows the 8-byte tag header
+            .map(|tag| PAddr::from(tag as *const Tag) + 8)
+    }
+
     /// Returns an iterator over all Multiboot tags.
     #[inline]
     fn tags(&'static self) -> Tags { Tags(&self.tag_start as *const Tag) }
@@ -221,6 +239,19 @@ pub enum TagType { /// Tag that indicates the end of multiboot tags
                  , FramebufferInfo  = 8
                  , ELFSections      = 9
                  , APMTable         = 10
+                 , EFI32SystemTable = 11
+                 , EFI64SystemTable = 12
+                 , SMBIOSTables     = 13
+                 , /// Copy of the ACPI 1.0 RSDP
+                   ACPIOldRSDP      = 14
+                 , /// Copy of the ACPI 2.0 RSDP
+                   ACPINewRSDP      = 15
+                 , NetworkingInfo   = 16
+                 , EFIMemoryMap     = 17
+                 , EFIBootServices  = 18
+                 , EFI32ImageHandle = 19
+                 , EFI64ImageHandle = 20
+                 , ImageLoadBase    = 21
                  }
 
 /// An iterator over Multiboot 2 tags.
@@ -405,6 +436,23 @@ impl ElfSectionsTag {
                      , self.section_size
                      )
     }
+
+    /// Returns the section header at `index` in the section header table.
+    ///
+    /// Unlike `sections()`, this doesn't skip null sections, so `index` is
+    /// the same as in section header fields like `sh_link`.
+    pub fn section(&'static self, index: u32)
+                  -> Option<&'static Section<Word>> {
+        if index >= self.n_sections { return None }
+        let first = &self.first_section as *const SectionHeader<Word>
+                                        as *const u8;
+        // this is safe; the bootloader put `n_sections` headers, each
+        // `section_size` bytes long, after the tag.
+        Some(unsafe {
+            &*(first.offset((index * self.section_size) as isize)
+                    as *const SectionHeader<Word>)
+        })
+    }
 }
 
 impl IntoIterator for &'static ElfSectionsTag {
@@ -126,6 +126,7 @@ static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard {
   , state: Modifiers::new()
 });
 
+/// Read a character from the keyboard, if the last key pressed was one.
 pub fn read_char() -> Option<char> {
     let mut lock = KEYBOARD.lock();
 
@@ -135,3 +136,16 @@ pub fn read_char() -> Option<char> {
     code.to_ascii()
         .map(|ascii| lock.state.modify(ascii) as char)
 }
+
+/// Handle a keyboard IRQ, echoing the key that was pressed to the terminal.
+///
+/// This is called by the keyboard IRQ handler.
+pub fn handle_irq() {
+    if let Some(input) = read_char() {
+        if input == '\r' {
+            println!("");
+        } else {
+            print!("{}", input);
+        }
+    }
+}
@@ -9,11 +9,90 @@
 use log;
 use log::{LogRecord, LogLevel, LogMetadata, LogLevelFilter};
 use arch::drivers::serial;
+use cpu::interrupts;
+use spin::Mutex;
+use time;
 
+use core::{cmp, fmt, str};
 use core::fmt::Write;
 
 struct SerialLogger;
 
+/// The number of log lines kept for crash dumps.
+pub const RECENT_LINES: usize = 16;
+/// The longest log line kept for crash dumps; longer lines are cut short.
+pub const RECENT_LINE_LEN: usize = 120;
+
+/// A ring buffer of the most recent log lines.
+struct Recent { lines: [[u8; RECENT_LINE_LEN]; RECENT_LINES]
+              , lens: [usize; RECENT_LINES]
+              , /// The index of the next line to overwrite
+                next: usize
+              , /// The number of lines logged, up to `RECENT_LINES`
+                count: usize
+              }
+
+/// Writes into a line of the ring buffer, dropping whatever doesn't fit.
+struct LineWriter<'a> { buf: &'a mut [u8; RECENT_LINE_LEN]
+                      , len: &'a mut usize
+                      }
+
+impl<'a> fmt::Write for LineWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = cmp::min(s.len(), RECENT_LINE_LEN - *self.len);
+        self.buf[*self.len..*self.len + n]
+            .copy_from_slice(&s.as_bytes()[..n]);
+        *self.len += n;
+        Ok(())
+    }
+}
+
+static RECENT: Mutex<Recent>
+    = Mutex::new(Recent { lines: [[0; RECENT_LINE_LEN]; RECENT_LINES]
+                        , lens: [0; RECENT_LINES]
+                        , next: 0
+                        , count: 0
+                        });
+
+/// Keep `record` in the recent log lines.
+fn remember(record: &LogRecord) {
+    // if we interrupted someone holding the lock, losing the line is better
+    // than deadlocking.
+    if let Some(mut recent) = RECENT.try_lock() {
+        let i = recent.next;
+        recent.next = (i + 1) % RECENT_LINES;
+        recent.count = cmp::min(recent.count + 1, RECENT_LINES);
+        let Recent { ref mut lines, ref mut lens, .. } = *recent;
+        lens[i] = 0;
+        let _ = write!( LineWriter { buf: &mut lines[i], len: &mut lens[i] }
+                      , "[ {} ][ {} ] {}: {}"
+                      , time::uptime(), record.level()
+                      , record.metadata().target(), record.args());
+    }
+}
+
+/// Call `f` with each of the recent log lines, oldest first.
+///
+/// Lines are skipped if the log is being written to.
+pub fn recent_lines<F>(mut f: F)
+where F: FnMut(&str) {
+    if let Some(recent) = RECENT.try_lock() {
+        let first = (recent.next + RECENT_LINES - recent.count) % RECENT_LINES;
+        for n in 0..recent.count {
+            let i = (first + n) % RECENT_LINES;
+            let line = &recent.lines[i][..recent.lens[i]];
+            // a line may have been cut short in the middle of a character.
+            let line = match str::from_utf8(line) {
+                Ok(line) => line
+              , Err(e) => unsafe {
+                    str::from_utf8_unchecked(&line[..e.valid_up_to()])
+                }
+            };
+            f(line)
+        }
+    }
+}
+
 pub fn initialize() -> Result<(), log::SetLoggerError> {
     unsafe {
         log::set_logger_raw(|max_log_level| {
@@ -37,31 +116,38 @@ impl log::Log for SerialLogger {
 
     #[inline]
     fn log(&self, record: &LogRecord) {
-        let meta = record.metadata();
-        match record.level() {
-            LogLevel::Trace if self.enabled(meta) => {
-                let location = record.location();
-                let _ = write!( *serial::COM1.lock()
-                              , "[ TRACE ][ {}:{} ] {}: {}\n"
-                              , location.module_path(), location.line()
-                              , meta.target()
-                              , record.args() );
+        // interrupt handlers log too, so if a thread were preempted while
+        // it held COM1, the next one to log on this CPU would deadlock.
+        interrupts::without_interrupts(|| {
+            let meta = record.metadata();
+            remember(record);
+            match record.level() {
+                LogLevel::Trace if self.enabled(meta) => {
+                    let location = record.location();
+                    let _ = write!( *serial::COM1.lock()
+                                  , "[ {} ][ TRACE ][ {}:{} ] {}: {}\n"
+                                  , time::uptime()
+                                  , location.module_path(), location.line()
+                                  , meta.target()
+                                  , record.args() );
+                }
+              , LogLevel::Debug if self.enabled(meta) => {
+                    let _ = write!( *serial::COM1.lock()
+                                  , "[ {} ][ DEBUG ] {}: {}\n"
+                                  , time::uptime()
+                                  , meta.target()
+                                  , record.args() );
+                }
+              , level => {
+                    let target = meta.target();
+                    let args = record.args();
+                    let _ = write!( *serial::COM1.lock()
+                                  , "[ {} ][ {} ] {}: {}\n"
+                                  , time::uptime(), level, target, args );
+                    // println!("{}: {}", target, args );
+                }
             }
-          , LogLevel::Debug if self.enabled(meta) => {
-                let _ = write!( *serial::COM1.lock()
-                              , "[ DEBUG ] {}: {}\n"
-                              , meta.target()
-                              , record.args() );
-            }
-          , level => {
-                let target = meta.target();
-                let args = record.args();
-                let _ = write!( *serial::COM1.lock()
-                              , "[ {} ] {}: {}\n"
-                              , level, target, args );
-                // println!("{}: {}", target, args );
-            }
-        }
+        })
     }
 
 }
@@ -23,7 +23,7 @@
 
 #![doc(html_root_url = "https://hawkw.github.io/sos-kernel/")]
 
-#![feature( lang_items, asm, naked_functions )]
+#![feature( lang_items, asm, naked_functions, global_asm )]
 #![feature( linkage )]
 #![feature( const_fn
           , slice_patterns
@@ -31,6 +31,7 @@
           , type_ascription
           , custom_derive )]
 #![feature(collections)]
+#![feature(unique)]
 
 #![cfg_attr(feature="clippy", feature(plugin))]
 #![cfg_attr(feature="clippy", plugin(clippy))]
@@ -52,19 +53,23 @@ extern crate spin;
 // -- SOS dependencies ------------------------------------------------------
 #[macro_use] extern crate vga;
 
+extern crate acpi;
 extern crate alloc;
 extern crate cpu;
 extern crate elf;
 extern crate paging;
 extern crate params;
 extern crate memory;
+extern crate sos_intrusive as intrusive;
 extern crate util;
 
 #[macro_use] pub mod io;
 
 pub mod heap;
-pub mod arch;
+#[macro_use] pub mod arch;
 pub mod logger;
+pub mod thread;
+pub mod time;
 
 use params::InitParams;
 
@@ -111,7 +116,10 @@ pub fn kernel_main() -> ! {
     // let mut frame_allocator = frame_alloc::FrameAllocator::new();
     // paging::test_paging(&mut frame_allocator);
 
-    loop { }
+    // there's nothing to do but wait for interrupts.
+    loop {
+        unsafe { asm!("hlt" :::: "volatile"); }
+    }
 }
 
 /// Kernel initialization function called into by architecture-specific init
@@ -130,9 +138,10 @@ pub fn kernel_main() -> ! {
 /// |      |           RUST-LAND KERNEL FUNCTIONS                   |
 /// |      V                                                        |
 /// | arch_init() ----------> kernel_init() --------> kernel_main() |
-/// | + collects boot info   + initializes interrupts               |
-/// |   from arch-specific   + initializes the heap                 |
-/// |   sources              + remaps the kernel into the higher    |
+/// | + collects boot info   + initializes interrupts  + idles      |
+/// |   from arch-specific   + initializes the heap      until an   |
+/// |   sources              + starts the other CPUs     interrupt  |
+/// |                        + remaps the kernel into the higher    |
 /// | + some CPU-specific      half of the address space            |
 /// |   configuration                                               |
 /// +---------------------------------------------------------------+
@@ -147,7 +156,7 @@ pub fn kernel_init(params: &InitParams) {
     // -- remap the kernel ----------------------------------------------------
     let mut frame_allocator = MemMapAllocator::from(params);
     kinfoln!(dots: " . ", "Remapping the kernel...");
-    let page_table = match kernel_remap(&params, &mut frame_allocator) {
+    let mut page_table = match kernel_remap(&params, &mut frame_allocator) {
         Ok(p) => {
             kinfoln!(dots: " . ", target: "Remapping the kernel", "[ OKAY ]");
             p
@@ -157,6 +166,10 @@ pub fn kernel_init(params: &InitParams) {
             panic!( "Could not remap kernel: {}", why)
         }
     };
+    debug!("Kernel page table after remapping:\n{}", page_table.dump());
+    if let Err(mapping) = page_table.check_wx() {
+        panic!("Writable and executable kernel mapping: {}", mapping)
+    }
 
     paging::test_paging(&mut frame_allocator);
 
@@ -167,18 +180,118 @@ pub fn kernel_init(params: &InitParams) {
             , "Heap begins at {:#x} and ends at {:#x}"
             , params.heap_base, params.heap_top);
 
-
     // -- initialize interrupts ----------------------------------------------
-    // attempt!( unsafe { arch::interrupts::initialize() } =>
-    //           "Initializing interrupts...", dots: " . " );
+    // the PICs have to be remapped before the APIC masks them, and the IDT
+    // has to be built before the APs load it.
+    attempt!( unsafe { arch::interrupts::initialize() } =>
+              "Initializing interrupts...", dots: " . " );
+    attempt!( unsafe { time::initialize() } =>
+              "Starting the system timer...", dots: " . " );
+    unsafe { arch::syscall::initialize(); }
+    let xfeatures = attempt!(
+        unsafe { arch::fpu::initialize(Some(arch::fpu::Policy::Lazy)) } =>
+        "Enabling the FPU...", dots: " . " );
+    kinfoln!( dots: " . . ", "FPU state components: {:?}, {} bytes per task"
+            , xfeatures, ::cpu::fpu::state_size());
+    arch_selftest();
+
+    // -- read the ACPI tables -----------------------------------------------
+    let tables = params.acpi_rsdp
+        .ok_or("ACPI RSDP not found!")
+        .and_then(|rsdp| arch::acpi::tables( rsdp, &mut page_table
+                                           , &mut frame_allocator));
+    if let Err(why) = tables {
+        kinfoln!(dots: " . ", "Could not read the ACPI tables: {}", why);
+    }
+
+    // -- switch to the APIC -------------------------------------------------
+    // the I/O APICs, and how the ISA IRQs are wired to them, come from the
+    // MADT, so this has to wait for the ACPI tables. without a MADT, the
+    // PICs stay in use.
+    let madt = tables.as_ref()
+                     .ok()
+                     .and_then(|tables| tables.madt.as_ref())
+                     .ok_or("ACPI MADT not found!");
+    let apic = madt.and_then(|madt| unsafe {
+        arch::interrupts::initialize_apic( madt, &mut page_table
+                                         , &mut frame_allocator)
+    });
+    if let Err(why) = apic {
+        kinfoln!(dots: " . ", "Could not enable the APIC: {}", why);
+    }
+
+    // -- start the application processors ----------------------------------
+    // this needs the heap, for the APs' stacks and descriptor tables.
+    let smp = apic.and_then(|_| madt).and_then(|madt| unsafe {
+        arch::smp::initialize(madt, &mut page_table, &mut frame_allocator)
+    });
+    match smp {
+        Ok(n) => kinfoln!(dots: " . ", "{} CPUs online", n)
+      , Err(why) => kinfoln!( dots: " . "
+                            , "Could not start other CPUs: {}", why)
+    }
+
+    // -- start the clock event devices --------------------------------------
+    // these interrupt through the APIC, so they can't be used without it.
+    if apic.is_ok() {
+        let hpet = tables.as_ref()
+                         .ok()
+                         .and_then(|tables| tables.hpet.as_ref())
+                         .ok_or("ACPI HPET table not found!")
+                         .and_then(|hpet| unsafe {
+                             arch::drivers::hpet::initialize( hpet
+                                                            , &mut page_table
+                                                            , &mut frame_allocator)
+                         });
+        if let Err(why) = hpet {
+            kinfoln!(dots: " . ", "Could not start the HPET: {}", why);
+        }
+        if let Err(why) = unsafe { arch::drivers::lapic_timer::initialize() } {
+            kinfoln!( dots: " . "
+                    , "Could not start the local APIC timer: {}", why);
+        }
+    }
 
     println!("\n{} {}-bit\n", VERSION_STRING, arch::ARCH_BITS);
 
+    // -- enable interrupts ---------------------------------------------------
+    unsafe { arch::interrupts::enable(); }
+
+    // -- start kernel threads ------------------------------------------------
+    // threads run with interrupts enabled, so they can't be started sooner.
+    attempt!( thread::initialize(page_table) =>
+              "Starting kernel threads...", dots: " . " );
+    thread_selftest();
+
     // -- call into kernel main loop ------------------------------------------
-    // (currently, this does nothing)
+    // (currently, this waits for interrupts)
     kernel_main()
 }
 
+/// Test system calls, watchpoints, and copying from user memory, if the
+/// `boot-selftest` feature is enabled.
+#[cfg(feature = "boot-selftest")]
+fn arch_selftest() {
+    attempt!( arch::syscall::test() =>
+              "Testing system calls...", dots: " . " );
+    attempt!( arch::debug::test() =>
+              "Testing hardware watchpoints...", dots: " . " );
+    attempt!( arch::usercopy::test() =>
+              "Testing user memory fault recovery...", dots: " . " );
+}
+
+#[cfg(not(feature = "boot-selftest"))]
+fn arch_selftest() { }
+
+/// Test kernel threads, if the `boot-selftest` feature is enabled.
+#[cfg(feature = "boot-selftest")]
+fn thread_selftest() {
+    attempt!( thread::test() =>
+              "Testing kernel threads...", dots: " . " );
+}
+
+#[cfg(not(feature = "boot-selftest"))]
+fn thread_selftest() { }
 
 /// This fake `main` function exists only to placate `cargo test`.
 #[cfg(test)]
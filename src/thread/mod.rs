@@ -0,0 +1,824 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Kernel threads.
+//!
+//! A thread is started with [`spawn`], which gives it its own stack (with a
+//! guard page), and FPU state. [`initialize`] turns the code that calls it
+//! into the first thread, so that it can be switched away from.
+//!
+//! Each thread is in one of four [`State`]s, and has a [`Priority`], which
+//! can be changed with [`set_priority`]. Real-time threads run first, in
+//! priority order; which normal thread runs next is up to the
+//! [`Scheduler`] in use for them, which is the fair scheduler unless
+//! another one is set with [`set_scheduler`]. A running thread is switched
+//! away from when it blocks, exits, or calls [`yield_now`], when the
+//! scheduler decides on a timer tick that it has run for long enough, or
+//! when a thread that should run instead becomes ready. When no thread is
+//! ready, the _idle thread_ halts the CPU until one is.
+//!
+//! The CPU time each thread uses is counted with the timestamp counter, and
+//! can be read with [`cpu_time`].
+//!
+//! Preemption can be disabled for critical sections with
+//! [`preempt_disable`] and [`preempt_enable`]; these nest, and a thread
+//! that should have been preempted in the meantime is preempted when the
+//! outermost section ends.
+//!
+//! A thread that exits stays dead until something [`join`]s it, which frees
+//! its stack. Threads currently only run on the bootstrap processor.
+//!
+//! # Examples
+//! ```ignore
+//! fn count_to(n: usize) {
+//!     for i in 0..n { info!("{}", i); }
+//! }
+//!
+//! let thread = thread::spawn(count_to, 10)?;
+//! thread::join(thread)?;
+//! ```
+//!
+//! [`spawn`]: fn.spawn.html
+//! [`initialize`]: fn.initialize.html
+//! [`State`]: enum.State.html
+//! [`Priority`]: sched/enum.Priority.html
+//! [`set_priority`]: fn.set_priority.html
+//! [`Scheduler`]: sched/trait.Scheduler.html
+//! [`set_scheduler`]: fn.set_scheduler.html
+//! [`yield_now`]: fn.yield_now.html
+//! [`preempt_disable`]: fn.preempt_disable.html
+//! [`preempt_enable`]: fn.preempt_enable.html
+//! [`cpu_time`]: fn.cpu_time.html
+//! [`join`]: fn.join.html
+use arch::fpu::FpuArea;
+use arch::irq::{self, IrqResult, IRQ};
+use collections::vec::Vec;
+use cpu::context::{self, Context, InterruptFrame};
+use cpu::interrupts;
+use cpu::interrupts::idt::Idt;
+use cpu::timer::timestamp;
+use intrusive::list::Node;
+use intrusive::rawlink::RawLink;
+use paging::arch::ActivePageTable;
+use spin::Mutex;
+use time::{self, Duration, Instant, TIMER_HZ};
+use time::clock_event::{self, ClockEventDevice};
+
+use core::{fmt, mem, ptr};
+use core::cell::Cell;
+use core::ptr::Unique;
+
+pub mod sched;
+pub mod stack;
+#[cfg(feature = "boot-selftest")]
+mod selftest;
+pub use self::sched::Priority;
+#[cfg(feature = "boot-selftest")]
+pub use self::selftest::test;
+use self::sched::{Classes, Entity, Fair, Scheduler};
+use self::stack::Stack;
+
+/// Size of each thread's stack, in bytes, not counting the guard page.
+pub const STACK_SIZE: usize = 4096 * 4;
+
+/// How long a thread runs before it is preempted, unless it's changed with
+/// `set_quantum`.
+pub const DEFAULT_QUANTUM: Duration = Duration::from_millis(50);
+
+/// How long a timer tick is.
+const TICK: Duration = Duration::from_nanos(1_000_000_000 / TIMER_HZ as u64);
+
+/// Identifies a thread.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ThreadId(usize);
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "thread {}", self.0)
+    }
+}
+
+/// What a thread is doing.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum State { /// Waiting for its turn to run
+                 Ready
+               , /// Running on a CPU
+                 Running
+               , /// Waiting for something else to happen, such as another
+                 /// thread exiting
+                 Blocked
+               , /// Finished, but not yet joined
+                 Dead
+               }
+
+/// A kernel thread.
+pub struct Thread { id: ThreadId
+                  , state: State
+                  , /// Where the thread's registers are saved when it isn't
+                    /// running
+                    context: Context
+                  , /// The thread's stack, or `None` for the first thread,
+                    /// which runs on the boot stack
+                    _stack: Option<Stack>
+                  , fpu: FpuArea
+                  , /// The function the thread calls when it starts, and its
+                    /// argument, or `None` for the first thread
+                    start: Option<(fn(usize), usize)>
+                  , /// The thread waiting for this one to exit
+                    joiner: Option<*mut Thread>
+                  , priority: Priority
+                  , /// What the thread's scheduler keeps track of
+                    sched: Entity
+                  , /// Timer ticks since the thread was last switched to
+                    ticks: usize
+                  , /// TSC cycles the thread has run for
+                    cpu_cycles: u64
+                  , /// The TSC when the thread's CPU time was last counted
+                    last_tsc: u64
+                  , /// How many times preemption has been disabled, and
+                    /// not enabled again
+                    preempt_count: usize
+                  , /// Links to the threads around this one in a run queue
+                    next: RawLink<Thread>
+                  , prev: RawLink<Thread>
+                  }
+
+impl Thread {
+    /// Returns the thread's ID.
+    #[inline] pub fn id(&self) -> ThreadId { self.id }
+
+    /// Returns the thread's state.
+    #[inline] pub fn state(&self) -> State { self.state }
+
+    /// Returns the thread's priority.
+    #[inline] pub fn priority(&self) -> Priority { self.priority }
+
+    /// Returns the number of timer ticks since the thread was last switched
+    /// to.
+    #[inline] pub fn ticks(&self) -> usize { self.ticks }
+}
+
+impl Node for Thread {
+    #[inline] fn prev(&self) -> &RawLink<Thread> {
+        &self.prev
+    }
+    #[inline] fn next(&self) -> &RawLink<Thread> {
+        &self.next
+    }
+    #[inline] fn prev_mut(&mut self) -> &mut RawLink<Thread> {
+        &mut self.prev
+    }
+    #[inline] fn next_mut(&mut self) -> &mut RawLink<Thread> {
+        &mut self.next
+    }
+}
+
+/// Every thread that hasn't been joined, and the scheduling classes that
+/// decide which of them runs.
+struct Threads { all: Vec<*mut Thread>
+               , scheduler: Classes
+               , next_id: usize
+               }
+
+// the threads are only accessed with the lock held.
+unsafe impl Send for Threads {}
+
+impl Threads {
+    /// Returns the thread `id`, if it hasn't been joined.
+    fn find(&self, id: ThreadId) -> Option<*mut Thread> {
+        self.all.iter()
+            .map(|&thread| thread)
+            .find(|&thread| unsafe { (*thread).id == id })
+    }
+
+    /// Stop tracking `thread`, which must be dead.
+    fn remove(&mut self, thread: *mut Thread) {
+        self.all.retain(|&t| t != thread);
+    }
+
+    /// Returns a new thread ID.
+    fn next_id(&mut self) -> ThreadId {
+        self.next_id += 1;
+        ThreadId(self.next_id - 1)
+    }
+
+    /// Give `thread` to the scheduler, to run when its turn comes.
+    unsafe fn make_ready(&mut self, thread: *mut Thread) {
+        (*thread).state = State::Ready;
+        self.scheduler.enqueue(Unique::new(thread));
+    }
+
+    /// Make `thread`, which isn't running, ready, and preempt the running
+    /// thread if `thread` should run instead.
+    unsafe fn wake(&mut self, thread: *mut Thread) {
+        self.make_ready(thread);
+        let current = current_ptr();
+        let idle = IDLE.with(|idle| idle.get());
+        if current == idle
+            || self.scheduler.wakeup_preempts(&*current, &*thread) {
+            NEED_RESCHED.with(|need| need.set(true));
+        }
+    }
+
+    /// Count the CPU time `thread`, which is running, has used since it
+    /// was last counted.
+    unsafe fn account(&mut self, thread: *mut Thread) {
+        let now = timestamp::rtdsc();
+        let cycles = now.wrapping_sub((*thread).last_tsc);
+        (*thread).last_tsc = now;
+        (*thread).cpu_cycles += cycles;
+        if thread != IDLE.with(|idle| idle.get()) {
+            self.scheduler.charge(&mut *thread, cycles);
+        }
+    }
+}
+
+/// The scheduler used for normal threads until `set_scheduler` is called.
+static mut FAIR: Fair = Fair::new();
+
+lazy_static! {
+    static ref THREADS: Mutex<Threads> = {
+        // this is safe; nothing else refers to `FAIR`.
+        let mut scheduler = Classes::new(unsafe { &mut FAIR });
+        scheduler.set_quantum(quantum_ticks(DEFAULT_QUANTUM).unwrap_or(1));
+        Mutex::new(Threads { all: Vec::new()
+                           , scheduler: scheduler
+                           , next_id: 0
+                           })
+    };
+}
+
+percpu! {
+    /// The thread running on this CPU.
+    static CURRENT: Cell<*mut Thread> = Cell::new(ptr::null_mut());
+    /// The thread that runs on this CPU when nothing else is ready.
+    static IDLE: Cell<*mut Thread> = Cell::new(ptr::null_mut());
+    /// True if the running thread should be switched away from as soon as
+    /// preemption is enabled.
+    static NEED_RESCHED: Cell<bool> = Cell::new(false);
+    /// When the clock event device was last programmed to tick, if ticks
+    /// come from it.
+    static LAST_TICK: Cell<Option<Instant>> = Cell::new(None);
+}
+
+/// Move `thread` to the heap, where it stays put while it's switched to and
+/// from, and return a pointer to it.
+fn allocate(thread: Thread) -> *mut Thread {
+    let mut vec = Vec::with_capacity(1);
+    vec.push(thread);
+    let ptr = vec.as_mut_ptr();
+    mem::forget(vec);
+    ptr
+}
+
+/// Drop a thread that was allocated with `allocate`, freeing its stack.
+unsafe fn free(thread: *mut Thread) {
+    drop(Vec::from_raw_parts(thread, 1, 1))
+}
+
+/// Returns a new thread, which will call `entry(arg)` when it's first
+/// switched to.
+fn new_thread(id: ThreadId, stack: Stack, entry: fn(usize), arg: usize)
+              -> *mut Thread {
+    let fpu = FpuArea::new();
+    // this is safe; the stack is ours, and nothing else will use it.
+    let context = unsafe {
+        Context::new(*stack.top() as *mut u8, thread_start, fpu.state())
+    };
+    allocate(Thread { id: id
+                    , state: State::Ready
+                    , context: context
+                    , _stack: Some(stack)
+                    , fpu: fpu
+                    , start: Some((entry, arg))
+                    , joiner: None
+                    , priority: Priority::default()
+                    , sched: Entity::new()
+                    , ticks: 0
+                    , cpu_cycles: 0
+                    , last_tsc: 0
+                    , preempt_count: 0
+                    , next: RawLink::none()
+                    , prev: RawLink::none()
+                    })
+}
+
+/// Returns the thread running on this CPU.
+#[inline]
+fn current_ptr() -> *mut Thread {
+    CURRENT.with(|current| current.get())
+}
+
+/// Returns the number of timer ticks in `quantum`, if it's at least one.
+fn quantum_ticks(quantum: Duration) -> Option<usize> {
+    match quantum.as_millis() * TIMER_HZ as u64 / 1_000 {
+        0 => None
+      , ticks => Some(ticks as usize)
+    }
+}
+
+/// Make the code that is running into the first thread, start the idle
+/// thread, and start preempting threads on timer ticks.
+///
+/// `table` is the kernel's page table, which threads' stacks have their
+/// guard pages made in from now on.
+///
+/// This must be called after the FPU and the timer are started, and before
+/// any other thread is spawned.
+pub fn initialize(table: ActivePageTable) -> Result<ThreadId, &'static str> {
+    if !current_ptr().is_null() {
+        return Err("Threads were already initialized!")
+    }
+    stack::set_page_table(table);
+    let idle_stack = Stack::new(STACK_SIZE)?;
+    let id = interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let id = threads.next_id();
+        let thread = allocate(Thread { id: id
+                                     , state: State::Running
+                                     , context: Context::empty()
+                                     , _stack: None
+                                     , fpu: FpuArea::new()
+                                     , start: None
+                                     , joiner: None
+                                     , priority: Priority::default()
+                                     , sched: Entity::new()
+                                     , ticks: 0
+                                     , cpu_cycles: 0
+                                     , last_tsc: unsafe { timestamp::rtdsc() }
+                                     , preempt_count: 0
+                                     , next: RawLink::none()
+                                     , prev: RawLink::none()
+                                     });
+        unsafe {
+            (*thread).context.fpu = (*thread).fpu.state();
+            ::arch::fpu::switch(::cpu::fpu::FpuState::none()
+                               , (*thread).context.fpu);
+        }
+        threads.all.push(thread);
+        CURRENT.with(|current| current.set(thread));
+
+        // the idle thread is never in the run queue, so it only runs when
+        // nothing else is ready.
+        let idle = new_thread(threads.next_id(), idle_stack, idle, 0);
+        IDLE.with(|cell| cell.set(idle));
+        id
+    });
+    start_ticks()?;
+    Ok(id)
+}
+
+/// Start the timer ticks that threads are preempted on.
+///
+/// They come from the clock event device, which is programmed for each
+/// tick's deadline, if there is one, and from the PIT otherwise.
+fn start_ticks() -> Result<(), &'static str> {
+    if let Some(device) = clock_event::device() {
+        clock_event::set_event_handler(clock_tick);
+        match unsafe { program_next_tick(device) } {
+            Ok(()) => {
+                kinfoln!( dots: " . . ", "Timer ticks come from the {}"
+                        , device.name());
+                return Ok(())
+            }
+          , Err(why) => kinfoln!( dots: " . . ", "Could not program the {}: {}"
+                                , device.name(), why)
+        }
+    }
+    irq::register_irq(IRQ::Timer, timer_tick)
+}
+
+/// Program `device` to interrupt us when the next timer tick is due.
+///
+/// If ticks were missed, the next one is due a tick from now, so that they
+/// don't all arrive at once.
+///
+/// # Safety
+/// + `device`'s event handler must be `clock_tick`.
+unsafe fn program_next_tick(device: &ClockEventDevice)
+                           -> Result<(), &'static str> {
+    let now = Instant::now();
+    let next = LAST_TICK.with(|last| {
+        let next = match last.get() {
+            Some(last) if last + TICK > now => last + TICK
+          , _ => now + TICK
+        };
+        last.set(Some(next));
+        next
+    });
+    device.program(next)
+}
+
+/// The idle thread, which waits for interrupts.
+///
+/// Whenever a thread becomes ready, the next timer tick preempts this.
+fn idle(_: usize) {
+    loop {
+        unsafe { asm!("hlt" :::: "volatile"); }
+    }
+}
+
+/// Start a new thread, which calls `entry(arg)`.
+///
+/// The thread is ready to run, with the default priority, and runs when the
+/// scheduler picks it. When `entry` returns, the thread exits.
+///
+/// # Returns
+/// + `Ok(ThreadId)` of the new thread
+/// + `Err` if threads haven't been initialized.
+pub fn spawn(entry: fn(usize), arg: usize) -> Result<ThreadId, &'static str> {
+    if current_ptr().is_null() {
+        return Err("Threads have not been initialized!")
+    }
+    let stack = Stack::new(STACK_SIZE)?;
+    interrupts::without_interrupts(|| {
+        let id = {
+            let mut threads = THREADS.lock();
+            let id = threads.next_id();
+            let thread = new_thread(id, stack, entry, arg);
+            threads.all.push(thread);
+            unsafe { threads.wake(thread) };
+            id
+        };
+        trace!("spawned {}", id);
+        unsafe { resched_if_needed() };
+        Ok(id)
+    })
+}
+
+/// Where new threads start running, when they're first switched to.
+extern "C" fn thread_start() -> ! {
+    let (entry, arg) = unsafe { (*current_ptr()).start }
+        .expect("The first thread was started again!");
+    // threads are switched to with interrupts disabled, but run with them
+    // enabled.
+    unsafe { Idt::enable_interrupts() };
+    entry(arg);
+    exit()
+}
+
+/// Returns the state of the thread `id`, or `None` if there's no such
+/// thread, or it has been joined.
+pub fn state(id: ThreadId) -> Option<State> {
+    interrupts::without_interrupts(|| {
+        THREADS.lock().find(id).map(|thread| unsafe { (*thread).state })
+    })
+}
+
+/// Returns the ID of the thread that is running.
+///
+/// # Panics
+/// + if threads haven't been initialized.
+pub fn current() -> ThreadId {
+    let thread = current_ptr();
+    assert!(!thread.is_null(), "Threads have not been initialized!");
+    unsafe { (*thread).id }
+}
+
+/// Returns the priority of the thread `id`, or `None` if there's no such
+/// thread, or it has been joined.
+pub fn priority(id: ThreadId) -> Option<Priority> {
+    interrupts::without_interrupts(|| {
+        THREADS.lock().find(id).map(|thread| unsafe { (*thread).priority })
+    })
+}
+
+/// Change the priority of the thread `id`.
+///
+/// If that means another thread should be running instead of the current
+/// one, the current thread is preempted right away, unless preemption is
+/// disabled.
+///
+/// # Returns
+/// + `Ok(())` if the priority was changed
+/// + `Err` if there's no such thread, or the priority is out of range.
+pub fn set_priority(id: ThreadId, priority: Priority)
+                   -> Result<(), &'static str> {
+    priority.check()?;
+    interrupts::without_interrupts(|| {
+        {
+            let mut threads = THREADS.lock();
+            let thread = threads.find(id).ok_or("No such thread!")?;
+            unsafe {
+                match (*thread).state {
+                    State::Ready => {
+                        // the thread's scheduler may change, and its place
+                        // in the run queue depends on its priority, so it
+                        // is enqueued again.
+                        threads.scheduler.remove(thread);
+                        (*thread).priority = priority;
+                        threads.wake(thread);
+                    }
+                  , State::Running => {
+                        (*thread).priority = priority;
+                        // if another thread should run instead, it will be
+                        // switched to.
+                        NEED_RESCHED.with(|need| need.set(true));
+                    }
+                  , State::Blocked | State::Dead =>
+                        (*thread).priority = priority
+                }
+            }
+        }
+        trace!("set the priority of {} to {}", id, priority);
+        unsafe { resched_if_needed() };
+        Ok(())
+    })
+}
+
+/// Returns how much CPU time the thread `id` has used.
+///
+/// # Returns
+/// + `None` if there's no such thread, it has been joined, or the TSC
+///   hasn't been calibrated.
+pub fn cpu_time(id: ThreadId) -> Option<Duration> {
+    let cycles = interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let thread = threads.find(id);
+        thread.map(|thread| unsafe {
+            if thread == current_ptr() {
+                threads.account(thread);
+            }
+            (*thread).cpu_cycles
+        })
+    });
+    cycles.and_then(time::tsc_duration)
+}
+
+/// Exit the thread that is running.
+///
+/// Its stack is freed when another thread joins it.
+pub fn exit() -> ! {
+    let thread = current_ptr();
+    trace!("{} exited", unsafe { (*thread).id });
+    unsafe { Idt::disable_interrupts() };
+    {
+        let mut threads = THREADS.lock();
+        unsafe {
+            (*thread).state = State::Dead;
+            if let Some(joiner) = (*thread).joiner {
+                threads.wake(joiner);
+            }
+        }
+    }
+    unsafe { schedule() };
+    unreachable!("A dead thread was switched back to!")
+}
+
+/// Wait for the thread `id` to exit, and free it.
+///
+/// # Returns
+/// + `Ok(())` once the thread has exited
+/// + `Err` if there's no such thread, it's the current thread, or another
+///   thread is already joining it.
+pub fn join(id: ThreadId) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| loop {
+        let me = current_ptr();
+        if me.is_null() {
+            return Err("Threads have not been initialized!")
+        }
+        {
+            let mut threads = THREADS.lock();
+            let thread = threads.find(id).ok_or("No such thread!")?;
+            if thread == me {
+                return Err("A thread can't join itself!")
+            }
+            unsafe {
+                match (*thread).joiner {
+                    Some(joiner) if joiner != me =>
+                        return Err("That thread is already being joined!")
+                  , _ => {}
+                }
+                if (*thread).state == State::Dead {
+                    threads.remove(thread);
+                    drop(threads);
+                    free(thread);
+                    return Ok(())
+                }
+                (*thread).joiner = Some(me);
+                (*me).state = State::Blocked;
+            }
+        }
+        // the thread we're joining wakes us when it exits.
+        unsafe { schedule() };
+    })
+}
+
+/// Give up the CPU to the next ready thread, if there is one.
+///
+/// If preemption is disabled, this happens once it's enabled again.
+pub fn yield_now() {
+    interrupts::without_interrupts(|| {
+        let thread = current_ptr();
+        if thread.is_null() { return }
+        if unsafe { (*thread).preempt_count } == 0 {
+            unsafe { schedule() }
+        } else {
+            NEED_RESCHED.with(|need| need.set(true))
+        }
+    })
+}
+
+/// Disable preemption of the running thread, until `preempt_enable` is
+/// called as many times as this was.
+///
+/// The thread can still block, but shouldn't.
+pub fn preempt_disable() {
+    interrupts::without_interrupts(|| {
+        let thread = current_ptr();
+        if !thread.is_null() {
+            unsafe { (*thread).preempt_count += 1 }
+        }
+    })
+}
+
+/// Undo one call to `preempt_disable`.
+///
+/// If that enables preemption, and the thread should have been preempted
+/// while it was disabled, it's preempted now.
+///
+/// # Panics
+/// + if preemption wasn't disabled.
+pub fn preempt_enable() {
+    interrupts::without_interrupts(|| {
+        let thread = current_ptr();
+        if thread.is_null() { return }
+        unsafe {
+            assert!( (*thread).preempt_count > 0
+                   , "preempt_enable() called more than preempt_disable()!");
+            (*thread).preempt_count -= 1;
+            resched_if_needed()
+        }
+    })
+}
+
+/// Returns the number of times preemption of the running thread has been
+/// disabled, and not enabled again.
+pub fn preempt_count() -> usize {
+    interrupts::without_interrupts(|| {
+        let thread = current_ptr();
+        if thread.is_null() { 0 } else { unsafe { (*thread).preempt_count } }
+    })
+}
+
+/// Run `f` with preemption disabled.
+pub fn without_preemption<F, R>(f: F) -> R
+where F: FnOnce() -> R {
+    preempt_disable();
+    let result = f();
+    preempt_enable();
+    result
+}
+
+/// Set how long threads run before they're preempted.
+///
+/// # Returns
+/// + `Ok(())` if the quantum was set
+/// + `Err` if it's shorter than a timer tick.
+pub fn set_quantum(quantum: Duration) -> Result<(), &'static str> {
+    let ticks = quantum_ticks(quantum)
+        .ok_or("Quantum is shorter than a timer tick!")?;
+    interrupts::without_interrupts(|| {
+        THREADS.lock().scheduler.set_quantum(ticks)
+    });
+    Ok(())
+}
+
+/// Replace the scheduler for normal threads, moving every ready normal
+/// thread to `scheduler`.
+///
+/// Real-time threads are always scheduled by the FIFO class.
+///
+/// # Returns
+/// + the old scheduler.
+pub fn set_scheduler(scheduler: &'static mut Scheduler)
+                    -> &'static mut Scheduler {
+    interrupts::without_interrupts(|| {
+        kinfoln!(dots: " . . ", "Using the {} scheduler", scheduler.name());
+        THREADS.lock().scheduler.set_normal(scheduler)
+    })
+}
+
+/// Handler for the timer IRQ, when timer ticks come from the PIT.
+fn timer_tick(_frame: &InterruptFrame) -> IrqResult {
+    tick();
+    IrqResult::Handled
+}
+
+/// Handler for the clock event device, when timer ticks come from it.
+fn clock_tick() {
+    tick();
+    if let Some(device) = clock_event::device() {
+        if let Err(why) = unsafe { program_next_tick(device) } {
+            // fall back to the PIT, rather than never preempting again.
+            warn!("Could not program the {}: {}", device.name(), why);
+            let _ = irq::register_irq(IRQ::Timer, timer_tick);
+        }
+    }
+}
+
+/// Count a timer tick, and decide if the running thread should be
+/// preempted.
+///
+/// The thread isn't switched away from here, since the interrupt hasn't
+/// ended yet; `irq_exit` does that.
+fn tick() {
+    let thread = current_ptr();
+    if thread.is_null() {
+        return
+    }
+    let idle = IDLE.with(|idle| idle.get());
+    let mut threads = THREADS.lock();
+    let preempt = unsafe {
+        (*thread).ticks += 1;
+        threads.account(thread);
+        if thread == idle { threads.scheduler.has_ready() }
+        else { threads.scheduler.tick(&mut *thread) }
+    };
+    if preempt {
+        NEED_RESCHED.with(|need| need.set(true));
+    }
+}
+
+/// Called by IRQ and clock event handlers once the end of the interrupt has
+/// been signalled, to switch threads if the running thread should be
+/// preempted.
+///
+/// Interrupts from user mode are handled on the CPU's kernel stack, which
+/// isn't any thread's, so they don't switch threads.
+pub fn irq_exit(frame: &InterruptFrame) {
+    if current_ptr().is_null() || frame.is_from_user() {
+        return
+    }
+    unsafe { resched_if_needed() }
+}
+
+/// Switch threads if the running thread should be preempted, and
+/// preemption is enabled.
+///
+/// # Safety
+/// + This must be called with interrupts disabled, and the threads
+///   unlocked.
+unsafe fn resched_if_needed() {
+    if (*current_ptr()).preempt_count == 0
+        && NEED_RESCHED.with(|need| need.get()) {
+        schedule()
+    }
+}
+
+/// Switch from the current thread to the next ready thread.
+///
+/// If the current thread is still running, it is enqueued again first, so
+/// if the scheduler picks it, this returns right away. If it isn't running,
+/// and no other thread is ready, this switches to the idle thread.
+///
+/// # Safety
+/// + This must be called with interrupts disabled.
+/// + Nothing here may log, or take any lock but `THREADS`: the thread
+///   being preempted may hold any other lock, and can't release it until
+///   it runs again.
+unsafe fn schedule() {
+    NEED_RESCHED.with(|need| need.set(false));
+    let prev = current_ptr();
+    let idle = IDLE.with(|idle| idle.get());
+    let next = {
+        let mut threads = THREADS.lock();
+        threads.account(prev);
+        if (*prev).state == State::Running {
+            if prev == idle { (*prev).state = State::Ready }
+            else { threads.make_ready(prev) }
+        }
+        match threads.scheduler.dequeue() {
+            Some(mut next) => next.as_mut() as *mut Thread
+          , None => idle
+        }
+    };
+    (*next).state = State::Running;
+    if next == prev {
+        return
+    }
+    (*next).ticks = 0;
+    (*next).last_tsc = timestamp::rtdsc();
+    CURRENT.with(|current| current.set(next));
+    ::arch::fpu::switch((*prev).context.fpu, (*next).context.fpu);
+    context::switch_to(&mut (*prev).context, &(*next).context);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantum_ticks() {
+        let tick = 1_000 / TIMER_HZ as u64;
+        assert_eq!(quantum_ticks(Duration::from_millis(0)), None);
+        assert_eq!(quantum_ticks(Duration::from_millis(tick - 1)), None);
+        assert_eq!(quantum_ticks(Duration::from_millis(tick)), Some(1));
+        assert_eq!(quantum_ticks(DEFAULT_QUANTUM), Some(50 / tick as usize));
+    }
+}
@@ -0,0 +1,117 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Fair scheduling, by virtual runtime.
+//!
+//! Each thread's _virtual runtime_ is the number of TSC cycles it has run
+//! for, scaled by the weight of its nice value: a thread with a lower nice
+//! value has a higher weight, so its virtual runtime grows more slowly, and
+//! it gets more of the CPU. The ready thread that has had the least virtual
+//! runtime runs next, so over time every thread gets its share.
+use super::{RunTree, Scheduler};
+use super::super::Thread;
+use intrusive::tree::Tree;
+use time::{self, Duration};
+
+use core::cmp;
+use core::ptr::Unique;
+
+/// The weight of a thread with a nice value of 0.
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// The weight of each nice value, from -20 to 19.
+///
+/// A thread gets about 10% more of the CPU than one with a nice value one
+/// higher; these are the weights Linux uses.
+const WEIGHTS: [u64; 40] = [ 88761, 71755, 56483, 46273, 36291
+                           , 29154, 23254, 18705, 14949, 11916
+                           , 9548, 7620, 6100, 4904, 3906
+                           , 3121, 2501, 1991, 1586, 1277
+                           , 1024, 820, 655, 526, 423
+                           , 335, 272, 215, 172, 137
+                           , 110, 87, 70, 56, 45
+                           , 36, 29, 23, 18, 15
+                           ];
+
+/// How much less virtual runtime than the running thread a thread that
+/// wakes up must have had to preempt it.
+const WAKEUP_GRANULARITY: Duration = Duration::from_millis(1);
+
+#[inline]
+fn weight(thread: &Thread) -> u64 {
+    WEIGHTS[(thread.priority.nice() + 20) as usize]
+}
+
+/// Fair scheduling: the ready thread with the least virtual runtime runs
+/// next, for at least a quantum.
+pub struct Fair { queue: RunTree
+                , /// The least virtual runtime a ready thread can have.
+                  ///
+                  /// This only increases, so that a thread that has been
+                  /// blocked for a long time, or is new, doesn't get the
+                  /// CPU to itself until it catches up.
+                  min_vruntime: u64
+                , /// The quantum, in timer ticks
+                  quantum: usize
+                }
+
+impl Fair {
+    /// Returns a new fair scheduler with no threads, and a quantum of one
+    /// tick.
+    pub const fn new() -> Self {
+        Fair { queue: Tree::new(), min_vruntime: 0, quantum: 1 }
+    }
+}
+
+impl Scheduler for Fair {
+    fn name(&self) -> &'static str { "fair" }
+
+    fn enqueue(&mut self, mut thread: Unique<Thread>) {
+        unsafe {
+            let thread = thread.as_mut();
+            let vruntime = cmp::max(thread.sched.vruntime, self.min_vruntime);
+            thread.sched.vruntime = vruntime;
+            thread.sched.key = vruntime;
+        }
+        self.queue.insert(thread)
+    }
+
+    fn dequeue(&mut self) -> Option<Unique<Thread>> {
+        self.queue.pop_first().map(|thread| {
+            let vruntime = unsafe { thread.as_ref() }.sched.vruntime;
+            self.min_vruntime = cmp::max(self.min_vruntime, vruntime);
+            thread
+        })
+    }
+
+    #[inline]
+    unsafe fn remove(&mut self, thread: *mut Thread) -> Unique<Thread> {
+        self.queue.remove(thread)
+    }
+
+    #[inline] fn has_ready(&self) -> bool { !self.queue.is_empty() }
+
+    fn tick(&mut self, current: &mut Thread) -> bool {
+        current.ticks() >= self.quantum
+            && self.queue.first()
+                   .map(|next| next.sched.vruntime < current.sched.vruntime)
+                   .unwrap_or(false)
+    }
+
+    fn charge(&mut self, thread: &mut Thread, cycles: u64) {
+        let delta = cycles * NICE_0_WEIGHT / weight(thread);
+        thread.sched.vruntime += delta;
+    }
+
+    fn wakeup_preempts(&self, current: &Thread, woken: &Thread) -> bool {
+        let granularity = time::tsc_cycles(WAKEUP_GRANULARITY).unwrap_or(0);
+        woken.sched.vruntime + granularity < current.sched.vruntime
+    }
+
+    fn set_quantum(&mut self, ticks: usize) { self.quantum = ticks }
+}
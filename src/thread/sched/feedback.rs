@@ -0,0 +1,165 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Multi-level feedback queue scheduling.
+//!
+//! Threads start in the highest-priority queue. A thread that uses up its
+//! allotment of the CPU at one level drops to the next, where it runs for a
+//! longer quantum, but only when nothing above it is ready. Threads that
+//! block a lot, such as interactive ones, stay near the top, and threads
+//! that spin stay near the bottom. So that the threads at the bottom can't
+//! starve, every thread is moved back to the top every `BOOST_INTERVAL`.
+use super::{RunQueue, Scheduler};
+use super::super::Thread;
+use intrusive::list::List;
+use time::TIMER_HZ;
+
+use core::ptr::Unique;
+
+/// The number of queues.
+pub const LEVELS: usize = 4;
+
+/// How often every thread is moved back to its highest level, in timer
+/// ticks.
+pub const BOOST_INTERVAL: usize = TIMER_HZ as usize;
+
+/// Returns the highest level that `thread` can be at.
+///
+/// Threads with positive nice values start lower down.
+#[inline]
+fn top_level(thread: &Thread) -> usize {
+    let nice = thread.priority.nice();
+    if nice > 0 { nice as usize * LEVELS / 20 } else { 0 }
+}
+
+/// Multi-level feedback queue scheduling: each level is a round-robin
+/// queue, with a quantum twice as long as the level above's.
+pub struct Feedback { queues: [RunQueue; LEVELS]
+                    , /// The quantum of the highest level, in timer ticks
+                      quantum: usize
+                    , /// Timer ticks since the last priority boost
+                      since_boost: usize
+                    , /// The number of priority boosts so far
+                      epoch: usize
+                    }
+
+impl Feedback {
+    /// Returns a new feedback queue scheduler with no threads, and a quantum
+    /// of one tick at the highest level.
+    pub const fn new() -> Self {
+        Feedback { queues: [List::new(), List::new(), List::new(), List::new()]
+                 , quantum: 1
+                 , since_boost: 0
+                 , epoch: 0
+                 }
+    }
+
+    /// Returns the quantum of `level`, in timer ticks.
+    ///
+    /// This is also how long a thread can run for at `level`, in total,
+    /// before it drops to the next level.
+    #[inline] fn quantum(&self, level: usize) -> usize {
+        self.quantum << level
+    }
+
+    /// Returns the highest level with a ready thread.
+    fn highest_ready(&self) -> Option<usize> {
+        self.queues.iter().position(|queue| !queue.is_empty())
+    }
+
+    /// Move `thread` back to its highest level, if there has been a boost
+    /// since it got its level.
+    fn boost(&self, thread: &mut Thread) {
+        if thread.sched.epoch != self.epoch {
+            thread.sched.epoch = self.epoch;
+            thread.sched.level = top_level(thread);
+            thread.sched.level_ticks = 0;
+        }
+    }
+
+    /// Move every thread back to its highest level.
+    fn boost_all(&mut self) {
+        self.epoch = self.epoch.wrapping_add(1);
+        // the ready threads are moved now; the others when they're next
+        // enqueued or ticked.
+        let mut boosted = RunQueue::new();
+        for level in 1..LEVELS {
+            while let Some(thread) = self.queues[level].pop_front() {
+                boosted.push_back(thread);
+            }
+        }
+        while let Some(thread) = boosted.pop_front() {
+            self.enqueue(thread);
+        }
+    }
+}
+
+impl Scheduler for Feedback {
+    fn name(&self) -> &'static str { "multi-level feedback queue" }
+
+    fn enqueue(&mut self, mut thread: Unique<Thread>) {
+        let level = unsafe {
+            let thread = thread.as_mut();
+            self.boost(thread);
+            // a thread whose nice value went up moves down to its new
+            // highest level.
+            let top = top_level(thread);
+            if thread.sched.level < top {
+                thread.sched.level = top;
+                thread.sched.level_ticks = 0;
+            }
+            thread.sched.level
+        };
+        self.queues[level].push_back(thread)
+    }
+
+    fn dequeue(&mut self) -> Option<Unique<Thread>> {
+        match self.highest_ready() {
+            Some(level) => self.queues[level].pop_front()
+          , None => None
+        }
+    }
+
+    #[inline]
+    unsafe fn remove(&mut self, thread: *mut Thread) -> Unique<Thread> {
+        self.queues[(*thread).sched.level].remove(thread)
+    }
+
+    fn has_ready(&self) -> bool { self.highest_ready().is_some() }
+
+    fn tick(&mut self, current: &mut Thread) -> bool {
+        self.since_boost += 1;
+        if self.since_boost >= BOOST_INTERVAL {
+            self.since_boost = 0;
+            self.boost_all();
+        }
+        self.boost(current);
+
+        current.sched.level_ticks += 1;
+        let level = current.sched.level;
+        let demoted = current.sched.level_ticks >= self.quantum(level)
+                   && level + 1 < LEVELS;
+        if demoted {
+            current.sched.level += 1;
+            current.sched.level_ticks = 0;
+        }
+        match self.highest_ready() {
+            // a thread at a higher level preempts this one right away.
+            Some(ready) if ready < current.sched.level => true
+          , Some(ready) if ready == current.sched.level =>
+                demoted || current.ticks() >= self.quantum(level)
+          , _ => false
+        }
+    }
+
+    fn wakeup_preempts(&self, current: &Thread, woken: &Thread) -> bool {
+        woken.sched.level < current.sched.level
+    }
+
+    fn set_quantum(&mut self, ticks: usize) { self.quantum = ticks }
+}
@@ -0,0 +1,76 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Real-time first-in, first-out scheduling.
+use super::{Priority, RunTree, Scheduler};
+use super::super::Thread;
+use intrusive::tree::{Tree, TreeNode};
+
+use core::ptr::Unique;
+
+/// Real-time FIFO scheduling: the ready thread with the highest priority
+/// runs until it blocks, exits, or yields, or a thread with a higher
+/// priority is ready. Threads with the same priority run in the order they
+/// became ready in.
+///
+/// There's no quantum, so real-time threads are never time-sliced.
+pub struct Fifo { queue: RunTree }
+
+/// Returns `thread`'s key in the run queue, which is lower for higher
+/// priorities.
+#[inline]
+fn rank(thread: &Thread) -> u64 {
+    match thread.priority {
+        Priority::RealTime(priority) => 99 - priority as u64
+      , Priority::Normal(_) => unreachable!("A normal thread is real-time!")
+    }
+}
+
+impl Fifo {
+    /// Returns a new FIFO scheduler with no threads.
+    pub const fn new() -> Self {
+        Fifo { queue: Tree::new() }
+    }
+}
+
+impl Scheduler for Fifo {
+    fn name(&self) -> &'static str { "real-time FIFO" }
+
+    fn enqueue(&mut self, mut thread: Unique<Thread>) {
+        unsafe {
+            let thread = thread.as_mut();
+            let key = rank(thread);
+            thread.sched.key = key;
+        }
+        self.queue.insert(thread)
+    }
+
+    #[inline] fn dequeue(&mut self) -> Option<Unique<Thread>> {
+        self.queue.pop_first()
+    }
+
+    #[inline]
+    unsafe fn remove(&mut self, thread: *mut Thread) -> Unique<Thread> {
+        self.queue.remove(thread)
+    }
+
+    #[inline] fn has_ready(&self) -> bool { !self.queue.is_empty() }
+
+    fn tick(&mut self, current: &mut Thread) -> bool {
+        self.queue.first()
+            .map(|next| next.key() < rank(current))
+            .unwrap_or(false)
+    }
+
+    fn wakeup_preempts(&self, current: &Thread, woken: &Thread) -> bool {
+        rank(woken) < rank(current)
+    }
+
+    /// Real-time threads don't have a quantum, so this does nothing.
+    fn set_quantum(&mut self, _ticks: usize) { }
+}
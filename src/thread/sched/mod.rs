@@ -0,0 +1,275 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Scheduling policies.
+//!
+//! The `thread` module decides _when_ to switch threads: when the running
+//! thread blocks, exits, or yields, when a timer tick says it should be
+//! preempted, or when a thread that should run instead wakes up. A
+//! [`Scheduler`] decides _which_ ready thread runs next, and whether the
+//! running thread has had the CPU for long enough.
+//!
+//! Threads are divided into two scheduling classes by their [`Priority`].
+//! Real-time threads are scheduled by the [`Fifo`] class, and always run
+//! before any normal thread. Normal threads are scheduled by whichever
+//! policy is in use for them: [`RoundRobin`], the multi-level feedback
+//! queue ([`Feedback`]), or the virtual-runtime policy ([`Fair`]).
+//!
+//! Ready threads are kept in intrusive run queues and trees, so enqueueing
+//! a thread never allocates.
+//!
+//! [`Scheduler`]: trait.Scheduler.html
+//! [`Priority`]: enum.Priority.html
+//! [`Fifo`]: fifo/struct.Fifo.html
+//! [`RoundRobin`]: round_robin/struct.RoundRobin.html
+//! [`Feedback`]: feedback/struct.Feedback.html
+//! [`Fair`]: fair/struct.Fair.html
+use super::Thread;
+use intrusive::list::List;
+use intrusive::tree::{Links, Tree, TreeNode};
+
+use core::{fmt, mem};
+use core::ptr::Unique;
+
+pub mod fair;
+pub mod feedback;
+pub mod fifo;
+pub mod round_robin;
+pub use self::fair::Fair;
+pub use self::feedback::Feedback;
+pub use self::fifo::Fifo;
+pub use self::round_robin::RoundRobin;
+
+/// A queue of threads that are ready to run.
+pub type RunQueue = List<Unique<Thread>, Thread>;
+
+/// Threads that are ready to run, ordered by the key their scheduler gave
+/// them when they were enqueued.
+pub type RunTree = Tree<Unique<Thread>, Thread>;
+
+/// How important a thread is.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Priority { /// A real-time priority, from 1 to 99.
+                    ///
+                    /// Real-time threads run before every normal thread,
+                    /// and aren't preempted except by real-time threads
+                    /// with higher priorities.
+                    RealTime(u8)
+                  , /// A normal thread's _nice value_, from -20 to 19.
+                    ///
+                    /// Threads with lower nice values get more of the CPU.
+                    Normal(i8)
+                  }
+
+impl Priority {
+    /// Returns true if this is a real-time priority.
+    #[inline] pub fn is_realtime(&self) -> bool {
+        match *self { Priority::RealTime(_) => true
+                    , Priority::Normal(_) => false
+                    }
+    }
+
+    /// Returns the nice value of a normal priority, or 0 for a real-time
+    /// one.
+    #[inline] pub fn nice(&self) -> i8 {
+        match *self { Priority::Normal(nice) => nice
+                    , Priority::RealTime(_) => 0
+                    }
+    }
+
+    /// Returns `Ok` if the priority is in range.
+    pub fn check(&self) -> Result<(), &'static str> {
+        match *self {
+            Priority::RealTime(1...99) | Priority::Normal(-20...19) => Ok(())
+          , Priority::RealTime(_) =>
+                Err("Real-time priorities must be from 1 to 99!")
+          , Priority::Normal(_) =>
+                Err("Nice values must be from -20 to 19!")
+        }
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self { Priority::Normal(0) }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Priority::RealTime(priority) =>
+                write!(f, "real-time priority {}", priority)
+          , Priority::Normal(nice) => write!(f, "nice {}", nice)
+        }
+    }
+}
+
+/// The state a thread's scheduler keeps in it.
+///
+/// Each scheduling class only uses its own fields.
+pub struct Entity { /// The thread's key in a `RunTree`, which mustn't
+                    /// change while it's in the tree
+                    key: u64
+                  , links: Links<Thread>
+                  , /// Weighted TSC cycles the thread has run for (`Fair`)
+                    vruntime: u64
+                  , /// The thread's queue (`Feedback`)
+                    level: usize
+                  , /// Timer ticks the thread has run for at its level
+                    /// (`Feedback`)
+                    level_ticks: usize
+                  , /// The priority boost the thread's level is from
+                    /// (`Feedback`)
+                    epoch: usize
+                  }
+
+impl Entity {
+    pub const fn new() -> Self {
+        Entity { key: 0
+               , links: Links::new()
+               , vruntime: 0
+               , level: 0
+               , level_ticks: 0
+               , epoch: 0
+               }
+    }
+}
+
+impl TreeNode for Thread {
+    type Key = u64;
+
+    #[inline] fn key(&self) -> u64 { self.sched.key }
+
+    #[inline] fn links(&self) -> &Links<Thread> { &self.sched.links }
+
+    #[inline] fn links_mut(&mut self) -> &mut Links<Thread> {
+        &mut self.sched.links
+    }
+}
+
+/// A scheduling policy.
+///
+/// Every method is called with interrupts disabled, and the scheduler's
+/// lock held.
+pub trait Scheduler {
+    /// Returns the name of the policy.
+    fn name(&self) -> &'static str;
+
+    /// Add `thread`, which is ready to run, to the run queue.
+    fn enqueue(&mut self, thread: Unique<Thread>);
+
+    /// Remove and return the thread that should run next, if any are ready.
+    fn dequeue(&mut self) -> Option<Unique<Thread>>;
+
+    /// Remove `thread`, which must have been enqueued, from the run queue.
+    unsafe fn remove(&mut self, thread: *mut Thread) -> Unique<Thread>;
+
+    /// Returns true if any threads are ready to run.
+    fn has_ready(&self) -> bool;
+
+    /// Called on every timer tick, with the running thread.
+    ///
+    /// # Returns
+    /// + true if the thread should be preempted.
+    fn tick(&mut self, current: &mut Thread) -> bool;
+
+    /// Called when `thread` has run for another `cycles` TSC cycles.
+    fn charge(&mut self, _thread: &mut Thread, _cycles: u64) { }
+
+    /// Called when `woken` has been enqueued, while `current` is running.
+    ///
+    /// # Returns
+    /// + true if `current` should be preempted to run `woken`.
+    fn wakeup_preempts(&self, _current: &Thread, _woken: &Thread) -> bool {
+        false
+    }
+
+    /// Set how many timer ticks a thread may run for before it's preempted.
+    fn set_quantum(&mut self, ticks: usize);
+}
+
+/// The scheduling classes: real-time threads are scheduled first, by the
+/// `Fifo` class, and normal threads by the `normal` scheduler.
+pub struct Classes { realtime: Fifo
+                   , normal: &'static mut Scheduler
+                   }
+
+impl Classes {
+    pub fn new(normal: &'static mut Scheduler) -> Self {
+        Classes { realtime: Fifo::new(), normal: normal }
+    }
+
+    /// Returns the class that schedules `thread`.
+    #[inline]
+    fn class(&mut self, thread: &Thread) -> &mut Scheduler {
+        if thread.priority.is_realtime() { &mut self.realtime }
+        else { &mut *self.normal }
+    }
+
+    /// Replace the scheduler for normal threads, moving every ready normal
+    /// thread to `normal`.
+    ///
+    /// # Returns
+    /// + the old scheduler.
+    pub fn set_normal(&mut self, normal: &'static mut Scheduler)
+                     -> &'static mut Scheduler {
+        while let Some(thread) = self.normal.dequeue() {
+            normal.enqueue(thread);
+        }
+        mem::replace(&mut self.normal, normal)
+    }
+}
+
+impl Scheduler for Classes {
+    fn name(&self) -> &'static str { self.normal.name() }
+
+    fn enqueue(&mut self, thread: Unique<Thread>) {
+        let class = self.class(unsafe { thread.as_ref() });
+        class.enqueue(thread)
+    }
+
+    fn dequeue(&mut self) -> Option<Unique<Thread>> {
+        self.realtime.dequeue().or_else(|| self.normal.dequeue())
+    }
+
+    unsafe fn remove(&mut self, thread: *mut Thread) -> Unique<Thread> {
+        self.class(&*thread).remove(thread)
+    }
+
+    fn has_ready(&self) -> bool {
+        self.realtime.has_ready() || self.normal.has_ready()
+    }
+
+    fn tick(&mut self, current: &mut Thread) -> bool {
+        if current.priority.is_realtime() {
+            self.realtime.tick(current)
+        } else {
+            // the normal scheduler is always told about the tick, even if
+            // a real-time thread preempts this one.
+            self.normal.tick(current) || self.realtime.has_ready()
+        }
+    }
+
+    fn charge(&mut self, thread: &mut Thread, cycles: u64) {
+        if thread.priority.is_realtime() {
+            self.realtime.charge(thread, cycles)
+        } else {
+            self.normal.charge(thread, cycles)
+        }
+    }
+
+    fn wakeup_preempts(&self, current: &Thread, woken: &Thread) -> bool {
+        match (current.priority.is_realtime(), woken.priority.is_realtime()) {
+            (false, true) => true
+          , (true, false) => false
+          , (true, true) => self.realtime.wakeup_preempts(current, woken)
+          , (false, false) => self.normal.wakeup_preempts(current, woken)
+        }
+    }
+
+    fn set_quantum(&mut self, ticks: usize) { self.normal.set_quantum(ticks) }
+}
@@ -0,0 +1,57 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Round-robin scheduling.
+use super::{RunQueue, Scheduler};
+use super::super::Thread;
+use intrusive::list::List;
+
+use core::ptr::Unique;
+
+/// Round-robin scheduling: ready threads run in turn, each for at most a
+/// quantum.
+///
+/// Nice values are ignored.
+pub struct RoundRobin { queue: RunQueue
+                      , /// The quantum, in timer ticks
+                        quantum: usize
+                      }
+
+impl RoundRobin {
+    /// Returns a new round-robin scheduler with no threads, and a quantum
+    /// of one tick.
+    pub const fn new() -> Self {
+        RoundRobin { queue: List::new(), quantum: 1 }
+    }
+}
+
+impl Scheduler for RoundRobin {
+    fn name(&self) -> &'static str { "round robin" }
+
+    #[inline] fn enqueue(&mut self, thread: Unique<Thread>) {
+        self.queue.push_back(thread)
+    }
+
+    #[inline] fn dequeue(&mut self) -> Option<Unique<Thread>> {
+        self.queue.pop_front()
+    }
+
+    #[inline]
+    unsafe fn remove(&mut self, thread: *mut Thread) -> Unique<Thread> {
+        self.queue.remove(thread)
+    }
+
+    #[inline] fn has_ready(&self) -> bool { !self.queue.is_empty() }
+
+    fn tick(&mut self, current: &mut Thread) -> bool {
+        // there's no point switching if nothing else can run.
+        current.ticks() >= self.quantum && self.has_ready()
+    }
+
+    fn set_quantum(&mut self, ticks: usize) { self.quantum = ticks }
+}
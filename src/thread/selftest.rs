@@ -0,0 +1,99 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A smoke test of kernel threads, run while booting if the `boot-selftest`
+//! feature is enabled.
+use super::{ cpu_time, current, join, priority, set_priority, spawn, state
+           , without_preemption, yield_now, Priority, State };
+use time::{Duration, TIMER_HZ};
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// Added to by the threads that `test` spawns.
+static TEST_SUM: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn test_thread(n: usize) {
+    TEST_SUM.fetch_add(n, Ordering::SeqCst);
+}
+
+/// Set to the argument of the first of the threads `test` spawns to call
+/// `test_first` to do so.
+static TEST_FIRST: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn test_first(n: usize) {
+    TEST_FIRST.compare_and_swap(0, n, Ordering::SeqCst);
+}
+
+/// Spins until a few timer ticks have passed, without yielding.
+fn test_spinner(_: usize) {
+    use arch::interrupts::ticks;
+    let start = ticks();
+    while ticks() < start + 2 * TIMER_HZ as usize / 10 { }
+    TEST_SUM.fetch_add(10, Ordering::SeqCst);
+}
+
+/// Spawn some threads and join them, and check that they ran, that a thread
+/// that never yields is preempted, that real-time threads run first, and
+/// that CPU time is counted.
+pub fn test() -> Result<(), &'static str> {
+    TEST_SUM.store(0, Ordering::SeqCst);
+    let a = spawn(test_thread, 1)?;
+    let b = spawn(test_thread, 2)?;
+    join(a)?;
+    join(b)?;
+    if TEST_SUM.load(Ordering::SeqCst) != 3 {
+        return Err("spawned threads didn't run!")
+    }
+    if state(a).is_some() || state(b).is_some() {
+        return Err("joined threads weren't freed!")
+    }
+
+    // if the spinner isn't preempted, this thread doesn't run again until
+    // it has finished.
+    let spinner = spawn(test_spinner, 0)?;
+    yield_now();
+    let preempted = TEST_SUM.load(Ordering::SeqCst) == 3;
+    // a thread's CPU time can't be read once it has been joined.
+    while state(spinner) != Some(State::Dead) {
+        yield_now();
+    }
+    let spun = cpu_time(spinner);
+    join(spinner)?;
+    if !preempted {
+        return Err("a spinning thread wasn't preempted!")
+    }
+    // the spinner ran for at least a tick, unless the TSC can't tell.
+    let tick = Duration::from_millis(1_000 / TIMER_HZ as u64);
+    if spun.map(|spun| spun < tick).unwrap_or(false) {
+        return Err("a thread's CPU time wasn't counted!")
+    }
+
+    // a thread made real-time runs before every normal thread, as soon as
+    // preemption is enabled.
+    if set_priority(current(), Priority::Normal(20)).is_ok() {
+        return Err("an out of range priority was accepted!")
+    }
+    TEST_FIRST.store(0, Ordering::SeqCst);
+    let (low, high) = without_preemption(|| -> Result<_, &'static str> {
+        let low = spawn(test_first, 1)?;
+        let high = spawn(test_first, 2)?;
+        set_priority(high, Priority::RealTime(10))?;
+        Ok((low, high))
+    })?;
+    let first = TEST_FIRST.load(Ordering::SeqCst);
+    let high_priority = priority(high);
+    join(low)?;
+    join(high)?;
+    if high_priority != Some(Priority::RealTime(10)) {
+        return Err("a thread's priority wasn't changed!")
+    }
+    if first != 2 {
+        return Err("a real-time thread didn't run first!")
+    }
+    Ok(())
+}
@@ -0,0 +1,129 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Kernel thread stacks.
+//!
+//! Each stack is allocated on the heap, and its lowest page is a _guard
+//! page_, which is made not present. A thread that overflows its stack
+//! faults on the guard page, rather than quietly overwriting whatever is
+//! below it.
+//!
+//! Guard pages are made in the kernel's page table, which the boot code
+//! hands over with [`set_page_table`] once it's done mapping things. Stacks
+//! needed before then, such as the application processors', are made with
+//! [`Stack::new_in`] instead.
+//!
+//! [`set_page_table`]: fn.set_page_table.html
+//! [`Stack::new_in`]: struct.Stack.html#method.new_in
+use collections::vec::Vec;
+use core::mem;
+use cpu::interrupts;
+use memory::{Page, VAddr, VirtualPage, PAGE_SIZE};
+use paging::arch::ActivePageTable;
+use spin::Mutex;
+
+/// The kernel's page table, once it has been handed over.
+///
+/// This is only locked with interrupts disabled, since stacks are freed
+/// with them disabled, and the lock could be held by a preempted thread.
+static PAGE_TABLE: Mutex<Option<ActivePageTable>> = Mutex::new(None);
+
+/// Hand over the kernel's page table, to make guard pages in.
+pub fn set_page_table(table: ActivePageTable) {
+    interrupts::without_interrupts(|| *PAGE_TABLE.lock() = Some(table))
+}
+
+/// Make `page` present or not present in the kernel's page table.
+fn set_present(page: VirtualPage, present: bool) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        PAGE_TABLE.lock()
+                  .as_mut()
+                  .ok_or("The kernel's page table hasn't been handed over!")
+                  .and_then(|table| table.set_present(page, present))
+    })
+}
+
+/// A kernel thread stack, with a guard page below it.
+pub struct Stack { _buf: Vec<u8>
+                 , /// The guard page, which is the lowest page of the stack
+                   guard: VirtualPage
+                 , /// The (exclusive) top of the stack
+                   top: VAddr
+                 }
+
+impl Stack {
+    /// Allocate a stack of `size` usable bytes, plus a guard page.
+    ///
+    /// # Returns
+    /// + `Ok(Stack)` if the stack was allocated
+    /// + `Err` if `size` isn't a nonzero number of pages, or the guard page
+    ///   couldn't be made, e.g. because the heap is mapped with huge pages.
+    pub fn new(size: usize) -> Result<Self, &'static str> {
+        Stack::allocate(size, |guard| set_present(guard, false))
+    }
+
+    /// Allocate a stack like `new`, making its guard page in `table`
+    /// rather than in the page table that was handed over.
+    ///
+    /// This is for stacks that are needed before the kernel's page table
+    /// is handed over. They can't be freed until it has been, so they
+    /// should be [`leak`]ed.
+    ///
+    /// [`leak`]: #method.leak
+    pub fn new_in(size: usize, table: &mut ActivePageTable)
+                 -> Result<Self, &'static str> {
+        Stack::allocate(size, |guard| table.set_present(guard, false))
+    }
+
+    /// Allocate a stack of `size` bytes, plus a guard page, which is made
+    /// not present by `make_guard`.
+    fn allocate<F>(size: usize, make_guard: F) -> Result<Self, &'static str>
+    where F: FnOnce(VirtualPage) -> Result<(), &'static str> {
+        let page_size = PAGE_SIZE as usize;
+        if size == 0 || size % page_size != 0 {
+            return Err("Stack size must be a nonzero number of pages!")
+        }
+        // the guard page has to be a whole page of our own, so allocate
+        // enough to align the stack to a page.
+        let mut buf: Vec<u8> = Vec::with_capacity(size + 2 * page_size);
+        let base = (buf.as_mut_ptr() as usize + page_size - 1)
+                 & !(page_size - 1);
+        let guard = VirtualPage::containing(VAddr::from(base));
+        make_guard(guard)?;
+        trace!("stack guard page at {:#x}", base);
+        Ok(Stack { _buf: buf
+                 , guard: guard
+                 , top: VAddr::from(base + page_size + size)
+                 })
+    }
+
+    /// Returns the (exclusive) top of the stack.
+    #[inline] pub fn top(&self) -> VAddr { self.top }
+
+    /// Returns the stack's guard page.
+    #[inline] pub fn guard_page(&self) -> VirtualPage { self.guard }
+
+    /// Keep the stack for the rest of the kernel's lifetime, and return its
+    /// top.
+    ///
+    /// This is for stacks that are never freed, such as the ones a CPU
+    /// runs on from boot.
+    pub fn leak(self) -> VAddr {
+        let top = self.top;
+        mem::forget(self);
+        top
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        // the guard page is heap memory; put it back before it's freed.
+        set_present(self.guard, true)
+            .expect("A stack's guard page couldn't be made present again!");
+    }
+}
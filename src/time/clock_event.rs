@@ -0,0 +1,151 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Clock event devices.
+//!
+//! A clock event device is a timer that can interrupt us once, after a
+//! given delay (_one-shot_ mode), or repeatedly (_periodic_ mode). Drivers
+//! [`register`] their devices, and the best one (the one with the highest
+//! [`rating`]) is used. Whoever wants to be woken up (e.g. the scheduler)
+//! sets the [`event handler`], and [`program`]s the device for the next
+//! deadline.
+//!
+//! [`register`]: fn.register.html
+//! [`rating`]: trait.ClockEventDevice.html#tymethod.rating
+//! [`event handler`]: fn.set_event_handler.html
+//! [`program`]: trait.ClockEventDevice.html#method.program
+use super::{Duration, Instant};
+use cpu::interrupts;
+use spin::Mutex;
+
+use core::{cmp, mem};
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+bitflags! {
+    /// The modes a `ClockEventDevice` supports.
+    pub flags Features: u8 {
+        /// The device can interrupt us repeatedly
+        const PERIODIC = 1 << 0
+      , /// The device can interrupt us once
+        const ONESHOT = 1 << 1
+      , /// Each CPU has its own device, and programming it only affects the
+        /// current CPU
+        const PER_CPU = 1 << 2
+    }
+}
+
+/// A timer that can interrupt us at a given time.
+pub trait ClockEventDevice: Sync {
+    /// Returns the name of the device.
+    fn name(&self) -> &'static str;
+
+    /// Returns the modes the device supports.
+    fn features(&self) -> Features;
+
+    /// Returns how good the device is; the highest rated device is used.
+    fn rating(&self) -> u32;
+
+    /// Returns the shortest delay the device can be programmed with.
+    fn min_delta(&self) -> Duration;
+
+    /// Returns the longest delay the device can be programmed with.
+    fn max_delta(&self) -> Duration;
+
+    /// Interrupt us every `period`.
+    ///
+    /// # Safety
+    /// + The device's interrupt handler must call `handle_event()`.
+    unsafe fn set_periodic(&self, period: Duration)
+                          -> Result<(), &'static str>;
+
+    /// Interrupt us once, after `delta`.
+    ///
+    /// `delta` must be between `min_delta()` and `max_delta()`.
+    ///
+    /// # Safety
+    /// + The same as `set_periodic`.
+    unsafe fn set_next_event(&self, delta: Duration)
+                            -> Result<(), &'static str>;
+
+    /// Stop the device from interrupting us.
+    ///
+    /// # Safety
+    /// + Whoever was waiting for the next event won't be woken up.
+    unsafe fn shutdown(&self);
+
+    /// Interrupt us once, at `deadline`.
+    ///
+    /// Deadlines that are too soon (or have already passed) or too far away
+    /// are moved to the nearest time the device can be programmed for.
+    ///
+    /// # Safety
+    /// + The same as `set_periodic`.
+    unsafe fn program(&self, deadline: Instant) -> Result<(), &'static str> {
+        let delta = deadline.duration_since(Instant::now());
+        self.set_next_event(cmp::min( cmp::max(delta, self.min_delta())
+                                    , self.max_delta()))
+    }
+}
+
+/// The best clock event device that has been registered.
+static DEVICE: Mutex<Option<&'static ClockEventDevice>> = Mutex::new(None);
+
+/// The function called when the clock event device fires, as a `usize`.
+static HANDLER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The number of times a clock event device has fired.
+static EVENTS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Register a clock event device.
+///
+/// The device will be used if it's rated higher than the one in use.
+pub fn register(device: &'static ClockEventDevice) {
+    interrupts::without_interrupts(|| {
+        let mut current = DEVICE.lock();
+        let better = current.map(|current| device.rating() > current.rating())
+                            .unwrap_or(true);
+        kinfoln!( dots: " . . ", "Clock event device {} (rating {}){}"
+                , device.name(), device.rating()
+                , if better { " will be used" } else { "" });
+        if better {
+            if let Some(old) = *current {
+                unsafe { old.shutdown() }
+            }
+            *current = Some(device);
+        }
+    })
+}
+
+/// Returns the clock event device in use, if there is one.
+pub fn device() -> Option<&'static ClockEventDevice> {
+    interrupts::without_interrupts(|| *DEVICE.lock())
+}
+
+/// Set the function that is called whenever the clock event device fires.
+///
+/// The handler is called in interrupt context.
+pub fn set_event_handler(handler: fn()) {
+    HANDLER.store(handler as usize, Ordering::Release);
+}
+
+/// Returns the number of times a clock event device has fired.
+#[inline]
+pub fn events() -> usize { EVENTS.load(Ordering::Relaxed) }
+
+/// Called by clock event devices' interrupt handlers when they fire.
+pub fn handle_event() {
+    EVENTS.fetch_add(1, Ordering::Relaxed);
+    match HANDLER.load(Ordering::Acquire) {
+        0 => {}
+      , handler => {
+            // this is safe, since only `fn()`s are stored in `HANDLER`.
+            let handler: fn() = unsafe { mem::transmute(handler) };
+            handler()
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Keeping time.
+//!
+//! The PIT fires the timer IRQ `TIMER_HZ` times a second. Time since boot is
+//! measured with the timestamp counter (TSC), whose frequency is calibrated
+//! against the PIT. The TSC is only used if it is _invariant_, i.e. it runs
+//! at the same rate in every power state; otherwise, or if it can't be used
+//! at all, time is measured by counting timer IRQs instead, which is much
+//! less precise.
+//!
+//! [`Instant`] and [`Duration`] are modelled on the types of the same names
+//! in `std::time`. Timers that can interrupt us at a given time are
+//! [`clock_event`] devices.
+//!
+//! [`Instant`]: struct.Instant.html
+//! [`Duration`]: struct.Duration.html
+//! [`clock_event`]: clock_event/index.html
+use arch::interrupts;
+use cpu::cpuid;
+use cpu::timer::{pit, timestamp};
+
+use core::{fmt, ops};
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+pub mod clock_event;
+
+/// Frequency of the timer IRQ, in Hz.
+pub const TIMER_HZ: u32 = 100;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const NANOS_PER_MILLI: u64 = 1_000_000;
+const NANOS_PER_MICRO: u64 = 1_000;
+
+/// The frequency of the TSC, in Hz, or 0 if it isn't used.
+static TSC_HZ: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The value of the TSC when `initialize()` was called.
+static BOOT_TSC: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The frequency of the timer IRQ that the PIT is actually running at.
+static PIT_HZ: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A span of time, with nanosecond precision.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Duration { nanos: u64 }
+
+impl Duration {
+    /// Returns a `Duration` of `nanos` nanoseconds.
+    #[inline]
+    pub const fn from_nanos(nanos: u64) -> Self { Duration { nanos: nanos } }
+
+    /// Returns a `Duration` of `micros` microseconds.
+    #[inline]
+    pub const fn from_micros(micros: u64) -> Self {
+        Duration { nanos: micros * NANOS_PER_MICRO }
+    }
+
+    /// Returns a `Duration` of `millis` milliseconds.
+    #[inline]
+    pub const fn from_millis(millis: u64) -> Self {
+        Duration { nanos: millis * NANOS_PER_MILLI }
+    }
+
+    /// Returns a `Duration` of `secs` seconds.
+    #[inline]
+    pub const fn from_secs(secs: u64) -> Self {
+        Duration { nanos: secs * NANOS_PER_SEC }
+    }
+
+    /// Returns the number of whole seconds in this `Duration`.
+    #[inline] pub fn as_secs(&self) -> u64 { self.nanos / NANOS_PER_SEC }
+
+    /// Returns the number of whole milliseconds in this `Duration`.
+    #[inline] pub fn as_millis(&self) -> u64 { self.nanos / NANOS_PER_MILLI }
+
+    /// Returns the number of whole microseconds in this `Duration`.
+    #[inline] pub fn as_micros(&self) -> u64 { self.nanos / NANOS_PER_MICRO }
+
+    /// Returns the number of nanoseconds in this `Duration`.
+    #[inline] pub fn as_nanos(&self) -> u64 { self.nanos }
+
+    /// Returns the fractional part of this `Duration`, in nanoseconds.
+    #[inline] pub fn subsec_nanos(&self) -> u32 {
+        (self.nanos % NANOS_PER_SEC) as u32
+    }
+
+    /// Returns `self - other`, or `None` if `other` is longer.
+    #[inline]
+    pub fn checked_sub(&self, other: Duration) -> Option<Duration> {
+        self.nanos.checked_sub(other.nanos).map(Duration::from_nanos)
+    }
+}
+
+impl ops::Add for Duration {
+    type Output = Duration;
+    #[inline] fn add(self, rhs: Duration) -> Duration {
+        Duration { nanos: self.nanos + rhs.nanos }
+    }
+}
+
+impl ops::Sub for Duration {
+    type Output = Duration;
+    #[inline] fn sub(self, rhs: Duration) -> Duration {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting durations")
+    }
+}
+
+impl ops::AddAssign for Duration {
+    #[inline] fn add_assign(&mut self, rhs: Duration) { *self = *self + rhs }
+}
+
+impl ops::SubAssign for Duration {
+    #[inline] fn sub_assign(&mut self, rhs: Duration) { *self = *self - rhs }
+}
+
+impl ops::Mul<u64> for Duration {
+    type Output = Duration;
+    #[inline] fn mul(self, rhs: u64) -> Duration {
+        Duration { nanos: self.nanos * rhs }
+    }
+}
+
+impl ops::Div<u64> for Duration {
+    type Output = Duration;
+    #[inline] fn div(self, rhs: u64) -> Duration {
+        Duration { nanos: self.nanos / rhs }
+    }
+}
+
+impl fmt::Display for Duration {
+    /// Formats the `Duration` as seconds, with microsecond precision.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "{}.{:06}s"
+              , self.as_secs(), self.subsec_nanos() / NANOS_PER_MICRO as u32)
+    }
+}
+
+/// A point in time, measured since boot.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Instant(Duration);
+
+impl Instant {
+    /// Returns the current time.
+    #[inline] pub fn now() -> Self { Instant(uptime()) }
+
+    /// Returns the time elapsed since `earlier`.
+    ///
+    /// If `earlier` is later than `self` (which can happen if the two were
+    /// read on different CPUs), this returns zero.
+    #[inline]
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.0.checked_sub(earlier.0).unwrap_or_default()
+    }
+
+    /// Returns the time elapsed since this `Instant`.
+    #[inline]
+    pub fn elapsed(&self) -> Duration { Instant::now().duration_since(*self) }
+
+    /// Returns the time between boot and this `Instant`.
+    #[inline] pub fn since_boot(&self) -> Duration { self.0 }
+}
+
+impl ops::Add<Duration> for Instant {
+    type Output = Instant;
+    #[inline] fn add(self, rhs: Duration) -> Instant { Instant(self.0 + rhs) }
+}
+
+impl ops::Sub<Duration> for Instant {
+    type Output = Instant;
+    #[inline] fn sub(self, rhs: Duration) -> Instant { Instant(self.0 - rhs) }
+}
+
+impl ops::Sub for Instant {
+    type Output = Duration;
+    #[inline] fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Returns the frequency of the TSC, in Hz, if it has been calibrated.
+#[inline]
+pub fn tsc_hz() -> Option<u64> {
+    match TSC_HZ.load(Ordering::Relaxed) { 0 => None
+                                         , hz => Some(hz as u64)
+                                         }
+}
+
+/// Returns the number of TSC cycles in `duration`, if the TSC has been
+/// calibrated.
+pub fn tsc_cycles(duration: Duration) -> Option<u64> {
+    tsc_hz().map(|hz|
+        // whole seconds and the remainder are converted separately, so this
+        // doesn't overflow.
+        duration.as_secs() * hz
+            + duration.subsec_nanos() as u64 * hz / NANOS_PER_SEC)
+}
+
+/// Returns how long `cycles` TSC cycles take, if the TSC has been
+/// calibrated.
+pub fn tsc_duration(cycles: u64) -> Option<Duration> {
+    tsc_hz().map(|hz| {
+        // split the cycles into whole seconds and the remainder, so that
+        // converting them to nanoseconds doesn't overflow.
+        let secs = cycles / hz;
+        let rest = cycles % hz;
+        Duration::from_nanos(secs * NANOS_PER_SEC + rest * NANOS_PER_SEC / hz)
+    })
+}
+
+/// Returns the time since `initialize()` was called.
+///
+/// Before then, this is always zero.
+pub fn uptime() -> Duration {
+    // the TSC is only read once it's been calibrated, since `rdtsc` faults
+    // on CPUs without one.
+    tsc_hz().and_then(|_| {
+        let cycles = unsafe { timestamp::rtdsc() }
+            .wrapping_sub(BOOT_TSC.load(Ordering::Relaxed) as u64);
+        tsc_duration(cycles)
+    }).unwrap_or_else(||
+        match PIT_HZ.load(Ordering::Relaxed) as u64 {
+            0 => Duration::default()
+          , pit_hz => Duration::from_nanos( interrupts::ticks() as u64
+                                          * NANOS_PER_SEC / pit_hz)
+        })
+}
+
+/// Wait until at least `duration` has passed.
+///
+/// This halts until the next interrupt between checks, so interrupts have to
+/// be enabled, or it will never return.
+pub fn sleep(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        unsafe { asm!("hlt" :::: "volatile"); }
+    }
+}
+
+/// Start the system timer, and calibrate the TSC, if it is invariant.
+///
+/// # Safety
+/// + This should be called once, by the bootstrap processor, during boot.
+pub unsafe fn initialize() -> Result<(), &'static str> {
+    let pit_hz = pit::set_frequency(TIMER_HZ);
+    PIT_HZ.store(pit_hz as usize, Ordering::Relaxed);
+    kinfoln!(dots: " . . ", "System timer running at {} Hz", pit_hz);
+
+    let tsc = if !cpuid::has_invariant_tsc() {
+        Err("CPU has no invariant TSC.")
+    } else {
+        timestamp::calibrate()
+    };
+    match tsc {
+        Ok(tsc_hz) => {
+            BOOT_TSC.store(timestamp::rtdsc() as usize, Ordering::Relaxed);
+            TSC_HZ.store(tsc_hz as usize, Ordering::Relaxed);
+            kinfoln!( dots: " . . ", "TSC runs at {}.{:03} MHz"
+                    , tsc_hz / 1_000_000, tsc_hz / 1_000 % 1_000);
+        }
+      , Err(why) => kinfoln!( dots: " . . ", "Not using the TSC: {}", why)
+    }
+    Ok(())
+}
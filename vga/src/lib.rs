@@ -19,7 +19,7 @@
 #![feature( const_fn
           , slice_patterns
           , unique )]
-#![cfg_attr(feature = "system_term", feature(lang_items))]
+#![cfg_attr(feature = "system_term", feature(lang_items, asm))]
 #![cfg_attr(feature = "clippy", feature(plugin))]
 #![cfg_attr(feature = "clippy", plugin(clippy))]
 #![no_std]
@@ -71,12 +71,39 @@ macro_rules! println {
 macro_rules! print {
     ($($arg:tt)*) => ({
             use core::fmt::Write;
-            $crate::CONSOLE.lock()
-                           .write_fmt(format_args!($($arg)*))
-                           .unwrap();
+            $crate::without_interrupts(|| {
+                $crate::CONSOLE.lock()
+                               .write_fmt(format_args!($($arg)*))
+                               .unwrap();
+            })
     });
 }
 
+/// Run `f` with interrupts disabled.
+///
+/// `print!` only locks the `CONSOLE` with interrupts disabled, so a thread
+/// can't be preempted in the middle of printing. This is the `cpu` crate's
+/// `without_interrupts`, which can't be used here, since `cpu` depends on
+/// this crate.
+#[cfg(feature = "system_term")]
+#[doc(hidden)]
+pub fn without_interrupts<F, R>(f: F) -> R
+where F: FnOnce() -> R {
+    const IF: u64 = 1 << 9;
+    let flags: u64;
+    unsafe {
+        asm!( "pushf
+               pop $0
+               cli"
+            : "=r"(flags) ::: "intel", "volatile");
+    }
+    let result = f();
+    if flags & IF != 0 {
+        unsafe { asm!("sti" :::: "volatile"); }
+    }
+    result
+}
+
 
 #[cfg(feature = "kinfo")]
 #[macro_use] pub mod kinfo;
@@ -12,28 +12,53 @@
 //! panics at runtime.
 
 use core::fmt::{Arguments, Write};
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use super::{Color, CONSOLE};
 
+/// A function the panic handler calls after printing the panic message,
+/// with the console and the message, file, and line of the panic.
+pub type PanicHook = fn(&mut Write, Arguments, &'static str, usize);
+
+/// The `PanicHook`, as an address, or 0 if there isn't one.
+static HOOK: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Set the function the panic handler calls after printing the message,
+/// to print a backtrace, write a crash dump, and so on.
+///
+/// This can only be set once; later calls do nothing.
+pub fn set_hook(f: PanicHook) {
+    HOOK.compare_and_swap(0, f as usize, Ordering::SeqCst);
+}
+
 /// Called to handle a panic.
 ///
 /// Since kernel panics are non-recoverable, this function prints out
 /// the error message and hangs forever.
 ///
-/// Eventually – way in the future – when we have disk I/O and stuff,
-/// we'll probably want to write out some core dumps here as well.
+/// If a `PanicHook` has been set, it's called after the message is printed;
+/// the kernel's hook prints a backtrace and writes a crash dump.
 #[lang = "panic_fmt"]
 #[no_mangle] #[inline(never)] #[cold]
 pub extern "C" fn rust_begin_unwind( args: Arguments
                                    , file: &'static str
                                    , line: usize )
                                    -> ! {
-    let _ = write!( CONSOLE.lock()
-                        .set_colors(Color::White, Color::Red)
+    let mut console = CONSOLE.lock();
+    let _ = write!( console.set_colors(Color::White, Color::Red)
                   , "Something has gone horribly wrong in {} at line {}. \
                     \n{}\n\
                     This is fine."
                   , file, line, args
                   );
     error!(target: file, "{}", args);
+    match HOOK.load(Ordering::SeqCst) {
+        0 => {}
+      , f => {
+            // this is safe; only `set_hook` stores to `HOOK`.
+            let hook: PanicHook = unsafe { mem::transmute(f) };
+            hook(&mut *console, args, file, line);
+        }
+    }
     loop { }
 }